@@ -57,6 +57,16 @@ impl Context {
         }
     }
 
+    /// Creates a context with its own app broadcaster, so that the workers using it can be
+    /// exited without exiting the app, see [`crate::WorkersHandle::remove_group`]
+    pub fn child(&self, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            config: self.config.clone(),
+            app: tokio::sync::broadcast::Sender::new(10),
+        }
+    }
+
     pub fn exit(&self) -> bool {
         self.app.send(AppMessage::Exit).is_ok()
     }
@@ -71,6 +81,16 @@ impl Context {
         Ok(message.to_string())
     }
 
+    /// Identifies this instance of the app among its replicas.
+    ///
+    /// Read from `instance_id`, falling back to the host name and the process id.
+    pub fn instance_id(&self) -> String {
+        self.config
+            .get_string("instance_id")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or_else(|_| format!("{}-{}", self.name, std::process::id()))
+    }
+
     pub fn etcd_url(&self) -> AppResult<String> {
         self.config.get_string("etcd_url").map_err(AppError::from)
     }
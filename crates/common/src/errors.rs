@@ -15,7 +15,7 @@ pub enum AppError {
     #[error("{0}")]
     ConfigError(String),
     #[error("{0}")]
-    EtcdClientError(#[from] etcd_client::Error),
+    EtcdClientError(Box<etcd_client::Error>),
     #[error("{0}")]
     SerdeJsonError(#[from] serde_json::Error),
     #[error("{0}")]
@@ -28,6 +28,8 @@ pub enum AppError {
     ReqwestError(#[from] reqwest::Error),
     #[error("{0}")]
     PostgresError(#[from] tokio_postgres::Error),
+    #[error("json-rpc error {code}: {message}")]
+    JsonRpcError { code: i64, message: String },
 }
 
 impl From<config::ConfigError> for AppError {
@@ -35,3 +37,9 @@ impl From<config::ConfigError> for AppError {
         AppError::ConfigError(error.to_string())
     }
 }
+
+impl From<etcd_client::Error> for AppError {
+    fn from(error: etcd_client::Error) -> Self {
+        AppError::EtcdClientError(Box::new(error))
+    }
+}
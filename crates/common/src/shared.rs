@@ -25,7 +25,7 @@ impl<T> SharedRef<T> {
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
         self.inner.lock().unwrap()
     }
 }
@@ -55,15 +55,15 @@ impl<T> SharedAsyncRef<T> {
         }
     }
 
-    pub async fn lock(&self) -> tokio::sync::RwLockWriteGuard<T> {
+    pub async fn lock(&self) -> tokio::sync::RwLockWriteGuard<'_, T> {
         self.inner.write().await
     }
 
-    pub async fn read(&self) -> tokio::sync::RwLockReadGuard<T> {
+    pub async fn read(&self) -> tokio::sync::RwLockReadGuard<'_, T> {
         self.inner.read().await
     }
 
-    pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<T> {
+    pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<'_, T> {
         self.inner.write().await
     }
 }
@@ -93,11 +93,11 @@ impl<T> SharedRwRef<T> {
         }
     }
 
-    pub fn read(&self) -> RwLockReadGuard<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
         self.inner.read().unwrap()
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<T> {
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
         self.inner.write().unwrap()
     }
 }
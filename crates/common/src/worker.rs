@@ -1,9 +1,9 @@
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
-use std::time::Duration;
-use tokio::time::timeout;
+use std::{collections::HashMap, time::Duration};
+use tokio::{sync::mpsc::Sender, time::timeout};
 
-use crate::{AppResult, Context, SharedRef};
+use crate::{AppError, AppResult, Context, MpSc, SharedRef};
 
 pub type SpawnResult = tokio::task::JoinHandle<AppResult<String>>;
 pub type WorkerRef = Box<dyn Worker + Send + Sync>;
@@ -38,11 +38,53 @@ impl RunningFlag {
     }
 }
 
+enum WorkersCommand {
+    AddGroup(String, Box<Context>, Vec<WorkerRef>),
+    RemoveGroup(String),
+}
+
+/// Adds and removes named groups of workers while the [`Workers`] are running.
+#[derive(Clone)]
+pub struct WorkersHandle {
+    sender: Sender<WorkersCommand>,
+}
+
+impl WorkersHandle {
+    /// Spawns a group of workers under a name, replacing the group of the same name.
+    ///
+    /// The workers of the group must be created with `context`, usually a
+    /// [`Context::child`], which is exited when the group is removed or the app exits.
+    pub fn add_group(
+        &self,
+        name: &str,
+        context: Context,
+        workers: Vec<WorkerRef>,
+    ) -> AppResult<()> {
+        self.send(WorkersCommand::AddGroup(
+            name.to_string(),
+            Box::new(context),
+            workers,
+        ))
+    }
+
+    /// Exits the workers of the group
+    pub fn remove_group(&self, name: &str) -> AppResult<()> {
+        self.send(WorkersCommand::RemoveGroup(name.to_string()))
+    }
+
+    fn send(&self, command: WorkersCommand) -> AppResult<()> {
+        self.sender
+            .try_send(command)
+            .map_err(|e| AppError::GenericError(format!("failed to send workers command: {}", e)))
+    }
+}
+
 pub struct Workers {
     context: Context,
     delay_millis: u64,
     workers: Vec<WorkerRef>,
     running: RunningFlag,
+    commands: MpSc<WorkersCommand>,
 }
 
 impl Workers {
@@ -52,6 +94,7 @@ impl Workers {
             delay_millis,
             workers: vec![],
             running: RunningFlag::default(),
+            commands: MpSc::new(100),
         }
     }
 
@@ -59,6 +102,13 @@ impl Workers {
         self.workers.push(worker);
     }
 
+    /// Handle to add and remove groups of workers, also before the workers are spawned
+    pub fn handle(&self) -> WorkersHandle {
+        WorkersHandle {
+            sender: self.commands.sender(),
+        }
+    }
+
     pub async fn run(&mut self) -> AppResult<String> {
         self.spawn().await.unwrap()
     }
@@ -67,6 +117,7 @@ impl Workers {
 impl Worker for Workers {
     fn spawn(&mut self) -> SpawnResult {
         let workers = self.workers.drain(..).collect::<Vec<WorkerRef>>();
+        let mut commands = self.commands.receiver();
         let running = self.running.clone();
         let context = self.context.clone();
         let delay_millis = self.delay_millis;
@@ -90,8 +141,44 @@ impl Worker for Workers {
             // enforcing a specific order
             let mut futures = futures.into_iter().collect::<FuturesUnordered<_>>();
 
+            // Workers of groups exit on their own when the group is removed, only a failure of
+            // one of them exits the app
+            let mut groups: HashMap<String, Context> = HashMap::new();
+            let mut group_futures = FuturesUnordered::new();
+
             // Wait for the first future to complete
-            if let Some(result) = futures.next().await {
+            let result = loop {
+                tokio::select! {
+                    result = futures.next() => break result,
+                    Some(command) = async { commands.as_mut()?.recv().await } => {
+                        match command {
+                            WorkersCommand::AddGroup(name, group_context, workers) => {
+                                if let Some(previous) = groups.insert(name.clone(), *group_context) {
+                                    previous.exit();
+                                }
+                                for mut worker in workers {
+                                    group_futures.push(worker.spawn());
+                                }
+                                log::info!("{} spawned worker group {}", context.name, name);
+                            }
+                            WorkersCommand::RemoveGroup(name) => match groups.remove(&name) {
+                                Some(group_context) => {
+                                    group_context.exit();
+                                    log::info!("{} removed worker group {}", context.name, name);
+                                }
+                                None => log::warn!("{} has no worker group {}", context.name, name),
+                            },
+                        }
+                    }
+                    Some(result) = group_futures.next() => {
+                        match result {
+                            Ok(Ok(name)) => log::info!("{} exited", name),
+                            result => break Some(result),
+                        }
+                    }
+                }
+            };
+            if let Some(result) = result {
                 if let Ok(result) = result {
                     match result {
                         Err(err) => {
@@ -105,6 +192,10 @@ impl Worker for Workers {
                     log::error!("worker error - {:?}", result);
                 }
             }
+            for group_context in groups.values() {
+                group_context.exit();
+            }
+            futures.extend(group_futures);
 
             // Sending exit signal to the other workers
             if !context.exit() {
@@ -156,3 +247,76 @@ impl Worker for Workers {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::*;
+
+    struct ExitWorker {
+        context: Context,
+        exited: SharedRef<bool>,
+    }
+
+    impl Worker for ExitWorker {
+        fn spawn(&mut self) -> SpawnResult {
+            let context = self.context.clone();
+            let exited = self.exited.clone();
+            let mut app = context.app.subscribe();
+            tokio::spawn(async move {
+                app.recv().await.ok();
+                *exited.lock() = true;
+                Ok(format!("{} exited", context.name))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_workers_add_and_remove_groups() {
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut workers = Workers::new(context.clone(), 0);
+        let app_exited = SharedRef::new(false);
+        workers.add_worker(Box::new(ExitWorker {
+            context: context.clone(),
+            exited: app_exited.clone(),
+        }));
+        let handle = workers.handle();
+        let spawned = workers.spawn();
+
+        let group = |name: &str| {
+            let group_context = context.child(name);
+            let exited = SharedRef::new(false);
+            let worker: WorkerRef = Box::new(ExitWorker {
+                context: group_context.clone(),
+                exited: exited.clone(),
+            });
+            (group_context, vec![worker], exited)
+        };
+        let (binance_context, binance_workers, binance_exited) = group("binance");
+        handle
+            .add_group("binance", binance_context, binance_workers)
+            .unwrap();
+        let (kraken_context, kraken_workers, kraken_exited) = group("kraken");
+        handle
+            .add_group("kraken", kraken_context, kraken_workers)
+            .unwrap();
+
+        handle.remove_group("binance").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !*binance_exited.lock() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the removed group to exit");
+        assert!(!*kraken_exited.lock());
+        assert!(!*app_exited.lock());
+        assert!(!spawned.is_finished());
+
+        context.exit();
+        spawned.await.unwrap().unwrap();
+        assert!(*kraken_exited.lock());
+        assert!(*app_exited.lock());
+    }
+}
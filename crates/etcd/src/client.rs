@@ -1,12 +1,17 @@
+use std::collections::HashMap;
+
 use common::{AppError, AppResult, Context, SharedAsyncRef};
-use serde::de::DeserializeOwned;
+use etcd_client::PutOptions;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::EtcdClientOptions;
 
 /// A ETCD client that can be shared across threads.
 ///
 /// This is a lazy wrapper around an etcd client that can be shared across threads.
 #[derive(Clone)]
 pub struct EtcdClient {
-    etcd_url: String,
+    options: EtcdClientOptions,
     etcd_conn_manager: SharedAsyncRef<EtcdConnectionManager>,
 }
 
@@ -18,57 +23,177 @@ struct EtcdConnectionManager {
 impl EtcdClient {
     /// Create a new ETCD client from a context
     pub fn from_context(context: &Context) -> AppResult<Self> {
-        let etcd_url = context.etcd_url()?;
-        Ok(Self::new(&etcd_url))
+        let options = EtcdClientOptions::from_context(context)?;
+        Ok(Self::with_options(options))
     }
 
-    /// Create a new ETCD client from a etcd url
+    /// Create a new ETCD client from a comma separated list of etcd urls
     pub fn new(etcd_url: &str) -> Self {
+        Self::with_options(EtcdClientOptions::new(etcd_url))
+    }
+
+    /// Create a new ETCD client with credentials, tls or timeouts
+    pub fn with_options(options: EtcdClientOptions) -> Self {
         Self {
-            etcd_url: etcd_url.to_string(),
+            options,
             etcd_conn_manager: SharedAsyncRef::default(),
         }
     }
 
     pub async fn connection_manager(&mut self) -> AppResult<etcd_client::Client> {
-        self.etcd_conn_manager
-            .lock()
-            .await
-            .get(&self.etcd_url)
-            .await
+        self.etcd_conn_manager.lock().await.get(&self.options).await
+    }
+
+    /// Drops the cached connection, so that the next request reconnects
+    pub async fn reset(&mut self) {
+        self.etcd_conn_manager.lock().await.etcd_conn_manager = None;
     }
 
     /// Watch a key
     pub async fn watch(
         &mut self,
         key: &str,
+    ) -> AppResult<(etcd_client::Watcher, etcd_client::WatchStream)> {
+        self.watch_from_revision(key, None).await
+    }
+
+    /// Watch a key, starting at the given revision instead of the current one
+    pub async fn watch_from_revision(
+        &mut self,
+        key: &str,
+        start_revision: Option<i64>,
     ) -> AppResult<(etcd_client::Watcher, etcd_client::WatchStream)> {
         let mut client = self.connection_manager().await?;
-        let (watcher, stream) = client.watch(key, None).await?;
+        let options = start_revision
+            .map(|revision| etcd_client::WatchOptions::new().with_start_revision(revision));
+        let (watcher, stream) = client.watch(key, options).await?;
         Ok((watcher, stream))
     }
 
+    /// Watch all keys under a prefix, starting at the given revision instead of the current one
+    pub async fn watch_prefix_from_revision(
+        &mut self,
+        prefix: &str,
+        start_revision: Option<i64>,
+    ) -> AppResult<(etcd_client::Watcher, etcd_client::WatchStream)> {
+        let mut client = self.connection_manager().await?;
+        let mut options = etcd_client::WatchOptions::new().with_prefix();
+        if let Some(revision) = start_revision {
+            options = options.with_start_revision(revision);
+        }
+        let (watcher, stream) = client.watch(prefix, Some(options)).await?;
+        Ok((watcher, stream))
+    }
+
+    /// Get all keys under a prefix, keyed by the full key
+    pub async fn get_prefix<M: DeserializeOwned>(
+        &mut self,
+        prefix: &str,
+    ) -> AppResult<HashMap<String, M>> {
+        let (values, _) = self.get_prefix_with_revision(prefix).await?;
+        Ok(values)
+    }
+
+    /// Get all keys under a prefix, keyed by the full key, along with the revision of the store
+    /// they were read at
+    pub async fn get_prefix_with_revision<M: DeserializeOwned>(
+        &mut self,
+        prefix: &str,
+    ) -> AppResult<(HashMap<String, M>, i64)> {
+        let (kvs, revision) = self.get_prefix_kvs(prefix).await?;
+        let values = kvs
+            .iter()
+            .map(|kv| {
+                let key = String::from_utf8_lossy(kv.key()).to_string();
+                let value = serde_json::from_slice::<M>(kv.value()).map_err(|e| {
+                    AppError::ConfigError(format!("invalid value of key {}: {}", key, e))
+                })?;
+                Ok((key, value))
+            })
+            .collect::<AppResult<HashMap<_, _>>>()?;
+        Ok((values, revision))
+    }
+
+    /// Get the raw key values under a prefix, along with the revision of the store they were
+    /// read at
+    pub async fn get_prefix_kvs(
+        &mut self,
+        prefix: &str,
+    ) -> AppResult<(Vec<etcd_client::KeyValue>, i64)> {
+        let mut client = self.connection_manager().await?;
+        let mut response = client
+            .get(prefix, Some(etcd_client::GetOptions::new().with_prefix()))
+            .await?;
+        let revision = response
+            .header()
+            .map(|header| header.revision())
+            .unwrap_or(0);
+        Ok((response.take_kvs(), revision))
+    }
+
     /// Get a key
     pub async fn get<M: DeserializeOwned>(&mut self, key: &str) -> AppResult<M> {
-        let mut client = self.connection_manager().await?;
-        let response = client.get(key, None).await?;
+        let (value, _) = self.get_with_revision(key).await?;
+        Ok(value)
+    }
 
-        let value = response
-            .kvs()
-            .first()
+    /// Get a key along with the revision of the store it was read at
+    pub async fn get_with_revision<M: DeserializeOwned>(
+        &mut self,
+        key: &str,
+    ) -> AppResult<(M, i64)> {
+        let (kv, revision) = self.get_kv(key).await?;
+        let value = kv
             .ok_or_else(|| AppError::ConfigError(format!("key {} not found", key)))
             .and_then(|kv| {
                 serde_json::from_slice::<M>(kv.value()).map_err(AppError::SerdeJsonError)
             })?;
 
-        Ok(value)
+        Ok((value, revision))
+    }
+
+    /// Get the raw key value, if present, along with the revision of the store it was read at
+    pub async fn get_kv(&mut self, key: &str) -> AppResult<(Option<etcd_client::KeyValue>, i64)> {
+        let mut client = self.connection_manager().await?;
+        let mut response = client.get(key, None).await?;
+        let revision = response
+            .header()
+            .map(|header| header.revision())
+            .unwrap_or(0);
+        let kv = response.take_kvs().into_iter().next();
+        Ok((kv, revision))
+    }
+
+    /// Put a value as json
+    pub async fn put<M: Serialize>(&mut self, key: &str, value: &M) -> AppResult<()> {
+        let mut client = self.connection_manager().await?;
+        let value = serde_json::to_vec(value)?;
+        client.put(key, value, None).await?;
+        Ok(())
+    }
+
+    /// Put a value as json, bound to a lease so that it is deleted when the lease expires
+    pub async fn put_with_lease<M: Serialize>(
+        &mut self,
+        key: &str,
+        value: &M,
+        lease: i64,
+    ) -> AppResult<()> {
+        let mut client = self.connection_manager().await?;
+        let value = serde_json::to_vec(value)?;
+        client
+            .put(key, value, Some(PutOptions::new().with_lease(lease)))
+            .await?;
+        Ok(())
     }
 }
 
 impl EtcdConnectionManager {
-    pub async fn get(&mut self, etcd_url: &str) -> AppResult<etcd_client::Client> {
+    pub async fn get(&mut self, options: &EtcdClientOptions) -> AppResult<etcd_client::Client> {
         if self.etcd_conn_manager.is_none() {
-            let client = etcd_client::Client::connect(vec![etcd_url], None).await?;
+            let client =
+                etcd_client::Client::connect(&options.endpoints, options.connect_options()?)
+                    .await?;
             self.etcd_conn_manager = Some(client);
         }
         Ok(self.etcd_conn_manager.clone().unwrap())
@@ -0,0 +1,272 @@
+use std::time::Duration;
+
+use common::{AppError, AppMessage, AppResult, Backoff, Context, SharedRef, SpawnResult, Worker};
+use etcd_client::ResignOptions;
+use tokio::{sync::broadcast::Receiver, time::Instant};
+
+use crate::{EtcdClient, ETCD_LEADER};
+
+/// Whether this instance currently holds the leadership of a [`LeaderElection`].
+///
+/// The leadership lapses on its own once the lease hasn't been kept alive for its ttl, even
+/// before the election gets to step down, as a standby may already have taken over by then.
+#[derive(Clone)]
+pub struct Leadership {
+    is_leader: SharedRef<bool>,
+    valid_until: SharedRef<Option<Instant>>,
+}
+
+impl Leadership {
+    fn new(is_leader: bool) -> Self {
+        Self {
+            is_leader: SharedRef::new(is_leader),
+            valid_until: SharedRef::new(None),
+        }
+    }
+
+    /// Leadership of an instance running without an election, which always leads
+    pub fn always() -> Self {
+        Self::new(true)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.is_leader.lock() && self.valid_until.lock().is_none_or(|t| Instant::now() < t)
+    }
+
+    fn set(&self, is_leader: bool) {
+        *self.is_leader.lock() = is_leader;
+    }
+
+    /// Extends the leadership until the lease may have expired
+    fn renew(&self, valid_until: Instant) {
+        *self.valid_until.lock() = Some(valid_until);
+    }
+}
+
+/// Campaigns for the leadership of an election among the replicas of the app.
+///
+/// The campaign is bound to a lease of `ttl_secs`, so if the leader dies a standby takes
+/// over once the lease expires. On exit the leader resigns and revokes its lease, so that
+/// a standby takes over right away.
+#[derive(Clone)]
+pub struct LeaderElection {
+    context: Context,
+    client: EtcdClient,
+    name: String,
+    ttl_secs: i64,
+    leadership: Leadership,
+}
+
+impl LeaderElection {
+    pub fn new(context: Context, client: EtcdClient, name: String, ttl_secs: i64) -> Self {
+        Self {
+            context,
+            client,
+            name,
+            ttl_secs,
+            leadership: Leadership::new(false),
+        }
+    }
+
+    /// Creates the election from the static config, if `leader_election_key` is set.
+    ///
+    /// The lease ttl is read from `leader_election_ttl_secs` (default 5).
+    pub fn from_context(context: Context, client: EtcdClient) -> Option<Self> {
+        let name = context.config.get_string("leader_election_key").ok()?;
+        let ttl_secs = context
+            .config
+            .get_int("leader_election_ttl_secs")
+            .unwrap_or(5);
+        Some(Self::new(context, client, name, ttl_secs))
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.clone()
+    }
+
+    fn set_leader(&self, is_leader: bool) {
+        self.leadership.set(is_leader);
+        ETCD_LEADER
+            .with_label_values(&[&self.name])
+            .set(if is_leader { 1.0 } else { 0.0 });
+    }
+
+    /// Campaigns while keeping the lease alive, and holds the leadership until the app exits
+    /// or the lease is lost. The lease counts as lost once `ttl_secs` pass without a
+    /// successful keep alive, as etcd may have expired it by then.
+    async fn campaign(&self, app: &mut Receiver<AppMessage>) -> AppResult<String> {
+        let mut client = self.client.clone().connection_manager().await?;
+        let ttl = Duration::from_secs(self.ttl_secs.max(1) as u64);
+        let granted_at = Instant::now();
+        let lease = client.lease_grant(self.ttl_secs, None).await?.id();
+        let lease_expiry = tokio::time::sleep_until(granted_at + ttl);
+        tokio::pin!(lease_expiry);
+        self.leadership.renew(lease_expiry.deadline());
+        let (mut keeper, mut keep_alive_stream) = client.lease_keep_alive(lease).await?;
+        let mut keep_alive_interval = tokio::time::interval(Duration::from_millis(
+            (self.ttl_secs.max(1) * 1000 / 3) as u64,
+        ));
+
+        let mut campaign_client = client.clone();
+        let instance_id = self.context.instance_id();
+        let campaign = campaign_client.campaign(self.name.as_str(), instance_id.as_str(), lease);
+        tokio::pin!(campaign);
+        let mut campaigning = true;
+        let mut leader_key = None;
+
+        log::info!(
+            "{} campaigning for {} as {}",
+            self.context.name,
+            self.name,
+            instance_id
+        );
+        let result = loop {
+            tokio::select! {
+                _ = app.recv() => {
+                    log::info!("{} received exit message", self.context.name);
+                    break Ok(format!("{} received exit message", self.context.name));
+                }
+                _ = &mut lease_expiry => {
+                    break Err(AppError::GenericError(format!(
+                        "lease of {} not kept alive within {}s",
+                        self.name, self.ttl_secs
+                    )));
+                }
+                _ = keep_alive_interval.tick() => {
+                    match tokio::time::timeout_at(lease_expiry.deadline(), keeper.keep_alive()).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => break Err(e.into()),
+                        Err(_) => {
+                            break Err(AppError::GenericError(format!(
+                                "lease keep alive of {} timed out",
+                                self.name
+                            )));
+                        }
+                    }
+                }
+                response = keep_alive_stream.message() => {
+                    match response {
+                        Ok(Some(response)) if response.ttl() > 0 => {
+                            lease_expiry.as_mut().reset(Instant::now() + ttl);
+                            self.leadership.renew(lease_expiry.deadline());
+                        }
+                        Ok(Some(_)) => {
+                            break Err(AppError::GenericError(format!("lease of {} expired", self.name)));
+                        }
+                        Ok(None) => {
+                            break Err(AppError::GenericError(format!("lease keep alive of {} closed", self.name)));
+                        }
+                        Err(e) => break Err(e.into()),
+                    }
+                }
+                response = &mut campaign, if campaigning => {
+                    campaigning = false;
+                    match response {
+                        Ok(mut response) => {
+                            leader_key = response.take_leader();
+                            log::info!("{} became leader of {}", self.context.name, self.name);
+                            self.set_leader(true);
+                        }
+                        Err(e) => break Err(e.into()),
+                    }
+                }
+            }
+        };
+
+        if self.leadership.is_leader() {
+            log::warn!(
+                "{} stepping down as leader of {}",
+                self.context.name,
+                self.name
+            );
+        }
+        self.set_leader(false);
+        if let Some(leader_key) = leader_key {
+            if let Err(e) = client
+                .resign(Some(ResignOptions::new().with_leader(leader_key)))
+                .await
+            {
+                log::warn!(
+                    "{} failed to resign from {}: {}",
+                    self.context.name,
+                    self.name,
+                    e
+                );
+            }
+        }
+        if let Err(e) = client.lease_revoke(lease).await {
+            log::warn!("{} failed to revoke lease: {}", self.context.name, e);
+        }
+        result
+    }
+}
+
+impl Worker for LeaderElection {
+    fn spawn(&mut self) -> SpawnResult {
+        let mut election = self.clone();
+
+        tokio::spawn(async move {
+            let mut app = election.context.app.subscribe();
+            let mut backoff = Backoff::new(0, 1, 10, 2);
+            loop {
+                let e = match election.campaign(&mut app).await {
+                    Ok(message) => return Ok(message),
+                    Err(e) => e,
+                };
+
+                let delay_secs = backoff.next().unwrap_or(10);
+                log::error!(
+                    "{} election {} failed, retrying in {}s: {}",
+                    election.context.name,
+                    election.name,
+                    delay_secs,
+                    e
+                );
+                election.client.reset().await;
+                tokio::select! {
+                    _ = app.recv() => {
+                        return Ok(format!("{} received exit message", election.context.name));
+                    }
+                    _ = tokio::time::sleep(Duration::from_secs(delay_secs as u64)) => {}
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_leader_election_from_context() {
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let client = EtcdClient::new("http://localhost:2379");
+        assert!(LeaderElection::from_context(context, client.clone()).is_none());
+
+        let config = Config::builder()
+            .set_override("leader_election_key", "/aionex/indexer/leader")
+            .unwrap()
+            .build()
+            .unwrap();
+        let election = LeaderElection::from_context(Context::from_config(config), client).unwrap();
+        assert_eq!(election.name, "/aionex/indexer/leader");
+        assert_eq!(election.ttl_secs, 5);
+        assert!(!election.leadership().is_leader());
+        assert!(Leadership::always().is_leader());
+    }
+
+    #[test]
+    fn test_leadership_lapses_without_keep_alive() {
+        let leadership = Leadership::new(false);
+        leadership.renew(Instant::now() + Duration::from_secs(5));
+        assert!(!leadership.is_leader());
+        leadership.set(true);
+        assert!(leadership.is_leader());
+
+        leadership.renew(Instant::now() - Duration::from_millis(1));
+        assert!(!leadership.is_leader());
+    }
+}
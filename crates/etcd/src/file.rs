@@ -0,0 +1,448 @@
+use std::{
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use common::{AppError, AppResult, Context, SpawnResult, Worker, WorkerRef};
+use notify::{RecursiveMode, Watcher};
+use serde::de::DeserializeOwned;
+
+use crate::{ConfigSource, EtcdWatcherHandler, CONFIG_FILE_INVALID_UPDATES, CONFIG_FILE_UPDATES};
+
+/// Format of a config file, derived from its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFileFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFileFormat {
+    pub fn from_path(path: &Path) -> AppResult<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("yaml") | Some("yml") => Ok(Self::Yaml),
+            Some("toml") => Ok(Self::Toml),
+            _ => Err(AppError::ConfigError(format!(
+                "config file {} must be json, yaml or toml",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn parse<C: DeserializeOwned>(&self, content: &str) -> AppResult<C> {
+        match self {
+            Self::Json => serde_json::from_str(content).map_err(AppError::from),
+            Self::Yaml => serde_yaml::from_str(content)
+                .map_err(|e| AppError::ConfigError(format!("invalid yaml: {}", e))),
+            Self::Toml => toml::from_str(content)
+                .map_err(|e| AppError::ConfigError(format!("invalid toml: {}", e))),
+        }
+    }
+}
+
+/// Config held in a local json, yaml or toml file, e.g. to run without etcd.
+pub struct FileConfigSource {
+    context: Context,
+    path: PathBuf,
+    format: ConfigFileFormat,
+    /// Content read by the last load, which the watcher compares changes against
+    content: Option<String>,
+}
+
+impl FileConfigSource {
+    pub fn new(context: Context, path: PathBuf) -> AppResult<Self> {
+        let format = ConfigFileFormat::from_path(&path)?;
+        Ok(Self {
+            context,
+            path,
+            format,
+            content: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<H, C> ConfigSource<H, C> for FileConfigSource
+where
+    H: EtcdWatcherHandler<C> + Clone + Send + Sync + 'static,
+    C: DeserializeOwned + Send + Sync + 'static + Clone,
+{
+    async fn load(&mut self) -> AppResult<C> {
+        let content = read_config_file(&self.path).await?;
+        let config = self.format.parse(&content)?;
+        self.content = Some(content);
+        Ok(config)
+    }
+
+    /// The loaded content is revision 0, edits count up from there
+    fn revision(&self) -> Option<i64> {
+        self.content.as_ref().map(|_| 0)
+    }
+
+    fn watcher(&self, handler: H) -> WorkerRef {
+        let mut watcher =
+            FileWatcher::<H, C>::new(self.context.clone(), self.path.clone(), self.format)
+                .with_content(self.content.clone());
+        watcher.add_handler(handler);
+        Box::new(watcher)
+    }
+}
+
+async fn read_config_file(path: &Path) -> AppResult<String> {
+    tokio::fs::read_to_string(path).await.map_err(|e| {
+        AppError::ConfigError(format!(
+            "failed to read config file {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Watches a config file and dispatches its changes to [`EtcdWatcherHandler`]s.
+///
+/// The directory of the file is watched rather than the file, so that editors replacing the
+/// file on save are picked up. Changes that fail to parse, validate or apply are rejected and
+/// the last good config stays in effect. Each change of the content is passed to the handlers
+/// as the next revision, counting from 0 for the content the watcher starts with.
+#[derive(Clone)]
+pub struct FileWatcher<H, C>
+where
+    H: EtcdWatcherHandler<C>,
+    C: DeserializeOwned + Send + 'static + Clone,
+{
+    context: Context,
+    path: PathBuf,
+    format: ConfigFileFormat,
+    content: Option<String>,
+    handlers: Vec<H>,
+    _marker: PhantomData<C>,
+}
+
+impl<H, C> FileWatcher<H, C>
+where
+    H: EtcdWatcherHandler<C> + Clone + Send + 'static,
+    C: DeserializeOwned + Send + 'static + Clone,
+{
+    pub fn new(context: Context, path: PathBuf, format: ConfigFileFormat) -> Self {
+        Self {
+            context,
+            path,
+            format,
+            content: None,
+            handlers: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the content the config was loaded from, so that only later changes are dispatched
+    pub fn with_content(mut self, content: Option<String>) -> Self {
+        self.content = content;
+        self
+    }
+
+    pub fn add_handler(&mut self, handler: H) {
+        self.handlers.push(handler);
+    }
+
+    /// Parses the content of the file and has every handler validate it
+    pub fn parse_update(&self, content: &str) -> AppResult<C> {
+        let config = self.format.parse::<C>(content)?;
+        for handler in self.handlers.iter() {
+            handler.validate(&config)?;
+        }
+        Ok(config)
+    }
+
+    fn handle_update(&self, content: &str, revision: i64) {
+        let path = self.path.display().to_string();
+        let result = self.parse_update(content).and_then(|config| {
+            for handler in self.handlers.iter() {
+                handler.handle_config_update(config.clone(), revision)?;
+            }
+            Ok(())
+        });
+        match result {
+            Ok(_) => {
+                CONFIG_FILE_UPDATES.with_label_values(&[&path]).inc();
+            }
+            Err(e) => {
+                log::error!(
+                    "{} rejected update {} of config file {}, keeping the last good config: {}",
+                    self.context.name,
+                    revision,
+                    path,
+                    e
+                );
+                CONFIG_FILE_INVALID_UPDATES
+                    .with_label_values(&[&path])
+                    .inc();
+            }
+        }
+    }
+
+    /// Reads the file and applies it as the next revision if it differs from the last content
+    async fn reload(&self, last_content: &mut Option<String>, revision: &mut i64) {
+        let content = match read_config_file(&self.path).await {
+            Ok(content) => content,
+            Err(e) => {
+                log::warn!("{} {}", self.context.name, e);
+                return;
+            }
+        };
+        if last_content.as_ref() == Some(&content) {
+            return;
+        }
+        *revision += 1;
+        self.handle_update(&content, *revision);
+        *last_content = Some(content);
+    }
+}
+
+impl<H, C> Worker for FileWatcher<H, C>
+where
+    H: EtcdWatcherHandler<C> + Clone + Send + Sync + 'static,
+    C: DeserializeOwned + Send + Sync + 'static + Clone,
+{
+    fn spawn(&mut self) -> SpawnResult {
+        let watcher = self.clone();
+
+        tokio::spawn(async move {
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
+            let file_name = watcher.path.file_name().map(|name| name.to_os_string());
+            let mut fs_watcher =
+                notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                    let relevant = match &event {
+                        Ok(event) => event.paths.iter().any(|path| {
+                            path.file_name().map(|name| name.to_os_string()) == file_name
+                        }),
+                        Err(_) => true,
+                    };
+                    if relevant {
+                        let _ = sender.try_send(event);
+                    }
+                })
+                .map_err(|e| {
+                    AppError::GenericError(format!("failed to watch config file: {}", e))
+                })?;
+            let directory = match watcher.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            fs_watcher
+                .watch(&directory, RecursiveMode::NonRecursive)
+                .map_err(|e| {
+                    AppError::GenericError(format!("failed to watch config file: {}", e))
+                })?;
+
+            log::info!(
+                "starting {} watcher for config file: {}",
+                watcher.context.name,
+                watcher.path.display()
+            );
+            let mut app = watcher.context.app.subscribe();
+            let mut last_content = watcher.content.clone();
+            let mut revision = 0;
+            // Edits saved after the load but before the watch was registered raise no event
+            watcher.reload(&mut last_content, &mut revision).await;
+            loop {
+                tokio::select! {
+                    _ = app.recv() => {
+                        log::info!("{} received exit message", watcher.context.name);
+                        return Ok(format!("{} received exit message", watcher.context.name));
+                    }
+                    event = receiver.recv() => {
+                        match event {
+                            Some(Ok(_)) => {}
+                            Some(Err(e)) => {
+                                log::error!("{} config file watch error: {}", watcher.context.name, e);
+                                continue;
+                            }
+                            None => return Err(AppError::GenericError("config file watch closed".to_string())),
+                        }
+                        // A save usually raises several events, let them settle before reading
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        while receiver.try_recv().is_ok() {}
+                        watcher.reload(&mut last_content, &mut revision).await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use serde::Deserialize;
+
+    use common::SharedRef;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Deserialize, PartialEq)]
+    struct TestConfig {
+        weight: u32,
+    }
+
+    #[derive(Clone, Default)]
+    struct TestHandler {
+        configs: SharedRef<Vec<TestConfig>>,
+        revisions: SharedRef<Vec<i64>>,
+    }
+
+    impl EtcdWatcherHandler<TestConfig> for TestHandler {
+        fn validate(&self, config: &TestConfig) -> AppResult<()> {
+            if config.weight > 100 {
+                return Err(AppError::ConfigError("weight above 100".to_string()));
+            }
+            Ok(())
+        }
+
+        fn handle_config_change(&self, config: TestConfig) {
+            self.configs.lock().push(config);
+        }
+
+        fn handle_config_update(&self, config: TestConfig, revision: i64) -> AppResult<()> {
+            // Passes validation but fails to apply
+            if config.weight == 70 {
+                return Err(AppError::ConfigError(
+                    "weight 70 can't be applied".to_string(),
+                ));
+            }
+            self.revisions.lock().push(revision);
+            self.handle_config_change(config);
+            Ok(())
+        }
+    }
+
+    fn context() -> Context {
+        Context::from_config(Config::builder().build().unwrap())
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("config-file-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_config_file_format() {
+        let yaml = ConfigFileFormat::from_path(Path::new("indexer.yml")).unwrap();
+        assert_eq!(yaml, ConfigFileFormat::Yaml);
+        assert_eq!(
+            yaml.parse::<TestConfig>("weight: 40").unwrap(),
+            TestConfig { weight: 40 }
+        );
+        let toml = ConfigFileFormat::from_path(Path::new("indexer.toml")).unwrap();
+        assert_eq!(
+            toml.parse::<TestConfig>("weight = 40").unwrap(),
+            TestConfig { weight: 40 }
+        );
+        let json = ConfigFileFormat::from_path(Path::new("indexer.json")).unwrap();
+        assert!(json.parse::<TestConfig>("weight = 40").is_err());
+        assert!(ConfigFileFormat::from_path(Path::new("indexer.ini")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_config_source_watches_changes() {
+        let dir = temp_dir("watch");
+        let path = dir.join("indexer.json");
+        std::fs::write(&path, r#"{"weight": 40}"#).unwrap();
+
+        let context = context();
+        let mut source = FileConfigSource::new(context.clone(), path.clone()).unwrap();
+        assert_eq!(
+            ConfigSource::<TestHandler, TestConfig>::revision(&source),
+            None
+        );
+        let config: TestConfig = ConfigSource::<TestHandler, TestConfig>::load(&mut source)
+            .await
+            .unwrap();
+        assert_eq!(config.weight, 40);
+        assert_eq!(
+            ConfigSource::<TestHandler, TestConfig>::revision(&source),
+            Some(0)
+        );
+
+        let handler = TestHandler::default();
+        let mut watcher =
+            ConfigSource::<TestHandler, TestConfig>::watcher(&source, handler.clone());
+        let handle = watcher.spawn();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Invalid updates are rejected, valid ones are dispatched
+        std::fs::write(&path, r#"{"weight": 140}"#).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        std::fs::write(&path, r#"{"weight": 60}"#).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handler.configs.lock().is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for config change");
+        assert_eq!(*handler.configs.lock(), vec![TestConfig { weight: 60 }]);
+
+        context.exit();
+        assert!(handle.await.unwrap().is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    async fn wait_for_configs(handler: &TestHandler, count: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handler.configs.lock().len() < count {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for config change");
+    }
+
+    #[tokio::test]
+    async fn test_file_watcher_dispatches_changes_after_load() {
+        let dir = temp_dir("after-load");
+        let path = dir.join("indexer.json");
+        std::fs::write(&path, r#"{"weight": 40}"#).unwrap();
+
+        let context = context();
+        let mut source = FileConfigSource::new(context.clone(), path.clone()).unwrap();
+        let config: TestConfig = ConfigSource::<TestHandler, TestConfig>::load(&mut source)
+            .await
+            .unwrap();
+        assert_eq!(config.weight, 40);
+
+        // Changed between the load and the start of the watcher
+        std::fs::write(&path, r#"{"weight": 50}"#).unwrap();
+        let handler = TestHandler::default();
+        let mut watcher =
+            ConfigSource::<TestHandler, TestConfig>::watcher(&source, handler.clone());
+        let handle = watcher.spawn();
+        // Applied once the watch is registered, without any further filesystem event
+        wait_for_configs(&handler, 1).await;
+
+        let invalid = CONFIG_FILE_INVALID_UPDATES
+            .with_label_values(&[&path.display().to_string()])
+            .get();
+        std::fs::write(&path, r#"{"weight": 70}"#).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        std::fs::write(&path, r#"{"weight": 60}"#).unwrap();
+        wait_for_configs(&handler, 2).await;
+        assert_eq!(
+            *handler.configs.lock(),
+            vec![TestConfig { weight: 50 }, TestConfig { weight: 60 }]
+        );
+        assert_eq!(*handler.revisions.lock(), vec![1, 3]);
+        assert_eq!(
+            CONFIG_FILE_INVALID_UPDATES
+                .with_label_values(&[&path.display().to_string()])
+                .get(),
+            invalid + 1.0
+        );
+
+        context.exit();
+        assert!(handle.await.unwrap().is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
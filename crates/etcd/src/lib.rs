@@ -1,7 +1,17 @@
 mod client;
+mod election;
+mod file;
 mod metrics;
+mod options;
+mod source;
+mod status;
 mod watcher;
 
 pub use client::*;
+pub use election::*;
+pub use file::*;
 pub use metrics::*;
+pub use options::*;
+pub use source::*;
+pub use status::*;
 pub use watcher::*;
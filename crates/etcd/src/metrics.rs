@@ -11,4 +11,43 @@ lazy_static! {
         &["key"]
     )
     .unwrap();
+    pub static ref ETCD_WATCHER_INVALID_UPDATES: prom::CounterVec = prom::register_counter_vec!(
+        "etcd_watcher_invalid_updates",
+        "Etcd watcher key updates rejected as invalid",
+        &["key"]
+    )
+    .unwrap();
+    pub static ref ETCD_WATCHER_HEALTHY: prom::GaugeVec = prom::register_gauge_vec!(
+        "etcd_watcher_healthy",
+        "Etcd watcher has an open watch stream",
+        &["key"]
+    )
+    .unwrap();
+    pub static ref ETCD_WATCHER_RECONNECTS: prom::CounterVec = prom::register_counter_vec!(
+        "etcd_watcher_reconnects",
+        "Etcd watcher reconnects after a failed watch",
+        &["key"]
+    )
+    .unwrap();
+    pub static ref CONFIG_FILE_UPDATES: prom::CounterVec =
+        prom::register_counter_vec!("config_file_updates", "Config file updates", &["path"])
+            .unwrap();
+    pub static ref CONFIG_FILE_INVALID_UPDATES: prom::CounterVec = prom::register_counter_vec!(
+        "config_file_invalid_updates",
+        "Config file updates rejected as invalid",
+        &["path"]
+    )
+    .unwrap();
+    pub static ref ETCD_STATUS_PUBLISHED: prom::CounterVec = prom::register_counter_vec!(
+        "etcd_status_published",
+        "Status updates published to etcd",
+        &["key"]
+    )
+    .unwrap();
+    pub static ref ETCD_LEADER: prom::GaugeVec = prom::register_gauge_vec!(
+        "etcd_leader",
+        "Instance is the leader of the election",
+        &["election"]
+    )
+    .unwrap();
 }
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use common::{AppError, AppResult, Context};
+use etcd_client::{Certificate, ConnectOptions, Identity, TlsOptions};
+
+/// Endpoints, credentials, tls and timeouts used to connect to etcd.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EtcdClientOptions {
+    pub endpoints: Vec<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// CA certificate used to verify the etcd servers, in PEM format
+    pub ca_cert_path: Option<String>,
+    /// Client certificate presented to the etcd servers, in PEM format
+    pub cert_path: Option<String>,
+    /// Private key of the client certificate, in PEM format
+    pub key_path: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+}
+
+impl EtcdClientOptions {
+    /// Options connecting to a comma separated list of endpoints without auth or tls
+    pub fn new(endpoints: &str) -> Self {
+        Self {
+            endpoints: endpoints
+                .split(',')
+                .map(|endpoint| endpoint.trim().to_string())
+                .filter(|endpoint| !endpoint.is_empty())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads the options from the static config.
+    ///
+    /// `etcd_url` holds a comma separated list of endpoints. The optional `etcd_username`,
+    /// `etcd_password`, `etcd_ca_cert_path`, `etcd_cert_path`, `etcd_key_path`,
+    /// `etcd_connect_timeout_millis` and `etcd_request_timeout_millis` configure the rest.
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let config = &context.config;
+        let millis = |key: &str| {
+            config
+                .get_int(key)
+                .ok()
+                .map(|millis| Duration::from_millis(millis as u64))
+        };
+        Ok(Self {
+            username: config.get_string("etcd_username").ok(),
+            password: config.get_string("etcd_password").ok(),
+            ca_cert_path: config.get_string("etcd_ca_cert_path").ok(),
+            cert_path: config.get_string("etcd_cert_path").ok(),
+            key_path: config.get_string("etcd_key_path").ok(),
+            connect_timeout: millis("etcd_connect_timeout_millis"),
+            request_timeout: millis("etcd_request_timeout_millis"),
+            ..Self::new(&context.etcd_url()?)
+        })
+    }
+
+    /// Builds the options passed to [`etcd_client::Client::connect`], reading the certificates
+    pub fn connect_options(&self) -> AppResult<Option<ConnectOptions>> {
+        let mut options = ConnectOptions::new();
+        let mut customized = false;
+
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => {
+                options = options.with_user(username.clone(), password.clone());
+                customized = true;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(AppError::ConfigError(
+                    "etcd username and password must be set together".to_string(),
+                ))
+            }
+        }
+
+        if let Some(tls) = self.tls_options()? {
+            options = options.with_tls(tls);
+            customized = true;
+        }
+        if let Some(timeout) = self.connect_timeout {
+            options = options.with_connect_timeout(timeout);
+            customized = true;
+        }
+        if let Some(timeout) = self.request_timeout {
+            options = options.with_timeout(timeout);
+            customized = true;
+        }
+
+        Ok(customized.then_some(options))
+    }
+
+    fn tls_options(&self) -> AppResult<Option<TlsOptions>> {
+        let identity = match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => Some(Identity::from_pem(
+                read_pem(cert_path)?,
+                read_pem(key_path)?,
+            )),
+            (None, None) => None,
+            _ => {
+                return Err(AppError::ConfigError(
+                    "etcd client certificate and key must be set together".to_string(),
+                ))
+            }
+        };
+
+        if !self.uses_https() && self.ca_cert_path.is_none() && identity.is_none() {
+            return Ok(None);
+        }
+        // The system roots verify servers unless a CA certificate is given on top
+        let mut tls = TlsOptions::new().with_native_roots();
+        if let Some(ca_cert_path) = &self.ca_cert_path {
+            tls = tls.ca_certificate(Certificate::from_pem(read_pem(ca_cert_path)?));
+        }
+        if let Some(identity) = identity {
+            tls = tls.identity(identity);
+        }
+        Ok(Some(tls))
+    }
+
+    /// Whether any endpoint is reached over tls
+    fn uses_https(&self) -> bool {
+        self.endpoints
+            .iter()
+            .any(|endpoint| endpoint.to_ascii_lowercase().starts_with("https://"))
+    }
+}
+
+fn read_pem(path: &str) -> AppResult<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|e| AppError::ConfigError(format!("failed to read etcd pem {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_etcd_client_options_from_context() {
+        let config = Config::builder()
+            .set_override("etcd_url", "https://etcd-0:2379, https://etcd-1:2379")
+            .unwrap()
+            .set_override("etcd_username", "indexer")
+            .unwrap()
+            .set_override("etcd_password", "secret")
+            .unwrap()
+            .set_override("etcd_request_timeout_millis", 3000)
+            .unwrap()
+            .build()
+            .unwrap();
+        let options = EtcdClientOptions::from_context(&Context::from_config(config)).unwrap();
+
+        assert_eq!(
+            options.endpoints,
+            vec!["https://etcd-0:2379", "https://etcd-1:2379"]
+        );
+        assert_eq!(options.username.as_deref(), Some("indexer"));
+        assert_eq!(options.password.as_deref(), Some("secret"));
+        assert_eq!(options.request_timeout, Some(Duration::from_millis(3000)));
+        assert_eq!(options.connect_timeout, None);
+        assert!(options.connect_options().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_etcd_client_options_defaults_to_no_connect_options() {
+        let options = EtcdClientOptions::new("http://localhost:2379");
+        assert_eq!(options.endpoints, vec!["http://localhost:2379"]);
+        assert!(options.connect_options().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_etcd_client_options_enable_tls_for_https() {
+        let options = EtcdClientOptions::new("https://etcd-0:2379");
+        assert!(options.tls_options().unwrap().is_some());
+        assert!(options.connect_options().unwrap().is_some());
+
+        let options = EtcdClientOptions::new("http://etcd-0:2379");
+        assert!(options.tls_options().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_etcd_client_options_rejects_partial_settings() {
+        let options = EtcdClientOptions {
+            username: Some("indexer".to_string()),
+            ..EtcdClientOptions::new("http://localhost:2379")
+        };
+        assert!(options.connect_options().is_err());
+
+        let options = EtcdClientOptions {
+            cert_path: Some("/etc/etcd/client.pem".to_string()),
+            ..EtcdClientOptions::new("https://localhost:2379")
+        };
+        assert!(options.connect_options().is_err());
+
+        let options = EtcdClientOptions {
+            ca_cert_path: Some("/does/not/exist.pem".to_string()),
+            ..EtcdClientOptions::new("https://localhost:2379")
+        };
+        assert!(options.connect_options().is_err());
+    }
+}
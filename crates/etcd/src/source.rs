@@ -0,0 +1,68 @@
+use common::{AppResult, Context, WorkerRef};
+use serde::de::DeserializeOwned;
+
+use crate::{EtcdClient, EtcdWatcher, EtcdWatcherHandler};
+
+/// Where the app config is loaded from at startup and watched for changes afterwards.
+#[async_trait::async_trait]
+pub trait ConfigSource<H, C>: Send
+where
+    H: EtcdWatcherHandler<C>,
+    C: DeserializeOwned + Send + 'static + Clone,
+{
+    /// Reads the current config
+    async fn load(&mut self) -> AppResult<C>;
+
+    /// Revision the config was last loaded at, if the source has one
+    fn revision(&self) -> Option<i64> {
+        None
+    }
+
+    /// Creates the worker dispatching changes of the config to the handler
+    fn watcher(&self, handler: H) -> WorkerRef;
+}
+
+/// Config held as json in a single etcd key.
+pub struct EtcdConfigSource {
+    context: Context,
+    client: EtcdClient,
+    key: String,
+    /// Revision the config was loaded at, which the watcher resumes after
+    revision: Option<i64>,
+}
+
+impl EtcdConfigSource {
+    pub fn new(context: Context, client: EtcdClient, key: String) -> Self {
+        Self {
+            context,
+            client,
+            key,
+            revision: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<H, C> ConfigSource<H, C> for EtcdConfigSource
+where
+    H: EtcdWatcherHandler<C> + Clone + Send + Sync + 'static,
+    C: DeserializeOwned + Send + Sync + 'static + Clone,
+{
+    async fn load(&mut self) -> AppResult<C> {
+        let (config, revision) = self.client.get_with_revision::<C>(&self.key).await?;
+        self.revision = Some(revision);
+        Ok(config)
+    }
+
+    fn revision(&self) -> Option<i64> {
+        self.revision
+    }
+
+    fn watcher(&self, handler: H) -> WorkerRef {
+        let mut watcher =
+            EtcdWatcher::<H, C>::new(self.context.clone(), self.client.clone(), self.key.clone())
+                .with_revision(self.revision);
+        watcher.add_handler(handler);
+        Box::new(watcher)
+    }
+}
@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use common::{AppError, AppMessage, AppResult, Backoff, Context, SpawnResult, Worker};
+use serde::Serialize;
+use tokio::sync::broadcast::Receiver;
+
+use crate::{EtcdClient, ETCD_STATUS_PUBLISHED};
+
+/// Provides the status an [`EtcdStatusPublisher`] writes to etcd.
+pub trait EtcdStatusProvider: Clone + Send + Sync + 'static {
+    type Status: Serialize + Send + Sync;
+
+    fn status(&self) -> Self::Status;
+}
+
+/// Periodically publishes the status of this instance as json to a key bound to a lease.
+///
+/// The key is refreshed every `interval` while the lease is kept alive. It is deleted when
+/// the instance exits, and expires after `ttl_secs` if the instance dies.
+#[derive(Clone)]
+pub struct EtcdStatusPublisher<P>
+where
+    P: EtcdStatusProvider,
+{
+    context: Context,
+    client: EtcdClient,
+    key: String,
+    ttl_secs: i64,
+    interval: Duration,
+    provider: P,
+}
+
+impl<P> EtcdStatusPublisher<P>
+where
+    P: EtcdStatusProvider,
+{
+    pub fn new(
+        context: Context,
+        client: EtcdClient,
+        key: String,
+        ttl_secs: i64,
+        provider: P,
+    ) -> Self {
+        Self {
+            context,
+            client,
+            key,
+            ttl_secs,
+            interval: Duration::from_millis((ttl_secs.max(1) * 1000 / 3) as u64),
+            provider,
+        }
+    }
+
+    /// Creates the publisher from the static config, if `instance_status_prefix` is set.
+    ///
+    /// The status is published to the instance id under the prefix, e.g.
+    /// `/aionex/indexer/instances/indexer-0`. The lease ttl is read from
+    /// `instance_status_ttl_secs` (default 15) and the refresh interval from
+    /// `instance_status_interval_ms` (default a third of the ttl), which must be shorter than
+    /// the ttl for the key to be refreshed before it expires.
+    pub fn from_context(
+        context: Context,
+        client: EtcdClient,
+        provider: P,
+    ) -> AppResult<Option<Self>> {
+        let Ok(prefix) = context.config.get_string("instance_status_prefix") else {
+            return Ok(None);
+        };
+        let key = format!("{}{}", prefix, context.instance_id());
+        let ttl_secs = context
+            .config
+            .get_int("instance_status_ttl_secs")
+            .unwrap_or(15);
+        if ttl_secs <= 0 {
+            return Err(AppError::ConfigError(format!(
+                "instance_status_ttl_secs must be positive, got {}",
+                ttl_secs
+            )));
+        }
+        let mut publisher = Self::new(context, client, key, ttl_secs, provider);
+        if let Ok(interval_ms) = publisher
+            .context
+            .config
+            .get_int("instance_status_interval_ms")
+        {
+            if interval_ms <= 0 || interval_ms >= ttl_secs * 1000 {
+                return Err(AppError::ConfigError(format!(
+                    "instance_status_interval_ms must be positive and below the ttl of {}s, got {}",
+                    ttl_secs, interval_ms
+                )));
+            }
+            publisher.interval = Duration::from_millis(interval_ms as u64);
+        }
+        Ok(Some(publisher))
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Publishes the status until the app exits or the lease is lost, then revokes the lease.
+    ///
+    /// The backoff is reset once the status is published.
+    async fn publish(
+        &mut self,
+        app: &mut Receiver<AppMessage>,
+        backoff: &mut Backoff,
+    ) -> AppResult<String> {
+        let mut client = self.client.connection_manager().await?;
+        let lease = client.lease_grant(self.ttl_secs, None).await?.id();
+        let (mut keeper, mut keep_alive_stream) = client.lease_keep_alive(lease).await?;
+        let mut interval = tokio::time::interval(self.interval);
+
+        log::info!("{} publishing status to {}", self.context.name, self.key);
+        let result = loop {
+            tokio::select! {
+                _ = app.recv() => {
+                    log::info!("{} received exit message", self.context.name);
+                    break Ok(format!("{} received exit message", self.context.name));
+                }
+                _ = interval.tick() => {
+                    if let Err(e) = keeper.keep_alive().await {
+                        break Err(e.into());
+                    }
+                    let status = self.provider.status();
+                    if let Err(e) = self.client.put_with_lease(&self.key, &status, lease).await {
+                        break Err(e);
+                    }
+                    ETCD_STATUS_PUBLISHED.with_label_values(&[&self.key]).inc();
+                    backoff.reset();
+                }
+                response = keep_alive_stream.message() => {
+                    match response {
+                        Ok(Some(response)) if response.ttl() > 0 => {}
+                        Ok(Some(_)) => {
+                            break Err(AppError::GenericError(format!("lease of {} expired", self.key)));
+                        }
+                        Ok(None) => {
+                            break Err(AppError::GenericError(format!("lease keep alive of {} closed", self.key)));
+                        }
+                        Err(e) => break Err(e.into()),
+                    }
+                }
+            }
+        };
+
+        // Revoking the lease deletes the key right away rather than once it expires
+        if let Err(e) = client.lease_revoke(lease).await {
+            log::warn!("{} failed to revoke lease: {}", self.context.name, e);
+        }
+        result
+    }
+}
+
+impl<P> Worker for EtcdStatusPublisher<P>
+where
+    P: EtcdStatusProvider,
+{
+    fn spawn(&mut self) -> SpawnResult {
+        let mut publisher = self.clone();
+
+        tokio::spawn(async move {
+            let mut app = publisher.context.app.subscribe();
+            let mut backoff = Backoff::new(0, 1, 10, 2);
+            loop {
+                let e = match publisher.publish(&mut app, &mut backoff).await {
+                    Ok(message) => return Ok(message),
+                    Err(e) => e,
+                };
+
+                let delay_secs = backoff.next().unwrap_or(10);
+                log::error!(
+                    "{} failed to publish status to {}, retrying in {}s: {}",
+                    publisher.context.name,
+                    publisher.key,
+                    delay_secs,
+                    e
+                );
+                publisher.client.reset().await;
+                tokio::select! {
+                    _ = app.recv() => {
+                        return Ok(format!("{} received exit message", publisher.context.name));
+                    }
+                    _ = tokio::time::sleep(Duration::from_secs(delay_secs as u64)) => {}
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestProvider;
+
+    impl EtcdStatusProvider for TestProvider {
+        type Status = String;
+
+        fn status(&self) -> String {
+            "running".to_string()
+        }
+    }
+
+    #[test]
+    fn test_status_publisher_from_context() {
+        let config = Config::builder()
+            .set_override("instance_status_prefix", "/indexer/instances/")
+            .unwrap()
+            .set_override("instance_id", "indexer-0")
+            .unwrap()
+            .set_override("instance_status_ttl_secs", 9)
+            .unwrap()
+            .build()
+            .unwrap();
+        let context = Context::from_config(config);
+        let client = EtcdClient::new("http://localhost:2379");
+
+        let publisher = EtcdStatusPublisher::from_context(context, client.clone(), TestProvider)
+            .unwrap()
+            .unwrap();
+        assert_eq!(publisher.key(), "/indexer/instances/indexer-0");
+        assert_eq!(publisher.ttl_secs, 9);
+        assert_eq!(publisher.interval, Duration::from_secs(3));
+
+        let context = Context::from_config(Config::builder().build().unwrap());
+        assert!(
+            EtcdStatusPublisher::from_context(context, client, TestProvider)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_status_publisher_rejects_invalid_timing() {
+        let publisher = |ttl_secs: i64, interval_ms: Option<i64>| {
+            let mut builder = Config::builder()
+                .set_override("instance_status_prefix", "/indexer/instances/")
+                .unwrap()
+                .set_override("instance_status_ttl_secs", ttl_secs)
+                .unwrap();
+            if let Some(interval_ms) = interval_ms {
+                builder = builder
+                    .set_override("instance_status_interval_ms", interval_ms)
+                    .unwrap();
+            }
+            let context = Context::from_config(builder.build().unwrap());
+            EtcdStatusPublisher::from_context(
+                context,
+                EtcdClient::new("http://localhost:2379"),
+                TestProvider,
+            )
+        };
+
+        assert!(publisher(0, None).is_err());
+        assert!(publisher(-5, None).is_err());
+        assert!(publisher(9, Some(0)).is_err());
+        assert!(publisher(9, Some(9000)).is_err());
+        assert!(publisher(9, Some(12000)).is_err());
+        let publisher = publisher(9, Some(8999)).unwrap().unwrap();
+        assert_eq!(publisher.interval, Duration::from_millis(8999));
+    }
+}
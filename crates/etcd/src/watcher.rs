@@ -1,9 +1,46 @@
-use std::{marker::PhantomData, time::Duration};
+use std::{collections::HashSet, marker::PhantomData, time::Duration};
 
-use common::{Context, SpawnResult, Worker};
-use serde::de::DeserializeOwned;
+use common::{AppError, AppMessage, AppResult, Backoff, Context, SpawnResult, Worker};
+use etcd_client::EventType;
+use jiff::Timestamp;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast::Receiver;
 
-use crate::{EtcdClient, ETCD_WATCHER_MESSAGES};
+use crate::{
+    EtcdClient, ETCD_WATCHER_HEALTHY, ETCD_WATCHER_INVALID_UPDATES, ETCD_WATCHER_KEY_UPDATES,
+    ETCD_WATCHER_MESSAGES, ETCD_WATCHER_RECONNECTS,
+};
+
+/// Outcome of the latest update of a watched key.
+///
+/// Written as json to the key configured by `etcd_status_key`, if any, so that whoever
+/// pushed the update can see whether it was applied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EtcdUpdateStatus {
+    pub key: String,
+    pub revision: i64,
+    pub accepted: bool,
+    /// Why the update was rejected
+    pub reason: Option<String>,
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub updated_at: Timestamp,
+}
+
+/// An update of a single key under a watched prefix.
+#[derive(Debug, Clone)]
+pub struct EtcdKeyChange<C> {
+    pub key: String,
+    /// The new value of the key, `None` if the key was deleted
+    pub config: Option<C>,
+}
+
+/// Where a watch resumes: the last seen revision and, when watching a prefix, the keys that
+/// were present under it at that revision.
+#[derive(Debug, Clone, Default)]
+struct WatchPosition {
+    revision: Option<i64>,
+    keys: HashSet<String>,
+}
 
 #[derive(Clone)]
 pub struct EtcdWatcher<H, C>
@@ -15,6 +52,8 @@ where
     client: EtcdClient,
     handlers: Vec<H>,
     key: String,
+    prefix: bool,
+    position: WatchPosition,
     _marker: PhantomData<C>,
 }
 
@@ -29,10 +68,36 @@ where
             client,
             handlers: Vec::new(),
             key,
+            prefix: false,
+            position: WatchPosition::default(),
             _marker: PhantomData,
         }
     }
 
+    /// Watches all keys under the key as a prefix instead of the key itself.
+    ///
+    /// Each key holds its own config and handlers receive only the keys that changed, see
+    /// [`EtcdWatcherHandler::handle_key_changes`]. Keys changed together in a transaction are
+    /// validated and applied together.
+    pub fn with_prefix(mut self) -> Self {
+        self.prefix = true;
+        self
+    }
+
+    /// Resumes watching after the revision the initial config was read at, so that updates
+    /// made between the read and the start of the watcher are not missed.
+    pub fn with_revision(mut self, revision: Option<i64>) -> Self {
+        self.position.revision = revision;
+        self
+    }
+
+    /// The keys under the prefix at the revision the initial config was read at, so that keys
+    /// deleted while the watch is down can be told apart after a resync.
+    pub fn with_keys(mut self, keys: impl IntoIterator<Item = String>) -> Self {
+        self.position.keys = keys.into_iter().collect();
+        self
+    }
+
     pub fn from_context(context: Context, key: String) -> Self {
         let client = EtcdClient::from_context(&context).unwrap();
         Self::new(context, client, key)
@@ -41,68 +106,793 @@ where
     pub fn add_handler(&mut self, handler: H) {
         self.handlers.push(handler);
     }
-}
 
-impl<H, C> Worker for EtcdWatcher<H, C>
-where
-    H: EtcdWatcherHandler<C> + Clone + Send + 'static,
-    C: DeserializeOwned + Send + 'static + Clone,
-{
-    fn spawn(&mut self) -> SpawnResult {
-        let mut client = self.client.clone();
-        let key = self.key.clone();
-        let context = self.context.clone();
-        let watcher = self.clone();
+    /// Deserializes an update of the key and has every handler validate it
+    pub fn parse_update(&self, value: &[u8]) -> AppResult<C> {
+        let config = serde_json::from_slice::<C>(value)?;
+        for handler in self.handlers.iter() {
+            handler.validate(&config)?;
+        }
+        Ok(config)
+    }
 
-        tokio::spawn(async move {
-            let heartbeat_duration = context
-                .config
-                .get_int("etcd_heartbeat_interval_millis")
-                .unwrap_or(5000) as u64;
-            let mut heartbeat_interval =
-                tokio::time::interval(Duration::from_millis(heartbeat_duration));
-            let mut num_messages_since_last_heartbeat = 0;
-            let mut app = watcher.context.app.subscribe();
+    /// Deserializes updates of keys under the prefix and has every handler validate them
+    pub fn parse_key_changes(
+        &self,
+        changes: &[(String, Option<&[u8]>)],
+    ) -> AppResult<Vec<EtcdKeyChange<C>>> {
+        let changes = changes
+            .iter()
+            .map(|(key, value)| {
+                let config = value
+                    .map(serde_json::from_slice::<C>)
+                    .transpose()
+                    .map_err(|e| {
+                        AppError::ConfigError(format!("invalid value of key {}: {}", key, e))
+                    })?;
+                Ok(EtcdKeyChange {
+                    key: key.clone(),
+                    config,
+                })
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        for handler in self.handlers.iter() {
+            handler.validate_key_changes(&changes)?;
+        }
+        Ok(changes)
+    }
 
-            log::info!("starting {} watcher for key: {}", context.name, key);
-            let (_watcher, mut stream) = client.watch(&key).await?;
+    /// Applies a put or delete of the key, keeping the current config if it is invalid
+    fn handle_event(&self, event_type: EventType, value: &[u8], revision: i64) -> EtcdUpdateStatus {
+        let result = match event_type {
+            EventType::Delete => Err(AppError::ConfigError(format!(
+                "key {} was deleted",
+                self.key
+            ))),
+            EventType::Put => self.parse_update(value).and_then(|config| {
+                for handler in self.handlers.iter() {
+                    handler.handle_config_update(config.clone(), revision)?;
+                }
+                Ok(())
+            }),
+        };
+        self.update_status(result, revision)
+    }
+
+    /// Applies updates of keys under the prefix, keeping the current configs if any is invalid
+    fn handle_key_changes(
+        &self,
+        changes: &[(String, Option<&[u8]>)],
+        revision: i64,
+    ) -> EtcdUpdateStatus {
+        let result = self.parse_key_changes(changes).and_then(|changes| {
+            for handler in self.handlers.iter() {
+                handler.handle_key_changes(changes.clone(), revision)?;
+            }
+            Ok(())
+        });
+        self.update_status(result, revision)
+    }
+
+    fn update_status(&self, result: AppResult<()>, revision: i64) -> EtcdUpdateStatus {
+        let reason = match result {
+            Ok(_) => {
+                ETCD_WATCHER_KEY_UPDATES
+                    .with_label_values(&[&self.key])
+                    .inc();
+                None
+            }
+            Err(e) => {
+                log::error!(
+                    "{} rejected update of key {} at revision {}, keeping the last good config: {}",
+                    self.context.name,
+                    self.key,
+                    revision,
+                    e
+                );
+                ETCD_WATCHER_INVALID_UPDATES
+                    .with_label_values(&[&self.key])
+                    .inc();
+                Some(e.to_string())
+            }
+        };
+
+        EtcdUpdateStatus {
+            key: self.key.clone(),
+            revision,
+            accepted: reason.is_none(),
+            reason,
+            updated_at: Timestamp::now(),
+        }
+    }
+
+    /// Writes the outcome of an update to the status key, if configured
+    async fn write_status(&self, client: &mut EtcdClient, status: EtcdUpdateStatus) {
+        if let Ok(status_key) = self.context.config.get_string("etcd_status_key") {
+            if let Err(e) = client.put(&status_key, &status).await {
+                log::error!(
+                    "{} failed to write status key {}: {}",
+                    self.context.name,
+                    status_key,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Re-reads the key after the revisions to resume from were compacted away, applying
+    /// its value if it changed since the last seen revision.
+    ///
+    /// When watching a prefix, keys that are gone since are applied as deletes along with
+    /// the keys that changed.
+    async fn resync(&self, client: &mut EtcdClient, position: &mut WatchPosition) -> AppResult<()> {
+        let last_revision = position.revision.unwrap_or(0);
+        if self.prefix {
+            let (kvs, store_revision) = client.get_prefix_kvs(&self.key).await?;
+            let changes = changed_keys(
+                kvs.iter()
+                    .map(|kv| (kv.key(), kv.value(), kv.mod_revision())),
+                last_revision,
+                &position.keys,
+            );
+            if !changes.is_empty() {
+                let status = self.handle_key_changes(&changes, store_revision);
+                self.write_status(client, status).await;
+            }
+            position.keys = kvs
+                .iter()
+                .map(|kv| String::from_utf8_lossy(kv.key()).to_string())
+                .collect();
+            position.revision = Some(store_revision);
+            return Ok(());
+        }
+
+        let (kv, store_revision) = client.get_kv(&self.key).await?;
+        let event = resync_event(
+            kv.as_ref().map(|kv| (kv.value(), kv.mod_revision())),
+            store_revision,
+            last_revision,
+        );
+        if let Some((event_type, value, event_revision)) = event {
+            let status = self.handle_event(event_type, value, event_revision);
+            self.write_status(client, status).await;
+        }
+        position.revision = Some(store_revision);
+        Ok(())
+    }
+
+    /// Watches the key until the app exits, resuming after the last seen revision.
+    ///
+    /// Returns an error when the watch stream fails, so that the caller can reconnect.
+    async fn watch(
+        &self,
+        client: &mut EtcdClient,
+        position: &mut WatchPosition,
+        backoff: &mut Backoff,
+        app: &mut Receiver<AppMessage>,
+    ) -> AppResult<String> {
+        let heartbeat_duration = self
+            .context
+            .config
+            .get_int("etcd_heartbeat_interval_millis")
+            .unwrap_or(5000) as u64;
+        let mut heartbeat_interval =
+            tokio::time::interval(Duration::from_millis(heartbeat_duration));
+        let mut num_messages_since_last_heartbeat = 0;
+
+        // Without the revision the initial config was read at, watch from the current one
+        if position.revision.is_none() {
+            let store_revision = if self.prefix {
+                let (kvs, store_revision) = client.get_prefix_kvs(&self.key).await?;
+                position.keys = kvs
+                    .iter()
+                    .map(|kv| String::from_utf8_lossy(kv.key()).to_string())
+                    .collect();
+                store_revision
+            } else {
+                client.get_kv(&self.key).await?.1
+            };
+            position.revision = Some(store_revision);
+        }
+
+        'watch: loop {
+            let start_revision = position.revision.map(|revision| revision + 1);
+            let (_watcher, mut stream) = if self.prefix {
+                client
+                    .watch_prefix_from_revision(&self.key, start_revision)
+                    .await?
+            } else {
+                client
+                    .watch_from_revision(&self.key, start_revision)
+                    .await?
+            };
+            log::info!(
+                "{} watching key {} from revision {:?}",
+                self.context.name,
+                self.key,
+                start_revision
+            );
+            backoff.reset();
+            ETCD_WATCHER_HEALTHY
+                .with_label_values(&[&self.key])
+                .set(1.0);
 
             loop {
                 tokio::select! {
                     _ = app.recv() => {
-                        log::info!("{} received exit message", context.name);
-                        return Ok(format!("{} received exit message", context.name));
+                        log::info!("{} received exit message", self.context.name);
+                        return Ok(format!("{} received exit message", self.context.name));
                     }
                     _ = heartbeat_interval.tick() => {
-                        log::info!("{} consumed {} messages since last heartbeat for key: {}", context.name, num_messages_since_last_heartbeat, key);
+                        log::info!("{} consumed {} messages since last heartbeat for key: {}", self.context.name, num_messages_since_last_heartbeat, self.key);
                         if num_messages_since_last_heartbeat > 0 {
-                            ETCD_WATCHER_MESSAGES.with_label_values(&[&key]).add(num_messages_since_last_heartbeat as f64);
+                            ETCD_WATCHER_MESSAGES.with_label_values(&[&self.key]).add(num_messages_since_last_heartbeat as f64);
                             num_messages_since_last_heartbeat = 0;
                         }
                     }
                     message = stream.message() => {
                         num_messages_since_last_heartbeat += 1;
-                        if let Ok(Some(response)) = message {
+                        let response = match message {
+                            Ok(Some(response)) => response,
+                            Ok(None) => {
+                                return Err(AppError::GenericError(format!("watch stream of key {} closed", self.key)));
+                            }
+                            Err(e) => return Err(e.into()),
+                        };
+
+                        if response.compact_revision() > 0 {
+                            log::warn!("{} revisions of key {} compacted up to {}, re-reading the key", self.context.name, self.key, response.compact_revision());
+                            self.resync(client, position).await?;
+                            continue 'watch;
+                        }
+                        if response.canceled() {
+                            return Err(AppError::GenericError(format!("watch of key {} canceled: {}", self.key, response.cancel_reason())));
+                        }
+
+                        if self.prefix {
+                            let mut changes = Vec::new();
+                            let mut last_revision = None;
                             for event in response.events() {
                                 if let Some(kv) = event.kv() {
-                                    //TODO: handle error
-                                    let config: C = serde_json::from_slice::<C>(kv.value()).unwrap();
-                                    for handler in watcher.handlers.iter() {
-                                        handler.handle_config_change(config.clone());
-                                    }
+                                    let key = String::from_utf8_lossy(kv.key()).to_string();
+                                    let value = match event.event_type() {
+                                        EventType::Put => {
+                                            position.keys.insert(key.clone());
+                                            Some(kv.value())
+                                        }
+                                        EventType::Delete => {
+                                            position.keys.remove(&key);
+                                            None
+                                        }
+                                    };
+                                    changes.push((key, value));
+                                    last_revision = Some(kv.mod_revision());
                                 }
                             }
+                            if let Some(last_revision) = last_revision {
+                                let status = self.handle_key_changes(&changes, last_revision);
+                                self.write_status(client, status).await;
+                                position.revision = Some(last_revision);
+                            }
+                            continue;
+                        }
+
+                        for event in response.events() {
+                            if let Some(kv) = event.kv() {
+                                let status = self.handle_event(event.event_type(), kv.value(), kv.mod_revision());
+                                self.write_status(client, status).await;
+                                position.revision = Some(kv.mod_revision());
+                            }
                         }
                     }
                 }
             }
+        }
+    }
+}
+
+impl<H, C> Worker for EtcdWatcher<H, C>
+where
+    H: EtcdWatcherHandler<C> + Clone + Send + Sync + 'static,
+    C: DeserializeOwned + Send + Sync + 'static + Clone,
+{
+    fn spawn(&mut self) -> SpawnResult {
+        let mut client = self.client.clone();
+        let watcher = self.clone();
+
+        tokio::spawn(async move {
+            let mut app = watcher.context.app.subscribe();
+            let mut backoff = Backoff::new(0, 1, 30, 2);
+            let mut position = watcher.position.clone();
+
+            log::info!(
+                "starting {} watcher for key: {}",
+                watcher.context.name,
+                watcher.key
+            );
+            loop {
+                let result = watcher
+                    .watch(&mut client, &mut position, &mut backoff, &mut app)
+                    .await;
+                ETCD_WATCHER_HEALTHY
+                    .with_label_values(&[&watcher.key])
+                    .set(0.0);
+                let e = match result {
+                    Ok(message) => return Ok(message),
+                    Err(e) => e,
+                };
+
+                let delay_secs = backoff.next().unwrap_or(30);
+                log::error!(
+                    "{} watch of key {} failed, reconnecting in {}s: {}",
+                    watcher.context.name,
+                    watcher.key,
+                    delay_secs,
+                    e
+                );
+                ETCD_WATCHER_RECONNECTS
+                    .with_label_values(&[&watcher.key])
+                    .inc();
+                client.reset().await;
+                tokio::select! {
+                    _ = app.recv() => {
+                        return Ok(format!("{} received exit message", watcher.context.name));
+                    }
+                    _ = tokio::time::sleep(Duration::from_secs(delay_secs as u64)) => {}
+                }
+            }
         })
     }
 }
 
+/// Keys under a prefix modified after the last seen revision, followed by deletes of the
+/// previously seen keys that are gone, as changes to apply together
+fn changed_keys<'a>(
+    kvs: impl Iterator<Item = (&'a [u8], &'a [u8], i64)>,
+    last_revision: i64,
+    previous_keys: &HashSet<String>,
+) -> Vec<(String, Option<&'a [u8]>)> {
+    let mut present = HashSet::new();
+    let mut changes = Vec::new();
+    for (key, value, mod_revision) in kvs {
+        let key = String::from_utf8_lossy(key).to_string();
+        present.insert(key.clone());
+        if mod_revision > last_revision {
+            changes.push((key, Some(value)));
+        }
+    }
+    let mut deleted = previous_keys
+        .iter()
+        .filter(|key| !present.contains(*key))
+        .map(|key| (key.clone(), None))
+        .collect::<Vec<_>>();
+    deleted.sort();
+    changes.extend(deleted);
+    changes
+}
+
+/// Event to apply for a key re-read at `store_revision`, if it changed after the last seen
+/// revision: a put of its value, or a delete if it is gone since.
+fn resync_event(
+    kv: Option<(&[u8], i64)>,
+    store_revision: i64,
+    last_revision: i64,
+) -> Option<(EventType, &[u8], i64)> {
+    match kv {
+        Some((value, mod_revision)) if mod_revision > last_revision => {
+            Some((EventType::Put, value, mod_revision))
+        }
+        None if last_revision > 0 => Some((EventType::Delete, &[], store_revision)),
+        _ => None,
+    }
+}
+
 pub trait EtcdWatcherHandler<C>
 where
     C: DeserializeOwned + Send + 'static + Clone,
 {
+    /// Checks an update before it is applied, rejecting it with an error
+    fn validate(&self, _config: &C) -> AppResult<()> {
+        Ok(())
+    }
+
     fn handle_config_change(&self, config: C);
+
+    /// Handles a config change along with the etcd revision it was made at.
+    ///
+    /// An error marks the update as rejected, the handler is expected to keep its last good
+    /// config in that case.
+    fn handle_config_update(&self, config: C, _revision: i64) -> AppResult<()> {
+        self.handle_config_change(config);
+        Ok(())
+    }
+
+    /// Checks updates of keys under a watched prefix before they are applied together
+    fn validate_key_changes(&self, changes: &[EtcdKeyChange<C>]) -> AppResult<()> {
+        for change in changes.iter() {
+            if let Some(config) = &change.config {
+                self.validate(config)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles updates of the keys that changed under a watched prefix at the given revision
+    fn handle_key_changes(&self, changes: Vec<EtcdKeyChange<C>>, _revision: i64) -> AppResult<()> {
+        for change in changes {
+            if let Some(config) = change.config {
+                self.handle_config_change(config);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::SharedRef;
+    use config::Config;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Deserialize)]
+    struct TestConfig {
+        weight: u32,
+    }
+
+    #[derive(Clone)]
+    struct TestHandler;
+
+    impl EtcdWatcherHandler<TestConfig> for TestHandler {
+        fn validate(&self, config: &TestConfig) -> AppResult<()> {
+            if config.weight > 100 {
+                return Err(AppError::ConfigError("weight above 100".to_string()));
+            }
+            Ok(())
+        }
+
+        fn handle_config_change(&self, _config: TestConfig) {}
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingHandler {
+        weights: SharedRef<Vec<u32>>,
+        deleted: SharedRef<Vec<String>>,
+    }
+
+    impl EtcdWatcherHandler<TestConfig> for RecordingHandler {
+        fn handle_config_change(&self, config: TestConfig) {
+            self.weights.lock().push(config.weight);
+        }
+
+        fn handle_key_changes(
+            &self,
+            changes: Vec<EtcdKeyChange<TestConfig>>,
+            _revision: i64,
+        ) -> AppResult<()> {
+            for change in changes {
+                match change.config {
+                    Some(config) => self.handle_config_change(config),
+                    None => self.deleted.lock().push(change.key),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn watcher() -> EtcdWatcher<TestHandler, TestConfig> {
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut watcher = EtcdWatcher::new(
+            context,
+            EtcdClient::new("http://localhost:2379"),
+            "/test/config".to_string(),
+        );
+        watcher.add_handler(TestHandler);
+        watcher
+    }
+
+    #[test]
+    fn test_parse_update() {
+        let watcher = watcher();
+        assert_eq!(
+            watcher.parse_update(br#"{"weight": 40}"#).unwrap().weight,
+            40
+        );
+    }
+
+    #[test]
+    fn test_parse_update_rejects_malformed_json() {
+        let watcher = watcher();
+        let result = watcher.parse_update(br#"{"weight": 40"#);
+        assert!(matches!(result, Err(AppError::SerdeJsonError(_))));
+        let result = watcher.parse_update(br#"{"weihgt": 40}"#);
+        assert!(matches!(result, Err(AppError::SerdeJsonError(_))));
+    }
+
+    #[test]
+    fn test_parse_update_rejects_invalid_config() {
+        let watcher = watcher();
+        let result = watcher.parse_update(br#"{"weight": 140}"#);
+        assert!(matches!(result, Err(AppError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_parse_key_changes() {
+        let watcher = watcher().with_prefix();
+        let changes = watcher
+            .parse_key_changes(&[
+                ("/test/config/a".to_string(), Some(br#"{"weight": 40}"#)),
+                ("/test/config/b".to_string(), None),
+            ])
+            .unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].key, "/test/config/a");
+        assert_eq!(changes[0].config.as_ref().unwrap().weight, 40);
+        assert!(changes[1].config.is_none());
+
+        let result = watcher.parse_key_changes(&[
+            ("/test/config/a".to_string(), Some(br#"{"weight": 40}"#)),
+            ("/test/config/b".to_string(), Some(br#"{"weight": 140}"#)),
+        ]);
+        assert!(matches!(result, Err(AppError::ConfigError(_))));
+
+        let result =
+            watcher.parse_key_changes(&[("/test/config/a".to_string(), Some(br#"{"weight""#))]);
+        assert!(matches!(result, Err(AppError::ConfigError(e)) if e.contains("/test/config/a")));
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let kvs: Vec<(&[u8], &[u8], i64)> = vec![
+            (b"/test/config/a", b"a", 5),
+            (b"/test/config/b", b"b", 7),
+            (b"/test/config/c", b"c", 9),
+        ];
+        let changes = changed_keys(kvs.clone().into_iter(), 6, &HashSet::new());
+        assert_eq!(
+            changes,
+            vec![
+                ("/test/config/b".to_string(), Some(&b"b"[..])),
+                ("/test/config/c".to_string(), Some(&b"c"[..])),
+            ]
+        );
+
+        // Keys seen before that are no longer present are deleted
+        let previous_keys = HashSet::from([
+            "/test/config/a".to_string(),
+            "/test/config/d".to_string(),
+            "/test/config/e".to_string(),
+        ]);
+        let changes = changed_keys(kvs.into_iter(), 6, &previous_keys);
+        assert_eq!(
+            changes,
+            vec![
+                ("/test/config/b".to_string(), Some(&b"b"[..])),
+                ("/test/config/c".to_string(), Some(&b"c"[..])),
+                ("/test/config/d".to_string(), None),
+                ("/test/config/e".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resync_event() {
+        // Changed after the last seen revision
+        assert_eq!(
+            resync_event(Some((b"new", 8)), 10, 6),
+            Some((EventType::Put, &b"new"[..], 8))
+        );
+        // Unchanged
+        assert_eq!(resync_event(Some((b"old", 6)), 10, 6), None);
+        // Deleted since the last seen revision
+        assert_eq!(
+            resync_event(None, 10, 6),
+            Some((EventType::Delete, &[][..], 10))
+        );
+        // Never seen
+        assert_eq!(resync_event(None, 10, 0), None);
+    }
+
+    /// Runs `watch` once in the background, returning the position it stopped at on exit
+    fn spawn_watch(
+        watcher: EtcdWatcher<RecordingHandler, TestConfig>,
+        mut client: EtcdClient,
+        mut position: WatchPosition,
+    ) -> (
+        tokio::sync::broadcast::Sender<AppMessage>,
+        tokio::task::JoinHandle<WatchPosition>,
+    ) {
+        let (sender, mut app) = tokio::sync::broadcast::channel(1);
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::new(0, 1, 30, 2);
+            watcher
+                .watch(&mut client, &mut position, &mut backoff, &mut app)
+                .await
+                .unwrap();
+            position
+        });
+        (sender, handle)
+    }
+
+    async fn wait_for_weights(handler: &RecordingHandler, count: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handler.weights.lock().len() < count {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for config change");
+    }
+
+    fn test_key(name: &str) -> String {
+        format!("/test/watcher-{}/{}", std::process::id(), name)
+    }
+
+    /// Runs against the etcd at `TEST_ETCD_URL`, skipped if unset
+    #[tokio::test]
+    async fn test_watch_resumes_after_load_and_reconnect() {
+        let Ok(etcd_url) = std::env::var("TEST_ETCD_URL") else {
+            return;
+        };
+        let mut client = EtcdClient::new(&etcd_url);
+        let key = test_key("reconnect");
+        client
+            .put(&key, &serde_json::json!({"weight": 10}))
+            .await
+            .unwrap();
+        let (_, load_revision) = client.get_with_revision::<TestConfig>(&key).await.unwrap();
+
+        // Updated between the load and the start of the watch
+        client
+            .put(&key, &serde_json::json!({"weight": 20}))
+            .await
+            .unwrap();
+        let handler = RecordingHandler::default();
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut watcher = EtcdWatcher::new(context, client.clone(), key.clone())
+            .with_revision(Some(load_revision));
+        watcher.add_handler(handler.clone());
+
+        let (exit, handle) = spawn_watch(watcher.clone(), client.clone(), watcher.position.clone());
+        wait_for_weights(&handler, 1).await;
+        exit.send(AppMessage::Exit).unwrap();
+        let position = handle.await.unwrap();
+
+        // Updated while disconnected, picked up when the watch resumes
+        client.reset().await;
+        client
+            .put(&key, &serde_json::json!({"weight": 30}))
+            .await
+            .unwrap();
+        let (exit, handle) = spawn_watch(watcher, client.clone(), position);
+        wait_for_weights(&handler, 2).await;
+        exit.send(AppMessage::Exit).unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(*handler.weights.lock(), vec![20, 30]);
+        client
+            .connection_manager()
+            .await
+            .unwrap()
+            .delete(key, None)
+            .await
+            .unwrap();
+    }
+
+    /// Runs against the etcd at `TEST_ETCD_URL`, skipped if unset
+    #[tokio::test]
+    async fn test_watch_resyncs_after_compaction() {
+        let Ok(etcd_url) = std::env::var("TEST_ETCD_URL") else {
+            return;
+        };
+        let mut client = EtcdClient::new(&etcd_url);
+        let key = test_key("compaction");
+        client
+            .put(&key, &serde_json::json!({"weight": 10}))
+            .await
+            .unwrap();
+        let (_, last_revision) = client.get_with_revision::<TestConfig>(&key).await.unwrap();
+        client
+            .put(&key, &serde_json::json!({"weight": 20}))
+            .await
+            .unwrap();
+        client
+            .put(&key, &serde_json::json!({"weight": 30}))
+            .await
+            .unwrap();
+        let (_, store_revision) = client.get_kv(&key).await.unwrap();
+        client
+            .connection_manager()
+            .await
+            .unwrap()
+            .compact(store_revision, None)
+            .await
+            .unwrap();
+
+        let handler = RecordingHandler::default();
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut watcher = EtcdWatcher::new(context, client.clone(), key.clone());
+        watcher.add_handler(handler.clone());
+
+        // Only the latest value is applied, then the watch continues from the store revision
+        let position = WatchPosition {
+            revision: Some(last_revision),
+            ..Default::default()
+        };
+        let (exit, handle) = spawn_watch(watcher, client.clone(), position);
+        wait_for_weights(&handler, 1).await;
+        client
+            .put(&key, &serde_json::json!({"weight": 40}))
+            .await
+            .unwrap();
+        wait_for_weights(&handler, 2).await;
+        exit.send(AppMessage::Exit).unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(*handler.weights.lock(), vec![30, 40]);
+        client
+            .connection_manager()
+            .await
+            .unwrap()
+            .delete(key, None)
+            .await
+            .unwrap();
+    }
+
+    /// Runs against the etcd at `TEST_ETCD_URL`, skipped if unset
+    #[tokio::test]
+    async fn test_watch_prefix_resync_reports_deleted_keys() {
+        let Ok(etcd_url) = std::env::var("TEST_ETCD_URL") else {
+            return;
+        };
+        let mut client = EtcdClient::new(&etcd_url);
+        let prefix = test_key("prefix-delete/");
+        let (a, b) = (format!("{}a", prefix), format!("{}b", prefix));
+        client
+            .put(&a, &serde_json::json!({"weight": 10}))
+            .await
+            .unwrap();
+        client
+            .put(&b, &serde_json::json!({"weight": 20}))
+            .await
+            .unwrap();
+        let (feeds, load_revision) = client
+            .get_prefix_with_revision::<TestConfig>(&prefix)
+            .await
+            .unwrap();
+
+        // Deleted and updated while the watch is down, then compacted away
+        let mut etcd = client.connection_manager().await.unwrap();
+        etcd.delete(b.clone(), None).await.unwrap();
+        client
+            .put(&a, &serde_json::json!({"weight": 30}))
+            .await
+            .unwrap();
+        let (_, store_revision) = client.get_kv(&a).await.unwrap();
+        etcd.compact(store_revision, None).await.unwrap();
+
+        let handler = RecordingHandler::default();
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut watcher = EtcdWatcher::new(context, client.clone(), prefix.clone())
+            .with_prefix()
+            .with_revision(Some(load_revision))
+            .with_keys(feeds.into_keys());
+        watcher.add_handler(handler.clone());
+
+        let (exit, handle) = spawn_watch(watcher.clone(), client.clone(), watcher.position.clone());
+        wait_for_weights(&handler, 1).await;
+        exit.send(AppMessage::Exit).unwrap();
+        let position = handle.await.unwrap();
+
+        assert_eq!(*handler.weights.lock(), vec![30]);
+        assert_eq!(*handler.deleted.lock(), vec![b]);
+        assert_eq!(position.keys, HashSet::from([a]));
+        etcd.delete(
+            prefix,
+            Some(etcd_client::DeleteOptions::new().with_prefix()),
+        )
+        .await
+        .unwrap();
+    }
 }
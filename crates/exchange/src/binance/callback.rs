@@ -3,7 +3,10 @@ use tokio::sync::broadcast::Sender;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use wsclient::{WsCallback, WsClient};
 
-use crate::{ExchangeConfig, ExchangeConfigChangeHandler};
+use crate::{
+    record_unknown_frame, DeadLetterStore, Exchange, ExchangeConfig, ExchangeConfigChangeHandler,
+    EXCHANGE_MESSAGES_PARSED,
+};
 
 use super::{BinanceChannelMessage, BinanceRequest, BinanceRequestMethod, BinanceResponse};
 
@@ -12,6 +15,7 @@ pub struct BinanceWsCallback {
     ws_client: WsClient,
     exchange_config: SharedRwRef<ExchangeConfig>,
     producer: Sender<AppInternalMessage>,
+    dead_letters: DeadLetterStore,
     next_request_id: u64,
 }
 
@@ -20,41 +24,39 @@ impl BinanceWsCallback {
         ws_client: WsClient,
         exchange_config: SharedRwRef<ExchangeConfig>,
         producer: Sender<AppInternalMessage>,
+        dead_letters: DeadLetterStore,
     ) -> Self {
         Self {
             ws_client,
             exchange_config,
             producer,
+            dead_letters,
             next_request_id: 0,
         }
     }
 
     pub fn subscribe(&mut self) -> AppResult<()> {
-        let exchange_config = self.exchange_config.read();
-        let instruments = exchange_config.get_instruments();
-        let channels = exchange_config.get_channels();
-        let mut params = Vec::new();
-        for instrument in instruments.iter() {
-            for channel in channels.iter() {
-                params.push(format!(
-                    "{}@{}",
-                    instrument.to_lowercase(),
-                    channel.to_lowercase()
-                ));
-            }
-        }
+        let config = self.exchange_config.read().clone();
+        let request = self.subscribe_request(&config)?;
+        self.ws_client.write_all(vec![request])
+    }
+
+    fn subscribe_request(&mut self, config: &ExchangeConfig) -> AppResult<Message> {
         self.next_request_id += 1;
         let request = BinanceRequest {
             method: BinanceRequestMethod::Subscribe,
-            params,
+            params: stream_names(config),
             id: self.next_request_id,
         };
         let json = serde_json::to_string(&request)?;
-        self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))
+        Ok(Message::Text(Utf8Bytes::from(&json)))
     }
 
-    pub fn try_parsing_channel_message(&self, text: &Utf8Bytes) -> Option<BinanceChannelMessage> {
-        serde_json::from_str::<BinanceChannelMessage>(text).ok()
+    pub fn try_parsing_channel_message(
+        &self,
+        text: &Utf8Bytes,
+    ) -> Result<BinanceChannelMessage, serde_json::Error> {
+        serde_json::from_str::<BinanceChannelMessage>(text)
     }
 
     pub fn try_parsing_response(&self, text: &Utf8Bytes) -> Option<BinanceResponse> {
@@ -67,27 +69,32 @@ impl BinanceWsCallback {
             || exchange_config.get_channels() != config.get_channels()
     }
 
-    pub fn unsubscribe(&self, config: &ExchangeConfig) -> AppResult<()> {
-        let mut params = Vec::new();
-        for instrument in config.get_instruments().iter() {
-            for channel in config.get_channels().iter() {
-                params.push(format!(
-                    "{}@{}",
-                    instrument.to_lowercase(),
-                    channel.to_lowercase()
-                ));
-            }
-        }
+    fn unsubscribe_request(&self, config: &ExchangeConfig) -> AppResult<Message> {
         let request = BinanceRequest {
             method: BinanceRequestMethod::Unsubscribe,
-            params,
+            params: stream_names(config),
             id: self.next_request_id,
         };
         let json = serde_json::to_string(&request)?;
-        self.ws_client.write(Message::Text(Utf8Bytes::from(&json)))
+        Ok(Message::Text(Utf8Bytes::from(&json)))
     }
 }
 
+/// The `<instrument>@<channel>` streams of the config
+fn stream_names(config: &ExchangeConfig) -> Vec<String> {
+    let mut params = Vec::new();
+    for instrument in config.get_instruments().iter() {
+        for channel in config.get_channels().iter() {
+            params.push(format!(
+                "{}@{}",
+                instrument.to_lowercase(),
+                channel.to_lowercase()
+            ));
+        }
+    }
+    params
+}
+
 #[async_trait::async_trait]
 impl WsCallback for BinanceWsCallback {
     async fn on_connect(&mut self, timestamp: jiff::Timestamp) -> AppResult<()> {
@@ -98,11 +105,14 @@ impl WsCallback for BinanceWsCallback {
     async fn on_message(
         &mut self,
         message: Message,
-        _received_time: jiff::Timestamp,
+        received_time: jiff::Timestamp,
     ) -> AppResult<()> {
         match message {
-            Message::Text(text) => {
-                if let Some(channel_message) = self.try_parsing_channel_message(&text) {
+            Message::Text(text) => match self.try_parsing_channel_message(&text) {
+                Ok(channel_message) => {
+                    EXCHANGE_MESSAGES_PARSED
+                        .with_label_values(&[&Exchange::Binance.to_string(), "channel"])
+                        .inc();
                     let ticker: Ticker = channel_message.into();
                     match self
                         .producer
@@ -113,12 +123,19 @@ impl WsCallback for BinanceWsCallback {
                             log::error!("failed to send ticker to consumer: {:?}", e);
                         }
                     }
-                } else if let Some(response) = self.try_parsing_response(&text) {
-                    log::info!("received binance response: {:?}", response);
-                } else {
-                    log::warn!("received unexpected message: {:?}", text);
                 }
-            }
+                Err(e) => {
+                    if let Some(response) = self.try_parsing_response(&text) {
+                        EXCHANGE_MESSAGES_PARSED
+                            .with_label_values(&[&Exchange::Binance.to_string(), "response"])
+                            .inc();
+                        log::info!("received binance response: {:?}", response);
+                    } else {
+                        self.dead_letters
+                            .record(Exchange::Binance, &text, received_time, e);
+                    }
+                }
+            },
             Message::Close(close) => {
                 if let Some(reason) = close {
                     log::error!("Binance connection closed: {}", reason);
@@ -130,7 +147,7 @@ impl WsCallback for BinanceWsCallback {
                 self.ws_client.write(Message::Pong(ping))?;
             }
             _ => {
-                log::error!("received unexpected message: {:?}", message);
+                record_unknown_frame(&Exchange::Binance, &message);
             }
         }
         Ok(())
@@ -152,9 +169,11 @@ impl ExchangeConfigChangeHandler for BinanceWsCallback {
         let subscription_changed = self.has_config_changed(&config);
 
         if subscription_changed {
-            self.unsubscribe(&self.exchange_config.read())?;
+            let unsubscribe = self.unsubscribe_request(&self.exchange_config.read())?;
+            let subscribe = self.subscribe_request(&config)?;
+            // Queued together so they are written in order, the config is kept if they can't be
+            self.ws_client.write_all(vec![unsubscribe, subscribe])?;
             *self.exchange_config.write() = config;
-            self.subscribe()?;
         }
         Ok(())
     }
@@ -2,7 +2,7 @@ use common::{AppInternalMessage, Context, SharedRwRef};
 use tokio::sync::broadcast::Sender;
 use wsclient::{WsClient, WsConsumer};
 
-use crate::ExchangeConfig;
+use crate::{DeadLetterStore, ExchangeConfig};
 
 use super::BinanceWsCallback;
 
@@ -10,22 +10,37 @@ use super::BinanceWsCallback;
 pub struct BinanceWsClient {
     client: WsClient,
     config: SharedRwRef<ExchangeConfig>,
+    dead_letters: DeadLetterStore,
 }
 
 impl BinanceWsClient {
     pub fn new(config: ExchangeConfig) -> Self {
         Self {
-            client: WsClient::new(config.ws_url.clone(), config.heartbeat_millis),
+            client: WsClient::new(config.ws_url.clone(), config.heartbeat_millis)
+                .with_connection_config(config.connection.clone())
+                .with_outbound_config(config.outbound.clone()),
             config: SharedRwRef::new(config),
+            dead_letters: DeadLetterStore::default(),
         }
     }
 
+    /// Sets the store receiving frames that fail to deserialize
+    pub fn with_dead_letter_store(mut self, dead_letters: DeadLetterStore) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
     pub fn consumer(
         &mut self,
         context: Context,
         sender: Sender<AppInternalMessage>,
     ) -> WsConsumer<BinanceWsCallback> {
-        let callback = BinanceWsCallback::new(self.client.clone(), self.config.clone(), sender);
+        let callback = BinanceWsCallback::new(
+            self.client.clone(),
+            self.config.clone(),
+            sender,
+            self.dead_letters.clone(),
+        );
         self.client
             .consumer(context.with_name("binance-ws-consumer"), callback)
     }
@@ -3,7 +3,10 @@ use tokio::sync::broadcast::Sender;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use wsclient::{WsCallback, WsClient};
 
-use crate::{ExchangeConfig, ExchangeConfigChangeHandler};
+use crate::{
+    record_unknown_frame, DeadLetterStore, Exchange, ExchangeConfig, ExchangeConfigChangeHandler,
+    EXCHANGE_MESSAGES_PARSED,
+};
 
 use super::{CoinbaseChannelMessage, CoinbaseRequest, CoinbaseRequestType, CoinbaseResponse};
 
@@ -12,6 +15,7 @@ pub struct CoinbaseWsCallback {
     client: WsClient,
     exchange_config: SharedRwRef<ExchangeConfig>,
     sender: Sender<AppInternalMessage>,
+    dead_letters: DeadLetterStore,
 }
 
 impl CoinbaseWsCallback {
@@ -19,31 +23,26 @@ impl CoinbaseWsCallback {
         client: WsClient,
         exchange_config: SharedRwRef<ExchangeConfig>,
         sender: Sender<AppInternalMessage>,
+        dead_letters: DeadLetterStore,
     ) -> Self {
         Self {
             client,
             exchange_config,
             sender,
+            dead_letters,
         }
     }
 
     pub fn subscribe(&self) -> AppResult<()> {
-        let exchange_config = self.exchange_config.read();
-        let instruments = exchange_config.get_instruments();
-        let channels = exchange_config.get_channels();
-
-        let request = CoinbaseRequest {
-            request_type: CoinbaseRequestType::Subscribe,
-            product_ids: instruments.iter().map(|s| s.to_string()).collect(),
-            channels: channels.iter().map(|s| s.to_string()).collect(),
-        };
-        let json = serde_json::to_string(&request)?;
-        self.client.write(Message::Text(Utf8Bytes::from(&json)))?;
-        Ok(())
+        let request = request(CoinbaseRequestType::Subscribe, &self.exchange_config.read())?;
+        self.client.write_all(vec![request])
     }
 
-    pub fn try_parsing_channel_message(&self, text: &Utf8Bytes) -> Option<CoinbaseChannelMessage> {
-        serde_json::from_str::<CoinbaseChannelMessage>(text).ok()
+    pub fn try_parsing_channel_message(
+        &self,
+        text: &Utf8Bytes,
+    ) -> Result<CoinbaseChannelMessage, serde_json::Error> {
+        serde_json::from_str::<CoinbaseChannelMessage>(text)
     }
 
     pub fn try_parsing_response(&self, text: &Utf8Bytes) -> Option<CoinbaseResponse> {
@@ -55,25 +54,25 @@ impl CoinbaseWsCallback {
         exchange_config.get_instruments() != config.get_instruments()
             || exchange_config.get_channels() != config.get_channels()
     }
+}
 
-    fn unsubscribe(&self, config: &ExchangeConfig) -> AppResult<()> {
-        let unsubscribe_request = CoinbaseRequest {
-            request_type: CoinbaseRequestType::Unsubscribe,
-            product_ids: config
-                .get_instruments()
-                .iter()
-                .map(|s| s.to_string())
-                .collect(),
-            channels: config
-                .get_channels()
-                .iter()
-                .map(|s| s.to_string())
-                .collect(),
-        };
-        let json = serde_json::to_string(&unsubscribe_request)?;
-        self.client.write(Message::Text(Utf8Bytes::from(&json)))?;
-        Ok(())
-    }
+/// A request for all the instruments and channels of the config
+fn request(request_type: CoinbaseRequestType, config: &ExchangeConfig) -> AppResult<Message> {
+    let request = CoinbaseRequest {
+        request_type,
+        product_ids: config
+            .get_instruments()
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        channels: config
+            .get_channels()
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+    };
+    let json = serde_json::to_string(&request)?;
+    Ok(Message::Text(Utf8Bytes::from(&json)))
 }
 
 #[async_trait::async_trait]
@@ -86,11 +85,14 @@ impl WsCallback for CoinbaseWsCallback {
     async fn on_message(
         &mut self,
         message: Message,
-        _received_time: jiff::Timestamp,
+        received_time: jiff::Timestamp,
     ) -> AppResult<()> {
         match message {
-            Message::Text(text) => {
-                if let Some(channel_message) = self.try_parsing_channel_message(&text) {
+            Message::Text(text) => match self.try_parsing_channel_message(&text) {
+                Ok(channel_message) => {
+                    EXCHANGE_MESSAGES_PARSED
+                        .with_label_values(&[&Exchange::Coinbase.to_string(), "channel"])
+                        .inc();
                     if let CoinbaseChannelMessage::Ticker(ticker) = channel_message {
                         let ticker: Ticker = ticker.into();
                         match self.sender.send(AppInternalMessage::Tickers(vec![ticker])) {
@@ -100,12 +102,19 @@ impl WsCallback for CoinbaseWsCallback {
                             }
                         }
                     }
-                } else if let Some(response) = self.try_parsing_response(&text) {
-                    log::info!("received coinbase response: {:?}", response);
-                } else {
-                    log::warn!("received unexpected message: {:?}", text);
                 }
-            }
+                Err(e) => {
+                    if let Some(response) = self.try_parsing_response(&text) {
+                        EXCHANGE_MESSAGES_PARSED
+                            .with_label_values(&[&Exchange::Coinbase.to_string(), "response"])
+                            .inc();
+                        log::info!("received coinbase response: {:?}", response);
+                    } else {
+                        self.dead_letters
+                            .record(Exchange::Coinbase, &text, received_time, e);
+                    }
+                }
+            },
 
             Message::Close(close) => {
                 if let Some(reason) = close {
@@ -118,7 +127,9 @@ impl WsCallback for CoinbaseWsCallback {
             Message::Ping(ping) => {
                 self.client.write(Message::Pong(ping))?;
             }
-            _ => {}
+            _ => {
+                record_unknown_frame(&Exchange::Coinbase, &message);
+            }
         }
         Ok(())
     }
@@ -137,9 +148,14 @@ impl ExchangeConfigChangeHandler for CoinbaseWsCallback {
         let subscription_changed = self.has_config_changed(&config);
 
         if subscription_changed {
-            self.unsubscribe(&self.exchange_config.read())?;
+            let unsubscribe = request(
+                CoinbaseRequestType::Unsubscribe,
+                &self.exchange_config.read(),
+            )?;
+            let subscribe = request(CoinbaseRequestType::Subscribe, &config)?;
+            // Queued together so they are written in order, the config is kept if they can't be
+            self.client.write_all(vec![unsubscribe, subscribe])?;
             *self.exchange_config.write() = config;
-            self.subscribe()?;
         }
         Ok(())
     }
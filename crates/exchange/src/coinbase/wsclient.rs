@@ -2,7 +2,7 @@ use common::{AppInternalMessage, Context, SharedRwRef};
 use tokio::sync::broadcast::Sender;
 use wsclient::{WsClient, WsConsumer};
 
-use crate::ExchangeConfig;
+use crate::{DeadLetterStore, ExchangeConfig};
 
 use super::CoinbaseWsCallback;
 
@@ -10,22 +10,37 @@ use super::CoinbaseWsCallback;
 pub struct CoinbaseWsClient {
     ws_client: WsClient,
     config: SharedRwRef<ExchangeConfig>,
+    dead_letters: DeadLetterStore,
 }
 
 impl CoinbaseWsClient {
     pub fn new(config: ExchangeConfig) -> Self {
         Self {
-            ws_client: WsClient::new(config.ws_url.clone(), config.heartbeat_millis),
+            ws_client: WsClient::new(config.ws_url.clone(), config.heartbeat_millis)
+                .with_connection_config(config.connection.clone())
+                .with_outbound_config(config.outbound.clone()),
             config: SharedRwRef::new(config),
+            dead_letters: DeadLetterStore::default(),
         }
     }
 
+    /// Sets the store receiving frames that fail to deserialize
+    pub fn with_dead_letter_store(mut self, dead_letters: DeadLetterStore) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
     pub fn consumer(
         &mut self,
         context: Context,
         sender: Sender<AppInternalMessage>,
     ) -> WsConsumer<CoinbaseWsCallback> {
-        let callback = CoinbaseWsCallback::new(self.ws_client.clone(), self.config.clone(), sender);
+        let callback = CoinbaseWsCallback::new(
+            self.ws_client.clone(),
+            self.config.clone(),
+            sender,
+            self.dead_letters.clone(),
+        );
         self.ws_client
             .consumer(context.with_name("coinbase-ws-consumer"), callback)
     }
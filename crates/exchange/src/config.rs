@@ -1,7 +1,8 @@
 use std::collections::HashSet;
 
-use common::{AppResult, Source};
+use common::{AppError, AppResult, Source};
 use serde::{Deserialize, Serialize};
+use wsclient::{OutboundConfig, WsConnectionConfig};
 
 /// Configuration for establishing and maintaining a WebSocket connection to a cryptocurrency exchange.
 ///
@@ -10,6 +11,8 @@ use serde::{Deserialize, Serialize};
 /// - `channels`: List of data feed channels to subscribe to (e.g., trades, orderbook, ticker)
 /// - `instruments`: Trading pairs to monitor (e.g., BTC-USD, ETH-USD)
 /// - `heartbeat_millis`: Heartbeat interval in milliseconds
+/// - `connection`: Optional proxy, TLS and handshake header settings for the connection
+/// - `outbound`: Optional capacity and rate limit of the queue of messages sent to the exchange
 ///
 /// # Example
 /// ```
@@ -23,14 +26,20 @@ use serde::{Deserialize, Serialize};
 ///     channels,
 ///     instruments,
 ///     heartbeat_millis: 30000,
+///     connection: Default::default(),
+///     outbound: Default::default(),
 /// };
 /// ```
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ExchangeConfig {
     pub ws_url: String,
     pub channels: HashSet<String>,
     pub instruments: HashSet<String>,
     pub heartbeat_millis: u64,
+    #[serde(default)]
+    pub connection: WsConnectionConfig,
+    #[serde(default)]
+    pub outbound: OutboundConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -74,6 +83,8 @@ impl ExchangeConfig {
             channels,
             instruments,
             heartbeat_millis,
+            connection: WsConnectionConfig::default(),
+            outbound: OutboundConfig::default(),
         }
     }
 
@@ -84,9 +95,43 @@ impl ExchangeConfig {
     pub fn get_channels(&self) -> &HashSet<String> {
         &self.channels
     }
+
+    pub fn validate(&self) -> AppResult<()> {
+        if !self.ws_url.starts_with("ws://") && !self.ws_url.starts_with("wss://") {
+            return Err(AppError::ConfigError(format!(
+                "ws_url {} is not a websocket url",
+                self.ws_url
+            )));
+        }
+        if self.heartbeat_millis == 0 {
+            return Err(AppError::ConfigError(
+                "heartbeat_millis must be greater than 0".to_string(),
+            ));
+        }
+        if !self.instruments.is_empty() && self.channels.is_empty() {
+            return Err(AppError::ConfigError(
+                "channels cannot be empty when instruments are configured".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether changing to the other config needs a new client, as the websocket url,
+    /// heartbeat, connection and outbound settings are only read when the client is created
+    pub fn requires_restart(&self, other: &ExchangeConfig) -> bool {
+        self.ws_url != other.ws_url
+            || self.heartbeat_millis != other.heartbeat_millis
+            || self.connection != other.connection
+            || self.outbound != other.outbound
+    }
 }
 
 pub trait ExchangeConfigChangeHandler {
+    /// Checks a config before any change of the app config is applied
+    fn validate_config_change(&self, config: &ExchangeConfig) -> AppResult<()> {
+        config.validate()
+    }
+
     fn handle_config_change(&mut self, config: ExchangeConfig) -> AppResult<()>;
 }
 
@@ -116,5 +161,83 @@ mod tests {
         assert_eq!(config.channels, channels);
         assert_eq!(config.instruments, instruments);
         assert_eq!(config.heartbeat_millis, 30000);
+        assert_eq!(config.connection, WsConnectionConfig::default());
+        assert_eq!(config.outbound, OutboundConfig::default());
+    }
+
+    #[test]
+    fn test_exchange_config_deserialize_with_connection() {
+        let config_json = serde_json::json!({
+            "ws_url": "wss://ws.exchange.com/socket",
+            "channels": ["ticker"],
+            "instruments": ["BTC-USD"],
+            "heartbeat_millis": 30000,
+            "connection": {
+                "proxy": {
+                    "type": "socks5",
+                    "host": "localhost",
+                    "port": 1080
+                },
+                "headers": {
+                    "X-Api-Key": "secret"
+                }
+            }
+        });
+
+        let config: ExchangeConfig = serde_json::from_value(config_json).unwrap();
+        assert_eq!(
+            config.connection.proxy,
+            Some(wsclient::ProxyConfig::Socks5 {
+                host: "localhost".to_string(),
+                port: 1080,
+                username: None,
+                password: None,
+            })
+        );
+        assert!(config.connection.tls.is_none());
+        assert_eq!(
+            config.connection.headers.get("X-Api-Key").unwrap(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn test_exchange_config_deserialize_with_outbound() {
+        let config_json = serde_json::json!({
+            "ws_url": "wss://stream.binance.com:9443/ws",
+            "channels": ["ticker"],
+            "instruments": ["BTCUSDT"],
+            "heartbeat_millis": 30000,
+            "outbound": {
+                "capacity": 1000,
+                "rate_limit_per_sec": 5
+            }
+        });
+
+        let config: ExchangeConfig = serde_json::from_value(config_json).unwrap();
+        assert_eq!(config.outbound.capacity, 1000);
+        assert_eq!(config.outbound.rate_limit_per_sec, Some(5));
+    }
+
+    #[test]
+    fn test_exchange_config_validate() {
+        let mut config = ExchangeConfig::new(
+            "wss://ws.kraken.com/v2".to_string(),
+            HashSet::from(["ticker".to_string()]),
+            HashSet::from(["BTC/USD".to_string()]),
+            3000,
+        );
+        assert!(config.validate().is_ok());
+
+        config.heartbeat_millis = 0;
+        assert!(config.validate().is_err());
+
+        config.heartbeat_millis = 3000;
+        config.channels.clear();
+        assert!(config.validate().is_err());
+
+        config.channels.insert("ticker".to_string());
+        config.ws_url = "https://ws.kraken.com/v2".to_string();
+        assert!(config.validate().is_err());
     }
 }
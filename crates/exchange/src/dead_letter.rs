@@ -0,0 +1,307 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use common::{AppError, AppResult, Context, SharedRef, SharedRwRef};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::{Exchange, EXCHANGE_MESSAGES_UNPARSED};
+
+/// A raw inbound frame that could not be deserialized into any known exchange message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DeadLetter {
+    pub exchange: Exchange,
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub received_time: Timestamp,
+    /// The deserialization error of the exchange's channel message
+    pub error: String,
+    pub payload: String,
+}
+
+/// Bounded store of the most recent [`DeadLetter`]s, shared by all exchange callbacks.
+///
+/// Dead letters are kept in an in-memory ring of `capacity` entries, evicting the oldest
+/// first. When a path is configured every dead letter is also appended to it as a JSON line,
+/// and once the file would grow beyond its max bytes it is rotated to `<path>.1`, replacing
+/// the previous one.
+#[derive(Clone)]
+pub struct DeadLetterStore {
+    capacity: usize,
+    letters: SharedRwRef<VecDeque<DeadLetter>>,
+    file: SharedRef<Option<DeadLetterFile>>,
+}
+
+/// The file dead letters are appended to
+struct DeadLetterFile {
+    path: PathBuf,
+    file: File,
+    size_bytes: u64,
+    max_bytes: u64,
+}
+
+impl DeadLetterFile {
+    fn open(path: PathBuf, max_bytes: u64) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size_bytes = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size_bytes,
+            max_bytes,
+        })
+    }
+
+    fn append(&mut self, line: &str) -> std::io::Result<()> {
+        let size = line.len() as u64 + 1;
+        if self.size_bytes > 0 && self.size_bytes + size > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size_bytes += size;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        std::fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size_bytes = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+impl Default for DeadLetterStore {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl DeadLetterStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            letters: SharedRwRef::new(VecDeque::with_capacity(capacity)),
+            file: SharedRef::new(None),
+        }
+    }
+
+    /// Also appends every dead letter to the file at the given path, rotating it once it
+    /// would grow beyond `max_bytes`
+    pub fn with_file(self, path: PathBuf, max_bytes: u64) -> AppResult<Self> {
+        let file = DeadLetterFile::open(path.clone(), max_bytes).map_err(|e| {
+            AppError::GenericError(format!(
+                "failed to open dead letter file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        *self.file.lock() = Some(file);
+        Ok(self)
+    }
+
+    /// Creates the store from the static config.
+    ///
+    /// Reads `dead_letter_capacity` (default 1000), the optional `dead_letter_path` and
+    /// `dead_letter_max_bytes` (default 100MB).
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let capacity = context
+            .config
+            .get_int("dead_letter_capacity")
+            .unwrap_or(1000) as usize;
+        let max_bytes = context
+            .config
+            .get_int("dead_letter_max_bytes")
+            .unwrap_or(100 * 1024 * 1024);
+        if max_bytes < 1 {
+            return Err(AppError::ConfigError(
+                "dead_letter_max_bytes must be positive".to_string(),
+            ));
+        }
+        let store = Self::new(capacity);
+        match context.config.get_string("dead_letter_path") {
+            Ok(path) => store.with_file(PathBuf::from(path), max_bytes as u64),
+            Err(_) => Ok(store),
+        }
+    }
+
+    /// Records a text frame that failed to deserialize, counting it as unparsed
+    pub fn record(
+        &self,
+        exchange: Exchange,
+        payload: &str,
+        received_time: Timestamp,
+        error: serde_json::Error,
+    ) {
+        log::warn!(
+            "received unexpected message from {}: {:?}",
+            exchange,
+            payload
+        );
+        EXCHANGE_MESSAGES_UNPARSED
+            .with_label_values(&[&exchange.to_string()])
+            .inc();
+        self.push(DeadLetter {
+            exchange,
+            received_time,
+            error: error.to_string(),
+            payload: payload.to_string(),
+        });
+    }
+
+    pub fn push(&self, dead_letter: DeadLetter) {
+        if let Some(file) = self.file.lock().as_mut() {
+            let result = serde_json::to_string(&dead_letter)
+                .map_err(std::io::Error::from)
+                .and_then(|line| file.append(&line));
+            if let Err(e) = result {
+                log::error!("failed to write dead letter to file: {}", e);
+            }
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+        let mut letters = self.letters.write();
+        if letters.len() == self.capacity {
+            letters.pop_front();
+        }
+        letters.push_back(dead_letter);
+    }
+
+    /// The most recent dead letters, newest first, optionally only those of one exchange
+    pub fn list(&self, exchange: Option<&Exchange>, limit: usize) -> Vec<DeadLetter> {
+        self.letters
+            .read()
+            .iter()
+            .rev()
+            .filter(|letter| exchange.is_none_or(|exchange| &letter.exchange == exchange))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.letters.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.letters.read().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dead_letter(exchange: Exchange, payload: &str) -> DeadLetter {
+        DeadLetter {
+            exchange,
+            received_time: Timestamp::now(),
+            error: "missing field `s`".to_string(),
+            payload: payload.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_dead_letter_store_evicts_oldest() {
+        let store = DeadLetterStore::new(2);
+        store.push(dead_letter(Exchange::Binance, "1"));
+        store.push(dead_letter(Exchange::Kraken, "2"));
+        store.push(dead_letter(Exchange::Binance, "3"));
+
+        assert_eq!(store.len(), 2);
+        let payloads = store
+            .list(None, 10)
+            .into_iter()
+            .map(|letter| letter.payload)
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, vec!["3", "2"]);
+
+        let binance = store.list(Some(&Exchange::Binance), 10);
+        assert_eq!(binance.len(), 1);
+        assert_eq!(binance[0].payload, "3");
+        assert_eq!(store.list(None, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_dead_letter_store_appends_to_file() {
+        let path = std::env::temp_dir()
+            .join(format!("dead-letters-{}", std::process::id()))
+            .join("dead_letters.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let store = DeadLetterStore::new(1)
+            .with_file(path.clone(), 1024)
+            .unwrap();
+        store.push(dead_letter(Exchange::Coinbase, "{\"type\":\"new\"}"));
+        store.push(dead_letter(Exchange::Coinbase, "not json"));
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let letters = lines
+            .lines()
+            .map(|line| serde_json::from_str::<DeadLetter>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[1].payload, "not json");
+        assert_eq!(letters[1].exchange, Exchange::Coinbase);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_dead_letter_file_is_rotated() {
+        let path = std::env::temp_dir()
+            .join(format!("dead-letters-rotated-{}", std::process::id()))
+            .join("dead_letters.jsonl");
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        // Letters of the same length, so that two fit in a file
+        let letter = |payload: &str| DeadLetter {
+            received_time: Timestamp::from_second(1).unwrap(),
+            ..dead_letter(Exchange::Kraken, payload)
+        };
+        let line = serde_json::to_string(&letter("0")).unwrap();
+        let max_bytes = 2 * (line.len() as u64 + 1);
+
+        let store = DeadLetterStore::new(0)
+            .with_file(path.clone(), max_bytes)
+            .unwrap();
+        for payload in ["1", "2", "3", "4", "5"] {
+            store.push(letter(payload));
+        }
+        let payloads = |path: &Path| {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<DeadLetter>(line).unwrap().payload)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(payloads(&path), vec!["5"]);
+        assert_eq!(payloads(&rotated_path(&path)), vec!["3", "4"]);
+
+        // The size of an existing file counts towards the limit
+        let store = DeadLetterStore::new(0)
+            .with_file(path.clone(), max_bytes)
+            .unwrap();
+        store.push(letter("6"));
+        store.push(letter("7"));
+        assert_eq!(payloads(&path), vec!["7"]);
+        assert_eq!(payloads(&rotated_path(&path)), vec!["5", "6"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -3,7 +3,10 @@ use tokio::sync::broadcast::Sender;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use wsclient::{WsCallback, WsClient};
 
-use crate::{ExchangeConfig, ExchangeConfigChangeHandler};
+use crate::{
+    record_unknown_frame, DeadLetterStore, Exchange, ExchangeConfig, ExchangeConfigChangeHandler,
+    EXCHANGE_MESSAGES_PARSED,
+};
 
 use super::{KrakenMessage, KrakenRequest, KrakenRequestParams, KrakenResponse};
 
@@ -12,6 +15,7 @@ pub struct KrakenWsCallback {
     client: WsClient,
     exchange_config: SharedRwRef<ExchangeConfig>,
     sender: Sender<AppInternalMessage>,
+    dead_letters: DeadLetterStore,
 }
 
 impl KrakenWsCallback {
@@ -19,36 +23,26 @@ impl KrakenWsCallback {
         client: WsClient,
         exchange_config: SharedRwRef<ExchangeConfig>,
         sender: Sender<AppInternalMessage>,
+        dead_letters: DeadLetterStore,
     ) -> Self {
         Self {
             client,
             exchange_config,
             sender,
+            dead_letters,
         }
     }
 
     pub fn subscribe(&mut self) -> AppResult<()> {
-        let cfg = self.exchange_config.read();
-        let instruments = cfg.get_instruments();
-        let channels = cfg.get_channels();
-        let mut requests = Vec::new();
-        for channel in channels.iter() {
-            requests.push(KrakenRequest::Subscribe {
-                params: KrakenRequestParams {
-                    channel: channel.clone(),
-                    symbol: instruments.iter().map(|s| s.to_string()).collect(),
-                },
-            });
-        }
-        for request in requests {
-            let json = serde_json::to_string(&request)?;
-            self.client.write(Message::Text(Utf8Bytes::from(&json)))?;
-        }
-        Ok(())
+        let requests = subscribe_requests(&self.exchange_config.read())?;
+        self.client.write_all(requests)
     }
 
-    pub fn try_parsing_channel_message(&self, text: &Utf8Bytes) -> Option<KrakenMessage> {
-        serde_json::from_str::<KrakenMessage>(text).ok()
+    pub fn try_parsing_channel_message(
+        &self,
+        text: &Utf8Bytes,
+    ) -> Result<KrakenMessage, serde_json::Error> {
+        serde_json::from_str::<KrakenMessage>(text)
     }
 
     pub fn try_parsing_response(&self, text: &Utf8Bytes) -> Option<KrakenResponse> {
@@ -60,29 +54,50 @@ impl KrakenWsCallback {
         exchange_config.get_instruments() != config.get_instruments()
             || exchange_config.get_channels() != config.get_channels()
     }
+}
 
-    pub fn unsubscribe(&self, config: &ExchangeConfig) -> AppResult<()> {
-        let mut requests = Vec::new();
-        for channel in config.get_channels().iter() {
-            requests.push(KrakenRequest::Unsubscribe {
-                params: KrakenRequestParams {
-                    channel: channel.clone(),
-                    symbol: config
-                        .get_instruments()
-                        .iter()
-                        .map(|s| s.to_string())
-                        .collect(),
-                },
-            });
-        }
-        for request in requests {
-            let json = serde_json::to_string(&request)?;
-            self.client.write(Message::Text(Utf8Bytes::from(&json)))?;
-        }
-        Ok(())
+/// A subscribe request per channel of the config
+fn subscribe_requests(config: &ExchangeConfig) -> AppResult<Vec<Message>> {
+    config
+        .get_channels()
+        .iter()
+        .map(|channel| {
+            to_message(&KrakenRequest::Subscribe {
+                params: request_params(config, channel),
+            })
+        })
+        .collect()
+}
+
+/// An unsubscribe request per channel of the config
+fn unsubscribe_requests(config: &ExchangeConfig) -> AppResult<Vec<Message>> {
+    config
+        .get_channels()
+        .iter()
+        .map(|channel| {
+            to_message(&KrakenRequest::Unsubscribe {
+                params: request_params(config, channel),
+            })
+        })
+        .collect()
+}
+
+fn request_params(config: &ExchangeConfig, channel: &str) -> KrakenRequestParams {
+    KrakenRequestParams {
+        channel: channel.to_string(),
+        symbol: config
+            .get_instruments()
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
     }
 }
 
+fn to_message(request: &KrakenRequest) -> AppResult<Message> {
+    let json = serde_json::to_string(request)?;
+    Ok(Message::Text(Utf8Bytes::from(&json)))
+}
+
 #[async_trait::async_trait]
 impl WsCallback for KrakenWsCallback {
     async fn on_connect(&mut self, timestamp: jiff::Timestamp) -> AppResult<()> {
@@ -93,11 +108,14 @@ impl WsCallback for KrakenWsCallback {
     async fn on_message(
         &mut self,
         message: Message,
-        _received_time: jiff::Timestamp,
+        received_time: jiff::Timestamp,
     ) -> AppResult<()> {
         match message {
-            Message::Text(text) => {
-                if let Some(channel_message) = self.try_parsing_channel_message(&text) {
+            Message::Text(text) => match self.try_parsing_channel_message(&text) {
+                Ok(channel_message) => {
+                    EXCHANGE_MESSAGES_PARSED
+                        .with_label_values(&[&Exchange::Kraken.to_string(), "channel"])
+                        .inc();
                     let tickers: Vec<Ticker> = channel_message.get_tickers_internal();
                     if !tickers.is_empty() {
                         match self.sender.send(AppInternalMessage::Tickers(tickers)) {
@@ -107,12 +125,19 @@ impl WsCallback for KrakenWsCallback {
                             }
                         }
                     }
-                } else if let Some(response) = self.try_parsing_response(&text) {
-                    log::info!("received kraken response: {:?}", response);
-                } else {
-                    log::warn!("received unexpected message: {:?}", text);
                 }
-            }
+                Err(e) => {
+                    if let Some(response) = self.try_parsing_response(&text) {
+                        EXCHANGE_MESSAGES_PARSED
+                            .with_label_values(&[&Exchange::Kraken.to_string(), "response"])
+                            .inc();
+                        log::info!("received kraken response: {:?}", response);
+                    } else {
+                        self.dead_letters
+                            .record(Exchange::Kraken, &text, received_time, e);
+                    }
+                }
+            },
             Message::Close(close) => {
                 if let Some(reason) = close {
                     log::error!("Kraken connection closed: {}", reason);
@@ -123,7 +148,9 @@ impl WsCallback for KrakenWsCallback {
             Message::Ping(ping) => {
                 self.client.write(Message::Pong(ping))?;
             }
-            _ => {}
+            _ => {
+                record_unknown_frame(&Exchange::Kraken, &message);
+            }
         }
         Ok(())
     }
@@ -143,9 +170,11 @@ impl ExchangeConfigChangeHandler for KrakenWsCallback {
     fn handle_config_change(&mut self, config: ExchangeConfig) -> AppResult<()> {
         let subscription_changed = self.has_config_changed(&config);
         if subscription_changed {
-            self.unsubscribe(&self.exchange_config.read())?;
+            let mut requests = unsubscribe_requests(&self.exchange_config.read())?;
+            requests.extend(subscribe_requests(&config)?);
+            // Queued together so they are written in order, the config is kept if they can't be
+            self.client.write_all(requests)?;
             *self.exchange_config.write() = config;
-            self.subscribe()?;
         }
         Ok(())
     }
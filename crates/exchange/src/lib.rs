@@ -1,9 +1,13 @@
 mod binance;
 mod coinbase;
 mod config;
+mod dead_letter;
 mod kraken;
+mod metrics;
 
 pub use binance::*;
 pub use coinbase::*;
 pub use config::*;
+pub use dead_letter::*;
 pub use kraken::*;
+pub use metrics::*;
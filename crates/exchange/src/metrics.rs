@@ -0,0 +1,46 @@
+use lazy_static::lazy_static;
+use prometheus as prom;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::Exchange;
+
+lazy_static! {
+    pub static ref EXCHANGE_MESSAGES_PARSED: prom::CounterVec = prom::register_counter_vec!(
+        "exchange_messages_parsed",
+        "Exchange messages parsed successfully",
+        &["exchange", "message_type"]
+    )
+    .unwrap();
+    pub static ref EXCHANGE_MESSAGES_UNPARSED: prom::CounterVec = prom::register_counter_vec!(
+        "exchange_messages_unparsed",
+        "Exchange messages that failed to deserialize",
+        &["exchange"]
+    )
+    .unwrap();
+    pub static ref EXCHANGE_MESSAGES_UNKNOWN: prom::CounterVec = prom::register_counter_vec!(
+        "exchange_messages_unknown",
+        "Exchange frames of an unexpected type",
+        &["exchange", "message_type"]
+    )
+    .unwrap();
+}
+
+/// Counts an inbound frame the exchange callback has no use for
+pub fn record_unknown_frame(exchange: &Exchange, message: &Message) {
+    let message_type = match message {
+        Message::Text(_) => "text",
+        Message::Binary(_) => "binary",
+        Message::Ping(_) => "ping",
+        Message::Pong(_) => "pong",
+        Message::Close(_) => "close",
+        Message::Frame(_) => "frame",
+    };
+    log::debug!(
+        "received unexpected {} frame from {}",
+        message_type,
+        exchange
+    );
+    EXCHANGE_MESSAGES_UNKNOWN
+        .with_label_values(&[&exchange.to_string(), message_type])
+        .inc();
+}
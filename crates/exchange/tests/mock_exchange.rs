@@ -0,0 +1,287 @@
+use std::{collections::HashSet, time::Duration};
+
+use common::{AppInternalMessage, Context, Source, Ticker, TickerSymbol, Worker};
+use config::Config;
+use exchange::{
+    BinanceWsClient, CoinbaseWsClient, DeadLetterStore, Exchange, ExchangeConfig,
+    ExchangeConfigChangeHandler, KrakenWsClient,
+};
+use mock_exchange::{MockExchange, MockExchangeServer, Scenario};
+use rust_decimal_macros::dec;
+use tokio::sync::broadcast::{self, Receiver};
+
+fn context() -> Context {
+    Context::from_config(Config::builder().build().unwrap())
+}
+
+fn exchange_config(ws_url: &str, channels: &[&str], instruments: &[&str]) -> ExchangeConfig {
+    ExchangeConfig::new(
+        ws_url.to_string(),
+        channels
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<HashSet<_>>(),
+        instruments
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<HashSet<_>>(),
+        1000,
+    )
+}
+
+async fn next_ticker(receiver: &mut Receiver<AppInternalMessage>) -> Ticker {
+    let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .expect("timed out waiting for ticker")
+        .unwrap();
+    let AppInternalMessage::Tickers(mut tickers) = message;
+    assert_eq!(tickers.len(), 1);
+    tickers.remove(0)
+}
+
+#[tokio::test]
+async fn test_binance_ws_callback() {
+    let server = MockExchangeServer::start(
+        MockExchange::Binance,
+        vec![Scenario::new()
+            .subscribe()
+            .ticker("BTCUSDT", dec!(100000.5))
+            .heartbeat()
+            .malformed("{not json")
+            .ticker("ETHUSDT", dec!(2700.25))],
+    )
+    .await;
+
+    let context = context();
+    let (sender, mut receiver) = broadcast::channel(100);
+    let config = exchange_config(server.url(), &["ticker"], &["BTCUSDT"]);
+    let dead_letters = DeadLetterStore::new(10);
+    let mut consumer = BinanceWsClient::new(config)
+        .with_dead_letter_store(dead_letters.clone())
+        .consumer(context.clone(), sender);
+    let handle = consumer.spawn();
+
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.symbol, TickerSymbol::BTCUSD);
+    assert_eq!(ticker.price, dec!(100000.5));
+    assert_eq!(ticker.source, Source::Binance);
+
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.symbol, TickerSymbol::ETHUSD);
+    assert_eq!(ticker.price, dec!(2700.25));
+
+    let dead_letters = dead_letters.list(None, 10);
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].exchange, Exchange::Binance);
+    assert_eq!(dead_letters[0].payload, "{not json");
+
+    let subscribe: serde_json::Value = serde_json::from_str(&server.received()[0]).unwrap();
+    assert_eq!(subscribe["method"], "SUBSCRIBE");
+    assert_eq!(subscribe["params"], serde_json::json!(["btcusdt@ticker"]));
+
+    context.exit();
+    assert!(handle.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_binance_ws_callback_reconnects() {
+    let server = MockExchangeServer::start(
+        MockExchange::Binance,
+        vec![
+            Scenario::new()
+                .subscribe()
+                .ticker("BTCUSDT", dec!(100))
+                .disconnect(),
+            Scenario::new().subscribe().ticker("BTCUSDT", dec!(101)),
+        ],
+    )
+    .await;
+
+    let context = context();
+    let (sender, mut receiver) = broadcast::channel(100);
+    let config = exchange_config(server.url(), &["ticker"], &["BTCUSDT"]);
+    let mut consumer = BinanceWsClient::new(config).consumer(context.clone(), sender);
+    let handle = consumer.spawn();
+
+    assert_eq!(next_ticker(&mut receiver).await.price, dec!(100));
+    assert_eq!(next_ticker(&mut receiver).await.price, dec!(101));
+    assert_eq!(server.connections(), 2);
+
+    context.exit();
+    assert!(handle.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_binance_config_change_is_ordered() {
+    let server = MockExchangeServer::start(
+        MockExchange::Binance,
+        vec![Scenario::new().subscribe().ticker("BTCUSDT", dec!(100))],
+    )
+    .await;
+
+    let context = context();
+    let (sender, mut receiver) = broadcast::channel(100);
+    let config = exchange_config(server.url(), &["ticker"], &["BTCUSDT"]);
+    let mut consumer = BinanceWsClient::new(config).consumer(context.clone(), sender);
+    let mut callback = consumer.callback.clone();
+    let handle = consumer.spawn();
+    next_ticker(&mut receiver).await;
+
+    let config = exchange_config(server.url(), &["ticker"], &["ETHUSDT"]);
+    callback.handle_config_change(config).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.received().len() < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for the resubscription");
+
+    let requests = server
+        .received()
+        .iter()
+        .map(|text| serde_json::from_str::<serde_json::Value>(text).unwrap())
+        .map(|request| (request["method"].clone(), request["params"][0].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        requests,
+        vec![
+            ("SUBSCRIBE".into(), "btcusdt@ticker".into()),
+            ("UNSUBSCRIBE".into(), "btcusdt@ticker".into()),
+            ("SUBSCRIBE".into(), "ethusdt@ticker".into()),
+        ]
+    );
+
+    context.exit();
+    assert!(handle.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_config_change_fails_when_the_outbound_queue_is_full() {
+    let mut config = exchange_config("ws://127.0.0.1:1", &["ticker"], &["BTC/USD"]);
+    config.outbound.capacity = 1;
+    let (sender, _) = broadcast::channel(100);
+    let consumer = KrakenWsClient::new(config).consumer(context(), sender);
+    let mut callback = consumer.callback.clone();
+
+    // Nothing drains the queue, which can't hold the unsubscribe and the subscribe
+    let config = exchange_config("ws://127.0.0.1:1", &["ticker"], &["ETH/USD"]);
+    assert!(callback.handle_config_change(config.clone()).is_err());
+    // The old config was kept, so the change is attempted again instead of being a no-op
+    assert!(callback.handle_config_change(config).is_err());
+}
+
+#[tokio::test]
+async fn test_kraken_ws_callback() {
+    let server = MockExchangeServer::start(
+        MockExchange::Kraken,
+        vec![Scenario::new()
+            .subscribe()
+            .heartbeat()
+            .ticker("BTC/USD", dec!(99000.1))
+            .ticker("ETH/USD", dec!(2650))],
+    )
+    .await;
+
+    let context = context();
+    let (sender, mut receiver) = broadcast::channel(100);
+    let config = exchange_config(server.url(), &["ticker"], &["BTC/USD", "ETH/USD"]);
+    let mut consumer = KrakenWsClient::new(config).consumer(context.clone(), sender);
+    let handle = consumer.spawn();
+
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.symbol, TickerSymbol::BTCUSD);
+    assert_eq!(ticker.price, dec!(99000.1));
+    assert_eq!(ticker.source, Source::Kraken);
+
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.symbol, TickerSymbol::ETHUSD);
+    assert_eq!(ticker.price, dec!(2650));
+
+    context.exit();
+    assert!(handle.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_kraken_ws_callback_subscription_error() {
+    let server = MockExchangeServer::start(
+        MockExchange::Kraken,
+        vec![Scenario::new()
+            .reject_subscribe()
+            .malformed("{\"channel\": \"ticker\", \"type\": \"update\", \"data\": [{}]}")
+            .malformed("not json at all")
+            .ticker("BTC/USD", dec!(99000))],
+    )
+    .await;
+
+    let context = context();
+    let (sender, mut receiver) = broadcast::channel(100);
+    let config = exchange_config(server.url(), &["ticker"], &["BTC/USD"]);
+    let dead_letters = DeadLetterStore::new(10);
+    let mut consumer = KrakenWsClient::new(config)
+        .with_dead_letter_store(dead_letters.clone())
+        .consumer(context.clone(), sender);
+    let handle = consumer.spawn();
+
+    // The rejected subscription and the malformed frames are skipped
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.price, dec!(99000));
+    assert_eq!(server.connections(), 1);
+    assert!(!handle.is_finished());
+
+    // The subscription error is not modelled as a kraken response, so it is dead lettered
+    // along with the frame that is not json
+    let dead_letters = dead_letters.list(Some(&Exchange::Kraken), 10);
+    assert_eq!(dead_letters.len(), 2);
+    assert_eq!(dead_letters[0].payload, "not json at all");
+    assert!(dead_letters[1]
+        .payload
+        .contains("Currency pair not supported"));
+
+    context.exit();
+    assert!(handle.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_coinbase_ws_callback() {
+    let server = MockExchangeServer::start(
+        MockExchange::Coinbase,
+        vec![Scenario::new()
+            .subscribe()
+            .heartbeat()
+            .ticker("BTC-USD", dec!(98000.75))
+            .malformed("{\"type\": \"ticker\"}")
+            .ticker("ETH-USD", dec!(2600))],
+    )
+    .await;
+
+    let context = context();
+    let (sender, mut receiver) = broadcast::channel(100);
+    let config = exchange_config(server.url(), &["ticker", "heartbeat"], &["BTC-USD"]);
+    let dead_letters = DeadLetterStore::new(10);
+    let mut consumer = CoinbaseWsClient::new(config)
+        .with_dead_letter_store(dead_letters.clone())
+        .consumer(context.clone(), sender);
+    let handle = consumer.spawn();
+
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.symbol, TickerSymbol::BTCUSD);
+    assert_eq!(ticker.price, dec!(98000.75));
+    assert_eq!(ticker.source, Source::Coinbase);
+
+    let ticker = next_ticker(&mut receiver).await;
+    assert_eq!(ticker.symbol, TickerSymbol::ETHUSD);
+    assert_eq!(ticker.price, dec!(2600));
+
+    let dead_letters = dead_letters.list(None, 10);
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].payload, "{\"type\": \"ticker\"}");
+    assert!(dead_letters[0].error.contains("missing field"));
+
+    let subscribe: serde_json::Value = serde_json::from_str(&server.received()[0]).unwrap();
+    assert_eq!(subscribe["type"], "subscribe");
+    assert_eq!(subscribe["product_ids"], serde_json::json!(["BTC-USD"]));
+
+    context.exit();
+    assert!(handle.await.unwrap().is_ok());
+}
@@ -0,0 +1,20 @@
+-- Tables as created by docker/sql/init.sh before the indexer owned its schema
+CREATE TABLE IF NOT EXISTS tickers (
+    id SERIAL PRIMARY KEY,
+    symbol VARCHAR(20) NOT NULL,
+    price DECIMAL NOT NULL,
+    source VARCHAR(50) NOT NULL,
+    timestamp BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+ALTER TABLE tickers ADD COLUMN IF NOT EXISTS stream VARCHAR(10) NOT NULL DEFAULT 'smoothed';
+CREATE INDEX IF NOT EXISTS idx_tickers_symbol_timestamp ON tickers (symbol, timestamp);
+CREATE INDEX IF NOT EXISTS idx_tickers_stream_symbol_timestamp ON tickers (stream, symbol, timestamp);
+
+CREATE TABLE IF NOT EXISTS config_audit (
+    id SERIAL PRIMARY KEY,
+    revision BIGINT,
+    updated_at BIGINT NOT NULL,
+    diff JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,41 @@
+-- Daily range partitions of tickers on a timestamptz column, the partitions themselves are
+-- created ahead of time by the indexer. The rows written before get a partition for each of
+-- their days, so the default partition only holds rows outside of any partition.
+ALTER TABLE tickers RENAME TO tickers_legacy;
+ALTER INDEX idx_tickers_symbol_timestamp RENAME TO idx_tickers_legacy_symbol_timestamp;
+ALTER INDEX idx_tickers_stream_symbol_timestamp RENAME TO idx_tickers_legacy_stream_symbol_timestamp;
+
+CREATE TABLE tickers (
+    symbol VARCHAR(20) NOT NULL,
+    price NUMERIC NOT NULL,
+    source VARCHAR(50) NOT NULL,
+    stream VARCHAR(10) NOT NULL DEFAULT 'smoothed',
+    timestamp TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+) PARTITION BY RANGE (timestamp);
+CREATE INDEX idx_tickers_stream_symbol_timestamp ON tickers (stream, symbol, timestamp);
+CREATE TABLE tickers_default PARTITION OF tickers DEFAULT;
+
+DO $$
+DECLARE
+    day DATE;
+BEGIN
+    FOR day IN
+        SELECT DISTINCT (to_timestamp(timestamp / 1000.0) AT TIME ZONE 'UTC')::date FROM tickers_legacy
+    LOOP
+        EXECUTE format(
+            'CREATE TABLE %I PARTITION OF tickers FOR VALUES FROM (%L) TO (%L)',
+            'tickers_p' || to_char(day, 'YYYYMMDD'),
+            day::text || ' 00:00:00+00',
+            (day + 1)::text || ' 00:00:00+00'
+        );
+    END LOOP;
+END $$;
+
+INSERT INTO tickers (symbol, price, source, stream, timestamp, created_at)
+SELECT symbol, price, source, stream, to_timestamp(timestamp / 1000.0), COALESCE(created_at, CURRENT_TIMESTAMP)
+FROM tickers_legacy;
+DROP TABLE tickers_legacy;
+
+ALTER TABLE config_audit
+    ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING to_timestamp(updated_at / 1000.0);
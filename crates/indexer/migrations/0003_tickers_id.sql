@@ -0,0 +1,3 @@
+-- Sequential id of every tick, the tiebreaker of ticks sharing a timestamp when paginating.
+-- Existing rows are numbered in no particular order.
+ALTER TABLE tickers ADD COLUMN id BIGSERIAL;
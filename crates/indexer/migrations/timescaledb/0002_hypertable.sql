@@ -0,0 +1,26 @@
+-- tickers as a TimescaleDB hypertable with daily chunks on a timestamptz column, chunks are
+-- created by TimescaleDB and dropped by its retention policy.
+CREATE EXTENSION IF NOT EXISTS timescaledb;
+
+ALTER TABLE tickers RENAME TO tickers_legacy;
+ALTER INDEX idx_tickers_symbol_timestamp RENAME TO idx_tickers_legacy_symbol_timestamp;
+ALTER INDEX idx_tickers_stream_symbol_timestamp RENAME TO idx_tickers_legacy_stream_symbol_timestamp;
+
+CREATE TABLE tickers (
+    symbol VARCHAR(20) NOT NULL,
+    price NUMERIC NOT NULL,
+    source VARCHAR(50) NOT NULL,
+    stream VARCHAR(10) NOT NULL DEFAULT 'smoothed',
+    timestamp TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+SELECT create_hypertable('tickers', 'timestamp', chunk_time_interval => INTERVAL '1 day');
+CREATE INDEX idx_tickers_stream_symbol_timestamp ON tickers (stream, symbol, timestamp DESC);
+
+INSERT INTO tickers (symbol, price, source, stream, timestamp, created_at)
+SELECT symbol, price, source, stream, to_timestamp(timestamp / 1000.0), COALESCE(created_at, CURRENT_TIMESTAMP)
+FROM tickers_legacy;
+DROP TABLE tickers_legacy;
+
+ALTER TABLE config_audit
+    ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING to_timestamp(updated_at / 1000.0);
@@ -0,0 +1,385 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use common::{AppError, AppResult, Backoff, Context, MpSc, SharedRwRef, SpawnResult, Worker};
+use etcd::Leadership;
+use jiff::Timestamp;
+use serde::Serialize;
+use tokio::{sync::mpsc::Sender, time::Instant};
+use tokio_postgres::Client;
+
+use crate::{config::IndexerConfigDiff, sink::postgres::is_data_error};
+
+/// An applied change of the app config.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ConfigAuditEntry {
+    /// The etcd revision of the change, if it came from etcd
+    pub revision: Option<i64>,
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub updated_at: Timestamp,
+    pub diff: IndexerConfigDiff,
+}
+
+/// Append-only trail of the config changes applied by this instance.
+///
+/// The most recent `capacity` entries are kept in memory, every entry is also handed to the
+/// [`ConfigAuditWriter`], if any, which persists it to the `config_audit` table.
+#[derive(Clone)]
+pub struct ConfigAuditLog {
+    capacity: usize,
+    entries: SharedRwRef<VecDeque<ConfigAuditEntry>>,
+    sender: Option<Sender<ConfigAuditEntry>>,
+}
+
+impl Default for ConfigAuditLog {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl ConfigAuditLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: SharedRwRef::new(VecDeque::with_capacity(capacity)),
+            sender: None,
+        }
+    }
+
+    /// Reads `config_audit_capacity` (default 1000) from the static config
+    pub fn from_context(context: &Context) -> Self {
+        let capacity = context
+            .config
+            .get_int("config_audit_capacity")
+            .unwrap_or(1000) as usize;
+        Self::new(capacity)
+    }
+
+    /// Also persists every entry through the writer
+    pub fn with_writer(mut self, writer: &ConfigAuditWriter) -> Self {
+        self.sender = Some(writer.mpsc.sender());
+        self
+    }
+
+    pub fn record(&self, revision: Option<i64>, diff: IndexerConfigDiff) {
+        let entry = ConfigAuditEntry {
+            revision,
+            updated_at: Timestamp::now(),
+            diff,
+        };
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.try_send(entry.clone()) {
+                log::error!("failed to persist config audit entry: {}", e);
+            }
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.write();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The most recent entries, newest first
+    pub fn list(&self, limit: usize) -> Vec<ConfigAuditEntry> {
+        self.entries
+            .read()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Persists the entries of a [`ConfigAuditLog`] to the `config_audit` table.
+///
+/// Every instance applies the same changes, so only the leader writes them. The connection is
+/// re-established with backoff once it is lost, entries are kept until they are written, up to
+/// the capacity of the log.
+pub struct ConfigAuditWriter {
+    context: Context,
+    database_url: String,
+    mpsc: MpSc<ConfigAuditEntry>,
+    leadership: Leadership,
+    capacity: usize,
+}
+
+impl ConfigAuditWriter {
+    pub fn new(context: Context) -> AppResult<Self> {
+        let database_url = context.config.get_string("database_url")?;
+        let capacity = context
+            .config
+            .get_int("config_audit_capacity")
+            .unwrap_or(1000) as usize;
+        Ok(Self {
+            context,
+            database_url,
+            mpsc: MpSc::new(100),
+            leadership: Leadership::always(),
+            capacity,
+        })
+    }
+
+    /// Only writes to the database while this instance is the leader
+    pub fn with_leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
+}
+
+/// Connection of the [`ConfigAuditWriter`], re-established with backoff once it is lost.
+struct AuditConnection {
+    name: String,
+    database_url: String,
+    client: Option<Client>,
+    backoff: Backoff,
+    next_attempt: Instant,
+}
+
+impl AuditConnection {
+    fn new(name: &str, database_url: String) -> Self {
+        Self {
+            name: name.to_string(),
+            database_url,
+            client: None,
+            backoff: Backoff::new(0, 1, 30, 2),
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// The client, connecting first if the connection is lost and the backoff has elapsed
+    async fn client(&mut self) -> Option<&Client> {
+        if self
+            .client
+            .as_ref()
+            .is_some_and(|client| client.is_closed())
+        {
+            log::warn!("{} lost the database connection", self.name);
+            self.client = None;
+        }
+        if self.client.is_none() && Instant::now() >= self.next_attempt {
+            match tokio_postgres::connect(&self.database_url, tokio_postgres::NoTls).await {
+                Ok((client, connection)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = connection.await {
+                            log::error!("database connection error: {}", e);
+                        }
+                    });
+                    log::info!("{} connected to the database", self.name);
+                    self.backoff.reset();
+                    self.client = Some(client);
+                }
+                Err(e) => self.back_off(&e.to_string()),
+            }
+        }
+        self.client.as_ref()
+    }
+
+    fn back_off(&mut self, reason: &str) {
+        self.client = None;
+        let delay_secs = self.backoff.next().unwrap_or(30);
+        log::error!(
+            "{} failed to reach the database, retrying in {}s: {}",
+            self.name,
+            delay_secs,
+            reason
+        );
+        self.next_attempt = Instant::now() + Duration::from_secs(delay_secs as u64);
+    }
+
+    /// Writes pending entries oldest first until none are left or a write fails
+    async fn flush(&mut self, pending: &mut VecDeque<ConfigAuditEntry>) {
+        while let Some(entry) = pending.front() {
+            let Some(client) = self.client().await else {
+                return;
+            };
+            match insert_entry(entry, client).await {
+                Ok(_) => {}
+                Err(AppError::PostgresError(e)) if e.code().is_some_and(is_data_error) => {
+                    log::error!(
+                        "{} dropping config audit entry of revision {:?}: {}",
+                        self.name,
+                        entry.revision,
+                        e
+                    );
+                }
+                Err(e) => {
+                    self.back_off(&e.to_string());
+                    return;
+                }
+            }
+            pending.pop_front();
+        }
+    }
+}
+
+impl Worker for ConfigAuditWriter {
+    fn spawn(&mut self) -> SpawnResult {
+        let context = self.context.clone();
+        let mut connection = AuditConnection::new(&context.name, self.database_url.clone());
+        let leadership = self.leadership.clone();
+        let capacity = self.capacity;
+        let receiver = self.mpsc.receiver();
+
+        tokio::spawn(async move {
+            let mut receiver = receiver.ok_or(AppError::GenericError(format!(
+                "{} spawned more than once",
+                context.name
+            )))?;
+            let mut app = context.app.subscribe();
+            let mut pending = VecDeque::new();
+            let mut retry = tokio::time::interval(Duration::from_secs(1));
+
+            loop {
+                tokio::select! {
+                    _ = app.recv() => {
+                        log::info!("{} received exit signal", context.name);
+                        return Ok(format!("{} exited", context.name));
+                    }
+                    entry = receiver.recv() => {
+                        let Some(entry) = entry else {
+                            return Ok(format!("{} exited", context.name));
+                        };
+                        if !leadership.is_leader() {
+                            continue;
+                        }
+                        if pending.len() >= capacity.max(1) {
+                            log::error!("{} dropping unwritten config audit entry", context.name);
+                            pending.pop_front();
+                        }
+                        pending.push_back(entry);
+                    }
+                    _ = retry.tick(), if !pending.is_empty() => {}
+                }
+                connection.flush(&mut pending).await;
+            }
+        })
+    }
+}
+
+pub async fn insert_entry(entry: &ConfigAuditEntry, client: &Client) -> AppResult<()> {
+    let diff = serde_json::to_string(&entry.diff)?;
+    client
+        .execute(
+            "INSERT INTO config_audit (revision, updated_at, diff) VALUES ($1, $2, $3::text::jsonb)",
+            &[&entry.revision, &SystemTime::from(entry.updated_at), &diff],
+        )
+        .await
+        .map_err(AppError::PostgresError)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use crate::schema::{migrate, MIGRATIONS};
+
+    use super::*;
+
+    fn writer_context(database_url: &str) -> Context {
+        Context::from_config(
+            Config::builder()
+                .set_override("database_url", database_url)
+                .unwrap()
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_config_audit_log_keeps_most_recent() {
+        let log = ConfigAuditLog::new(2);
+        log.record(Some(1), IndexerConfigDiff::default());
+        log.record(Some(2), IndexerConfigDiff::default());
+        log.record(None, IndexerConfigDiff::default());
+
+        let revisions = log
+            .list(10)
+            .into_iter()
+            .map(|entry| entry.revision)
+            .collect::<Vec<_>>();
+        assert_eq!(revisions, vec![None, Some(2)]);
+        assert_eq!(log.list(1).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_config_audit_writer_keeps_running_without_database() {
+        let context = writer_context("postgresql://postgres@127.0.0.1:1/postgres");
+        let mut writer = ConfigAuditWriter::new(context.clone()).unwrap();
+        let log = ConfigAuditLog::new(10).with_writer(&writer);
+        let handle = writer.spawn();
+
+        log.record(Some(1), IndexerConfigDiff::default());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!handle.is_finished());
+
+        context.exit();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    /// Runs against the Postgres at `TEST_DATABASE_URL` in a scratch schema, skipped if unset
+    #[tokio::test]
+    async fn test_config_audit_writer_retries_until_written() {
+        let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let (mut client, connection) =
+            tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let schema = format!("indexer_audit_test_{}", std::process::id());
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema}"
+            ))
+            .await
+            .unwrap();
+
+        // The table doesn't exist yet, so the first write fails and is retried
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        let context = writer_context(&format!(
+            "{}{}options=-csearch_path%3D{}",
+            database_url, separator, schema
+        ));
+        let mut writer = ConfigAuditWriter::new(context.clone()).unwrap();
+        let log = ConfigAuditLog::new(10).with_writer(&writer);
+        let handle = writer.spawn();
+        log.record(Some(7), IndexerConfigDiff::default());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        migrate(&mut client, MIGRATIONS).await.unwrap();
+
+        let revisions = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let rows = client
+                    .query("SELECT revision FROM config_audit", &[])
+                    .await
+                    .unwrap();
+                if !rows.is_empty() {
+                    return rows
+                        .iter()
+                        .map(|row| row.get::<_, Option<i64>>(0))
+                        .collect::<Vec<_>>();
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the audit entry");
+        assert_eq!(revisions, vec![Some(7)]);
+
+        context.exit();
+        assert!(handle.await.unwrap().is_ok());
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+    }
+}
@@ -1,14 +1,20 @@
 use std::collections::HashMap;
 
-use common::{Context, SharedRwRef};
-use etcd::EtcdWatcherHandler;
+use common::{AppError, AppResult, Context, SharedRef, SharedRwRef, WorkerRef};
+use etcd::{ConfigSource, EtcdClient, EtcdKeyChange, EtcdWatcher, EtcdWatcherHandler};
 use exchange::{Exchange, ExchangeConfig, ExchangeConfigChangeHandler};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use wsclient::ReplayConfig;
 
-use crate::processing::{
-    SmoothingConfig, SmoothingConfigChangeHandler, WeightedAverageConfig,
-    WeightedAverageConfigChangeHandler,
+use crate::{
+    audit::ConfigAuditLog,
+    processing::{
+        SmoothingConfig, SmoothingConfigChangeHandler, WeightedAverageConfig,
+        WeightedAverageConfigChangeHandler,
+    },
+    status::InstanceStatusTracker,
+    utils::FeedManager,
 };
 
 pub type ExchangeConfigHandlerRef = Box<dyn ExchangeConfigChangeHandler + Send + Sync>;
@@ -16,22 +22,75 @@ pub type SmoothingConfigChangeHandlerRef = Box<dyn SmoothingConfigChangeHandler
 pub type WeightedAverageConfigChangeHandlerRef =
     Box<dyn WeightedAverageConfigChangeHandler + Send + Sync>;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct IndexerConfig {
     #[serde(flatten)]
     config: HashMap<Exchange, FeedConfig>,
 }
 
 #[allow(unused)]
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct FeedConfig {
     exchange_config: ExchangeConfig,
     smoothing_config: SmoothingConfig,
     weight: Decimal,
 }
 
+impl FeedConfig {
+    pub fn exchange_config(&self) -> &ExchangeConfig {
+        &self.exchange_config
+    }
+
+    pub fn smoothing_config(&self) -> &SmoothingConfig {
+        &self.smoothing_config
+    }
+}
+
 #[allow(unused)]
 impl IndexerConfig {
+    /// Assembles the config from per-exchange keys under a prefix, e.g. `/indexer/feeds/binance`
+    pub fn from_feeds(feeds: HashMap<String, FeedConfig>) -> AppResult<Self> {
+        let mut config = Self::default();
+        for (key, feed_config) in feeds {
+            config.insert(exchange_from_key(&key)?, feed_config);
+        }
+        Ok(config)
+    }
+
+    pub fn insert(&mut self, exchange: Exchange, feed_config: FeedConfig) {
+        self.config.insert(exchange, feed_config);
+    }
+
+    pub fn remove(&mut self, exchange: &Exchange) -> Option<FeedConfig> {
+        self.config.remove(exchange)
+    }
+
+    /// Applies updates of per-exchange keys, returning the exchanges whose feed changed
+    fn apply_key_changes(
+        &mut self,
+        changes: &[EtcdKeyChange<FeedConfig>],
+    ) -> AppResult<Vec<Exchange>> {
+        let mut exchanges = Vec::with_capacity(changes.len());
+        for change in changes {
+            let exchange = exchange_from_key(&change.key)?;
+            match &change.config {
+                Some(feed_config) => self.insert(exchange.clone(), feed_config.clone()),
+                None => {
+                    self.remove(&exchange);
+                }
+            }
+            exchanges.push(exchange);
+        }
+        Ok(exchanges)
+    }
+
+    /// The exchanges and their feed configs, ordered by exchange
+    pub fn feeds(&self) -> Vec<(&Exchange, &FeedConfig)> {
+        let mut feeds = self.config.iter().collect::<Vec<_>>();
+        feeds.sort_by_key(|(exchange, _)| exchange.to_string());
+        feeds
+    }
+
     pub fn get_exchange_config(&self, exchange: Exchange) -> Option<&ExchangeConfig> {
         self.config
             .get(&exchange)
@@ -49,11 +108,191 @@ impl IndexerConfig {
             .get(&exchange)
             .map(|feed_config| &feed_config.weight)
     }
+
+    /// The sections of each exchange's feed config that differ in the new config.
+    ///
+    /// An exchange config whose connection changed can't be applied to the running feed, so
+    /// its whole feed is restarted instead of applying its exchange and smoothing configs.
+    pub fn diff(&self, new: &IndexerConfig) -> IndexerConfigDiff {
+        let mut exchanges = self
+            .config
+            .keys()
+            .chain(new.config.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        exchanges.sort_by_key(|exchange| exchange.to_string());
+        exchanges.dedup();
+
+        let mut changes = Vec::new();
+        for exchange in exchanges {
+            match (self.config.get(&exchange), new.config.get(&exchange)) {
+                (None, Some(feed_config)) => changes.push(FeedConfigChange::Added {
+                    exchange,
+                    feed_config: Box::new(feed_config.clone()),
+                }),
+                (Some(_), None) => changes.push(FeedConfigChange::Removed { exchange }),
+                (Some(old), Some(new)) => {
+                    if old.exchange_config.requires_restart(&new.exchange_config) {
+                        changes.push(FeedConfigChange::Restarted {
+                            exchange: exchange.clone(),
+                            old: Box::new(old.clone()),
+                            new: Box::new(new.clone()),
+                        });
+                    } else {
+                        if old.exchange_config != new.exchange_config {
+                            changes.push(FeedConfigChange::ExchangeConfig {
+                                exchange: exchange.clone(),
+                                old: Box::new(old.exchange_config.clone()),
+                                new: Box::new(new.exchange_config.clone()),
+                            });
+                        }
+                        if old.smoothing_config != new.smoothing_config {
+                            changes.push(FeedConfigChange::SmoothingConfig {
+                                exchange: exchange.clone(),
+                                old: old.smoothing_config.clone(),
+                                new: new.smoothing_config.clone(),
+                            });
+                        }
+                    }
+                    if old.weight != new.weight {
+                        changes.push(FeedConfigChange::Weight {
+                            exchange,
+                            old: old.weight,
+                            new: new.weight,
+                        });
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        IndexerConfigDiff { changes }
+    }
+
+    /// Weights of the exchanges that have instruments configured
+    pub fn get_active_weights(&self) -> HashMap<Exchange, Decimal> {
+        self.config
+            .iter()
+            .filter(|(_, feed_config)| !feed_config.exchange_config.instruments.is_empty())
+            .map(|(exchange, feed_config)| (exchange.clone(), feed_config.weight))
+            .collect()
+    }
+}
+
+/// A changed section of an exchange's feed config
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum FeedConfigChange {
+    Added {
+        exchange: Exchange,
+        feed_config: Box<FeedConfig>,
+    },
+    Removed {
+        exchange: Exchange,
+    },
+    /// The connection of the exchange changed, so its feed is started anew
+    Restarted {
+        exchange: Exchange,
+        old: Box<FeedConfig>,
+        new: Box<FeedConfig>,
+    },
+    ExchangeConfig {
+        exchange: Exchange,
+        old: Box<ExchangeConfig>,
+        new: Box<ExchangeConfig>,
+    },
+    SmoothingConfig {
+        exchange: Exchange,
+        old: SmoothingConfig,
+        new: SmoothingConfig,
+    },
+    Weight {
+        exchange: Exchange,
+        old: Decimal,
+        new: Decimal,
+    },
+}
+
+/// Difference between two [`IndexerConfig`]s, see [`IndexerConfig::diff`]
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct IndexerConfigDiff {
+    pub changes: Vec<FeedConfigChange>,
+}
+
+impl IndexerConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Parses the exchange from the last segment of a per-exchange key, e.g. `/indexer/feeds/binance`
+pub fn exchange_from_key(key: &str) -> AppResult<Exchange> {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|_| AppError::ConfigError(format!("unknown exchange in key {}", key)))
+}
+
+/// App config assembled from per-exchange keys under an etcd prefix.
+pub struct EtcdFeedsConfigSource {
+    context: Context,
+    client: EtcdClient,
+    prefix: String,
+    /// Revision the config was loaded at, which the watcher resumes after
+    revision: Option<i64>,
+    /// Keys present under the prefix at that revision
+    keys: Vec<String>,
+}
+
+impl EtcdFeedsConfigSource {
+    pub fn new(context: Context, client: EtcdClient, prefix: String) -> Self {
+        Self {
+            context,
+            client,
+            prefix,
+            revision: None,
+            keys: Vec::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ConfigSource<IndexerConfigChangeHandler, IndexerConfig> for EtcdFeedsConfigSource {
+    async fn load(&mut self) -> AppResult<IndexerConfig> {
+        let (feeds, revision) = self
+            .client
+            .get_prefix_with_revision::<FeedConfig>(&self.prefix)
+            .await?;
+        let keys = feeds.keys().cloned().collect();
+        let config = IndexerConfig::from_feeds(feeds)?;
+        self.revision = Some(revision);
+        self.keys = keys;
+        Ok(config)
+    }
+
+    fn revision(&self) -> Option<i64> {
+        self.revision
+    }
+
+    fn watcher(&self, handler: IndexerConfigChangeHandler) -> WorkerRef {
+        let mut watcher = EtcdWatcher::<IndexerConfigChangeHandler, FeedConfig>::new(
+            self.context.clone(),
+            self.client.clone(),
+            self.prefix.clone(),
+        )
+        .with_prefix()
+        .with_revision(self.revision)
+        .with_keys(self.keys.clone());
+        watcher.add_handler(handler);
+        Box::new(watcher)
+    }
 }
 
 #[derive(Clone)]
 pub struct IndexerConfigChangeHandler {
     context: Context,
+    current_config: SharedRwRef<IndexerConfig>,
+    audit_log: ConfigAuditLog,
+    feed_manager: Option<FeedManager>,
+    status: Option<InstanceStatusTracker>,
     exchange_config_callbacks: SharedRwRef<HashMap<Exchange, ExchangeConfigHandlerRef>>,
     smoothing_config_callbacks: SharedRwRef<HashMap<Exchange, SmoothingConfigChangeHandlerRef>>,
     weighted_average_config_callbacks: SharedRwRef<Vec<WeightedAverageConfigChangeHandlerRef>>,
@@ -63,14 +302,60 @@ impl IndexerConfigChangeHandler {
     pub fn new(context: Context) -> Self {
         Self {
             context,
+            current_config: SharedRwRef::new(IndexerConfig::default()),
+            audit_log: ConfigAuditLog::default(),
+            feed_manager: None,
+            status: None,
             exchange_config_callbacks: SharedRwRef::new(HashMap::new()),
             smoothing_config_callbacks: SharedRwRef::new(HashMap::new()),
             weighted_average_config_callbacks: SharedRwRef::new(Vec::new()),
         }
     }
 
+    /// Records the applied config changes to the audit log
+    pub fn with_audit_log(mut self, audit_log: ConfigAuditLog) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Starts the feeds of added exchanges and stops those of removed exchanges
+    pub fn with_feed_manager(mut self, feed_manager: FeedManager) -> Self {
+        self.feed_manager = Some(feed_manager);
+        self
+    }
+
+    /// Reports the applied config revision and the connection state of the feeds
+    pub fn with_status(mut self, status: InstanceStatusTracker) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Starts the feeds of all exchanges of the config through the feed manager
+    pub fn start_feeds(&self, config: &IndexerConfig) -> AppResult<()> {
+        self.validate_replay(config)?;
+        if let Some(feed_manager) = &self.feed_manager {
+            for (exchange, feed_config) in config.feeds() {
+                feed_manager.start(exchange, feed_config, self)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaying a single recording file is only possible with a single feed
+    fn validate_replay(&self, config: &IndexerConfig) -> AppResult<()> {
+        match ReplayConfig::from_context(&self.context) {
+            Some(replay_config) => replay_config.validate(config.feeds().len()),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the config the workers were started with, which per-exchange key changes apply to
+    pub fn set_current_config(&self, config: IndexerConfig) {
+        *self.current_config.write() = config;
+    }
+
     pub fn add_exchange_config_handler(
-        &mut self,
+        &self,
         exchange: Exchange,
         handler: ExchangeConfigHandlerRef,
     ) {
@@ -80,7 +365,7 @@ impl IndexerConfigChangeHandler {
     }
 
     pub fn add_smoothing_config_handler(
-        &mut self,
+        &self,
         exchange: Exchange,
         handler: SmoothingConfigChangeHandlerRef,
     ) {
@@ -89,8 +374,24 @@ impl IndexerConfigChangeHandler {
             .insert(exchange, handler);
     }
 
+    /// Tracks whether the websocket of an exchange's feed is connected
+    pub fn add_connection_status(&self, exchange: Exchange, connected: SharedRef<bool>) {
+        if let Some(status) = &self.status {
+            status.add_connection(exchange, connected);
+        }
+    }
+
+    /// Unregisters the exchange and smoothing config handlers of an exchange
+    pub fn remove_feed_handlers(&self, exchange: &Exchange) {
+        self.exchange_config_callbacks.write().remove(exchange);
+        self.smoothing_config_callbacks.write().remove(exchange);
+        if let Some(status) = &self.status {
+            status.remove_connection(exchange);
+        }
+    }
+
     pub fn add_weighted_average_config_handler(
-        &mut self,
+        &self,
         handler: WeightedAverageConfigChangeHandlerRef,
     ) {
         self.weighted_average_config_callbacks.write().push(handler);
@@ -98,75 +399,385 @@ impl IndexerConfigChangeHandler {
 }
 
 impl EtcdWatcherHandler<IndexerConfig> for IndexerConfigChangeHandler {
+    fn validate(&self, config: &IndexerConfig) -> AppResult<()> {
+        self.validate_config(config)
+    }
+
     fn handle_config_change(&self, config: IndexerConfig) {
-        let mut weights = HashMap::new();
-        for (exchange, feed_config) in &config.config {
-            if let Some(handler) = self.exchange_config_callbacks.write().get_mut(exchange) {
-                match handler.handle_config_change(feed_config.exchange_config.clone()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("error handling exchange config change: {}", e);
-                        let _ = self
-                            .context
-                            .log_and_exit(&format!("error handling exchange config change: {}", e))
-                            .unwrap();
+        let _ = self.apply_config(config, None);
+    }
+
+    fn handle_config_update(&self, config: IndexerConfig, revision: i64) -> AppResult<()> {
+        self.apply_config(config, Some(revision))
+    }
+}
+
+impl EtcdWatcherHandler<FeedConfig> for IndexerConfigChangeHandler {
+    fn validate_key_changes(&self, changes: &[EtcdKeyChange<FeedConfig>]) -> AppResult<()> {
+        let mut config = self.current_config.read().clone();
+        config.apply_key_changes(changes)?;
+        self.validate_config(&config)
+    }
+
+    fn handle_config_change(&self, feed_config: FeedConfig) {
+        log::warn!(
+            "{} ignoring feed config without a key: {:?}",
+            self.context.name,
+            feed_config
+        );
+    }
+
+    fn handle_key_changes(
+        &self,
+        changes: Vec<EtcdKeyChange<FeedConfig>>,
+        revision: i64,
+    ) -> AppResult<()> {
+        let mut config = self.current_config.read().clone();
+        config.apply_key_changes(&changes)?;
+        self.apply_config(config, Some(revision))
+    }
+}
+
+impl IndexerConfigChangeHandler {
+    /// Checks every section that differs from the current config with the handlers it would
+    /// be dispatched to, so that a change is either applied as a whole or not at all
+    pub fn validate_config(&self, config: &IndexerConfig) -> AppResult<()> {
+        let weighted_average_config = WeightedAverageConfig::new(config.get_active_weights())
+            .map_err(|e| AppError::ConfigError(format!("invalid weights: {}", e)))?;
+        for handler in self.weighted_average_config_callbacks.read().iter() {
+            handler
+                .validate_config_change(&weighted_average_config)
+                .map_err(|e| AppError::ConfigError(format!("invalid weights: {}", e)))?;
+        }
+        self.validate_replay(config)?;
+
+        let invalid = |exchange: &Exchange, section: &str, e: AppError| {
+            AppError::ConfigError(format!("invalid {} of {}: {}", section, exchange, e))
+        };
+        for change in self.current_config.read().diff(config).changes {
+            match change {
+                FeedConfigChange::Added {
+                    exchange,
+                    feed_config,
+                } => {
+                    feed_config
+                        .exchange_config
+                        .validate()
+                        .map_err(|e| invalid(&exchange, "exchange config", e))?;
+                    feed_config
+                        .smoothing_config
+                        .validate()
+                        .map_err(|e| invalid(&exchange, "smoothing config", e))?;
+                }
+                FeedConfigChange::Restarted { exchange, new, .. } => {
+                    if self.feed_manager.is_none() {
+                        return Err(invalid(
+                            &exchange,
+                            "exchange config",
+                            AppError::ConfigError(
+                                "the connection can't be changed without restarting the indexer"
+                                    .to_string(),
+                            ),
+                        ));
                     }
+                    new.exchange_config
+                        .validate()
+                        .map_err(|e| invalid(&exchange, "exchange config", e))?;
+                    new.smoothing_config
+                        .validate()
+                        .map_err(|e| invalid(&exchange, "smoothing config", e))?;
                 }
-            }
-            if let Some(handler) = self.smoothing_config_callbacks.write().get_mut(exchange) {
-                match handler.handle_config_change(exchange, feed_config.smoothing_config.clone()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("error handling smoothing config change: {}", e);
-                        let _ = self
-                            .context
-                            .log_and_exit(&format!("error handling smoothing config change: {}", e))
-                            .unwrap();
+                FeedConfigChange::ExchangeConfig { exchange, new, .. } => {
+                    match self.exchange_config_callbacks.read().get(&exchange) {
+                        Some(handler) => handler.validate_config_change(&new),
+                        None => new.validate(),
+                    }
+                    .map_err(|e| invalid(&exchange, "exchange config", e))?;
+                }
+                FeedConfigChange::SmoothingConfig { exchange, new, .. } => {
+                    match self.smoothing_config_callbacks.read().get(&exchange) {
+                        Some(handler) => handler.validate_config_change(&new),
+                        None => new.validate(),
                     }
+                    .map_err(|e| invalid(&exchange, "smoothing config", e))?;
                 }
+                FeedConfigChange::Removed { .. } | FeedConfigChange::Weight { .. } => {}
             }
-            if !feed_config.exchange_config.instruments.is_empty() {
-                weights.insert(exchange.clone(), feed_config.weight);
+        }
+        Ok(())
+    }
+
+    /// Validates the config and dispatches only the sections that differ from the current
+    /// config to their handlers.
+    ///
+    /// The weights are re-applied whenever the active weights change, since they are
+    /// normalized across all exchanges. If a handler fails, the sections applied so far,
+    /// including the failing one as it may have been applied in part, are rolled back and the
+    /// current config stays in effect.
+    fn apply_config(&self, config: IndexerConfig, revision: Option<i64>) -> AppResult<()> {
+        let current_config = self.current_config.read().clone();
+        let diff = current_config.diff(&config);
+        if diff.is_empty() {
+            log::info!(
+                "{} config at revision {:?} is unchanged",
+                self.context.name,
+                revision
+            );
+            self.set_applied_revision(revision);
+            return Ok(());
+        }
+        if let Err(e) = self.validate_config(&config) {
+            log::error!(
+                "{} rejected config at revision {:?}: {}",
+                self.context.name,
+                revision,
+                e
+            );
+            return Err(e);
+        }
+
+        let mut applied = Vec::with_capacity(diff.changes.len());
+        let mut result = Ok(());
+        for change in diff.changes.iter() {
+            log::info!("{} applying config change: {:?}", self.context.name, change);
+            applied.push(change);
+            result = self.apply_change(change);
+            if result.is_err() {
+                break;
             }
         }
+        let weights_changed = current_config.get_active_weights() != config.get_active_weights();
+        if result.is_ok() && weights_changed {
+            result = self.apply_weights(&config);
+        }
 
-        let weighted_average_config = WeightedAverageConfig::new(weights);
-        if let Err(e) = &weighted_average_config {
-            log::error!("error creating weighted average config: {}", e);
-            let _ = self
-                .context
-                .log_and_exit(&format!("error creating weighted average config: {}", e))
-                .unwrap();
-            return;
+        if let Err(e) = result {
+            log::error!(
+                "{} failed to apply config at revision {:?}, rolling back: {}",
+                self.context.name,
+                revision,
+                e
+            );
+            if weights_changed {
+                if let Err(e) = self.apply_weights(&current_config) {
+                    log::error!("{} failed to roll back weights: {}", self.context.name, e);
+                }
+            }
+            for change in applied.into_iter().rev() {
+                if let Err(e) = self.revert_change(change, &current_config) {
+                    log::error!(
+                        "{} failed to roll back config change {:?}: {}",
+                        self.context.name,
+                        change,
+                        e
+                    );
+                }
+            }
+            return Err(AppError::ConfigError(format!(
+                "failed to apply config, rolled back: {}",
+                e
+            )));
         }
 
-        let weighted_average_config = weighted_average_config.unwrap();
-        for handler in self.weighted_average_config_callbacks.write().iter_mut() {
-            match handler.handle_config_change(weighted_average_config.clone()) {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("error handling weighted average config change: {}", e);
-                    let _ = self
-                        .context
-                        .log_and_exit(&format!(
-                            "error handling weighted average config change: {}",
-                            e
-                        ))
-                        .unwrap();
+        self.audit_log.record(revision, diff);
+        self.set_current_config(config);
+        self.set_applied_revision(revision);
+        Ok(())
+    }
+
+    fn set_applied_revision(&self, revision: Option<i64>) {
+        if let (Some(status), Some(revision)) = (&self.status, revision) {
+            status.set_config_revision(revision);
+        }
+    }
+
+    fn apply_change(&self, change: &FeedConfigChange) -> AppResult<()> {
+        match change {
+            FeedConfigChange::Added {
+                exchange,
+                feed_config,
+            } => match &self.feed_manager {
+                Some(feed_manager) => feed_manager.start(exchange, feed_config, self),
+                None => {
+                    self.apply_exchange_config(exchange, &feed_config.exchange_config)?;
+                    self.apply_smoothing_config(exchange, &feed_config.smoothing_config)
+                }
+            },
+            FeedConfigChange::Removed { exchange } => match &self.feed_manager {
+                Some(feed_manager) => feed_manager.stop(exchange, self),
+                None => {
+                    log::warn!(
+                        "{} feed config of {} was removed, its feed is left running",
+                        self.context.name,
+                        exchange
+                    );
+                    Ok(())
+                }
+            },
+            FeedConfigChange::Restarted { exchange, new, .. } => self.restart_feed(exchange, new),
+            FeedConfigChange::ExchangeConfig { exchange, new, .. } => {
+                self.apply_exchange_config(exchange, new)
+            }
+            FeedConfigChange::SmoothingConfig { exchange, new, .. } => {
+                self.apply_smoothing_config(exchange, new)
+            }
+            FeedConfigChange::Weight { .. } => Ok(()),
+        }
+    }
+
+    /// Restores the section of the current config that a change replaced
+    fn revert_change(&self, change: &FeedConfigChange, current: &IndexerConfig) -> AppResult<()> {
+        match change {
+            FeedConfigChange::Added { exchange, .. } => match &self.feed_manager {
+                Some(feed_manager) => feed_manager.stop(exchange, self),
+                None => Ok(()),
+            },
+            FeedConfigChange::Removed { exchange } => {
+                match (&self.feed_manager, current.config.get(exchange)) {
+                    (Some(feed_manager), Some(feed_config)) => {
+                        feed_manager.start(exchange, feed_config, self)
+                    }
+                    _ => Ok(()),
                 }
             }
+            FeedConfigChange::Restarted { exchange, old, .. } => self.restart_feed(exchange, old),
+            FeedConfigChange::ExchangeConfig { exchange, old, .. } => {
+                self.apply_exchange_config(exchange, old)
+            }
+            FeedConfigChange::SmoothingConfig { exchange, old, .. } => {
+                self.apply_smoothing_config(exchange, old)
+            }
+            FeedConfigChange::Weight { .. } => Ok(()),
         }
     }
+
+    fn restart_feed(&self, exchange: &Exchange, feed_config: &FeedConfig) -> AppResult<()> {
+        match &self.feed_manager {
+            Some(feed_manager) => feed_manager.restart(exchange, feed_config, self),
+            None => Err(AppError::ConfigError(format!(
+                "the feed of {} can't be restarted without a feed manager",
+                exchange
+            ))),
+        }
+    }
+
+    fn apply_exchange_config(
+        &self,
+        exchange: &Exchange,
+        exchange_config: &ExchangeConfig,
+    ) -> AppResult<()> {
+        match self.exchange_config_callbacks.write().get_mut(exchange) {
+            Some(handler) => handler.handle_config_change(exchange_config.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn apply_smoothing_config(
+        &self,
+        exchange: &Exchange,
+        smoothing_config: &SmoothingConfig,
+    ) -> AppResult<()> {
+        match self.smoothing_config_callbacks.write().get_mut(exchange) {
+            Some(handler) => handler.handle_config_change(exchange, smoothing_config.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn apply_weights(&self, config: &IndexerConfig) -> AppResult<()> {
+        let weighted_average_config = WeightedAverageConfig::new(config.get_active_weights())?;
+        for handler in self.weighted_average_config_callbacks.write().iter_mut() {
+            handler.handle_config_change(weighted_average_config.clone())?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use exchange::Exchange;
+    use std::collections::HashMap;
+
+    use common::{AppError, AppResult, Context, SharedRef};
+    use config::Config;
+    use etcd::{EtcdKeyChange, EtcdWatcherHandler};
+    use exchange::{Exchange, ExchangeConfig, ExchangeConfigChangeHandler};
     use rust_decimal_macros::dec;
 
-    use crate::processing::SmoothingConfig;
+    use crate::processing::{SmaParams, SmoothingConfig, SmoothingConfigChangeHandler};
+
+    use crate::audit::ConfigAuditLog;
+
+    use super::{
+        exchange_from_key, FeedConfig, FeedConfigChange, IndexerConfig, IndexerConfigChangeHandler,
+        IndexerConfigDiff,
+    };
 
-    use super::IndexerConfig;
+    #[derive(Clone, Default)]
+    struct RecordingHandler {
+        changes: SharedRef<usize>,
+    }
+
+    impl ExchangeConfigChangeHandler for RecordingHandler {
+        fn handle_config_change(&mut self, _config: ExchangeConfig) -> AppResult<()> {
+            *self.changes.lock() += 1;
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct FailingHandler;
+
+    impl ExchangeConfigChangeHandler for FailingHandler {
+        fn handle_config_change(&mut self, _config: ExchangeConfig) -> AppResult<()> {
+            Err(AppError::WebsocketError("connection closed".to_string()))
+        }
+    }
+
+    /// Takes on the config before failing, like a handler that fails midway
+    #[derive(Clone, Default)]
+    struct PartiallyFailingHandler {
+        configs: SharedRef<Vec<ExchangeConfig>>,
+    }
+
+    impl ExchangeConfigChangeHandler for PartiallyFailingHandler {
+        fn handle_config_change(&mut self, config: ExchangeConfig) -> AppResult<()> {
+            let mut configs = self.configs.lock();
+            configs.push(config);
+            if configs.len() == 1 {
+                return Err(AppError::WebsocketError("connection closed".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingSmoothingHandler {
+        configs: SharedRef<Vec<SmoothingConfig>>,
+    }
+
+    impl SmoothingConfigChangeHandler for RecordingSmoothingHandler {
+        fn handle_config_change(
+            &mut self,
+            _exchange: &Exchange,
+            config: SmoothingConfig,
+        ) -> AppResult<()> {
+            self.configs.lock().push(config);
+            Ok(())
+        }
+    }
+
+    fn feed_config(instruments: &[&str], weight: f64) -> FeedConfig {
+        serde_json::from_value(serde_json::json!({
+            "exchange_config": {
+                "ws_url": "wss://ws.kraken.com/v2",
+                "channels": ["ticker"],
+                "instruments": instruments,
+                "heartbeat_millis": 3000
+            },
+            "smoothing_config": { "type": "pass_thru" },
+            "weight": weight
+        }))
+        .unwrap()
+    }
 
     #[test]
     fn test_indexer_config_deserialize() {
@@ -319,4 +930,312 @@ mod tests {
             Some(&dec!(30.0))
         );
     }
+
+    #[test]
+    fn test_indexer_config_validate() {
+        let handler = IndexerConfigChangeHandler::new(Context::from_config(
+            Config::builder().build().unwrap(),
+        ));
+        let feed = |instruments: &[&str], weight: f64| {
+            serde_json::json!({
+                "exchange_config": {
+                    "ws_url": "wss://ws.kraken.com/v2",
+                    "channels": ["ticker"],
+                    "instruments": instruments,
+                    "heartbeat_millis": 3000
+                },
+                "smoothing_config": { "type": "pass_thru" },
+                "weight": weight
+            })
+        };
+
+        let config: IndexerConfig = serde_json::from_value(serde_json::json!({
+            "kraken": feed(&["BTC/USD"], 60.0),
+            "binance": feed(&["BTCUSDT"], 40.0),
+            "coinbase": feed(&[], 30.0),
+        }))
+        .unwrap();
+        assert!(handler.validate(&config).is_ok());
+
+        let config: IndexerConfig = serde_json::from_value(serde_json::json!({
+            "kraken": feed(&["BTC/USD"], 60.0),
+            "binance": feed(&["BTCUSDT"], 30.0),
+        }))
+        .unwrap();
+        assert!(handler.validate(&config).is_err());
+    }
+
+    #[test]
+    fn test_indexer_config_from_feeds() {
+        let config = IndexerConfig::from_feeds(HashMap::from([
+            (
+                "/indexer/feeds/binance".to_string(),
+                feed_config(&["BTCUSDT"], 40.0),
+            ),
+            (
+                "/indexer/feeds/kraken".to_string(),
+                feed_config(&["BTC/USD"], 60.0),
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(config.get_weight(Exchange::Binance), Some(&dec!(40.0)));
+        assert_eq!(config.get_weight(Exchange::Kraken), Some(&dec!(60.0)));
+        assert!(config.get_weight(Exchange::Coinbase).is_none());
+
+        assert!(exchange_from_key("/indexer/feeds/bitstamp").is_err());
+    }
+
+    #[test]
+    fn test_indexer_config_key_changes() {
+        let audit_log = ConfigAuditLog::new(10);
+        let handler = IndexerConfigChangeHandler::new(Context::from_config(
+            Config::builder().build().unwrap(),
+        ))
+        .with_audit_log(audit_log.clone());
+        let binance = RecordingHandler::default();
+        let kraken = RecordingHandler::default();
+        handler.add_exchange_config_handler(Exchange::Binance, Box::new(binance.clone()));
+        handler.add_exchange_config_handler(Exchange::Kraken, Box::new(kraken.clone()));
+
+        let mut config = IndexerConfig::default();
+        config.insert(Exchange::Binance, feed_config(&["BTCUSDT"], 40.0));
+        config.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 60.0));
+        handler.set_current_config(config);
+
+        // Changing one weight alone no longer sums to 100
+        let changes = vec![EtcdKeyChange {
+            key: "/indexer/feeds/binance".to_string(),
+            config: Some(feed_config(&["BTCUSDT"], 50.0)),
+        }];
+        assert!(handler.validate_key_changes(&changes).is_err());
+
+        let changes = vec![
+            EtcdKeyChange {
+                key: "/indexer/feeds/binance".to_string(),
+                config: Some(feed_config(&["BTCUSDT", "ETHUSDT"], 50.0)),
+            },
+            EtcdKeyChange {
+                key: "/indexer/feeds/coinbase".to_string(),
+                config: Some(feed_config(&[], 0.0)),
+            },
+            EtcdKeyChange {
+                key: "/indexer/feeds/kraken".to_string(),
+                config: Some(feed_config(&["BTC/USD"], 50.0)),
+            },
+        ];
+        assert!(handler.validate_key_changes(&changes).is_ok());
+        EtcdWatcherHandler::<FeedConfig>::handle_key_changes(&handler, changes, 7).unwrap();
+        // Only binance's instruments changed, kraken's weight is not an exchange config change
+        assert_eq!(*binance.changes.lock(), 1);
+        assert_eq!(*kraken.changes.lock(), 0);
+
+        let changes = vec![EtcdKeyChange {
+            key: "/indexer/feeds/coinbase".to_string(),
+            config: None,
+        }];
+        assert!(handler.validate_key_changes(&changes).is_ok());
+        EtcdWatcherHandler::<FeedConfig>::handle_key_changes(&handler, changes, 8).unwrap();
+        assert_eq!(*binance.changes.lock(), 1);
+        assert_eq!(*kraken.changes.lock(), 0);
+
+        let config = handler.current_config.read();
+        assert_eq!(config.get_weight(Exchange::Binance), Some(&dec!(50.0)));
+        assert!(config.get_weight(Exchange::Coinbase).is_none());
+
+        let entries = audit_log.list(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].revision, Some(8));
+        assert_eq!(
+            entries[0].diff.changes,
+            vec![FeedConfigChange::Removed {
+                exchange: Exchange::Coinbase
+            }]
+        );
+        assert_eq!(entries[1].revision, Some(7));
+        assert_eq!(entries[1].diff.changes.len(), 4);
+    }
+
+    #[test]
+    fn test_indexer_config_diff() {
+        let mut old = IndexerConfig::default();
+        old.insert(Exchange::Binance, feed_config(&["BTCUSDT"], 40.0));
+        old.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 60.0));
+        assert!(old.diff(&old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 50.0));
+        new.insert(Exchange::Coinbase, feed_config(&["BTC-USD"], 10.0));
+        new.remove(&Exchange::Binance);
+
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.changes,
+            vec![
+                FeedConfigChange::Removed {
+                    exchange: Exchange::Binance
+                },
+                FeedConfigChange::Added {
+                    exchange: Exchange::Coinbase,
+                    feed_config: Box::new(feed_config(&["BTC-USD"], 10.0)),
+                },
+                FeedConfigChange::Weight {
+                    exchange: Exchange::Kraken,
+                    old: dec!(60.0),
+                    new: dec!(50.0),
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["changes"][0]["change"], "removed");
+        assert_eq!(json["changes"][2]["new"], "50");
+
+        // A new connection restarts the feed with its whole config
+        let mut kraken = feed_config(&["BTC/USD", "ETH/USD"], 60.0);
+        kraken.exchange_config.heartbeat_millis = 10000;
+        kraken.smoothing_config = SmoothingConfig::SimpleMovingAverage {
+            params: SmaParams { window: 5 },
+        };
+        let mut new = old.clone();
+        new.insert(Exchange::Kraken, kraken.clone());
+        assert_eq!(
+            old.diff(&new).changes,
+            vec![FeedConfigChange::Restarted {
+                exchange: Exchange::Kraken,
+                old: Box::new(feed_config(&["BTC/USD"], 60.0)),
+                new: Box::new(kraken),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_indexer_config_rolls_back_failed_change() {
+        let audit_log = ConfigAuditLog::new(10);
+        let handler = IndexerConfigChangeHandler::new(Context::from_config(
+            Config::builder().build().unwrap(),
+        ))
+        .with_audit_log(audit_log.clone());
+        let binance_smoothing = RecordingSmoothingHandler::default();
+        handler
+            .add_smoothing_config_handler(Exchange::Binance, Box::new(binance_smoothing.clone()));
+        handler.add_exchange_config_handler(Exchange::Kraken, Box::new(FailingHandler));
+
+        let mut current = IndexerConfig::default();
+        current.insert(Exchange::Binance, feed_config(&["BTCUSDT"], 40.0));
+        current.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 60.0));
+        handler.set_current_config(current.clone());
+
+        // An invalid section rejects the whole change before anything is applied
+        let mut config = current.clone();
+        let mut kraken = feed_config(&["BTC/USD"], 60.0);
+        kraken.exchange_config.ws_url = "https://ws.kraken.com".to_string();
+        config.insert(Exchange::Kraken, kraken);
+        let mut binance = feed_config(&["BTCUSDT"], 40.0);
+        binance.smoothing_config = SmoothingConfig::SimpleMovingAverage {
+            params: SmaParams { window: 5 },
+        };
+        config.insert(Exchange::Binance, binance.clone());
+        assert!(handler.validate_config(&config).is_err());
+        assert!(handler.handle_config_update(config.clone(), 3).is_err());
+        assert!(binance_smoothing.configs.lock().is_empty());
+
+        // A handler failing midway rolls back the sections applied before it
+        config.insert(Exchange::Kraken, feed_config(&["BTC/USD", "ETH/USD"], 60.0));
+        assert!(handler.validate_config(&config).is_ok());
+        assert!(handler.handle_config_update(config, 4).is_err());
+        assert_eq!(
+            *binance_smoothing.configs.lock(),
+            vec![binance.smoothing_config.clone(), SmoothingConfig::PassThru]
+        );
+        assert_eq!(
+            handler.current_config.read().diff(&current),
+            IndexerConfigDiff::default()
+        );
+        assert!(audit_log.list(10).is_empty());
+    }
+
+    #[test]
+    fn test_indexer_config_rejects_connection_change_without_feed_manager() {
+        let handler = IndexerConfigChangeHandler::new(Context::from_config(
+            Config::builder().build().unwrap(),
+        ));
+        let kraken = RecordingHandler::default();
+        handler.add_exchange_config_handler(Exchange::Kraken, Box::new(kraken.clone()));
+        let mut current = IndexerConfig::default();
+        current.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 60.0));
+        handler.set_current_config(current);
+
+        let mut feed = feed_config(&["BTC/USD"], 60.0);
+        feed.exchange_config.ws_url = "wss://ws-auth.kraken.com/v2".to_string();
+        let mut config = IndexerConfig::default();
+        config.insert(Exchange::Kraken, feed);
+        assert!(handler.handle_config_update(config, 2).is_err());
+        assert_eq!(*kraken.changes.lock(), 0);
+    }
+
+    #[test]
+    fn test_indexer_config_rejects_second_feed_replaying_a_file() {
+        let handler = IndexerConfigChangeHandler::new(Context::from_config(
+            Config::builder()
+                .set_override("ws_replay_path", "recording.jsonl")
+                .unwrap()
+                .build()
+                .unwrap(),
+        ));
+        let mut current = IndexerConfig::default();
+        current.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 60.0));
+        assert!(handler.start_feeds(&current).is_ok());
+        handler.set_current_config(current.clone());
+
+        let mut config = current.clone();
+        config.insert(Exchange::Binance, feed_config(&["BTCUSDT"], 40.0));
+        assert!(handler.start_feeds(&config).is_err());
+        assert!(handler.handle_config_update(config, 2).is_err());
+    }
+
+    #[test]
+    fn test_indexer_config_rolls_back_partially_applied_change() {
+        let handler = IndexerConfigChangeHandler::new(Context::from_config(
+            Config::builder().build().unwrap(),
+        ));
+        let binance_smoothing = RecordingSmoothingHandler::default();
+        handler
+            .add_smoothing_config_handler(Exchange::Binance, Box::new(binance_smoothing.clone()));
+        let kraken = PartiallyFailingHandler::default();
+        handler.add_exchange_config_handler(Exchange::Kraken, Box::new(kraken.clone()));
+
+        let mut current = IndexerConfig::default();
+        current.insert(Exchange::Binance, feed_config(&["BTCUSDT"], 40.0));
+        current.insert(Exchange::Kraken, feed_config(&["BTC/USD"], 60.0));
+        handler.set_current_config(current.clone());
+
+        let mut config = current.clone();
+        let mut binance = feed_config(&["BTCUSDT"], 40.0);
+        binance.smoothing_config = SmoothingConfig::SimpleMovingAverage {
+            params: SmaParams { window: 5 },
+        };
+        config.insert(Exchange::Binance, binance.clone());
+        config.insert(Exchange::Kraken, feed_config(&["BTC/USD", "ETH/USD"], 60.0));
+        assert!(handler.handle_config_update(config.clone(), 2).is_err());
+
+        // The failing change is reverted as well as the one applied before it
+        assert_eq!(
+            *kraken.configs.lock(),
+            vec![
+                config
+                    .get_exchange_config(Exchange::Kraken)
+                    .unwrap()
+                    .clone(),
+                current
+                    .get_exchange_config(Exchange::Kraken)
+                    .unwrap()
+                    .clone(),
+            ]
+        );
+        assert_eq!(
+            *binance_smoothing.configs.lock(),
+            vec![binance.smoothing_config, SmoothingConfig::PassThru]
+        );
+        assert!(handler.current_config.read().diff(&current).is_empty());
+    }
 }
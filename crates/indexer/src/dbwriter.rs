@@ -1,10 +1,18 @@
-use std::time::Duration;
+use std::{str::FromStr, time::Duration};
 
-use common::{AppError, AppInternalMessage, AppResult, Broadcaster, Context, SpawnResult, Worker};
+use common::{
+    AppError, AppInternalMessage, AppResult, Broadcaster, Context, SpawnResult, Ticker, Worker,
+};
+use etcd::Leadership;
 use lazy_static::lazy_static;
 use prometheus as prom;
-use tokio::sync::broadcast::error::RecvError;
-use tokio_postgres::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc::Sender};
+
+use crate::{
+    sink::{sink_from_context, SinkKind, TickSink},
+    spool::TickSpool,
+};
 
 lazy_static! {
     pub static ref DBWRITER_MESSAGES_WRITTEN: prom::CounterVec = prom::register_counter_vec!(
@@ -19,50 +27,273 @@ lazy_static! {
         &[]
     )
     .unwrap();
+    pub static ref DBWRITER_TICKERS_DROPPED: prom::CounterVec = prom::register_counter_vec!(
+        "dbwriter_tickers_dropped",
+        "DB writer tickers dropped as the spool was full or the database rejected them",
+        &[]
+    )
+    .unwrap();
+    pub static ref DBWRITER_INSERT_FAILURES: prom::CounterVec =
+        prom::register_counter_vec!("dbwriter_insert_failures", "DB writer failed inserts", &[])
+            .unwrap();
+    pub static ref DBWRITER_SPOOLED_BATCHES: prom::GaugeVec = prom::register_gauge_vec!(
+        "dbwriter_spooled_batches",
+        "DB writer batches waiting in the spool",
+        &[]
+    )
+    .unwrap();
+    pub static ref DBWRITER_SPOOL_LINES_SKIPPED: prom::CounterVec = prom::register_counter_vec!(
+        "dbwriter_spool_lines_skipped",
+        "DB writer spool lines that could not be read back on startup",
+        &[]
+    )
+    .unwrap();
+    pub static ref DBWRITER_CONNECTED: prom::GaugeVec = prom::register_gauge_vec!(
+        "dbwriter_connected",
+        "DB writer is connected to the database",
+        &[]
+    )
+    .unwrap();
+}
+
+/// The streams of tickers flowing through the indexer that can be persisted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TickStream {
+    /// Ticks as received from the exchanges
+    Raw,
+    /// Ticks of the exchanges after smoothing
+    #[default]
+    Smoothed,
+    /// The weighted average index published by the indexer
+    Index,
+}
+
+impl TickStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Smoothed => "smoothed",
+            Self::Index => "index",
+        }
+    }
+}
+
+impl FromStr for TickStream {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> AppResult<Self> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "smoothed" => Ok(Self::Smoothed),
+            "index" => Ok(Self::Index),
+            _ => Err(AppError::ConfigError(format!(
+                "unknown ticker stream {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// A ticker along with the stream it was taken from, stored as one row of `tickers`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTicker {
+    /// Batches spooled before streams were tagged are smoothed tickers
+    #[serde(default)]
+    pub stream: TickStream,
+    #[serde(flatten)]
+    pub ticker: Ticker,
 }
 
 #[derive(Clone)]
 pub struct DbWriter {
     context: Context,
-    broadcaster: Broadcaster<AppInternalMessage>,
-    database_url: String,
+    persisted_streams: Vec<TickStream>,
+    streams: Vec<(TickStream, Broadcaster<AppInternalMessage>)>,
     insertion_interval_ms: u64,
-    messages: Vec<AppInternalMessage>,
+    messages: Vec<(TickStream, AppInternalMessage)>,
+    leadership: Leadership,
 }
 
 impl DbWriter {
-    pub fn new(context: Context, broadcaster: Broadcaster<AppInternalMessage>) -> AppResult<Self> {
-        let database_url = context.config.get_string("database_url")?;
+    /// The streams to persist are read from `database_streams`, a comma separated list of
+    /// `raw`, `smoothed` and `index` (default `smoothed,index`), the storage they are written
+    /// to from `database_sink`
+    pub fn new(context: Context) -> AppResult<Self> {
+        SinkKind::from_context(&context)?;
+        let persisted_streams = context
+            .config
+            .get_string("database_streams")
+            .unwrap_or("smoothed,index".to_string())
+            .split(',')
+            .map(|stream| stream.trim())
+            .filter(|stream| !stream.is_empty())
+            .map(TickStream::from_str)
+            .collect::<AppResult<Vec<_>>>()?;
         let insertion_interval_ms = context
             .config
             .get_int("database_insertion_interval_ms")
             .unwrap_or(2000) as u64;
         Ok(Self {
             context,
-            broadcaster,
-            database_url,
+            persisted_streams,
+            streams: Vec::new(),
             insertion_interval_ms,
             messages: vec![],
+            leadership: Leadership::always(),
         })
     }
+
+    /// Only writes to the database while this instance is the leader
+    pub fn with_leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
+
+    /// Whether the stream is listed in `database_streams`
+    pub fn persists(&self, stream: TickStream) -> bool {
+        self.persisted_streams.contains(&stream)
+    }
+
+    /// Persists the tickers of the broadcaster as the given stream, if it is to be persisted
+    pub fn with_stream(
+        mut self,
+        stream: TickStream,
+        broadcaster: Broadcaster<AppInternalMessage>,
+    ) -> Self {
+        if self.persists(stream) {
+            self.streams.push((stream, broadcaster));
+        }
+        self
+    }
+}
+
+impl DbWriter {
+    /// Writes a batch, spooling it if the sink is unavailable or older batches are still
+    /// spooled, so that batches are written in order
+    async fn write(
+        &self,
+        batch: Vec<StoredTicker>,
+        sink: &mut dyn TickSink,
+        spool: &mut TickSpool,
+    ) {
+        if spool.is_empty() && sink.ready().await {
+            match sink.write(&batch).await {
+                Ok(rows) => {
+                    log::debug!("{} wrote {} tickers", self.context.name, rows);
+                    return;
+                }
+                Err(e) if sink.is_rejected(&e) => {
+                    self.drop_batch(&batch, &e.to_string());
+                    return;
+                }
+                Err(e) => {
+                    log::error!("{} failed to write batch: {}", self.context.name, e);
+                    DBWRITER_INSERT_FAILURES.with_label_values(&[]).inc();
+                    sink.disconnect();
+                }
+            }
+        }
+        let num_tickers = batch.len();
+        match spool.push(batch) {
+            Ok(true) => {}
+            Ok(false) => {
+                log::error!(
+                    "{} spool is full, dropping {} tickers",
+                    self.context.name,
+                    num_tickers
+                );
+                DBWRITER_TICKERS_DROPPED
+                    .with_label_values(&[])
+                    .inc_by(num_tickers as f64);
+            }
+            Err(e) => {
+                log::error!(
+                    "{} failed to spool {} tickers: {}",
+                    self.context.name,
+                    num_tickers,
+                    e
+                );
+                DBWRITER_TICKERS_DROPPED
+                    .with_label_values(&[])
+                    .inc_by(num_tickers as f64);
+            }
+        }
+        DBWRITER_SPOOLED_BATCHES
+            .with_label_values(&[])
+            .set(spool.len() as f64);
+    }
+
+    /// Writes spooled batches oldest first until the spool is empty or a write fails
+    async fn drain(&self, sink: &mut dyn TickSink, spool: &mut TickSpool) {
+        if spool.is_empty() {
+            return;
+        }
+        if !sink.ready().await {
+            return;
+        }
+        let num_batches = spool.len();
+        while let Some(batch) = spool.front() {
+            match sink.write(batch).await {
+                Ok(_) => {}
+                Err(e) if sink.is_rejected(&e) => {
+                    self.drop_batch(batch, &e.to_string());
+                }
+                Err(e) => {
+                    log::error!("{} failed to write spooled batch: {}", self.context.name, e);
+                    DBWRITER_INSERT_FAILURES.with_label_values(&[]).inc();
+                    sink.disconnect();
+                    break;
+                }
+            }
+            spool.pop_front();
+        }
+        if let Err(e) = spool.sync().await {
+            log::error!("{} failed to sync spool: {}", self.context.name, e);
+        }
+        if spool.len() < num_batches {
+            log::info!(
+                "{} drained {} of {} spooled batches",
+                self.context.name,
+                num_batches - spool.len(),
+                num_batches
+            );
+        }
+        DBWRITER_SPOOLED_BATCHES
+            .with_label_values(&[])
+            .set(spool.len() as f64);
+    }
+
+    fn drop_batch(&self, batch: &[StoredTicker], reason: &str) {
+        log::error!(
+            "{} sink rejected {} tickers, dropping them: {}",
+            self.context.name,
+            batch.len(),
+            reason
+        );
+        DBWRITER_TICKERS_DROPPED
+            .with_label_values(&[])
+            .inc_by(batch.len() as f64);
+    }
 }
 
 impl Worker for DbWriter {
     fn spawn(&mut self) -> SpawnResult {
         let mut dbwriter = self.clone();
         tokio::spawn(async move {
-            let (mut client, connection) =
-                tokio_postgres::connect(&dbwriter.database_url, tokio_postgres::NoTls)
-                    .await
-                    .map_err(AppError::PostgresError)?;
-
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    log::error!("database connection error: {}", e);
-                }
-            });
+            let context = dbwriter.context.clone();
+            let mut spool = tokio::task::spawn_blocking(move || TickSpool::from_context(&context))
+                .await
+                .map_err(|e| AppError::GenericError(format!("spool load task failed: {}", e)))??;
+            DBWRITER_SPOOLED_BATCHES
+                .with_label_values(&[])
+                .set(spool.len() as f64);
+            let mut sink = sink_from_context(&dbwriter.context, dbwriter.leadership.clone())?;
             let mut app = dbwriter.context.app.subscribe();
-            let mut receiver = dbwriter.broadcaster.receiver();
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(1000);
+            for (stream, broadcaster) in dbwriter.streams.iter() {
+                forward_stream(&dbwriter.context, *stream, broadcaster, sender.clone());
+            }
             let mut insertion_interval =
                 tokio::time::interval(Duration::from_millis(dbwriter.insertion_interval_ms));
 
@@ -70,27 +301,29 @@ impl Worker for DbWriter {
                 tokio::select! {
                     _ = app.recv() => {
                         log::info!("{} received exit signal", dbwriter.context.name);
+                        if let Err(e) = sink.close().await {
+                            log::error!("{} failed to close sink: {}", dbwriter.context.name, e);
+                        }
                         return Ok(format!("{} exited", dbwriter.context.name));
                     }
-                    message = receiver.recv() => {
-                        match message {
-                            Ok(message) => {
-                                dbwriter.messages.push(message);
-                            },
-                            Err(RecvError::Lagged(lag)) => {
-                                DBWRITER_MESSAGES_DROPPED.with_label_values(&[]).inc_by(lag as f64);
-                                log::warn!("{} broadcaster receiver lagged by {}", dbwriter.context.name, lag);
-                                continue;
-                            },
-                            Err(e) => {
-                                log::error!("broadcaster receiver error: {}", e);
-                            }
-                        }
+                    Some(message) = receiver.recv() => {
+                        dbwriter.messages.push(message);
                     }
                     _ = insertion_interval.tick() => {
+                        // Spooled batches were already taken on by this instance, so they are
+                        // written regardless of the leadership
+                        sink.maintain().await;
+                        dbwriter.drain(sink.as_mut(), &mut spool).await;
+
                         let num_messages = dbwriter.messages.len();
-                        if !dbwriter.messages.is_empty() {
-                            insert_messages(dbwriter.messages.drain(..).collect(), &mut client).await?;
+                        if !dbwriter.leadership.is_leader() {
+                            // The leader writes the same messages, a standby only keeps up with the stream
+                            dbwriter.messages.clear();
+                        } else if !dbwriter.messages.is_empty() {
+                            let batch = flatten_tickers(dbwriter.messages.drain(..).collect());
+                            if !batch.is_empty() {
+                                dbwriter.write(batch, sink.as_mut(), &mut spool).await;
+                            }
                             DBWRITER_MESSAGES_WRITTEN.with_label_values(&[]).inc_by(num_messages as f64);
                         }
                     }
@@ -100,40 +333,201 @@ impl Worker for DbWriter {
     }
 }
 
-pub async fn insert_messages(
-    messages: Vec<AppInternalMessage>,
-    client: &mut Client,
-) -> AppResult<()> {
+/// Forwards the messages of a persisted stream to the writer until the app exits
+fn forward_stream(
+    context: &Context,
+    stream: TickStream,
+    broadcaster: &Broadcaster<AppInternalMessage>,
+    sender: Sender<(TickStream, AppInternalMessage)>,
+) {
+    let name = context.name.clone();
+    let mut app = context.app.subscribe();
+    let mut receiver = broadcaster.receiver();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = app.recv() => return,
+                message = receiver.recv() => {
+                    match message {
+                        Ok(message) => {
+                            if sender.send((stream, message)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(RecvError::Lagged(lag)) => {
+                            DBWRITER_MESSAGES_DROPPED.with_label_values(&[]).inc_by(lag as f64);
+                            log::warn!("{} {} receiver lagged by {}", name, stream.as_str(), lag);
+                        }
+                        Err(RecvError::Closed) => {
+                            log::error!("{} {} broadcaster closed", name, stream.as_str());
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+pub fn flatten_tickers(messages: Vec<(TickStream, AppInternalMessage)>) -> Vec<StoredTicker> {
     let mut flat_tickers = Vec::new();
-    for message in messages {
+    for (stream, message) in messages {
         match message {
-            AppInternalMessage::Tickers(mut tickers) => {
-                flat_tickers.append(&mut tickers);
+            AppInternalMessage::Tickers(tickers) => {
+                flat_tickers.extend(
+                    tickers
+                        .into_iter()
+                        .map(|ticker| StoredTicker { stream, ticker }),
+                );
             }
         }
     }
+    flat_tickers
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use common::{Source, TickerSymbol};
+    use config::Config;
+    use jiff::Timestamp;
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    /// Sink that fails every write while down and rejects batches with a zero price
+    #[derive(Default)]
+    struct FakeSink {
+        down: bool,
+        connected: bool,
+        written: Vec<Vec<StoredTicker>>,
+    }
 
-    let values: Vec<_> = flat_tickers
-        .iter()
-        .map(|ticker| {
-            format!(
-                "('{}', {}, {}, '{}')",
-                ticker.symbol,
-                ticker.price,
-                ticker.timestamp.as_millisecond(),
-                ticker.source
+    #[async_trait]
+    impl TickSink for FakeSink {
+        async fn ready(&mut self) -> bool {
+            self.connected = !self.down;
+            self.connected
+        }
+
+        async fn write(&mut self, tickers: &[StoredTicker]) -> AppResult<u64> {
+            if self.down {
+                return Err(AppError::GenericError("connection lost".to_string()));
+            }
+            if tickers.iter().any(|t| t.ticker.price.is_zero()) {
+                return Err(AppError::ConfigError("invalid price".to_string()));
+            }
+            self.written.push(tickers.to_vec());
+            Ok(tickers.len() as u64)
+        }
+
+        fn is_rejected(&self, e: &AppError) -> bool {
+            matches!(e, AppError::ConfigError(_))
+        }
+
+        fn disconnect(&mut self) {
+            self.connected = false;
+        }
+    }
+
+    fn dbwriter() -> DbWriter {
+        DbWriter::new(Context::from_config(Config::builder().build().unwrap())).unwrap()
+    }
+
+    fn batch(price: i64) -> Vec<StoredTicker> {
+        vec![StoredTicker {
+            stream: TickStream::Index,
+            ticker: Ticker {
+                symbol: TickerSymbol::BTCUSD,
+                price: Decimal::from(price),
+                source: Source::IndexerWeightedAverage,
+                timestamp: Timestamp::from_second(1).unwrap(),
+            },
+        }]
+    }
+
+    fn prices(batches: &[Vec<StoredTicker>]) -> Vec<Decimal> {
+        batches.iter().flatten().map(|t| t.ticker.price).collect()
+    }
+
+    #[test]
+    fn test_database_streams_selection() {
+        let writer = dbwriter();
+        assert!(!writer.persists(TickStream::Raw));
+        assert!(writer.persists(TickStream::Smoothed));
+        assert!(writer.persists(TickStream::Index));
+
+        let context = |streams: &str| {
+            Context::from_config(
+                Config::builder()
+                    .set_override("database_streams", streams)
+                    .unwrap()
+                    .build()
+                    .unwrap(),
             )
-        })
-        .collect();
-
-    let query = format!(
-        "INSERT INTO tickers (symbol, price, timestamp, source) VALUES {}",
-        values.join(", ")
-    );
-
-    client
-        .execute(&query, &[])
-        .await
-        .map_err(AppError::PostgresError)?;
-    Ok(())
+        };
+        let writer = DbWriter::new(context(" raw, index ,")).unwrap();
+        assert!(writer.persists(TickStream::Raw));
+        assert!(!writer.persists(TickStream::Smoothed));
+        assert!(writer.persists(TickStream::Index));
+
+        // Only the listed streams are subscribed to
+        let writer = writer
+            .with_stream(TickStream::Raw, Broadcaster::new(10))
+            .with_stream(TickStream::Smoothed, Broadcaster::new(10))
+            .with_stream(TickStream::Index, Broadcaster::new(10));
+        let streams = writer
+            .streams
+            .iter()
+            .map(|(stream, _)| *stream)
+            .collect::<Vec<_>>();
+        assert_eq!(streams, vec![TickStream::Raw, TickStream::Index]);
+
+        assert!(DbWriter::new(context("raw,candles")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_spools_failed_batches_and_drains_them_on_reconnect() {
+        let writer = dbwriter();
+        let mut sink = FakeSink::default();
+        let mut spool = TickSpool::new(None, 1024 * 1024).unwrap();
+
+        writer.write(batch(1), &mut sink, &mut spool).await;
+        sink.down = true;
+        writer.write(batch(2), &mut sink, &mut spool).await;
+        assert!(!sink.connected);
+        writer.drain(&mut sink, &mut spool).await;
+        writer.write(batch(3), &mut sink, &mut spool).await;
+        assert_eq!(spool.len(), 2);
+        assert_eq!(prices(&sink.written), vec![Decimal::from(1)]);
+
+        sink.down = false;
+        writer.drain(&mut sink, &mut spool).await;
+        assert!(spool.is_empty());
+        writer.write(batch(4), &mut sink, &mut spool).await;
+        assert_eq!(
+            prices(&sink.written),
+            (1..=4).map(Decimal::from).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drops_rejected_batches() {
+        let writer = dbwriter();
+        let mut sink = FakeSink::default();
+        let mut spool = TickSpool::new(None, 1024 * 1024).unwrap();
+
+        writer.write(batch(0), &mut sink, &mut spool).await;
+        assert!(spool.is_empty());
+        assert!(sink.written.is_empty());
+
+        // A rejected spooled batch doesn't hold back the ones behind it
+        sink.down = true;
+        writer.write(batch(0), &mut sink, &mut spool).await;
+        writer.write(batch(1), &mut sink, &mut spool).await;
+        sink.down = false;
+        writer.drain(&mut sink, &mut spool).await;
+        assert!(spool.is_empty());
+        assert_eq!(prices(&sink.written), vec![Decimal::from(1)]);
+    }
 }
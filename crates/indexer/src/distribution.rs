@@ -1,109 +1,797 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    time::Duration,
+};
 
-use common::{AppError, AppInternalMessage, AppResult, Broadcaster, Context, Ticker, Worker};
+use common::{
+    AppError, AppInternalMessage, AppResult, Backoff, Broadcaster, Context, Source, Ticker,
+    TickerSymbol, Worker,
+};
+use etcd::Leadership;
+use jiff::Timestamp;
 use lazy_static::lazy_static;
 use prometheus as prom;
-use reqwest::Client;
-use tokio::sync::broadcast::error::RecvError;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+
+use crate::jsonrpc::{check_response, is_permanent_error, JsonRpcConfig};
 
 lazy_static! {
     pub static ref DISTRIBUTION_MESSAGES_SENT: prom::CounterVec = prom::register_counter_vec!(
         "distribution_messages_sent",
         "Distribution messages sent",
-        &[]
+        &["target"]
     )
     .unwrap();
     pub static ref DISTRIBUTION_MESSAGES_DROPPED: prom::CounterVec = prom::register_counter_vec!(
         "distribution_messages_dropped",
         "Distribution messages dropped",
-        &[]
+        &["target"]
+    )
+    .unwrap();
+    pub static ref DISTRIBUTION_FAILURES: prom::CounterVec = prom::register_counter_vec!(
+        "distribution_failures",
+        "Distribution failures",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref DISTRIBUTION_RETRIES: prom::CounterVec = prom::register_counter_vec!(
+        "distribution_retries",
+        "Distribution batches sent again after a failure",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref DISTRIBUTION_RETRY_QUEUE: prom::GaugeVec = prom::register_gauge_vec!(
+        "distribution_retry_queue",
+        "Distribution batches waiting to be retried",
+        &["target"]
     )
     .unwrap();
-    pub static ref DISTRIBUTION_FAILURES: prom::CounterVec =
-        prom::register_counter_vec!("distribution_failures", "Distribution failures", &[]).unwrap();
+    pub static ref DISTRIBUTION_BATCHES_FAILED: prom::CounterVec = prom::register_counter_vec!(
+        "distribution_batches_failed",
+        "Distribution batches given up on",
+        &["target"]
+    )
+    .unwrap();
+}
+
+/// A batch of tickers posted to the distribution endpoint.
+///
+/// In the `raw` format only the tickers are posted, with the `id` in the `Idempotency-Key`
+/// header and the sequence number in the `X-Sequence` header. A batch keeps its id and
+/// sequence number when it is retried or replayed, so receivers can drop the ones they have
+/// seen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistributionBatch {
+    /// Unique across instances and restarts
+    pub id: String,
+    /// Increases by one with every batch of an instance, starting at 1 when it starts
+    pub sequence: u64,
+    pub instance_id: String,
+    pub tickers: Vec<Ticker>,
 }
 
+/// Hands out the ids and sequence numbers of the batches of this instance
+#[derive(Debug, Clone)]
+struct BatchSequence {
+    instance_id: String,
+    started_at: i64,
+    sequence: u64,
+}
+
+impl BatchSequence {
+    fn new(instance_id: String) -> Self {
+        Self {
+            instance_id,
+            started_at: Timestamp::now().as_millisecond(),
+            sequence: 0,
+        }
+    }
+
+    fn next(&mut self, tickers: Vec<Ticker>) -> DistributionBatch {
+        self.sequence += 1;
+        DistributionBatch {
+            id: format!("{}-{}-{}", self.instance_id, self.started_at, self.sequence),
+            sequence: self.sequence,
+            instance_id: self.instance_id.clone(),
+            tickers,
+        }
+    }
+}
+
+/// How failed batches are retried and where the ones given up on are kept.
+///
+/// Read from the `retry` object of a target in `distribution_targets`, with the same fields, or
+/// for the `distribution_url` target from the static config:
+/// - `distribution_max_attempts`: attempts per batch, including the first (default 10)
+/// - `distribution_retry_capacity`: batches waiting to be retried, the oldest is given up on
+///   once it is full (default 1000)
+/// - `distribution_timeout_ms`: timeout of each request (default 5000)
+/// - `distribution_retries_per_tick`: batches retried per distribution interval, so a large
+///   backlog doesn't hold up the new batches (default 10)
+/// - `distribution_failed_path`: file the batches given up on are appended to as json lines
+/// - `distribution_replay_failed`: sends the batches of `distribution_failed_path` again on
+///   startup (default false)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub capacity: usize,
+    pub timeout_ms: u64,
+    pub retries_per_tick: usize,
+    pub failed_path: Option<PathBuf>,
+    pub replay_failed: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            capacity: 1000,
+            timeout_ms: 5000,
+            retries_per_tick: 10,
+            failed_path: None,
+            replay_failed: false,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn from_context(context: &Context) -> Self {
+        let config = &context.config;
+        Self {
+            max_attempts: config
+                .get_int("distribution_max_attempts")
+                .unwrap_or(10)
+                .max(1) as u32,
+            capacity: config
+                .get_int("distribution_retry_capacity")
+                .unwrap_or(1000)
+                .max(0) as usize,
+            timeout_ms: config.get_int("distribution_timeout_ms").unwrap_or(5000) as u64,
+            retries_per_tick: config
+                .get_int("distribution_retries_per_tick")
+                .unwrap_or(10)
+                .max(1) as usize,
+            failed_path: config
+                .get_string("distribution_failed_path")
+                .ok()
+                .map(PathBuf::from),
+            replay_failed: config
+                .get_bool("distribution_replay_failed")
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// A batch waiting for its next attempt
+struct PendingBatch {
+    batch: DistributionBatch,
+    backoff: Backoff,
+    next_attempt: Instant,
+}
+
+/// Bounded queue of the batches to retry, in the order they were first sent
+struct RetryQueue {
+    max_attempts: u32,
+    capacity: usize,
+    entries: VecDeque<PendingBatch>,
+}
+
+impl RetryQueue {
+    fn new(config: &RetryConfig) -> Self {
+        Self {
+            max_attempts: config.max_attempts,
+            capacity: config.capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Schedules the first retry of a batch, returning the batch that no longer fits if any
+    fn push(&mut self, batch: DistributionBatch) -> Option<DistributionBatch> {
+        if self.max_attempts <= 1 || self.capacity == 0 {
+            return Some(batch);
+        }
+        // The first attempt already happened, the remaining ones are retries
+        let mut backoff = Backoff::new(self.max_attempts - 1, 1, 60, 2);
+        let delay_secs = backoff.next().unwrap_or(1);
+        let overflow = if self.entries.len() == self.capacity {
+            self.entries.pop_front().map(|pending| pending.batch)
+        } else {
+            None
+        };
+        self.entries.push_back(PendingBatch {
+            batch,
+            backoff,
+            next_attempt: Instant::now() + Duration::from_secs(delay_secs as u64),
+        });
+        overflow
+    }
+
+    /// The oldest batch, if its next attempt is due
+    fn pop_due(&mut self) -> Option<PendingBatch> {
+        if self
+            .entries
+            .front()
+            .is_some_and(|pending| pending.next_attempt <= Instant::now())
+        {
+            return self.entries.pop_front();
+        }
+        None
+    }
+
+    /// Puts a batch whose retry failed back in front, returning it if it ran out of attempts
+    fn reschedule(&mut self, mut pending: PendingBatch) -> Option<DistributionBatch> {
+        let Some(delay_secs) = pending.backoff.next() else {
+            return Some(pending.batch);
+        };
+        pending.next_attempt = Instant::now() + Duration::from_secs(delay_secs as u64);
+        self.entries.push_front(pending);
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Batches that could not be delivered, appended to `distribution_failed_path` as json lines
+/// so they can be replayed.
+///
+/// Replayed batches stay in the file until they are resolved: a batch given up on again is
+/// already in it, the ones sent are removed by rewriting the file once every replayed batch
+/// is resolved, so a crash before that replays them again.
+#[derive(Default)]
+struct FailedBatches {
+    path: Option<PathBuf>,
+    /// Ids of the replayed batches that were neither sent nor given up on again yet
+    replaying: HashSet<String>,
+    /// Ids of the replayed batches that were sent, to be removed from the file
+    sent: HashSet<String>,
+}
+
+impl FailedBatches {
+    fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, batch: &DistributionBatch) -> AppResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.replaying.remove(&batch.id) {
+            return self.rewrite_if_resolved();
+        }
+        let line = serde_json::to_string(batch)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| failed_batches_error(path, e))?;
+        writeln!(file, "{}", line).map_err(|e| failed_batches_error(path, e))?;
+        Ok(())
+    }
+
+    /// Records that a batch was sent, which removes it from the file if it was replayed
+    fn sent(&mut self, batch: &DistributionBatch) -> AppResult<()> {
+        if !self.replaying.remove(&batch.id) {
+            return Ok(());
+        }
+        self.sent.insert(batch.id.clone());
+        self.rewrite_if_resolved()
+    }
+
+    /// Reads the failed batches to replay them, the file is left as is until they are resolved
+    fn load(&mut self) -> AppResult<Vec<DistributionBatch>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let batches = read_failed_batches(path)?;
+        self.replaying = batches.iter().map(|batch| batch.id.clone()).collect();
+        Ok(batches)
+    }
+
+    /// Rewrites the file without the sent batches once no replayed batch is pending
+    fn rewrite_if_resolved(&mut self) -> AppResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.replaying.is_empty() || self.sent.is_empty() {
+            return Ok(());
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path).map_err(|e| failed_batches_error(&tmp_path, e))?;
+        for batch in read_failed_batches(path)? {
+            if !self.sent.contains(&batch.id) {
+                let line = serde_json::to_string(&batch)?;
+                writeln!(file, "{}", line).map_err(|e| failed_batches_error(&tmp_path, e))?;
+            }
+        }
+        file.sync_all()
+            .map_err(|e| failed_batches_error(&tmp_path, e))?;
+        std::fs::rename(&tmp_path, path).map_err(|e| failed_batches_error(path, e))?;
+        self.sent.clear();
+        Ok(())
+    }
+}
+
+/// Reads the batches of the file, skipping the lines that can't be parsed
+fn read_failed_batches(path: &std::path::Path) -> AppResult<Vec<DistributionBatch>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(failed_batches_error(path, e)),
+    };
+    let mut batches = Vec::new();
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line.map_err(|e| failed_batches_error(path, e))?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice(&line) {
+            Ok(batch) => batches.push(batch),
+            Err(e) => log::error!(
+                "skipping unreadable line of {} bytes in {}: {}",
+                line.len(),
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(batches)
+}
+
+fn failed_batches_error(path: &std::path::Path, e: std::io::Error) -> AppError {
+    AppError::GenericError(format!(
+        "failed distribution batches {} error: {}",
+        path.display(),
+        e
+    ))
+}
+
+/// Whether the endpoint refused the batch itself, in which case retrying won't help
+fn is_permanent(e: &AppError) -> bool {
+    match e {
+        AppError::ReqwestError(e) => e.status().is_some_and(|status| {
+            status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS
+        }),
+        AppError::JsonRpcError { code, .. } => is_permanent_error(*code),
+        _ => false,
+    }
+}
+
+/// How batches are posted, read from `distribution_format` or the `format` object of a target,
+/// e.g. `{"type": "jsonrpc", "method": "publish"}`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DistributionFormat {
+    /// The tickers of the [`DistributionBatch`] as a bare JSON array, its id and sequence are
+    /// only sent in the `Idempotency-Key` and `X-Sequence` headers. Any 2xx response is a
+    /// success (`raw`, the default)
+    #[default]
+    Raw,
+    /// JSON-RPC 2.0 requests whose responses are checked for errors (`jsonrpc`)
+    JsonRpc(JsonRpcConfig),
+}
+
+impl DistributionFormat {
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let format = context
+            .config
+            .get_string("distribution_format")
+            .unwrap_or("raw".to_string());
+        match format.as_str() {
+            "raw" => Ok(Self::Raw),
+            "jsonrpc" => Ok(Self::JsonRpc(JsonRpcConfig::from_context(context))),
+            _ => Err(AppError::ConfigError(format!(
+                "unknown distribution format {}",
+                format
+            ))),
+        }
+    }
+}
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+/// An endpoint the index is distributed to, each one is served by its own
+/// [`DistributionWorker`] so a slow target does not hold up the others.
+///
+/// The targets are read from `distribution_targets`, a JSON array of these objects, e.g.
+/// `[{"name": "partner", "url": "http://partner/ticks", "headers": {"Authorization": "Bearer
+/// token"}, "symbols": ["BTCUSD"], "interval_ms": 1000, "format": {"type": "jsonrpc"}}]`.
+/// Without it a single `default` target is read from `distribution_url` and the other
+/// `distribution_*` keys of the static config.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DistributionTarget {
+    /// Names the worker and labels the distribution metrics
+    pub name: String,
+    pub url: String,
+    /// Sent with every request, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Only these symbols are distributed, all of them if empty
+    #[serde(default)]
+    pub symbols: Vec<TickerSymbol>,
+    /// Only tickers from these sources are distributed, all of them if empty
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// How often a batch is posted
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default)]
+    pub format: DistributionFormat,
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+impl DistributionTarget {
+    /// A target receiving every ticker, with the default interval, format and retries
+    pub fn new(name: &str, url: String) -> Self {
+        Self {
+            name: name.to_string(),
+            url,
+            headers: BTreeMap::new(),
+            symbols: Vec::new(),
+            sources: Vec::new(),
+            interval_ms: default_interval_ms(),
+            format: DistributionFormat::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// The targets of `distribution_targets`, or the `default` target of `distribution_url`
+    pub fn all_from_context(context: &Context) -> AppResult<Vec<Self>> {
+        let targets = match context.config.get_string("distribution_targets") {
+            Ok(targets) => serde_json::from_str::<Vec<Self>>(&targets).map_err(|e| {
+                AppError::ConfigError(format!("invalid distribution_targets: {}", e))
+            })?,
+            Err(_) => vec![Self::from_context(context)?],
+        };
+        validate_targets(&targets)?;
+        Ok(targets)
+    }
+
+    /// The `default` target of `distribution_url`
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let url = context.config.get_string("distribution_url")?;
+        let interval_ms = context
+            .config
+            .get_int("distribution_time_interval_ms")
+            .unwrap_or(default_interval_ms() as i64);
+        Ok(Self {
+            interval_ms: interval_ms.max(1) as u64,
+            format: DistributionFormat::from_context(context)?,
+            retry: RetryConfig::from_context(context),
+            ..Self::new("default", url)
+        })
+    }
+
+    /// Whether the ticker passes the symbol and source filters of the target
+    fn accepts(&self, ticker: &Ticker) -> bool {
+        (self.symbols.is_empty() || self.symbols.contains(&ticker.symbol))
+            && (self.sources.is_empty() || self.sources.contains(&ticker.source))
+    }
+
+    fn header_map(&self) -> AppResult<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let invalid = || {
+                AppError::ConfigError(format!(
+                    "invalid header {} of distribution target {}",
+                    name, self.name
+                ))
+            };
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+            let mut value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+}
+
+/// Targets need distinct names, and distinct files for the batches given up on
+fn validate_targets(targets: &[DistributionTarget]) -> AppResult<()> {
+    let invalid = |reason: String| Err(AppError::ConfigError(reason));
+    if targets.is_empty() {
+        return invalid("distribution_targets is empty".to_string());
+    }
+    let mut names = HashSet::new();
+    let mut failed_paths = HashSet::new();
+    for target in targets {
+        if target.name.is_empty() || !names.insert(&target.name) {
+            return invalid(format!(
+                "distribution target names must be unique and not empty: {:?}",
+                target.name
+            ));
+        }
+        if target.retry.retries_per_tick == 0 {
+            return invalid(format!(
+                "retries_per_tick of distribution target {} must be positive",
+                target.name
+            ));
+        }
+        if target.interval_ms == 0 {
+            return invalid(format!(
+                "interval_ms of distribution target {} must be positive",
+                target.name
+            ));
+        }
+        if let DistributionFormat::JsonRpc(JsonRpcConfig {
+            batch_size: Some(0),
+            ..
+        }) = target.format
+        {
+            return invalid(format!(
+                "batch_size of distribution target {} must be positive",
+                target.name
+            ));
+        }
+        if let Some(path) = &target.retry.failed_path {
+            if !failed_paths.insert(path) {
+                return invalid(format!(
+                    "distribution targets share the failed path {}",
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Posts the tickers of the weighted average broadcaster to a [`DistributionTarget`]
 #[derive(Clone)]
 pub struct DistributionWorker {
     context: Context,
     client: Client,
-    url: String,
+    target: DistributionTarget,
     receiver: Broadcaster<AppInternalMessage>,
     messages: Vec<AppInternalMessage>,
+    leadership: Leadership,
 }
 
 impl DistributionWorker {
-    pub fn new(context: Context, url: String, receiver: Broadcaster<AppInternalMessage>) -> Self {
-        Self {
+    pub fn new(
+        context: Context,
+        target: DistributionTarget,
+        receiver: Broadcaster<AppInternalMessage>,
+    ) -> AppResult<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_millis(target.retry.timeout_ms))
+            .default_headers(target.header_map()?)
+            .build()?;
+        Ok(Self {
             context,
-            client: Client::new(),
-            url,
+            client,
+            target,
             receiver,
             messages: Vec::new(),
-        }
+            leadership: Leadership::always(),
+        })
     }
 
-    async fn send_internal_message(&self, messages: Vec<AppInternalMessage>) -> AppResult<()> {
-        let mut flat_tickers: Vec<Ticker> = Vec::new();
-        for message in messages {
-            match message {
-                AppInternalMessage::Tickers(tickers) => {
-                    flat_tickers.extend(tickers.into_iter());
-                }
-            }
-        }
+    /// Only distributes while this instance is the leader
+    pub fn with_leadership(mut self, leadership: Leadership) -> Self {
+        self.leadership = leadership;
+        self
+    }
 
-        let response = self
+    async fn send_batch(&self, batch: &DistributionBatch) -> AppResult<()> {
+        let request = self
             .client
-            .post(self.url.clone())
-            .json(&flat_tickers)
-            .send()
-            .await
-            .map_err(AppError::ReqwestError)?;
+            .post(self.target.url.clone())
+            .header("Idempotency-Key", &batch.id)
+            .header("X-Sequence", batch.sequence);
+        let jsonrpc_requests = match &self.target.format {
+            DistributionFormat::Raw => None,
+            DistributionFormat::JsonRpc(config) => Some(config.requests(batch)),
+        };
+        let request = match (&self.target.format, &jsonrpc_requests) {
+            (DistributionFormat::JsonRpc(config), Some(requests)) => {
+                request.json(&config.body(requests)?)
+            }
+            _ => request.json(&batch.tickers),
+        };
+        let response = request.send().await.map_err(AppError::ReqwestError)?;
 
         if !response.status().is_success() {
             return Err(AppError::ReqwestError(
                 response.error_for_status().unwrap_err(),
             ));
         }
+        if let (DistributionFormat::JsonRpc(config), Some(requests)) =
+            (&self.target.format, &jsonrpc_requests)
+        {
+            let body = response.text().await.map_err(AppError::ReqwestError)?;
+            check_response(requests, config.is_batched(), &body)?;
+        }
         Ok(())
     }
+
+    /// Sends a new batch, queueing it for a retry if sending fails
+    async fn deliver(
+        &self,
+        batch: DistributionBatch,
+        queue: &mut RetryQueue,
+        failed: &mut FailedBatches,
+    ) {
+        let Err(e) = self.send_batch(&batch).await else {
+            return;
+        };
+        log::error!(
+            "{} failed to send batch {}: {}",
+            self.context.name,
+            batch.sequence,
+            e
+        );
+        DISTRIBUTION_FAILURES
+            .with_label_values(&[&self.target.name])
+            .inc();
+        if is_permanent(&e) {
+            self.give_up(&batch, failed);
+        } else if let Some(overflow) = queue.push(batch) {
+            self.give_up(&overflow, failed);
+        }
+        DISTRIBUTION_RETRY_QUEUE
+            .with_label_values(&[&self.target.name])
+            .set(queue.len() as f64);
+    }
+
+    /// Retries the queued batches whose backoff has elapsed, oldest first, until one fails or
+    /// `retries_per_tick` were sent
+    async fn retry(&self, queue: &mut RetryQueue, failed: &mut FailedBatches) {
+        for _ in 0..self.target.retry.retries_per_tick {
+            let Some(pending) = queue.pop_due() else {
+                break;
+            };
+            DISTRIBUTION_RETRIES
+                .with_label_values(&[&self.target.name])
+                .inc();
+            let Err(e) = self.send_batch(&pending.batch).await else {
+                log::info!(
+                    "{} sent batch {} after {} retries",
+                    self.context.name,
+                    pending.batch.sequence,
+                    pending.backoff.get_iteration_count()
+                );
+                if let Err(e) = failed.sent(&pending.batch) {
+                    log::error!(
+                        "{} failed to update failed batches: {}",
+                        self.context.name,
+                        e
+                    );
+                }
+                continue;
+            };
+            log::warn!(
+                "{} failed to retry batch {}: {}",
+                self.context.name,
+                pending.batch.sequence,
+                e
+            );
+            DISTRIBUTION_FAILURES
+                .with_label_values(&[&self.target.name])
+                .inc();
+            if is_permanent(&e) {
+                self.give_up(&pending.batch, failed);
+                continue;
+            }
+            if let Some(batch) = queue.reschedule(pending) {
+                self.give_up(&batch, failed);
+            }
+            break;
+        }
+        DISTRIBUTION_RETRY_QUEUE
+            .with_label_values(&[&self.target.name])
+            .set(queue.len() as f64);
+    }
+
+    fn give_up(&self, batch: &DistributionBatch, failed: &mut FailedBatches) {
+        log::error!(
+            "{} giving up on batch {} with {} tickers",
+            self.context.name,
+            batch.sequence,
+            batch.tickers.len()
+        );
+        DISTRIBUTION_BATCHES_FAILED
+            .with_label_values(&[&self.target.name])
+            .inc();
+        if let Err(e) = failed.push(batch) {
+            log::error!("{} failed to persist batch: {}", self.context.name, e);
+        }
+    }
+}
+
+/// The tickers of the messages that pass the filters of the target
+fn flatten_tickers(messages: Vec<AppInternalMessage>, target: &DistributionTarget) -> Vec<Ticker> {
+    let mut flat_tickers: Vec<Ticker> = Vec::new();
+    for message in messages {
+        match message {
+            AppInternalMessage::Tickers(tickers) => {
+                flat_tickers.extend(tickers.into_iter().filter(|ticker| target.accepts(ticker)));
+            }
+        }
+    }
+    flat_tickers
 }
 
 impl Worker for DistributionWorker {
     fn spawn(&mut self) -> common::SpawnResult {
         let mut worker = self.clone();
         let context = self.context.clone();
-        let distubtion_time_interval_ms = context
-            .config
-            .get_int("distribution_time_interval_ms")
-            .unwrap_or(5000);
         let mut app = worker.context.app.subscribe();
 
         tokio::spawn(async move {
             let mut message_receiver = worker.receiver.receiver();
             let mut distribution_interval =
-                tokio::time::interval(Duration::from_millis(distubtion_time_interval_ms as u64));
+                tokio::time::interval(Duration::from_millis(worker.target.interval_ms));
+            let mut sequence = BatchSequence::new(context.instance_id());
+            let mut queue = RetryQueue::new(&worker.target.retry);
+            let mut failed = FailedBatches::new(worker.target.retry.failed_path.clone());
+            if worker.target.retry.replay_failed {
+                let batches = failed.load().unwrap_or_else(|e| {
+                    log::error!("{} failed to read failed batches: {}", context.name, e);
+                    Vec::new()
+                });
+                log::info!(
+                    "{} replaying {} failed batches",
+                    context.name,
+                    batches.len()
+                );
+                for batch in batches {
+                    if let Some(overflow) = queue.push(batch) {
+                        worker.give_up(&overflow, &mut failed);
+                    }
+                }
+                // Replayed batches are due right away
+                for pending in queue.entries.iter_mut() {
+                    pending.next_attempt = Instant::now();
+                }
+            }
 
             loop {
                 tokio::select! {
                     _ = app.recv() => {
                         log::info!("{} received exit message", context.name);
+                        for pending in queue.entries.drain(..) {
+                            worker.give_up(&pending.batch, &mut failed);
+                        }
                         return Ok(format!("{} received exit message", context.name));
                     }
                     _ = distribution_interval.tick() => {
+                        // Queued batches were already taken on by this instance, so they are
+                        // retried regardless of the leadership
+                        worker.retry(&mut queue, &mut failed).await;
+
                         let messages = worker.messages.drain(..).collect::<Vec<_>>();
                         let num_messages = messages.len();
-                        if num_messages > 0 {
-                            log::info!("{} sending {} messages", context.name, num_messages);
-                            DISTRIBUTION_MESSAGES_SENT.with_label_values(&[]).inc_by(num_messages as f64);
-                            match worker.send_internal_message(messages).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    log::error!("error sending internal message: {}", e);
-                                    DISTRIBUTION_FAILURES.with_label_values(&[]).inc();
-                                }
+                        if !worker.leadership.is_leader() {
+                            log::debug!("{} is not the leader, skipping {} messages", context.name, num_messages);
+                        } else if num_messages > 0 {
+                            let tickers = flatten_tickers(messages, &worker.target);
+                            if tickers.is_empty() {
+                                log::debug!("{} filtered out all of {} messages", context.name, num_messages);
+                                continue;
                             }
+                            log::info!("{} sending {} messages", context.name, num_messages);
+                            DISTRIBUTION_MESSAGES_SENT.with_label_values(&[&worker.target.name]).inc_by(num_messages as f64);
+                            let batch = sequence.next(tickers);
+                            worker.deliver(batch, &mut queue, &mut failed).await;
                         }
                     }
                     message = message_receiver.recv() => {
@@ -112,7 +800,7 @@ impl Worker for DistributionWorker {
                                 worker.messages.push(message);
                             },
                             Err(RecvError::Lagged(u)) => {
-                                DISTRIBUTION_MESSAGES_DROPPED.with_label_values(&[]).inc_by(u as f64);
+                                DISTRIBUTION_MESSAGES_DROPPED.with_label_values(&[&worker.target.name]).inc_by(u as f64);
                                 log::warn!("{} lagged listening to internal messages : {}", context.name, u);
                             }
                             Err(e) => {
@@ -125,3 +813,345 @@ impl Worker for DistributionWorker {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use common::{SharedRef, Source, TickerSymbol};
+    use config::Config;
+    use rust_decimal_macros::dec;
+    use warp::Filter;
+
+    use super::*;
+
+    fn batch(sequence: &mut BatchSequence) -> DistributionBatch {
+        sequence.next(vec![Ticker {
+            symbol: TickerSymbol::BTCUSD,
+            price: dec!(100),
+            source: Source::IndexerWeightedAverage,
+            timestamp: Timestamp::from_second(1).unwrap(),
+        }])
+    }
+
+    /// Serves the distribution endpoint answering with the given statuses in turn, then 200,
+    /// recording the idempotency key of every request
+    async fn endpoint(statuses: Vec<u16>) -> (String, SharedRef<Vec<String>>) {
+        let requests = SharedRef::new(Vec::new());
+        let recorded = requests.clone();
+        let count = Arc::new(AtomicUsize::new(0));
+        let route = warp::post()
+            .and(warp::header::<String>("idempotency-key"))
+            .and(warp::header::<u64>("x-sequence"))
+            .and(warp::body::json::<Vec<Ticker>>())
+            .map(move |key: String, sequence: u64, tickers: Vec<Ticker>| {
+                assert!(key.ends_with(&format!("-{}", sequence)));
+                assert_eq!(tickers.len(), 1);
+                recorded.lock().push(key);
+                let status = statuses
+                    .get(count.fetch_add(1, Ordering::SeqCst))
+                    .copied()
+                    .unwrap_or(200);
+                warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", address), requests)
+    }
+
+    fn worker(target: DistributionTarget) -> DistributionWorker {
+        DistributionWorker::new(
+            Context::from_config(Config::builder().build().unwrap()).with_name("distribution-test"),
+            target,
+            Broadcaster::new(10),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retries_with_the_same_idempotency_key() {
+        let (url, requests) = endpoint(vec![503, 503]).await;
+        let worker = worker(DistributionTarget::new("test", url));
+        let mut sequence = BatchSequence::new("indexer-0".to_string());
+        let mut queue = RetryQueue::new(&worker.target.retry);
+        let mut failed = FailedBatches::default();
+
+        let first = batch(&mut sequence);
+        worker.deliver(first.clone(), &mut queue, &mut failed).await;
+        assert_eq!(queue.len(), 1);
+        // Not due yet
+        worker.retry(&mut queue, &mut failed).await;
+        assert_eq!(queue.len(), 1);
+
+        for _ in 0..2 {
+            queue.entries[0].next_attempt = Instant::now();
+            worker.retry(&mut queue, &mut failed).await;
+        }
+        assert_eq!(queue.len(), 0);
+        assert_eq!(*requests.lock(), vec![first.id.clone(); 3]);
+
+        let second = batch(&mut sequence);
+        assert_eq!(second.sequence, 2);
+        assert_ne!(second.id, first.id);
+    }
+
+    #[tokio::test]
+    async fn test_persists_batches_given_up_on() {
+        let path =
+            std::env::temp_dir().join(format!("distribution-failed-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (url, _) = endpoint(vec![400, 503, 503]).await;
+        let config = Config::builder()
+            .set_override("distribution_url", url)
+            .unwrap()
+            .set_override("distribution_max_attempts", 2)
+            .unwrap()
+            .set_override("distribution_failed_path", path.to_str().unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let target = DistributionTarget::from_context(&Context::from_config(config)).unwrap();
+        let worker = worker(target);
+        let mut sequence = BatchSequence::new("indexer-0".to_string());
+        let mut queue = RetryQueue::new(&worker.target.retry);
+        let mut failed = FailedBatches::new(worker.target.retry.failed_path.clone());
+
+        // Rejected outright
+        let rejected = batch(&mut sequence);
+        worker
+            .deliver(rejected.clone(), &mut queue, &mut failed)
+            .await;
+        assert_eq!(queue.len(), 0);
+
+        // Out of attempts
+        let unavailable = batch(&mut sequence);
+        worker
+            .deliver(unavailable.clone(), &mut queue, &mut failed)
+            .await;
+        queue.entries[0].next_attempt = Instant::now();
+        worker.retry(&mut queue, &mut failed).await;
+        assert_eq!(queue.len(), 0);
+
+        // A torn line is skipped, and replayed batches stay in the file until they are sent
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"id\": \"indexer-0").unwrap();
+        let mut failed = FailedBatches::new(Some(path.clone()));
+        let batches = failed.load().unwrap();
+        let ids = batches
+            .iter()
+            .map(|batch| batch.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![rejected.id.clone(), unavailable.id.clone()]);
+        assert_eq!(read_failed_batches(&path).unwrap().len(), 2);
+
+        for batch in batches {
+            assert!(queue.push(batch).is_none());
+        }
+        queue.entries[0].next_attempt = Instant::now();
+        worker.retry(&mut queue, &mut failed).await;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(read_failed_batches(&path).unwrap().len(), 2);
+
+        // Given up on again, the batch is kept once and the sent one removed
+        let pending = queue.entries.pop_front().unwrap();
+        worker.give_up(&pending.batch, &mut failed);
+        let ids = read_failed_batches(&path)
+            .unwrap()
+            .into_iter()
+            .map(|batch| batch.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![unavailable.id]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_caps_retries_per_tick() {
+        let (url, requests) = endpoint(vec![503; 3]).await;
+        let worker = worker(DistributionTarget {
+            retry: RetryConfig {
+                retries_per_tick: 2,
+                ..RetryConfig::default()
+            },
+            ..DistributionTarget::new("test", url)
+        });
+        let mut sequence = BatchSequence::new("indexer-0".to_string());
+        let mut queue = RetryQueue::new(&worker.target.retry);
+        let mut failed = FailedBatches::default();
+
+        for _ in 0..3 {
+            worker
+                .deliver(batch(&mut sequence), &mut queue, &mut failed)
+                .await;
+        }
+        for pending in queue.entries.iter_mut() {
+            pending.next_attempt = Instant::now();
+        }
+        worker.retry(&mut queue, &mut failed).await;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(requests.lock().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_jsonrpc_errors() {
+        let route = warp::post()
+            .and(warp::body::json::<serde_json::Value>())
+            .map(|request: serde_json::Value| {
+                let code = match request["params"]["sequence"].as_u64() {
+                    Some(1) => -32000,
+                    _ => -32602,
+                };
+                warp::reply::json(&serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "error": { "code": code, "message": "failed" },
+                    "id": request["id"],
+                }))
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let worker = worker(DistributionTarget {
+            format: DistributionFormat::JsonRpc(JsonRpcConfig::default()),
+            ..DistributionTarget::new("test", format!("http://{}", address))
+        });
+        let mut sequence = BatchSequence::new("indexer-0".to_string());
+        let mut queue = RetryQueue::new(&worker.target.retry);
+        let mut failed = FailedBatches::default();
+
+        // Server errors are retried, invalid params are not
+        worker
+            .deliver(batch(&mut sequence), &mut queue, &mut failed)
+            .await;
+        assert_eq!(queue.len(), 1);
+        worker
+            .deliver(batch(&mut sequence), &mut queue, &mut failed)
+            .await;
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sends_target_headers() {
+        let route = warp::post()
+            .and(warp::header::<String>("authorization"))
+            .map(|authorization: String| {
+                let status = if authorization == "Bearer secret" {
+                    200
+                } else {
+                    401
+                };
+                warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let target = DistributionTarget {
+            headers: BTreeMap::from([("Authorization".to_string(), "Bearer secret".to_string())]),
+            ..DistributionTarget::new("test", format!("http://{}", address))
+        };
+        let worker = worker(target);
+        let mut sequence = BatchSequence::new("indexer-0".to_string());
+        assert!(worker.send_batch(&batch(&mut sequence)).await.is_ok());
+    }
+
+    #[test]
+    fn test_targets_from_context() {
+        let context = |config: Config| Context::from_config(config);
+        let config = Config::builder()
+            .set_override("distribution_url", "http://localhost:1080")
+            .unwrap()
+            .set_override("distribution_time_interval_ms", 1000)
+            .unwrap()
+            .build()
+            .unwrap();
+        let targets = DistributionTarget::all_from_context(&context(config)).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].name, "default");
+        assert_eq!(targets[0].interval_ms, 1000);
+
+        let targets = r#"[
+            {"name": "all", "url": "http://localhost:1080"},
+            {
+                "name": "btc",
+                "url": "http://localhost:1081",
+                "headers": {"Authorization": "Bearer secret"},
+                "symbols": ["BTCUSD"],
+                "sources": ["indexer_weighted_average"],
+                "interval_ms": 250,
+                "format": {"type": "jsonrpc", "batch_size": 100},
+                "retry": {"max_attempts": 3, "failed_path": "/tmp/btc.jsonl"}
+            }
+        ]"#;
+        let config = Config::builder()
+            .set_override("distribution_targets", targets)
+            .unwrap()
+            .build()
+            .unwrap();
+        let targets = DistributionTarget::all_from_context(&context(config)).unwrap();
+        assert_eq!(
+            targets[0],
+            DistributionTarget::new("all", targets[0].url.clone())
+        );
+        let btc = &targets[1];
+        assert_eq!(btc.interval_ms, 250);
+        assert_eq!(
+            btc.format,
+            DistributionFormat::JsonRpc(JsonRpcConfig {
+                batch_size: Some(100),
+                ..JsonRpcConfig::default()
+            })
+        );
+        assert_eq!(btc.retry.max_attempts, 3);
+        assert_eq!(btc.retry.capacity, 1000);
+
+        let ticker = |symbol, source| Ticker {
+            symbol,
+            price: dec!(100),
+            source,
+            timestamp: Timestamp::from_second(1).unwrap(),
+        };
+        let messages = vec![AppInternalMessage::Tickers(vec![
+            ticker(TickerSymbol::BTCUSD, Source::IndexerWeightedAverage),
+            ticker(TickerSymbol::ETHUSD, Source::IndexerWeightedAverage),
+            ticker(TickerSymbol::BTCUSD, Source::Binance),
+        ])];
+        assert_eq!(flatten_tickers(messages.clone(), &targets[0]).len(), 3);
+        let tickers = flatten_tickers(messages, btc);
+        assert_eq!(tickers.len(), 1);
+        assert_eq!(tickers[0].symbol, TickerSymbol::BTCUSD);
+        assert_eq!(tickers[0].source, Source::IndexerWeightedAverage);
+
+        for targets in [
+            "[]",
+            r#"[{"name": "a", "url": "http://a"}, {"name": "a", "url": "http://b"}]"#,
+            r#"[{"name": "a", "url": "http://a", "interval_ms": 0}]"#,
+            r#"[{"name": "a", "url": "http://a", "retry": {"retries_per_tick": 0}}]"#,
+            r#"[{"name": "a", "url": "http://a", "format": {"type": "jsonrpc", "batch_size": 0}}]"#,
+            r#"[{"name": "a", "url": "http://a", "symbol": ["BTCUSD"]}]"#,
+        ] {
+            let config = Config::builder()
+                .set_override("distribution_targets", targets)
+                .unwrap()
+                .build()
+                .unwrap();
+            assert!(DistributionTarget::all_from_context(&context(config)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_retry_queue_is_bounded() {
+        let config = RetryConfig {
+            capacity: 2,
+            ..RetryConfig::default()
+        };
+        let mut queue = RetryQueue::new(&config);
+        let mut sequence = BatchSequence::new("indexer-0".to_string());
+        let first = batch(&mut sequence);
+        assert!(queue.push(first.clone()).is_none());
+        assert!(queue.push(batch(&mut sequence)).is_none());
+        let overflow = queue.push(batch(&mut sequence)).unwrap();
+        assert_eq!(overflow.id, first.id);
+        assert_eq!(queue.len(), 2);
+    }
+}
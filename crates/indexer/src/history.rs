@@ -0,0 +1,667 @@
+use std::{convert::Infallible, sync::Arc, time::SystemTime};
+
+use common::{AppError, AppResult, Context, SharedAsyncRef, TickerSymbol};
+use jiff::{SignedDuration, Timestamp};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{Client, Row};
+use warp::{
+    http::{header, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::dbwriter::TickStream;
+
+/// Rows returned per page unless the query asks for fewer
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 10_000;
+
+/// A stored ticker as served by the history endpoints
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TickRecord {
+    pub symbol: String,
+    pub price: Decimal,
+    pub source: String,
+    pub stream: String,
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub timestamp: Timestamp,
+}
+
+/// Open, high, low and close of the prices within a bucket starting at `timestamp`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Candle {
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub timestamp: Timestamp,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub ticks: i64,
+}
+
+/// A page of results along with the cursor to pass to get the next one, if any
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// Rows that can be served as CSV
+pub trait CsvRecord {
+    const HEADER: &'static str;
+
+    fn csv_row(&self) -> String;
+}
+
+impl CsvRecord for TickRecord {
+    const HEADER: &'static str = "symbol,price,source,stream,timestamp";
+
+    fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            self.symbol, self.price, self.source, self.stream, self.timestamp
+        )
+    }
+}
+
+impl CsvRecord for Candle {
+    const HEADER: &'static str = "timestamp,open,high,low,close,ticks";
+
+    fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.timestamp, self.open, self.high, self.low, self.close, self.ticks
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Width of the candles, e.g. `1m`, `15m`, `1h` or `1d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandleInterval {
+    pub secs: i64,
+}
+
+impl std::str::FromStr for CandleInterval {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> AppResult<Self> {
+        let invalid = || AppError::ConfigError(format!("invalid candle interval {}", s));
+        let unit = s.chars().last().ok_or_else(invalid)?;
+        let count = s[..s.len() - unit.len_utf8()]
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+        let unit_secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        if !(1..=366).contains(&count) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            secs: count * unit_secs,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LatestQuery {
+    pub symbol: Option<TickerSymbol>,
+    pub stream: Option<TickStream>,
+    pub format: Option<Format>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RangeQuery {
+    pub symbol: TickerSymbol,
+    pub stream: Option<TickStream>,
+    /// RFC 3339 start of the range, inclusive, one hour before `to` if absent
+    pub from: Option<String>,
+    /// RFC 3339 end of the range, exclusive, now if absent
+    pub to: Option<String>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub format: Option<Format>,
+    /// Only used for candles
+    pub interval: Option<String>,
+}
+
+impl RangeQuery {
+    fn stream(&self) -> TickStream {
+        self.stream.unwrap_or(TickStream::Index)
+    }
+
+    fn range(&self) -> AppResult<(Timestamp, Timestamp)> {
+        let parse = |value: &str| {
+            value
+                .parse::<Timestamp>()
+                .map_err(|e| AppError::ConfigError(format!("invalid timestamp {}: {}", value, e)))
+        };
+        let to = match &self.to {
+            Some(to) => parse(to)?,
+            None => Timestamp::now(),
+        };
+        let from = match &self.from {
+            Some(from) => parse(from)?,
+            None => to - SignedDuration::from_hours(1),
+        };
+        if from >= to {
+            return Err(AppError::ConfigError("from must be before to".to_string()));
+        }
+        Ok((from, to))
+    }
+
+    fn limit(&self) -> AppResult<i64> {
+        match self.limit {
+            None => Ok(DEFAULT_LIMIT),
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+            Some(_) => Err(AppError::ConfigError(format!(
+                "limit must be between 1 and {}",
+                MAX_LIMIT
+            ))),
+        }
+    }
+}
+
+/// Position after the last tick of a page, ticks are ordered by timestamp and then by id,
+/// which is unique, so that ticks sharing a timestamp are neither skipped nor repeated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TickCursor {
+    pub timestamp: Timestamp,
+    pub id: i64,
+}
+
+impl TickCursor {
+    pub fn encode(&self) -> String {
+        format!("{}~{}", self.timestamp, self.id)
+    }
+
+    pub fn decode(cursor: &str) -> AppResult<Self> {
+        let invalid = || AppError::ConfigError(format!("invalid cursor {}", cursor));
+        let (timestamp, id) = cursor.split_once('~').ok_or_else(invalid)?;
+        Ok(Self {
+            timestamp: timestamp.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Serves the tickers stored by the [`crate::dbwriter::DbWriter`] from the Postgres database
+/// at `database_url`, connecting on the first query and again after the connection is lost.
+#[derive(Clone)]
+pub struct HistoryStore {
+    database_url: String,
+    client: SharedAsyncRef<Option<Arc<Client>>>,
+}
+
+impl HistoryStore {
+    pub fn new(database_url: String) -> Self {
+        Self {
+            database_url,
+            client: SharedAsyncRef::new(None),
+        }
+    }
+
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        Ok(Self::new(context.config.get_string("database_url")?))
+    }
+
+    async fn client(&self) -> AppResult<Arc<Client>> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = client.as_ref().filter(|client| !client.is_closed()) {
+            return Ok(client.clone());
+        }
+        let (connected, connection) =
+            tokio_postgres::connect(&self.database_url, tokio_postgres::NoTls)
+                .await
+                .map_err(AppError::PostgresError)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("history database connection error: {}", e);
+            }
+        });
+        let connected = Arc::new(connected);
+        *client = Some(connected.clone());
+        Ok(connected)
+    }
+
+    /// The most recent tick of every symbol, or of the given one
+    pub async fn latest(&self, query: &LatestQuery) -> AppResult<Vec<TickRecord>> {
+        let stream = query.stream.unwrap_or(TickStream::Index).as_str();
+        let symbol = query.symbol.as_ref().map(|symbol| symbol.to_string());
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT DISTINCT ON (symbol) symbol, price, source, stream, timestamp FROM tickers
+                 WHERE stream = $1 AND ($2::text IS NULL OR symbol = $2)
+                 ORDER BY symbol, timestamp DESC",
+                &[&stream, &symbol],
+            )
+            .await
+            .map_err(AppError::PostgresError)?;
+        rows.iter().map(tick_record).collect()
+    }
+
+    /// The ticks of a symbol within the range, oldest first
+    pub async fn ticks(&self, query: &RangeQuery) -> AppResult<Page<TickRecord>> {
+        let (from, to) = query.range()?;
+        let limit = query.limit()?;
+        let cursor = query
+            .cursor
+            .as_deref()
+            .map(TickCursor::decode)
+            .transpose()?;
+        let (after, after_id) = match &cursor {
+            Some(cursor) => (cursor.timestamp, cursor.id),
+            // Ids start at 1
+            None => (from - SignedDuration::from_micros(1), 0),
+        };
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT symbol, price, source, stream, timestamp, id FROM tickers
+                 WHERE stream = $1 AND symbol = $2 AND timestamp >= $3 AND timestamp < $4
+                   AND (timestamp, id) > ($5, $6)
+                 ORDER BY timestamp, id
+                 LIMIT $7",
+                &[
+                    &query.stream().as_str(),
+                    &query.symbol.to_string(),
+                    &SystemTime::from(from),
+                    &SystemTime::from(to),
+                    &SystemTime::from(after),
+                    &after_id,
+                    &limit,
+                ],
+            )
+            .await
+            .map_err(AppError::PostgresError)?;
+        let data = rows
+            .iter()
+            .map(tick_record)
+            .collect::<AppResult<Vec<_>>>()?;
+        let next_cursor = match (data.last(), rows.last()) {
+            (Some(last), Some(row)) if data.len() as i64 == limit => Some(
+                TickCursor {
+                    timestamp: last.timestamp,
+                    id: row.try_get(5).map_err(AppError::PostgresError)?,
+                }
+                .encode(),
+            ),
+            _ => None,
+        };
+        Ok(Page { data, next_cursor })
+    }
+
+    /// Candles of a symbol within the range, oldest first. Buckets are aligned to the unix
+    /// epoch and the cursor is the start of the next bucket.
+    pub async fn candles(&self, query: &RangeQuery) -> AppResult<Page<Candle>> {
+        let interval = query
+            .interval
+            .as_deref()
+            .unwrap_or("1m")
+            .parse::<CandleInterval>()?;
+        let (from, to) = query.range()?;
+        let from = match query.cursor.as_deref() {
+            Some(cursor) => cursor
+                .parse::<Timestamp>()
+                .map_err(|_| AppError::ConfigError(format!("invalid cursor {}", cursor)))?,
+            None => from,
+        };
+        let limit = query.limit()?;
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT bucket,
+                        (array_agg(price ORDER BY timestamp))[1],
+                        max(price),
+                        min(price),
+                        (array_agg(price ORDER BY timestamp DESC))[1],
+                        count(*)
+                 FROM (
+                     SELECT date_bin(make_interval(secs => $1), timestamp, TIMESTAMPTZ 'epoch') AS bucket,
+                            price, timestamp
+                     FROM tickers
+                     WHERE stream = $2 AND symbol = $3 AND timestamp >= $4 AND timestamp < $5
+                 ) ticks
+                 GROUP BY bucket
+                 ORDER BY bucket
+                 LIMIT $6",
+                &[
+                    &(interval.secs as f64),
+                    &query.stream().as_str(),
+                    &query.symbol.to_string(),
+                    &SystemTime::from(from),
+                    &SystemTime::from(to),
+                    &limit,
+                ],
+            )
+            .await
+            .map_err(AppError::PostgresError)?;
+        let data = rows.iter().map(candle).collect::<AppResult<Vec<_>>>()?;
+        let next_cursor = match data.last() {
+            Some(last) if data.len() as i64 == limit => {
+                let next = last.timestamp + SignedDuration::from_secs(interval.secs);
+                (next < to).then(|| next.to_string())
+            }
+            _ => None,
+        };
+        Ok(Page { data, next_cursor })
+    }
+}
+
+fn timestamp(row: &Row, index: usize) -> AppResult<Timestamp> {
+    let time = row
+        .try_get::<_, SystemTime>(index)
+        .map_err(AppError::PostgresError)?;
+    Timestamp::try_from(time)
+        .map_err(|e| AppError::GenericError(format!("invalid timestamp: {}", e)))
+}
+
+fn tick_record(row: &Row) -> AppResult<TickRecord> {
+    Ok(TickRecord {
+        symbol: row.try_get(0).map_err(AppError::PostgresError)?,
+        price: row.try_get(1).map_err(AppError::PostgresError)?,
+        source: row.try_get(2).map_err(AppError::PostgresError)?,
+        stream: row.try_get(3).map_err(AppError::PostgresError)?,
+        timestamp: timestamp(row, 4)?,
+    })
+}
+
+fn candle(row: &Row) -> AppResult<Candle> {
+    Ok(Candle {
+        timestamp: timestamp(row, 0)?,
+        open: row.try_get(1).map_err(AppError::PostgresError)?,
+        high: row.try_get(2).map_err(AppError::PostgresError)?,
+        low: row.try_get(3).map_err(AppError::PostgresError)?,
+        close: row.try_get(4).map_err(AppError::PostgresError)?,
+        ticks: row.try_get(5).map_err(AppError::PostgresError)?,
+    })
+}
+
+/// Renders the rows as json or as CSV with a header, the next cursor is sent in the
+/// `x-next-cursor` header of CSV responses
+pub fn render<T: Serialize + CsvRecord>(page: AppResult<Page<T>>, format: Format) -> Response {
+    let page = match page {
+        Ok(page) => page,
+        Err(e) => return error_response(e),
+    };
+    match format {
+        Format::Json => warp::reply::json(&page).into_response(),
+        Format::Csv => {
+            let mut body = String::from(T::HEADER);
+            body.push('\n');
+            for row in page.data.iter() {
+                body.push_str(&row.csv_row());
+                body.push('\n');
+            }
+            let mut response =
+                warp::reply::with_header(body, header::CONTENT_TYPE, "text/csv").into_response();
+            if let Some(cursor) = page.next_cursor.and_then(|c| c.parse().ok()) {
+                response.headers_mut().insert("x-next-cursor", cursor);
+            }
+            response
+        }
+    }
+}
+
+/// Invalid queries are answered with 400, an unavailable database with 503
+fn error_response(e: AppError) -> Response {
+    let status = match e {
+        AppError::ConfigError(_) => StatusCode::BAD_REQUEST,
+        _ => {
+            log::error!("history query failed: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    };
+    let body = warp::reply::json(&serde_json::json!({ "error": e.to_string() }));
+    warp::reply::with_status(body, status).into_response()
+}
+
+/// `/index/latest`, `/index/ticks` and `/index/candles`, answered with 404 when tickers are not
+/// stored in Postgres
+pub fn history_routes(
+    store: Option<HistoryStore>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let store = warp::any().map(move || store.clone());
+
+    let latest = warp::path!("index" / "latest")
+        .and(warp::get())
+        .and(warp::query::<LatestQuery>())
+        .and(store.clone())
+        .and_then(
+            |query: LatestQuery, store: Option<HistoryStore>| async move {
+                let Some(store) = store else {
+                    return Ok::<_, Infallible>(not_stored());
+                };
+                let format = query.format.unwrap_or_default();
+                let page = store.latest(&query).await.map(|data| Page {
+                    data,
+                    next_cursor: None,
+                });
+                Ok(render(page, format))
+            },
+        );
+
+    let ticks = warp::path!("index" / "ticks")
+        .and(warp::get())
+        .and(warp::query::<RangeQuery>())
+        .and(store.clone())
+        .and_then(
+            |query: RangeQuery, store: Option<HistoryStore>| async move {
+                let Some(store) = store else {
+                    return Ok::<_, Infallible>(not_stored());
+                };
+                Ok(render(
+                    store.ticks(&query).await,
+                    query.format.unwrap_or_default(),
+                ))
+            },
+        );
+
+    let candles = warp::path!("index" / "candles")
+        .and(warp::get())
+        .and(warp::query::<RangeQuery>())
+        .and(store)
+        .and_then(
+            |query: RangeQuery, store: Option<HistoryStore>| async move {
+                let Some(store) = store else {
+                    return Ok::<_, Infallible>(not_stored());
+                };
+                Ok(render(
+                    store.candles(&query).await,
+                    query.format.unwrap_or_default(),
+                ))
+            },
+        );
+
+    latest.or(ticks).unify().or(candles).unify()
+}
+
+fn not_stored() -> Response {
+    let body = warp::reply::json(&serde_json::json!({
+        "error": "history is only served when tickers are stored in postgres"
+    }));
+    warp::reply::with_status(body, StatusCode::NOT_FOUND).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::schema::{migrate, MIGRATIONS};
+
+    use super::*;
+
+    #[test]
+    fn test_candle_interval() {
+        assert_eq!("1m".parse::<CandleInterval>().unwrap().secs, 60);
+        assert_eq!("4h".parse::<CandleInterval>().unwrap().secs, 4 * 3600);
+        assert_eq!("1d".parse::<CandleInterval>().unwrap().secs, 86400);
+        for invalid in ["", "m", "0m", "1w", "-1h", "1.5m"] {
+            assert!(invalid.parse::<CandleInterval>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_range_query() {
+        let query = RangeQuery {
+            symbol: TickerSymbol::BTCUSD,
+            stream: None,
+            from: None,
+            to: Some("2025-02-12T21:00:00Z".to_string()),
+            limit: None,
+            cursor: None,
+            format: None,
+            interval: None,
+        };
+        let (from, to) = query.range().unwrap();
+        assert_eq!(from, "2025-02-12T20:00:00Z".parse().unwrap());
+        assert_eq!(to, "2025-02-12T21:00:00Z".parse().unwrap());
+        assert_eq!(query.limit().unwrap(), DEFAULT_LIMIT);
+        assert_eq!(query.stream(), TickStream::Index);
+
+        let query = RangeQuery {
+            from: Some("2025-02-12T21:00:00Z".to_string()),
+            to: Some("2025-02-12T20:00:00Z".to_string()),
+            limit: Some(MAX_LIMIT + 1),
+            ..query
+        };
+        assert!(query.range().is_err());
+        assert!(query.limit().is_err());
+    }
+
+    #[test]
+    fn test_tick_cursor() {
+        let cursor = TickCursor {
+            timestamp: "2025-02-12T21:12:33.778451Z".parse().unwrap(),
+            id: 42,
+        };
+        assert_eq!(TickCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(TickCursor::decode("42").is_err());
+        assert!(TickCursor::decode("2025-02-12T21:12:33.778451Z~binance").is_err());
+    }
+
+    /// Runs against the Postgres at `TEST_DATABASE_URL` in a scratch schema, skipped if unset
+    #[tokio::test]
+    async fn test_ticks_sharing_a_timestamp_are_paginated_once() {
+        let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let (mut client, connection) =
+            tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let schema = format!("indexer_history_test_{}", std::process::id());
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema}"
+            ))
+            .await
+            .unwrap();
+        migrate(&mut client, MIGRATIONS).await.unwrap();
+        // Ticks of the same source and timestamp, e.g. trades of one exchange in the same
+        // microsecond
+        for (price, at) in [
+            (1, "2025-02-12T20:00:00Z"),
+            (2, "2025-02-12T20:00:00Z"),
+            (3, "2025-02-12T20:00:00Z"),
+            (4, "2025-02-12T20:00:01Z"),
+        ] {
+            client
+                .execute(
+                    "INSERT INTO tickers (symbol, price, source, stream, timestamp)
+                     VALUES ('BTCUSD', $1::int, 'binance', 'raw', $2::text::timestamptz)",
+                    &[&price, &at],
+                )
+                .await
+                .unwrap();
+        }
+
+        let store = HistoryStore::new(format!(
+            "{}?options=-csearch_path%3D{}",
+            database_url, schema
+        ));
+        let mut query = RangeQuery {
+            symbol: TickerSymbol::BTCUSD,
+            stream: Some(TickStream::Raw),
+            from: Some("2025-02-12T20:00:00Z".to_string()),
+            to: Some("2025-02-12T21:00:00Z".to_string()),
+            limit: Some(2),
+            cursor: None,
+            format: None,
+            interval: None,
+        };
+        let mut prices = vec![];
+        loop {
+            let page = store.ticks(&query).await.unwrap();
+            prices.extend(page.data.iter().map(|tick| tick.price));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(prices, vec![dec!(1), dec!(2), dec!(3), dec!(4)]);
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_render_csv() {
+        let page = Page {
+            data: vec![Candle {
+                timestamp: "2025-02-12T21:00:00Z".parse().unwrap(),
+                open: dec!(100),
+                high: dec!(102.5),
+                low: dec!(99),
+                close: dec!(101),
+                ticks: 4,
+            }],
+            next_cursor: Some("2025-02-12T21:01:00Z".to_string()),
+        };
+        let response = render(Ok(page), Format::Csv);
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        assert_eq!(response.headers()["x-next-cursor"], "2025-02-12T21:01:00Z");
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "timestamp,open,high,low,close,ticks\n2025-02-12T21:00:00Z,100,102.5,99,101,4\n"
+        );
+
+        let response = render::<Candle>(
+            Err(AppError::ConfigError("invalid".to_string())),
+            Format::Json,
+        );
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_history_routes_without_postgres() {
+        let routes = history_routes(None);
+        let response = warp::test::request()
+            .path("/index/latest?symbol=BTCUSD")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
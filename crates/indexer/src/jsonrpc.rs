@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+
+use common::{AppError, AppResult, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::distribution::DistributionBatch;
+
+const JSONRPC_VERSION: &str = "2.0";
+
+/// A JSON-RPC 2.0 request, the params are passed by name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcRequest<P> {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: P,
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: Option<String>,
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<JsonRpcErrorObject>,
+    /// Null if the server could not read the id of the request
+    #[serde(default)]
+    pub id: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct JsonRpcErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(default)]
+    pub data: Option<Value>,
+}
+
+impl From<JsonRpcErrorObject> for AppError {
+    fn from(error: JsonRpcErrorObject) -> Self {
+        AppError::JsonRpcError {
+            code: error.code,
+            message: error.message,
+        }
+    }
+}
+
+/// Errors a server returns for requests it will never accept: parse error, invalid request,
+/// method not found and invalid params
+pub fn is_permanent_error(code: i64) -> bool {
+    matches!(code, -32700 | -32600 | -32601 | -32602)
+}
+
+/// How a [`DistributionBatch`] is posted as JSON-RPC.
+///
+/// Read from the `method` and `batch_size` of the `format` of a target, or from the static
+/// config:
+/// - `distribution_jsonrpc_method`: the method called (default `indexer_publishTickers`)
+/// - `distribution_jsonrpc_batch_size`: splits the tickers into a JSON-RPC batch of requests
+///   of up to this many tickers each, a single request holds all tickers if absent
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct JsonRpcConfig {
+    pub method: String,
+    pub batch_size: Option<usize>,
+}
+
+impl Default for JsonRpcConfig {
+    fn default() -> Self {
+        Self {
+            method: "indexer_publishTickers".to_string(),
+            batch_size: None,
+        }
+    }
+}
+
+impl JsonRpcConfig {
+    pub fn from_context(context: &Context) -> Self {
+        let method = context
+            .config
+            .get_string("distribution_jsonrpc_method")
+            .unwrap_or(Self::default().method);
+        let batch_size = context
+            .config
+            .get_int("distribution_jsonrpc_batch_size")
+            .ok()
+            .map(|size| size.max(1) as usize);
+        Self { method, batch_size }
+    }
+
+    /// The requests of a batch, the request ids are derived from the batch id so they stay
+    /// the same when the batch is retried
+    pub fn requests(&self, batch: &DistributionBatch) -> Vec<JsonRpcRequest<DistributionBatch>> {
+        let request = |id: String, params: DistributionBatch| JsonRpcRequest {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: self.method.clone(),
+            params,
+            id,
+        };
+        let Some(batch_size) = self.batch_size else {
+            return vec![request(batch.id.clone(), batch.clone())];
+        };
+        batch
+            .tickers
+            .chunks(batch_size)
+            .enumerate()
+            .map(|(index, tickers)| {
+                let params = DistributionBatch {
+                    tickers: tickers.to_vec(),
+                    ..batch.clone()
+                };
+                request(format!("{}:{}", batch.id, index), params)
+            })
+            .collect()
+    }
+
+    /// Whether the requests are sent as a JSON-RPC batch array
+    pub fn is_batched(&self) -> bool {
+        self.batch_size.is_some()
+    }
+
+    /// The body posted for the requests of a batch
+    pub fn body(&self, requests: &[JsonRpcRequest<DistributionBatch>]) -> AppResult<Value> {
+        let body = if self.is_batched() {
+            serde_json::to_value(requests)?
+        } else {
+            serde_json::to_value(&requests[0])?
+        };
+        Ok(body)
+    }
+}
+
+/// Checks the response to the requests, failing with the first error object returned.
+///
+/// A batch response is matched to the requests by id and every request must be answered. The
+/// response to a single request is accepted even if its id differs, as some servers don't
+/// echo it back.
+pub fn check_response(
+    requests: &[JsonRpcRequest<DistributionBatch>],
+    batched: bool,
+    body: &str,
+) -> AppResult<()> {
+    let invalid = |reason: &str| {
+        AppError::GenericError(format!("invalid json-rpc response, {}: {}", reason, body))
+    };
+    if !batched {
+        let response = serde_json::from_str::<JsonRpcResponse>(body)
+            .map_err(|_| invalid("expected a response object"))?;
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+        if response.result.is_none() {
+            return Err(invalid("neither result nor error"));
+        }
+        if response.id != Value::String(requests[0].id.clone()) {
+            log::warn!(
+                "json-rpc response id {} does not match request id {}",
+                response.id,
+                requests[0].id
+            );
+        }
+        return Ok(());
+    }
+
+    // A server that can't read the batch answers with a single error object
+    let responses = match serde_json::from_str::<Vec<JsonRpcResponse>>(body) {
+        Ok(responses) => responses,
+        Err(_) => {
+            let response = serde_json::from_str::<JsonRpcResponse>(body)
+                .map_err(|_| invalid("expected a batch response"))?;
+            return Err(response
+                .error
+                .map(AppError::from)
+                .unwrap_or_else(|| invalid("expected a batch response")));
+        }
+    };
+    let mut responses = responses
+        .into_iter()
+        .filter_map(|response| Some((response.id.as_str()?.to_string(), response)))
+        .collect::<HashMap<_, _>>();
+    for request in requests {
+        let Some(response) = responses.remove(&request.id) else {
+            return Err(invalid(&format!("no response to request {}", request.id)));
+        };
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Source, Ticker, TickerSymbol};
+    use jiff::Timestamp;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn batch() -> DistributionBatch {
+        let ticker = |symbol| Ticker {
+            symbol,
+            price: dec!(100),
+            source: Source::IndexerWeightedAverage,
+            timestamp: Timestamp::from_second(1).unwrap(),
+        };
+        DistributionBatch {
+            id: "indexer-0-1-7".to_string(),
+            sequence: 7,
+            instance_id: "indexer-0".to_string(),
+            tickers: vec![
+                ticker(TickerSymbol::BTCUSD),
+                ticker(TickerSymbol::ETHUSD),
+                ticker(TickerSymbol::BTCUSD),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_single_request() {
+        let config = JsonRpcConfig::default();
+        let body = config.body(&config.requests(&batch())).unwrap();
+        assert_eq!(body["jsonrpc"], "2.0");
+        assert_eq!(body["method"], "indexer_publishTickers");
+        assert_eq!(body["id"], "indexer-0-1-7");
+        assert_eq!(body["params"]["sequence"], 7);
+        assert_eq!(body["params"]["tickers"].as_array().unwrap().len(), 3);
+
+        let requests = config.requests(&batch());
+        let ok = r#"{"jsonrpc": "2.0", "result": {"status": "ok"}, "id": "indexer-0-1-7"}"#;
+        assert!(check_response(&requests, false, ok).is_ok());
+        // The mock server answers with a fixed id
+        let other_id = r#"{"jsonrpc": "2.0", "result": {"status": "ok"}, "id": "some-id"}"#;
+        assert!(check_response(&requests, false, other_id).is_ok());
+
+        let error = r#"{"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": "indexer-0-1-7"}"#;
+        let result = check_response(&requests, false, error);
+        assert!(matches!(
+            result,
+            Err(AppError::JsonRpcError { code: -32601, .. })
+        ));
+        assert!(check_response(&requests, false, r#"{"jsonrpc": "2.0", "id": 1}"#).is_err());
+        assert!(check_response(&requests, false, "[]").is_err());
+    }
+
+    #[test]
+    fn test_batch_request() {
+        let config = JsonRpcConfig {
+            batch_size: Some(2),
+            ..JsonRpcConfig::default()
+        };
+        let body = config.body(&config.requests(&batch())).unwrap();
+        let requests = body.as_array().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["id"], "indexer-0-1-7:0");
+        assert_eq!(requests[1]["id"], "indexer-0-1-7:1");
+        assert_eq!(requests[1]["params"]["id"], "indexer-0-1-7");
+        assert_eq!(
+            requests[1]["params"]["tickers"].as_array().unwrap().len(),
+            1
+        );
+
+        let requests = config.requests(&batch());
+        let ok = r#"[
+            {"jsonrpc": "2.0", "result": true, "id": "indexer-0-1-7:1"},
+            {"jsonrpc": "2.0", "result": true, "id": "indexer-0-1-7:0"}
+        ]"#;
+        assert!(check_response(&requests, true, ok).is_ok());
+
+        let missing = r#"[{"jsonrpc": "2.0", "result": true, "id": "indexer-0-1-7:0"}]"#;
+        assert!(check_response(&requests, true, missing).is_err());
+
+        let failed = r#"[
+            {"jsonrpc": "2.0", "result": true, "id": "indexer-0-1-7:0"},
+            {"jsonrpc": "2.0", "error": {"code": -32000, "message": "busy"}, "id": "indexer-0-1-7:1"}
+        ]"#;
+        let result = check_response(&requests, true, failed);
+        assert!(matches!(
+            result,
+            Err(AppError::JsonRpcError { code: -32000, .. })
+        ));
+
+        let rejected = r#"{"jsonrpc": "2.0", "error": {"code": -32600, "message": "Invalid Request"}, "id": null}"#;
+        let result = check_response(&requests, true, rejected);
+        assert!(matches!(
+            result,
+            Err(AppError::JsonRpcError { code: -32600, .. })
+        ));
+    }
+
+    #[test]
+    fn test_permanent_errors() {
+        assert!(is_permanent_error(-32602));
+        assert!(!is_permanent_error(-32603));
+        assert!(!is_permanent_error(-32000));
+    }
+}
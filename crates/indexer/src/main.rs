@@ -1,11 +1,19 @@
 use common::run_app;
 use runner::IndexerRunner;
 
+mod audit;
 mod config;
 mod dbwriter;
 mod distribution;
+mod history;
+mod jsonrpc;
 mod processing;
 mod runner;
+mod schema;
+mod sink;
+mod spool;
+mod status;
+mod stream;
 mod utils;
 
 fn main() {
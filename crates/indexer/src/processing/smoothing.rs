@@ -1,6 +1,6 @@
 use std::collections::{HashMap, VecDeque};
 
-use common::{AppInternalMessage, AppResult, SharedRwRef, Ticker, TickerSymbol};
+use common::{AppError, AppInternalMessage, AppResult, SharedRwRef, Ticker, TickerSymbol};
 use exchange::Exchange;
 use feed_processing::FeedProcessor;
 use jiff::Timestamp;
@@ -61,8 +61,35 @@ impl std::fmt::Display for SmoothingConfig {
     }
 }
 
+impl SmoothingConfig {
+    pub fn validate(&self) -> AppResult<()> {
+        match self {
+            SmoothingConfig::PassThru => Ok(()),
+            SmoothingConfig::SimpleMovingAverage { params } if params.window == 0 => Err(
+                AppError::ConfigError("sma window must be greater than 0".to_string()),
+            ),
+            SmoothingConfig::ExponentialMovingAverage { params } if params.window == 0 => Err(
+                AppError::ConfigError("ema window must be greater than 0".to_string()),
+            ),
+            SmoothingConfig::ExponentialMovingAverage { params }
+                if params.smoothing <= Decimal::ZERO =>
+            {
+                Err(AppError::ConfigError(
+                    "ema smoothing must be greater than 0".to_string(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 /// A trait for handling changes to the smoothing configuration
 pub trait SmoothingConfigChangeHandler {
+    /// Checks a config before any change of the app config is applied
+    fn validate_config_change(&self, config: &SmoothingConfig) -> AppResult<()> {
+        config.validate()
+    }
+
     fn handle_config_change(
         &mut self,
         exchange: &Exchange,
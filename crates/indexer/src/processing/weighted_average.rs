@@ -51,6 +51,11 @@ impl WeightedAverageConfig {
 }
 
 pub trait WeightedAverageConfigChangeHandler {
+    /// Checks a config before any change of the app config is applied
+    fn validate_config_change(&self, config: &WeightedAverageConfig) -> AppResult<()> {
+        config.validate().map(|_| ())
+    }
+
     fn handle_config_change(&mut self, config: WeightedAverageConfig) -> AppResult<()>;
 }
 
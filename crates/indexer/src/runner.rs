@@ -1,33 +1,60 @@
-use std::collections::HashMap;
-
 use warp::Filter;
 
 use crate::{
-    config::{IndexerConfig, IndexerConfigChangeHandler},
-    dbwriter::DbWriter,
-    distribution::DistributionWorker,
+    audit::{ConfigAuditLog, ConfigAuditWriter},
+    config::{EtcdFeedsConfigSource, IndexerConfig, IndexerConfigChangeHandler},
+    dbwriter::{DbWriter, TickStream},
+    distribution::{DistributionTarget, DistributionWorker},
+    history::{history_routes, HistoryStore},
     processing::{WeightedAverageConfig, WeightedAverageProcessor},
-    utils::{add_binance_workers, add_coinbase_workers, add_kraken_workers},
+    sink::SinkKind,
+    status::{IndexStatusWorker, InstanceStatusTracker},
+    stream::{stream_routes, StreamConfig, StreamHub, StreamSnapshotWorker},
+    utils::FeedManager,
 };
-use common::{static_config, AppResult, Broadcaster, Context, Runner, Workers};
+use common::{static_config, AppError, AppResult, Broadcaster, Context, Runner, Workers};
 use config::Config;
-use etcd::{EtcdClient, EtcdWatcher};
-use exchange::Exchange;
+use etcd::{
+    ConfigSource, EtcdClient, EtcdConfigSource, EtcdStatusPublisher, FileConfigSource,
+    LeaderElection, Leadership,
+};
+use exchange::{DeadLetterStore, Exchange};
 use feed_processing::FeedProcessingWorker;
 use prometheus::Encoder;
+use serde::Deserialize;
 
 pub struct IndexerRunner {
     context: Context,
 }
 
 impl IndexerRunner {
-    pub async fn get_app_config(
+    /// Picks where the app config comes from: a local file at `app_config_path`, per-exchange
+    /// keys under the etcd `app_config_prefix`, or the single etcd `app_config_key`
+    fn config_source(
         &self,
-        config_key: &str,
-        etcd_client: &mut EtcdClient,
-    ) -> AppResult<IndexerConfig> {
-        let config = etcd_client.get::<IndexerConfig>(config_key).await?;
-        Ok(config)
+        etcd_client: Option<EtcdClient>,
+    ) -> AppResult<Box<dyn ConfigSource<IndexerConfigChangeHandler, IndexerConfig>>> {
+        if let Ok(config_path) = self.context.config.get_string("app_config_path") {
+            let source = FileConfigSource::new(self.context.clone(), config_path.into())?;
+            return Ok(Box::new(source));
+        }
+
+        let etcd_client = etcd_client.ok_or(AppError::ConfigError(
+            "etcd_url is required unless app_config_path is set".to_string(),
+        ))?;
+        if let Ok(config_prefix) = self.context.config.get_string("app_config_prefix") {
+            return Ok(Box::new(EtcdFeedsConfigSource::new(
+                self.context.clone(),
+                etcd_client,
+                config_prefix,
+            )));
+        }
+        let config_key = self.context.config.get_string("app_config_key")?;
+        Ok(Box::new(EtcdConfigSource::new(
+            self.context.clone(),
+            etcd_client,
+            config_key,
+        )))
     }
 }
 
@@ -44,69 +71,115 @@ impl Default for IndexerRunner {
 #[async_trait::async_trait]
 impl Runner for IndexerRunner {
     async fn run(&mut self) -> AppResult<String> {
-        let config_key = self.context.config.get_string("app_config_key")?;
-        start_metrics_server(self.context.clone())?;
-
+        let dead_letters = DeadLetterStore::from_context(&self.context)?;
         let mut workers = Workers::new(self.context.clone(), 0);
-        let mut etcd_client = EtcdClient::from_context(&self.context).unwrap();
+
+        // Etcd is only needed for the config when it doesn't come from a local file, for
+        // leader election and for publishing the instance status
+        let needs_etcd = ["leader_election_key", "instance_status_prefix"]
+            .iter()
+            .any(|key| self.context.config.get_string(key).is_ok())
+            || self.context.config.get_string("app_config_path").is_err();
+        let etcd_client = if needs_etcd {
+            Some(EtcdClient::from_context(&self.context)?)
+        } else {
+            None
+        };
 
         // Get App Config Intiailly.
-        // We expecte the app config to be present in etcd for initial startup.
-        let app_config = self.get_app_config(&config_key, &mut etcd_client).await?;
+        // We expect the app config to be present in its source for initial startup.
+        let mut config_source = self.config_source(etcd_client.clone())?;
+        let app_config = config_source.load().await?;
 
-        let mut indexer_config_change_handler = IndexerConfigChangeHandler::new(
-            self.context.with_name("indexer-config-change-handler"),
-        );
         let broadcaster = Broadcaster::new(2000);
 
-        let dbwriter = DbWriter::new(self.context.clone(), broadcaster.clone())?;
-        workers.add_worker(Box::new(dbwriter));
+        // Every instance ingests, but only the leader writes to the database and distributes
+        let election = etcd_client.clone().and_then(|etcd_client| {
+            LeaderElection::from_context(self.context.with_name("leader-election"), etcd_client)
+        });
+        let leadership = match election {
+            Some(election) => {
+                let leadership = election.leadership();
+                workers.add_worker(Box::new(election));
+                leadership
+            }
+            None => Leadership::always(),
+        };
 
-        // Add Binance Workers
-        add_binance_workers(
-            &self.context,
-            &mut workers,
-            &app_config,
-            broadcaster.clone(),
-            &mut indexer_config_change_handler,
+        // Applied config changes are kept for the http endpoint and persisted by the leader
+        // when the tickers are stored in Postgres, which owns the `config_audit` table
+        let mut config_audit_log = ConfigAuditLog::from_context(&self.context);
+        if SinkKind::from_context(&self.context)?.is_postgres() {
+            let config_audit_writer =
+                ConfigAuditWriter::new(self.context.with_name("config-audit-writer"))?
+                    .with_leadership(leadership.clone());
+            config_audit_log = config_audit_log.with_writer(&config_audit_writer);
+            workers.add_worker(Box::new(config_audit_writer));
+        }
+        // Index history is served from the tables the dbwriter populates in Postgres
+        let history = if SinkKind::from_context(&self.context)?.is_postgres() {
+            Some(HistoryStore::from_context(&self.context)?)
+        } else {
+            None
+        };
+        // The index is streamed to websocket and SSE clients as it is published
+        let weighted_average_broadcaster = Broadcaster::new(2000);
+        let stream_hub = StreamHub::new(
+            self.context.with_name("index-stream"),
+            weighted_average_broadcaster.clone(),
+            StreamConfig::from_context(&self.context),
         );
+        workers.add_worker(Box::new(StreamSnapshotWorker::new(
+            self.context.with_name("stream-snapshot-worker"),
+            stream_hub.clone(),
+        )));
+        start_metrics_server(
+            self.context.clone(),
+            dead_letters.clone(),
+            config_audit_log.clone(),
+            history,
+            stream_hub,
+        )?;
 
-        // Add Kraken Workers
-        add_kraken_workers(
-            &self.context,
-            &mut workers,
-            &app_config,
-            broadcaster.clone(),
-            &mut indexer_config_change_handler,
-        );
+        // Each exchange's feed runs as a group of workers, started and stopped as exchanges
+        // are added to and removed from the config
+        let instance_status = InstanceStatusTracker::new(&self.context);
 
-        // Add Coinbase Workers
-        add_coinbase_workers(
-            &self.context,
-            &mut workers,
-            &app_config,
+        // Persist the streams listed in `database_streams`, the raw ticks are only merged out
+        // of the feeds when they are persisted
+        let mut dbwriter = DbWriter::new(self.context.clone())?
+            .with_leadership(leadership.clone())
+            .with_stream(TickStream::Smoothed, broadcaster.clone())
+            .with_stream(TickStream::Index, weighted_average_broadcaster.clone());
+        let mut feed_manager = FeedManager::new(
+            self.context.with_name("feed-manager"),
+            workers.handle(),
             broadcaster.clone(),
-            &mut indexer_config_change_handler,
+            dead_letters.clone(),
         );
+        if dbwriter.persists(TickStream::Raw) {
+            let raw_broadcaster = Broadcaster::new(2000);
+            dbwriter = dbwriter.with_stream(TickStream::Raw, raw_broadcaster.clone());
+            feed_manager = feed_manager.with_raw_broadcaster(raw_broadcaster);
+        }
+        workers.add_worker(Box::new(dbwriter));
 
-        // Add Weighted Average Processor
-        let mut weights = HashMap::new();
-        weights.insert(
-            Exchange::Binance,
-            *app_config.get_weight(Exchange::Binance).unwrap(),
-        );
-        weights.insert(
-            Exchange::Kraken,
-            *app_config.get_weight(Exchange::Kraken).unwrap(),
-        );
-        weights.insert(
-            Exchange::Coinbase,
-            *app_config.get_weight(Exchange::Coinbase).unwrap(),
-        );
+        let indexer_config_change_handler = IndexerConfigChangeHandler::new(
+            self.context.with_name("indexer-config-change-handler"),
+        )
+        .with_audit_log(config_audit_log)
+        .with_feed_manager(feed_manager)
+        .with_status(instance_status.clone());
+        indexer_config_change_handler.set_current_config(app_config.clone());
+        if let Some(revision) = config_source.revision() {
+            instance_status.set_config_revision(revision);
+        }
+
+        indexer_config_change_handler.start_feeds(&app_config)?;
 
-        let weighted_average_config = WeightedAverageConfig::new(weights)?;
+        // Add Weighted Average Processor
+        let weighted_average_config = WeightedAverageConfig::new(app_config.get_active_weights())?;
         let weighted_average_processor = WeightedAverageProcessor::new(weighted_average_config)?;
-        let weighted_average_broadcaster = Broadcaster::new(2000);
         let weighted_average_worker = FeedProcessingWorker::new(
             self.context.clone().with_name("weighted-average-processor"),
             broadcaster.clone(),
@@ -114,26 +187,45 @@ impl Runner for IndexerRunner {
             weighted_average_processor.clone(),
         );
         workers.add_worker(Box::new(weighted_average_worker.clone()));
+        workers.add_worker(Box::new(IndexStatusWorker::new(
+            self.context.with_name("index-status-worker"),
+            weighted_average_broadcaster.clone(),
+            instance_status.clone(),
+        )));
         indexer_config_change_handler
             .add_weighted_average_config_handler(Box::new(weighted_average_processor));
 
-        // Add Distribution Worker
-        let distribution_url = self.context.config.get_string("distribution_url")?;
-        let distribution_worker = DistributionWorker::new(
-            self.context.clone().with_name("distribution-worker"),
-            distribution_url,
-            weighted_average_broadcaster.clone(),
-        );
-        workers.add_worker(Box::new(distribution_worker));
+        // Add a Distribution Worker per target
+        for target in DistributionTarget::all_from_context(&self.context)? {
+            let name = if target.name == "default" {
+                "distribution-worker".to_string()
+            } else {
+                format!("distribution-worker-{}", target.name)
+            };
+            let distribution_worker = DistributionWorker::new(
+                self.context.clone().with_name(&name),
+                target,
+                weighted_average_broadcaster.clone(),
+            )?
+            .with_leadership(leadership.clone());
+            workers.add_worker(Box::new(distribution_worker));
+        }
 
-        // Add EtcdWatcher
-        let mut etcd_watcher = EtcdWatcher::<IndexerConfigChangeHandler, IndexerConfig>::new(
-            self.context.clone(),
-            etcd_client,
-            config_key,
-        );
-        etcd_watcher.add_handler(indexer_config_change_handler);
-        workers.add_worker(Box::new(etcd_watcher));
+        // Publish what this instance is running to etcd, if `instance_status_prefix` is set
+        let status_publisher = match etcd_client {
+            Some(etcd_client) => EtcdStatusPublisher::from_context(
+                self.context.with_name("instance-status-publisher"),
+                etcd_client,
+                instance_status,
+            )?,
+            None => None,
+        };
+        if let Some(status_publisher) = status_publisher {
+            workers.add_worker(Box::new(status_publisher));
+        }
+
+        // Add Config Watcher
+        workers.add_worker(config_source.watcher(indexer_config_change_handler));
 
         // Run Workers
         workers.run().await?;
@@ -145,7 +237,24 @@ impl Runner for IndexerRunner {
     }
 }
 
-pub fn start_metrics_server(context: Context) -> AppResult<String> {
+#[derive(Debug, Deserialize)]
+struct ConfigAuditQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeadLettersQuery {
+    exchange: Option<Exchange>,
+    limit: Option<usize>,
+}
+
+pub fn start_metrics_server(
+    context: Context,
+    dead_letters: DeadLetterStore,
+    config_audit_log: ConfigAuditLog,
+    history: Option<HistoryStore>,
+    stream_hub: StreamHub,
+) -> AppResult<String> {
     let metrics = warp::path("metrics").map(|| {
         let encoder = prometheus::TextEncoder::new();
         let mut buffer = vec![];
@@ -153,13 +262,41 @@ pub fn start_metrics_server(context: Context) -> AppResult<String> {
         String::from_utf8(buffer).unwrap()
     });
 
-    let mut app = context.app.subscribe();
-    let (_, server) =
-        warp::serve(metrics).bind_with_graceful_shutdown(([0, 0, 0, 0], 7070), async move {
-            app.recv().await.ok();
-            log::info!("metrics server shutdown");
+    // Most recent frames that failed to deserialize, e.g. `/dead-letters?exchange=kraken&limit=10`
+    let dead_letters = warp::path("dead-letters")
+        .and(warp::query::<DeadLettersQuery>())
+        .map(move |query: DeadLettersQuery| {
+            let letters = dead_letters.list(query.exchange.as_ref(), query.limit.unwrap_or(100));
+            warp::reply::json(&letters)
+        });
+
+    // Most recent applied config changes, e.g. `/config-audit?limit=10`
+    let config_audit = warp::path("config-audit")
+        .and(warp::query::<ConfigAuditQuery>())
+        .map(move |query: ConfigAuditQuery| {
+            warp::reply::json(&config_audit_log.list(query.limit.unwrap_or(100)))
         });
 
+    // Index history, e.g. `/index/latest`, `/index/ticks?symbol=BTCUSD&from=...&format=csv`
+    // and `/index/candles?symbol=BTCUSD&interval=5m`
+    let history = history_routes(history);
+
+    // Live index, e.g. `/index/ws?symbols=BTCUSD` and `/index/sse?sources=indexer_weighted_average`
+    let stream = stream_routes(stream_hub);
+
+    let mut app = context.app.subscribe();
+    let (_, server) = warp::serve(
+        metrics
+            .or(dead_letters)
+            .or(config_audit)
+            .or(history)
+            .or(stream),
+    )
+    .bind_with_graceful_shutdown(([0, 0, 0, 0], 7070), async move {
+        app.recv().await.ok();
+        log::info!("metrics server shutdown");
+    });
+
     tokio::spawn(async move {
         server.await;
     });
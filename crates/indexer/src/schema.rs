@@ -0,0 +1,508 @@
+use std::collections::HashSet;
+
+use common::{AppError, AppResult, Context};
+use jiff::{civil::Date, tz::TimeZone, Span, Timestamp};
+use tokio_postgres::Client;
+
+/// A schema migration embedded in the binary.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Migrations in the order they are applied, a version is never changed once released.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "partitioned_tickers",
+        sql: include_str!("../migrations/0002_partitioned_tickers.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "tickers_id",
+        sql: include_str!("../migrations/0003_tickers_id.sql"),
+    },
+];
+
+/// Migrations of a TimescaleDB database, where `tickers` is a hypertable instead of being
+/// partitioned by the indexer. Their versions start at 1001 so that they are never mistaken
+/// for the ones of [`MIGRATIONS`].
+pub const TIMESCALE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1001,
+        name: "initial",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 1002,
+        name: "timescale_hypertable",
+        sql: include_str!("../migrations/timescaledb/0002_hypertable.sql"),
+    },
+    Migration {
+        version: 1003,
+        name: "tickers_id",
+        sql: include_str!("../migrations/0003_tickers_id.sql"),
+    },
+];
+
+/// Key of the advisory lock serializing the migrations of concurrently starting instances
+const MIGRATION_LOCK_ID: i64 = 0x0069_6e64_6578_6572;
+/// Key of the advisory lock serializing the creation of partitions by concurrent instances
+const PARTITION_LOCK_ID: i64 = 0x0070_6172_7469_7469;
+
+/// Each chain of migrations has its own range of a thousand versions
+fn version_range(version: i32) -> i32 {
+    (version - 1) / 1000
+}
+
+/// Applies the given migrations missing from `schema_migrations`, returning their versions.
+///
+/// All migrations are applied in one transaction holding an advisory lock, so instances
+/// starting at the same time don't race and a failing migration leaves the schema as it was.
+/// A database migrated by the other chain, i.e. created for another sink, is refused.
+pub async fn migrate(client: &mut Client, migrations: &[Migration]) -> AppResult<Vec<i32>> {
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::PostgresError)?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID])
+        .await
+        .map_err(AppError::PostgresError)?;
+    transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        )
+        .await
+        .map_err(AppError::PostgresError)?;
+    let range = migrations.first().map_or(0, |m| version_range(m.version));
+    let applied = transaction
+        .query("SELECT version FROM schema_migrations", &[])
+        .await
+        .map_err(AppError::PostgresError)?
+        .iter()
+        .map(|row| row.get::<_, i32>(0))
+        .collect::<HashSet<_>>();
+    let mut foreign = applied
+        .iter()
+        .filter(|version| version_range(**version) != range)
+        .collect::<Vec<_>>();
+    if !foreign.is_empty() {
+        foreign.sort();
+        return Err(AppError::ConfigError(format!(
+            "the database was migrated for another sink (versions {:?}), it is not converted",
+            foreign
+        )));
+    }
+
+    let mut versions = Vec::new();
+    for migration in migrations.iter() {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+        log::info!(
+            "applying migration {} {}",
+            migration.version,
+            migration.name
+        );
+        transaction
+            .batch_execute(migration.sql)
+            .await
+            .map_err(AppError::PostgresError)?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                &[&migration.version, &migration.name],
+            )
+            .await
+            .map_err(AppError::PostgresError)?;
+        versions.push(migration.version);
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::PostgresError)?;
+    Ok(versions)
+}
+
+/// How the daily partitions of `tickers` are maintained.
+///
+/// - `database_partition_days_ahead`: days after today partitions are created for, at least
+///   one so tomorrow's partition exists before midnight (default 3)
+/// - `database_retention_days`: days of tickers kept, older partitions are dropped, tickers
+///   are kept forever if absent
+/// - `database_partition_maintenance_interval_secs`: how often partitions are maintained
+///   (default 3600)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionConfig {
+    pub days_ahead: i64,
+    pub retention_days: Option<i64>,
+    pub maintenance_interval_secs: u64,
+}
+
+impl Default for PartitionConfig {
+    fn default() -> Self {
+        Self {
+            days_ahead: 3,
+            retention_days: None,
+            maintenance_interval_secs: 3600,
+        }
+    }
+}
+
+impl PartitionConfig {
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let days_ahead = context
+            .config
+            .get_int("database_partition_days_ahead")
+            .unwrap_or(3);
+        let retention_days = context.config.get_int("database_retention_days").ok();
+        if days_ahead < 1 || retention_days.is_some_and(|days| days < 1) {
+            return Err(AppError::ConfigError(
+                "database_partition_days_ahead and database_retention_days must be positive"
+                    .to_string(),
+            ));
+        }
+        let maintenance_interval_secs = context
+            .config
+            .get_int("database_partition_maintenance_interval_secs")
+            .unwrap_or(3600)
+            .max(1) as u64;
+        Ok(Self {
+            days_ahead,
+            retention_days,
+            maintenance_interval_secs,
+        })
+    }
+
+    /// The days partitions should exist for, from today to `days_ahead` days after
+    pub fn days_to_create(&self, today: Date) -> AppResult<Vec<Date>> {
+        (0..=self.days_ahead)
+            .map(|days| add_days(today, days))
+            .collect()
+    }
+
+    /// The first day whose tickers are kept, if any are dropped
+    pub fn retention_cutoff(&self, today: Date) -> AppResult<Option<Date>> {
+        self.retention_days
+            .map(|days| add_days(today, -days))
+            .transpose()
+    }
+}
+
+fn add_days(date: Date, days: i64) -> AppResult<Date> {
+    date.checked_add(Span::new().days(days))
+        .map_err(|e| AppError::GenericError(format!("invalid partition date: {}", e)))
+}
+
+pub fn partition_name(day: Date) -> String {
+    format!("tickers_p{}", day.strftime("%Y%m%d"))
+}
+
+/// The day of a daily partition of `tickers`, none for other tables
+pub fn partition_day(name: &str) -> Option<Date> {
+    let day = name.strip_prefix("tickers_p")?;
+    Date::strptime("%Y%m%d", day).ok()
+}
+
+/// Creates the partition of a day unless it exists.
+///
+/// The partition is created detached and attached once the rows of its day are moved out of
+/// the default partition, which would otherwise violate the constraint of the new partition.
+async fn create_partition(client: &Client, day: Date) -> AppResult<()> {
+    let name = partition_name(day);
+    let from = format!("{} 00:00:00+00", day);
+    let to = format!("{} 00:00:00+00", add_days(day, 1)?);
+    let statement = format!(
+        "DO $$
+        BEGIN
+            PERFORM pg_advisory_xact_lock({lock});
+            IF to_regclass('{name}') IS NULL THEN
+                CREATE TABLE {name} (LIKE tickers INCLUDING DEFAULTS);
+                WITH moved AS (
+                    DELETE FROM tickers_default
+                    WHERE timestamp >= '{from}' AND timestamp < '{to}'
+                    RETURNING symbol, price, source, stream, timestamp, created_at
+                )
+                INSERT INTO {name} (symbol, price, source, stream, timestamp, created_at)
+                SELECT * FROM moved;
+                ALTER TABLE tickers ATTACH PARTITION {name} FOR VALUES FROM ('{from}') TO ('{to}');
+            END IF;
+        END $$",
+        lock = PARTITION_LOCK_ID,
+    );
+    client
+        .batch_execute(&statement)
+        .await
+        .map_err(AppError::PostgresError)
+}
+
+/// Creates the daily partitions ahead of time and drops the ones past the retention.
+///
+/// Tickers past the retention are also deleted from the default partition. Only the leader
+/// drops partitions, creating them is safe from every instance. A day whose partition can't
+/// be created doesn't keep the others from being created, the last error is returned.
+pub async fn maintain_partitions(
+    client: &Client,
+    config: &PartitionConfig,
+    now: Timestamp,
+    drop_expired: bool,
+) -> AppResult<()> {
+    let today = now.to_zoned(TimeZone::UTC).date();
+    let mut result = Ok(());
+    for day in config.days_to_create(today)? {
+        if let Err(e) = create_partition(client, day).await {
+            log::error!("failed to create partition {}: {}", partition_name(day), e);
+            result = Err(e);
+        }
+    }
+
+    if !drop_expired {
+        return result;
+    }
+    let Some(cutoff) = config.retention_cutoff(today)? else {
+        return result;
+    };
+    let partitions = client
+        .query(
+            "SELECT child.relname::text FROM pg_inherits
+             JOIN pg_class parent ON pg_inherits.inhparent = parent.oid
+             JOIN pg_class child ON pg_inherits.inhrelid = child.oid
+             WHERE parent.relname = 'tickers'",
+            &[],
+        )
+        .await
+        .map_err(AppError::PostgresError)?;
+    for row in partitions.iter() {
+        let name = row.get::<_, String>(0);
+        if partition_day(&name).is_some_and(|day| day < cutoff) {
+            log::info!("dropping expired partition {}", name);
+            client
+                .batch_execute(&format!("DROP TABLE IF EXISTS {}", name))
+                .await
+                .map_err(AppError::PostgresError)?;
+        }
+    }
+    let cutoff = format!("{} 00:00:00+00", cutoff);
+    client
+        .execute(
+            "DELETE FROM tickers_default WHERE timestamp < $1::text::timestamptz",
+            &[&cutoff],
+        )
+        .await
+        .map_err(AppError::PostgresError)?;
+    result
+}
+
+/// Sets the TimescaleDB retention policy of `tickers` to `retention_days`, removing it if
+/// tickers are kept forever
+pub async fn apply_retention_policy(client: &Client, config: &PartitionConfig) -> AppResult<()> {
+    client
+        .batch_execute("SELECT remove_retention_policy('tickers', if_exists => true)")
+        .await
+        .map_err(AppError::PostgresError)?;
+    if let Some(days) = config.retention_days {
+        client
+            .batch_execute(&format!(
+                "SELECT add_retention_policy('tickers', drop_after => INTERVAL '{} days')",
+                days
+            ))
+            .await
+            .map_err(AppError::PostgresError)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use jiff::civil::date;
+
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (migrations, first) in [(MIGRATIONS, 1), (TIMESCALE_MIGRATIONS, 1001)] {
+            let versions = migrations.iter().map(|m| m.version).collect::<Vec<_>>();
+            let expected = (first..first + migrations.len() as i32).collect::<Vec<_>>();
+            assert_eq!(versions, expected);
+            assert!(versions
+                .iter()
+                .all(|version| version_range(*version) == version_range(first)));
+        }
+    }
+
+    #[test]
+    fn test_partition_days() {
+        let config = PartitionConfig {
+            days_ahead: 2,
+            retention_days: Some(30),
+            ..PartitionConfig::default()
+        };
+        let today = date(2025, 12, 31);
+        assert_eq!(
+            config.days_to_create(today).unwrap(),
+            vec![today, date(2026, 1, 1), date(2026, 1, 2)]
+        );
+        assert_eq!(
+            config.retention_cutoff(today).unwrap(),
+            Some(date(2025, 12, 1))
+        );
+        assert_eq!(
+            PartitionConfig::default().retention_cutoff(today).unwrap(),
+            None
+        );
+
+        assert_eq!(partition_name(today), "tickers_p20251231");
+        assert_eq!(partition_day("tickers_p20251231"), Some(today));
+        assert_eq!(partition_day("tickers_default"), None);
+    }
+
+    #[test]
+    fn test_partition_config_from_context() {
+        let config = Config::builder()
+            .set_override("database_retention_days", 7)
+            .unwrap()
+            .build()
+            .unwrap();
+        let partition_config =
+            PartitionConfig::from_context(&Context::from_config(config)).unwrap();
+        assert_eq!(partition_config.days_ahead, 3);
+        assert_eq!(partition_config.retention_days, Some(7));
+
+        let config = Config::builder()
+            .set_override("database_retention_days", 0)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(PartitionConfig::from_context(&Context::from_config(config)).is_err());
+
+        let config = Config::builder()
+            .set_override("database_partition_days_ahead", 0)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(PartitionConfig::from_context(&Context::from_config(config)).is_err());
+    }
+
+    /// Runs against the Postgres at `TEST_DATABASE_URL` in a scratch schema, skipped if unset
+    #[tokio::test]
+    async fn test_migrate_rows_dated_today() {
+        let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let (mut client, connection) =
+            tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let schema = format!("indexer_test_{}", std::process::id());
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema}"
+            ))
+            .await
+            .unwrap();
+
+        let now = Timestamp::now();
+        migrate(&mut client, &MIGRATIONS[..1]).await.unwrap();
+        client
+            .execute(
+                "INSERT INTO tickers (symbol, price, source, timestamp) VALUES ('BTCUSD', 1, 'test', $1)",
+                &[&now.as_millisecond()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(migrate(&mut client, MIGRATIONS).await.unwrap(), vec![2, 3]);
+
+        let config = PartitionConfig {
+            days_ahead: 1,
+            ..PartitionConfig::default()
+        };
+        maintain_partitions(&client, &config, now, true)
+            .await
+            .unwrap();
+        // Rows written before their partition exists land in the default partition and are
+        // moved once it is created
+        let today = now.to_zoned(TimeZone::UTC).date();
+        let at = format!("{} 12:00:00+00", add_days(today, 2).unwrap());
+        client
+            .execute(
+                "INSERT INTO tickers (symbol, price, source, timestamp) VALUES ('BTCUSD', 1, 'test', $1::text::timestamptz)",
+                &[&at],
+            )
+            .await
+            .unwrap();
+        let config = PartitionConfig {
+            days_ahead: 2,
+            ..PartitionConfig::default()
+        };
+        maintain_partitions(&client, &config, now, true)
+            .await
+            .unwrap();
+
+        let count = |table: String| {
+            let client = &client;
+            async move {
+                client
+                    .query_one(&format!("SELECT count(*) FROM {}", table), &[])
+                    .await
+                    .unwrap()
+                    .get::<_, i64>(0)
+            }
+        };
+        assert_eq!(count(partition_name(today)).await, 1);
+        assert_eq!(count(partition_name(add_days(today, 2).unwrap())).await, 1);
+        assert_eq!(count("tickers_default".to_string()).await, 0);
+        assert_eq!(count("tickers".to_string()).await, 2);
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+    }
+
+    /// Runs against the Postgres at `TEST_DATABASE_URL` in a scratch schema, skipped if unset
+    #[tokio::test]
+    async fn test_migration_chains_are_kept_apart() {
+        let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let (mut client, connection) =
+            tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let schema = format!("indexer_chains_test_{}", std::process::id());
+        let reset = format!(
+            "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema}"
+        );
+        client.batch_execute(&reset).await.unwrap();
+
+        migrate(&mut client, MIGRATIONS).await.unwrap();
+        let e = migrate(&mut client, TIMESCALE_MIGRATIONS)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, AppError::ConfigError(_)));
+
+        // And the other way round
+        client.batch_execute(&reset).await.unwrap();
+        migrate(&mut client, &TIMESCALE_MIGRATIONS[..1])
+            .await
+            .unwrap();
+        assert!(migrate(&mut client, MIGRATIONS).await.is_err());
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use common::{AppError, AppResult, Context};
+use etcd::Leadership;
+
+use crate::dbwriter::StoredTicker;
+
+pub mod clickhouse;
+pub mod parquet_file;
+pub mod postgres;
+
+pub use clickhouse::ClickHouseSink;
+pub use parquet_file::ParquetSink;
+pub use postgres::PostgresSink;
+
+/// Storage the [`crate::dbwriter::DbWriter`] writes its batches of tickers to.
+///
+/// The writer only hands a batch to the sink once [`TickSink::ready`] returns true and spools
+/// it otherwise, so sinks that connect to a server re-establish the connection there.
+#[async_trait]
+pub trait TickSink: Send {
+    /// Connects if needed, returning whether the sink can be written to
+    async fn ready(&mut self) -> bool;
+
+    /// Writes a batch of tickers, returning the number of rows written
+    async fn write(&mut self, tickers: &[StoredTicker]) -> AppResult<u64>;
+
+    /// Whether the sink itself rejected the batch, in which case retrying won't help
+    fn is_rejected(&self, e: &AppError) -> bool;
+
+    /// Drops the connection after a failed write, the next [`TickSink::ready`] reconnects
+    fn disconnect(&mut self) {}
+
+    /// Housekeeping such as partition maintenance, called on every insertion tick
+    async fn maintain(&mut self) {}
+
+    /// Flushes whatever is buffered when the writer exits
+    async fn close(&mut self) -> AppResult<()> {
+        Ok(())
+    }
+}
+
+/// The storage backends tickers can be written to, selected by `database_sink`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SinkKind {
+    /// Daily partitioned `tickers` table in Postgres
+    #[default]
+    Postgres,
+    /// `tickers` hypertable in TimescaleDB
+    Timescale,
+    /// MergeTree `tickers` table in ClickHouse, written over HTTP
+    ClickHouse,
+    /// Rotating local parquet files
+    Parquet,
+}
+
+impl SinkKind {
+    /// Reads `database_sink` (default `postgres`)
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        match context.config.get_string("database_sink") {
+            Ok(sink) => sink.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Whether the sink writes to the Postgres database at `database_url`
+    pub fn is_postgres(&self) -> bool {
+        matches!(self, Self::Postgres | Self::Timescale)
+    }
+}
+
+impl FromStr for SinkKind {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> AppResult<Self> {
+        match s {
+            "postgres" => Ok(Self::Postgres),
+            "timescaledb" => Ok(Self::Timescale),
+            "clickhouse" => Ok(Self::ClickHouse),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(AppError::ConfigError(format!(
+                "unknown database sink {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Creates the sink selected by `database_sink` from the static config
+pub fn sink_from_context(
+    context: &Context,
+    leadership: Leadership,
+) -> AppResult<Box<dyn TickSink>> {
+    let sink: Box<dyn TickSink> = match SinkKind::from_context(context)? {
+        SinkKind::Postgres => Box::new(PostgresSink::from_context(context, leadership)?),
+        SinkKind::Timescale => {
+            Box::new(PostgresSink::from_context(context, leadership)?.with_timescale())
+        }
+        SinkKind::ClickHouse => Box::new(ClickHouseSink::from_context(context)?),
+        SinkKind::Parquet => Box::new(ParquetSink::from_context(context)?),
+    };
+    Ok(sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::*;
+
+    #[test]
+    fn test_sink_kind_from_context() {
+        let context = Context::from_config(Config::builder().build().unwrap());
+        assert_eq!(
+            SinkKind::from_context(&context).unwrap(),
+            SinkKind::Postgres
+        );
+
+        let config = Config::builder()
+            .set_override("database_sink", "timescaledb")
+            .unwrap()
+            .build()
+            .unwrap();
+        let kind = SinkKind::from_context(&Context::from_config(config)).unwrap();
+        assert_eq!(kind, SinkKind::Timescale);
+        assert!(kind.is_postgres());
+        assert!(!SinkKind::Parquet.is_postgres());
+        assert!("mysql".parse::<SinkKind>().is_err());
+    }
+}
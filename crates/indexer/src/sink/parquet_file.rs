@@ -0,0 +1,401 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use common::{AppError, AppResult, Context};
+use jiff::Timestamp;
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    file::{
+        properties::WriterProperties, serialized_reader::SerializedFileReader,
+        writer::SerializedFileWriter,
+    },
+    schema::{parser::parse_message_type, types::Type},
+};
+
+use super::TickSink;
+use crate::dbwriter::StoredTicker;
+
+/// Columns of the parquet files, prices are kept as decimal strings so no precision is lost
+const TICKER_SCHEMA: &str = "
+message tickers {
+    REQUIRED BYTE_ARRAY symbol (STRING);
+    REQUIRED BYTE_ARRAY price (STRING);
+    REQUIRED BYTE_ARRAY source (STRING);
+    REQUIRED INT64 timestamp (TIMESTAMP(MICROS, true));
+    REQUIRED BYTE_ARRAY stream (STRING);
+}
+";
+
+/// Configuration for writing tickers to local parquet files.
+///
+/// Read from the static config:
+/// - `parquet_dir`: directory the files are written to
+/// - `parquet_rotation_secs`: age after which a new file is started (default 3600)
+/// - `parquet_max_files`: number of files kept, all are kept if absent
+#[derive(Debug, Clone)]
+pub struct ParquetConfig {
+    pub dir: PathBuf,
+    pub rotation_secs: i64,
+    pub max_files: Option<usize>,
+}
+
+impl ParquetConfig {
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let dir = context.config.get_string("parquet_dir")?;
+        let rotation_secs = context
+            .config
+            .get_int("parquet_rotation_secs")
+            .unwrap_or(3600)
+            .max(1);
+        let max_files = context
+            .config
+            .get_int("parquet_max_files")
+            .ok()
+            .map(|files| files.max(1) as usize);
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            rotation_secs,
+            max_files,
+        })
+    }
+}
+
+/// The file currently written to, renamed to its final name once it is closed
+struct OpenFile {
+    writer: SerializedFileWriter<File>,
+    path: PathBuf,
+    opened_at: Timestamp,
+}
+
+/// Writes tickers to rotating local parquet files.
+///
+/// Every batch is written as a row group of the open file. A parquet file is only readable
+/// once its footer is written, so it is written as `tickers.{millis}-{counter}.parquet.inprogress`
+/// and renamed to `tickers.{millis}-{counter}.parquet` when it is rotated or the writer exits.
+/// The `.inprogress` files left behind by a crash are cleaned up when the sink is created, so
+/// the directory must not be shared by several writers.
+pub struct ParquetSink {
+    name: String,
+    config: ParquetConfig,
+    schema: Arc<Type>,
+    properties: Arc<WriterProperties>,
+    file: Option<OpenFile>,
+    file_index: u64,
+}
+
+impl ParquetSink {
+    pub fn new(name: &str, config: ParquetConfig) -> AppResult<Self> {
+        fs::create_dir_all(&config.dir).map_err(|e| {
+            AppError::ConfigError(format!(
+                "failed to create parquet dir {}: {}",
+                config.dir.display(),
+                e
+            ))
+        })?;
+        recover_unfinished_files(name, &config.dir)?;
+        let schema = parse_message_type(TICKER_SCHEMA).map_err(parquet_error)?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::UNCOMPRESSED)
+            .build();
+        Ok(Self {
+            name: name.to_string(),
+            config,
+            schema: Arc::new(schema),
+            properties: Arc::new(properties),
+            file: None,
+            file_index: 0,
+        })
+    }
+
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        Self::new(&context.name, ParquetConfig::from_context(context)?)
+    }
+
+    fn open(&mut self) -> AppResult<&mut OpenFile> {
+        if self.file.is_none() {
+            // Millisecond resolution is not enough to keep names unique when files are
+            // rotated quickly, so a counter is appended as a tie breaker.
+            self.file_index += 1;
+            let path = self.config.dir.join(format!(
+                "tickers.{:013}-{:06}.parquet.inprogress",
+                Timestamp::now().as_millisecond(),
+                self.file_index
+            ));
+            let file = File::create(&path).map_err(|e| file_error(&path, e))?;
+            let writer =
+                SerializedFileWriter::new(file, self.schema.clone(), self.properties.clone())
+                    .map_err(parquet_error)?;
+            self.file = Some(OpenFile {
+                writer,
+                path,
+                opened_at: Timestamp::now(),
+            });
+        }
+        Ok(self.file.as_mut().expect("file was just opened"))
+    }
+
+    /// Writes the footer of the open file and gives it its final name
+    fn finish(&mut self) -> AppResult<()> {
+        let Some(OpenFile { writer, path, .. }) = self.file.take() else {
+            return Ok(());
+        };
+        writer.close().map_err(parquet_error)?;
+        let final_path = path.with_extension("");
+        fs::rename(&path, &final_path).map_err(|e| file_error(&path, e))?;
+        log::info!("{} finished {}", self.name, final_path.display());
+        self.remove_old_files()
+    }
+
+    fn remove_old_files(&self) -> AppResult<()> {
+        let Some(max_files) = self.config.max_files else {
+            return Ok(());
+        };
+        let mut files = fs::read_dir(&self.config.dir)
+            .map_err(|e| file_error(&self.config.dir, e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("tickers.") && name.ends_with(".parquet"))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        let num_old_files = files.len().saturating_sub(max_files);
+        for path in files.into_iter().take(num_old_files) {
+            fs::remove_file(&path).map_err(|e| file_error(&path, e))?;
+        }
+        Ok(())
+    }
+
+    fn write_row_group(&mut self, tickers: &[StoredTicker]) -> AppResult<()> {
+        let symbols = tickers
+            .iter()
+            .map(|t| ByteArray::from(t.ticker.symbol.to_string().into_bytes()))
+            .collect::<Vec<_>>();
+        let prices = tickers
+            .iter()
+            .map(|t| ByteArray::from(t.ticker.price.to_string().into_bytes()))
+            .collect::<Vec<_>>();
+        let sources = tickers
+            .iter()
+            .map(|t| ByteArray::from(t.ticker.source.to_string().into_bytes()))
+            .collect::<Vec<_>>();
+        let timestamps = tickers
+            .iter()
+            .map(|t| t.ticker.timestamp.as_microsecond())
+            .collect::<Vec<_>>();
+        let streams = tickers
+            .iter()
+            .map(|t| ByteArray::from(t.stream.as_str()))
+            .collect::<Vec<_>>();
+
+        let file = self.open()?;
+        let mut row_group = file.writer.next_row_group().map_err(parquet_error)?;
+        for values in [&symbols, &prices, &sources] {
+            write_byte_array_column(&mut row_group, values)?;
+        }
+        let mut column = row_group
+            .next_column()
+            .map_err(parquet_error)?
+            .ok_or_else(|| AppError::GenericError("missing timestamp column".to_string()))?;
+        column
+            .typed::<Int64Type>()
+            .write_batch(&timestamps, None, None)
+            .map_err(parquet_error)?;
+        column.close().map_err(parquet_error)?;
+        write_byte_array_column(&mut row_group, &streams)?;
+        row_group.close().map_err(parquet_error)?;
+        Ok(())
+    }
+}
+
+/// Gives the files left in progress by a crash their final name if their footer was written,
+/// and removes the others as they can't be read without it
+fn recover_unfinished_files(name: &str, dir: &Path) -> AppResult<()> {
+    let paths = fs::read_dir(dir)
+        .map_err(|e| file_error(dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "inprogress"));
+    for path in paths {
+        let readable = File::open(&path)
+            .ok()
+            .is_some_and(|file| SerializedFileReader::new(file).is_ok());
+        if readable {
+            let final_path = path.with_extension("");
+            fs::rename(&path, &final_path).map_err(|e| file_error(&path, e))?;
+            log::warn!("{} recovered {}", name, final_path.display());
+        } else {
+            fs::remove_file(&path).map_err(|e| file_error(&path, e))?;
+            log::error!(
+                "{} removed {} left unfinished by a crash",
+                name,
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn write_byte_array_column(
+    row_group: &mut parquet::file::writer::SerializedRowGroupWriter<'_, File>,
+    values: &[ByteArray],
+) -> AppResult<()> {
+    let mut column = row_group
+        .next_column()
+        .map_err(parquet_error)?
+        .ok_or_else(|| AppError::GenericError("missing parquet column".to_string()))?;
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(values, None, None)
+        .map_err(parquet_error)?;
+    column.close().map_err(parquet_error)
+}
+
+#[async_trait]
+impl TickSink for ParquetSink {
+    async fn ready(&mut self) -> bool {
+        true
+    }
+
+    async fn write(&mut self, tickers: &[StoredTicker]) -> AppResult<u64> {
+        if tickers.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = self.write_row_group(tickers) {
+            // A failed row group leaves the writer unusable, the rows written so far are kept
+            // and the batch is retried in a new file
+            if let Err(e) = self.finish() {
+                log::error!("{} failed to finish parquet file: {}", self.name, e);
+            }
+            return Err(e);
+        }
+        Ok(tickers.len() as u64)
+    }
+
+    fn is_rejected(&self, _e: &AppError) -> bool {
+        false
+    }
+
+    async fn maintain(&mut self) {
+        let expired = self.file.as_ref().is_some_and(|file| {
+            Timestamp::now().as_second() - file.opened_at.as_second() >= self.config.rotation_secs
+        });
+        if expired {
+            if let Err(e) = self.finish() {
+                log::error!("{} failed to rotate parquet file: {}", self.name, e);
+            }
+        }
+    }
+
+    async fn close(&mut self) -> AppResult<()> {
+        self.finish()
+    }
+}
+
+fn parquet_error(e: parquet::errors::ParquetError) -> AppError {
+    AppError::GenericError(format!("parquet error: {}", e))
+}
+
+fn file_error(path: &Path, e: std::io::Error) -> AppError {
+    AppError::GenericError(format!("parquet file {} error: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Source, Ticker, TickerSymbol};
+    use parquet::file::reader::FileReader;
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::dbwriter::TickStream;
+
+    fn batch(size: i64) -> Vec<StoredTicker> {
+        (0..size)
+            .map(|price| StoredTicker {
+                stream: TickStream::Smoothed,
+                ticker: Ticker {
+                    symbol: TickerSymbol::ETHUSD,
+                    price: Decimal::from(price),
+                    source: Source::IndexerWeightedAverage,
+                    timestamp: Timestamp::from_second(price).unwrap(),
+                },
+            })
+            .collect()
+    }
+
+    fn finished_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "parquet"))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_rotates_files() {
+        let dir = std::env::temp_dir().join(format!("parquet-sink-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = ParquetConfig {
+            dir: dir.clone(),
+            rotation_secs: 3600,
+            max_files: Some(2),
+        };
+        let mut sink = ParquetSink::new("test", config).unwrap();
+
+        assert_eq!(sink.write(&batch(3)).await.unwrap(), 3);
+        assert_eq!(sink.write(&batch(2)).await.unwrap(), 2);
+        // Not readable until it is finished
+        assert!(finished_files(&dir).is_empty());
+        sink.close().await.unwrap();
+
+        let files = finished_files(&dir);
+        assert_eq!(files.len(), 1);
+        let reader = SerializedFileReader::new(File::open(&files[0]).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 5);
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+
+        for _ in 0..2 {
+            sink.write(&batch(1)).await.unwrap();
+            sink.close().await.unwrap();
+        }
+        let files = finished_files(&dir);
+        assert_eq!(files.len(), 2);
+        let reader = SerializedFileReader::new(File::open(&files[0]).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_recovers_unfinished_files() {
+        let dir = std::env::temp_dir().join(format!("parquet-recover-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = ParquetConfig {
+            dir: dir.clone(),
+            rotation_secs: 3600,
+            max_files: None,
+        };
+        let mut sink = ParquetSink::new("test", config.clone()).unwrap();
+        sink.write(&batch(2)).await.unwrap();
+        sink.close().await.unwrap();
+        // A crash right after the footer was written, and one before
+        let finished = finished_files(&dir).remove(0);
+        fs::rename(&finished, finished.with_extension("parquet.inprogress")).unwrap();
+        let torn = dir.join("tickers.0000000000001-000001.parquet.inprogress");
+        fs::write(&torn, b"PAR1").unwrap();
+
+        ParquetSink::new("test", config).unwrap();
+        assert_eq!(finished_files(&dir), vec![finished]);
+        assert!(!torn.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use common::{AppError, AppResult, Backoff, Context};
+use etcd::Leadership;
+use jiff::Timestamp;
+use tokio::time::Instant;
+use tokio_postgres::{binary_copy::BinaryCopyInWriter, error::SqlState, types::Type, Client};
+
+use super::TickSink;
+use crate::{
+    dbwriter::{StoredTicker, DBWRITER_CONNECTED},
+    schema::{
+        apply_retention_policy, maintain_partitions, migrate, Migration, PartitionConfig,
+        MIGRATIONS, TIMESCALE_MIGRATIONS,
+    },
+};
+
+/// Writes tickers to the `tickers` table of the Postgres database at `database_url`.
+///
+/// The connection is re-established with backoff once it is lost and the schema is migrated
+/// on every connect. In Postgres the partitions of `tickers` are maintained right after and
+/// then every `maintenance_interval_secs`, in TimescaleDB `tickers` is a hypertable whose
+/// chunks are dropped by a retention policy instead.
+pub struct PostgresSink {
+    name: String,
+    database_url: String,
+    batch_size: usize,
+    client: Option<Client>,
+    backoff: Backoff,
+    next_attempt: Instant,
+    timescale: bool,
+    partition_config: PartitionConfig,
+    leadership: Leadership,
+    next_maintenance: Instant,
+}
+
+impl PostgresSink {
+    pub fn new(
+        name: &str,
+        database_url: String,
+        batch_size: usize,
+        partition_config: PartitionConfig,
+        leadership: Leadership,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            database_url,
+            batch_size,
+            client: None,
+            backoff: Backoff::new(0, 1, 30, 2),
+            next_attempt: Instant::now(),
+            timescale: false,
+            partition_config,
+            leadership,
+            next_maintenance: Instant::now(),
+        }
+    }
+
+    /// Reads `database_url` and `database_insertion_batch_size` (default 5000) along with the
+    /// [`PartitionConfig`]
+    pub fn from_context(context: &Context, leadership: Leadership) -> AppResult<Self> {
+        let database_url = context.config.get_string("database_url")?;
+        let batch_size = context
+            .config
+            .get_int("database_insertion_batch_size")
+            .unwrap_or(5000) as usize;
+        let partition_config = PartitionConfig::from_context(context)?;
+        Ok(Self::new(
+            &context.name,
+            database_url,
+            batch_size,
+            partition_config,
+            leadership,
+        ))
+    }
+
+    /// Keeps `tickers` as a TimescaleDB hypertable instead of partitioning it
+    pub fn with_timescale(mut self) -> Self {
+        self.timescale = true;
+        self
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        if self.timescale {
+            TIMESCALE_MIGRATIONS
+        } else {
+            MIGRATIONS
+        }
+    }
+
+    async fn connect(&self) -> AppResult<Client> {
+        let (mut client, connection) =
+            tokio_postgres::connect(&self.database_url, tokio_postgres::NoTls)
+                .await
+                .map_err(AppError::PostgresError)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("database connection error: {}", e);
+            }
+        });
+        let versions = migrate(&mut client, self.migrations()).await?;
+        if !versions.is_empty() {
+            log::info!("{} applied migrations {:?}", self.name, versions);
+        }
+        if self.timescale {
+            apply_retention_policy(&client, &self.partition_config).await?;
+        }
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl TickSink for PostgresSink {
+    async fn ready(&mut self) -> bool {
+        if self
+            .client
+            .as_ref()
+            .is_some_and(|client| client.is_closed())
+        {
+            log::warn!("{} lost the database connection", self.name);
+            self.disconnect();
+        }
+        if self.client.is_none() && Instant::now() >= self.next_attempt {
+            match self.connect().await {
+                Ok(client) => {
+                    log::info!("{} connected to the database", self.name);
+                    self.backoff.reset();
+                    self.client = Some(client);
+                    self.next_maintenance = Instant::now();
+                    DBWRITER_CONNECTED.with_label_values(&[]).set(1.0);
+                }
+                Err(e) => {
+                    let delay_secs = self.backoff.next().unwrap_or(30);
+                    log::error!(
+                        "{} failed to connect to the database, retrying in {}s: {}",
+                        self.name,
+                        delay_secs,
+                        e
+                    );
+                    self.next_attempt = Instant::now() + Duration::from_secs(delay_secs as u64);
+                }
+            }
+        }
+        self.client.is_some()
+    }
+
+    async fn write(&mut self, tickers: &[StoredTicker]) -> AppResult<u64> {
+        let Some(client) = self.client.as_mut() else {
+            return Err(AppError::GenericError(
+                "not connected to the database".to_string(),
+            ));
+        };
+        insert_tickers(tickers, client, self.batch_size).await
+    }
+
+    /// The database rejected the data of the batch itself, any other error such as a
+    /// failover, a lock timeout or a full disk is retried from the spool
+    fn is_rejected(&self, e: &AppError) -> bool {
+        matches!(e, AppError::PostgresError(e) if e.code().is_some_and(is_data_error))
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        DBWRITER_CONNECTED.with_label_values(&[]).set(0.0);
+    }
+
+    async fn maintain(&mut self) {
+        if self.timescale || Instant::now() < self.next_maintenance {
+            return;
+        }
+        let Some(client) = &self.client else {
+            return;
+        };
+        self.next_maintenance =
+            Instant::now() + Duration::from_secs(self.partition_config.maintenance_interval_secs);
+        let drop_expired = self.leadership.is_leader();
+        if let Err(e) = maintain_partitions(
+            client,
+            &self.partition_config,
+            Timestamp::now(),
+            drop_expired,
+        )
+        .await
+        {
+            log::error!("{} failed to maintain partitions: {}", self.name, e);
+        }
+    }
+}
+
+/// SQLSTATE classes 22 (data exception) and 23 (integrity constraint violation)
+pub(crate) fn is_data_error(code: &SqlState) -> bool {
+    let code = code.code();
+    code.starts_with("22") || code.starts_with("23")
+}
+
+const COPY_TICKERS: &str =
+    "COPY tickers (symbol, price, source, timestamp, stream) FROM STDIN BINARY";
+const TICKER_COLUMN_TYPES: [Type; 5] = [
+    Type::VARCHAR,
+    Type::NUMERIC,
+    Type::VARCHAR,
+    Type::TIMESTAMPTZ,
+    Type::VARCHAR,
+];
+
+/// Writes tickers to the `tickers` table tagged with their stream, returning the number of rows.
+///
+/// Rows are streamed with `COPY ... FROM STDIN BINARY` in chunks of `batch_size` within one
+/// transaction, so no value is interpolated into SQL and large batches stay within the
+/// limits of a single statement.
+pub async fn insert_tickers(
+    tickers: &[StoredTicker],
+    client: &mut Client,
+    batch_size: usize,
+) -> AppResult<u64> {
+    if tickers.is_empty() {
+        return Ok(0);
+    }
+
+    let transaction = client
+        .transaction()
+        .await
+        .map_err(AppError::PostgresError)?;
+    let mut rows = 0;
+    for chunk in tickers.chunks(batch_size.max(1)) {
+        let sink = transaction
+            .copy_in(COPY_TICKERS)
+            .await
+            .map_err(AppError::PostgresError)?;
+        let writer = BinaryCopyInWriter::new(sink, &TICKER_COLUMN_TYPES);
+        tokio::pin!(writer);
+        for StoredTicker { stream, ticker } in chunk {
+            let symbol = ticker.symbol.to_string();
+            let source = ticker.source.to_string();
+            let timestamp = SystemTime::from(ticker.timestamp);
+            writer
+                .as_mut()
+                .write(&[
+                    &symbol,
+                    &ticker.price,
+                    &source,
+                    &timestamp,
+                    &stream.as_str(),
+                ])
+                .await
+                .map_err(AppError::PostgresError)?;
+        }
+        rows += writer.finish().await.map_err(AppError::PostgresError)?;
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(AppError::PostgresError)?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Source, Ticker, TickerSymbol};
+    use rust_decimal::Decimal;
+
+    use crate::dbwriter::TickStream;
+
+    use super::*;
+
+    #[test]
+    fn test_only_data_errors_are_rejected() {
+        assert!(is_data_error(&SqlState::NUMERIC_VALUE_OUT_OF_RANGE));
+        assert!(is_data_error(&SqlState::CHECK_VIOLATION));
+        assert!(is_data_error(&SqlState::UNIQUE_VIOLATION));
+        assert!(!is_data_error(&SqlState::ADMIN_SHUTDOWN));
+        assert!(!is_data_error(&SqlState::READ_ONLY_SQL_TRANSACTION));
+        assert!(!is_data_error(&SqlState::DISK_FULL));
+        assert!(!is_data_error(&SqlState::LOCK_NOT_AVAILABLE));
+        assert!(!is_data_error(&SqlState::UNDEFINED_TABLE));
+    }
+
+    /// Runs against the Postgres at `TEST_DATABASE_URL` in a scratch schema, skipped if unset
+    #[tokio::test]
+    async fn test_insert_tickers_round_trips_through_binary_copy() {
+        let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let (mut client, connection) =
+            tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let schema = format!("indexer_copy_test_{}", std::process::id());
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema}"
+            ))
+            .await
+            .unwrap();
+        migrate(&mut client, MIGRATIONS).await.unwrap();
+
+        let tickers = [
+            (
+                TickStream::Raw,
+                Source::Binance,
+                "64123.456789",
+                1_739_394_753_778_451,
+            ),
+            (
+                TickStream::Smoothed,
+                Source::IndexerSmoothing,
+                "0.00000001",
+                1_739_394_753_000_000,
+            ),
+            (
+                TickStream::Index,
+                Source::IndexerWeightedAverage,
+                "1000000",
+                1_739_394_754_000_001,
+            ),
+        ]
+        .into_iter()
+        .map(|(stream, source, price, micros)| StoredTicker {
+            stream,
+            ticker: Ticker {
+                symbol: TickerSymbol::BTCUSD,
+                price: price.parse().unwrap(),
+                source,
+                timestamp: Timestamp::from_microsecond(micros).unwrap(),
+            },
+        })
+        .collect::<Vec<_>>();
+
+        // Chunks smaller than the batch are copied within the same transaction
+        assert_eq!(insert_tickers(&tickers, &mut client, 2).await.unwrap(), 3);
+        assert_eq!(insert_tickers(&[], &mut client, 2).await.unwrap(), 0);
+
+        let rows = client
+            .query(
+                "SELECT symbol, price, source, timestamp, stream FROM tickers ORDER BY timestamp",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+        let mut expected = tickers.clone();
+        expected.sort_by_key(|t| t.ticker.timestamp);
+        for (row, StoredTicker { stream, ticker }) in rows.iter().zip(expected.iter()) {
+            assert_eq!(row.get::<_, String>(0), ticker.symbol.to_string());
+            assert_eq!(row.get::<_, Decimal>(1), ticker.price);
+            assert_eq!(row.get::<_, String>(2), ticker.source.to_string());
+            assert_eq!(
+                Timestamp::try_from(row.get::<_, SystemTime>(3)).unwrap(),
+                ticker.timestamp
+            );
+            assert_eq!(row.get::<_, String>(4), stream.as_str());
+        }
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+    }
+}
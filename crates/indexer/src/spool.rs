@@ -0,0 +1,289 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use common::{AppError, AppResult, Context};
+
+use crate::dbwriter::{StoredTicker, DBWRITER_SPOOL_LINES_SKIPPED};
+
+/// Bounded buffer of ticker batches that could not be written to the database yet.
+///
+/// Batches are kept in memory and, if `database_spool_path` is set, appended to that file as
+/// json lines and synced to disk before they are acknowledged, so they survive a restart. The
+/// file is rewritten with the remaining batches after a drain, so a crash in between may write
+/// a drained batch twice. Lines that can't be read back, such as a tail torn by a crash, are
+/// skipped. Batches beyond `database_spool_max_bytes` (default 100MB) are rejected.
+pub struct TickSpool {
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    size_bytes: u64,
+    batches: VecDeque<(Arc<Vec<StoredTicker>>, u64)>,
+    dirty: bool,
+}
+
+impl TickSpool {
+    pub fn new(path: Option<PathBuf>, max_bytes: u64) -> AppResult<Self> {
+        let mut spool = Self {
+            path,
+            max_bytes,
+            size_bytes: 0,
+            batches: VecDeque::new(),
+            dirty: false,
+        };
+        spool.load()?;
+        Ok(spool)
+    }
+
+    pub fn from_context(context: &Context) -> AppResult<Self> {
+        let path = context
+            .config
+            .get_string("database_spool_path")
+            .ok()
+            .map(PathBuf::from);
+        let max_bytes = context
+            .config
+            .get_int("database_spool_max_bytes")
+            .unwrap_or(100 * 1024 * 1024) as u64;
+        Self::new(path, max_bytes)
+    }
+
+    /// Number of spooled batches
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    /// Spools a batch, returning false if it doesn't fit
+    pub fn push(&mut self, batch: Vec<StoredTicker>) -> AppResult<bool> {
+        let line = serde_json::to_string(&batch)?;
+        let size = line.len() as u64 + 1;
+        if self.size_bytes + size > self.max_bytes {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| spool_error(path, e))?;
+            writeln!(file, "{}", line).map_err(|e| spool_error(path, e))?;
+            file.sync_data().map_err(|e| spool_error(path, e))?;
+        }
+        self.size_bytes += size;
+        self.batches.push_back((Arc::new(batch), size));
+        Ok(true)
+    }
+
+    /// The oldest batch
+    pub fn front(&self) -> Option<&Vec<StoredTicker>> {
+        self.batches.front().map(|(batch, _)| batch.as_ref())
+    }
+
+    /// Removes the oldest batch, the file is only updated on [`TickSpool::sync`]
+    pub fn pop_front(&mut self) -> Option<Arc<Vec<StoredTicker>>> {
+        let (batch, size) = self.batches.pop_front()?;
+        self.size_bytes -= size;
+        self.dirty = true;
+        Some(batch)
+    }
+
+    /// Rewrites the file with the remaining batches after some were removed.
+    ///
+    /// The file may be as large as `database_spool_max_bytes`, so it is written on the
+    /// blocking thread pool.
+    pub async fn sync(&mut self) -> AppResult<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let batches = self
+            .batches
+            .iter()
+            .map(|(batch, _)| batch.clone())
+            .collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || rewrite(&path, &batches))
+            .await
+            .map_err(|e| AppError::GenericError(format!("spool sync task failed: {}", e)))??;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Reads back the batches spooled before a restart, rewriting the file without the lines
+    /// that couldn't be parsed so that later batches aren't appended to a torn line
+    fn load(&mut self) -> AppResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(spool_error(path, e)),
+        };
+        let mut skipped = 0;
+        for line in BufReader::new(file).split(b'\n') {
+            let line = line.map_err(|e| spool_error(path, e))?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<Vec<StoredTicker>>(&line) {
+                Ok(batch) => {
+                    let size = line.len() as u64 + 1;
+                    self.size_bytes += size;
+                    self.batches.push_back((Arc::new(batch), size));
+                }
+                Err(e) => {
+                    log::error!(
+                        "skipping unreadable line of {} bytes in spool {}: {}",
+                        line.len(),
+                        path.display(),
+                        e
+                    );
+                    skipped += 1;
+                }
+            }
+        }
+        if skipped > 0 {
+            DBWRITER_SPOOL_LINES_SKIPPED
+                .with_label_values(&[])
+                .inc_by(skipped as f64);
+            let batches = self
+                .batches
+                .iter()
+                .map(|(batch, _)| batch.clone())
+                .collect::<Vec<_>>();
+            rewrite(path, &batches)?;
+        }
+        if !self.batches.is_empty() {
+            log::warn!(
+                "loaded {} spooled batches from {}",
+                self.batches.len(),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Atomically replaces the spool file with the given batches
+fn rewrite(path: &Path, batches: &[Arc<Vec<StoredTicker>>]) -> AppResult<()> {
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path).map_err(|e| spool_error(&tmp_path, e))?;
+    let mut writer = BufWriter::new(file);
+    for batch in batches {
+        let line = serde_json::to_string(batch.as_ref())?;
+        writeln!(writer, "{}", line).map_err(|e| spool_error(&tmp_path, e))?;
+    }
+    let file = writer
+        .into_inner()
+        .map_err(|e| spool_error(&tmp_path, e.into_error()))?;
+    file.sync_all().map_err(|e| spool_error(&tmp_path, e))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| spool_error(path, e))?;
+    Ok(())
+}
+
+fn spool_error(path: &Path, e: std::io::Error) -> AppError {
+    AppError::GenericError(format!("spool {} error: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Source, Ticker, TickerSymbol};
+    use jiff::Timestamp;
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::dbwriter::TickStream;
+
+    fn ticker(price: i64) -> Ticker {
+        Ticker {
+            symbol: TickerSymbol::BTCUSD,
+            price: Decimal::from(price),
+            source: Source::IndexerWeightedAverage,
+            timestamp: Timestamp::from_second(price).unwrap(),
+        }
+    }
+
+    fn batch(price: i64) -> Vec<StoredTicker> {
+        vec![StoredTicker {
+            stream: TickStream::Index,
+            ticker: ticker(price),
+        }]
+    }
+
+    #[tokio::test]
+    async fn test_tick_spool_survives_restart() {
+        let path = std::env::temp_dir().join(format!("tick-spool-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut spool = TickSpool::new(Some(path.clone()), 1024).unwrap();
+        for price in 1..=3 {
+            assert!(spool.push(batch(price)).unwrap());
+        }
+        assert_eq!(spool.pop_front().unwrap()[0].ticker.price, Decimal::from(1));
+        spool.sync().await.unwrap();
+
+        let mut spool = TickSpool::new(Some(path.clone()), 1024).unwrap();
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.front().unwrap()[0].stream, TickStream::Index);
+        assert_eq!(spool.front().unwrap()[0].ticker.price, Decimal::from(2));
+        spool.pop_front();
+        spool.pop_front();
+        assert!(spool.is_empty());
+        spool.sync().await.unwrap();
+        assert!(TickSpool::new(Some(path.clone()), 1024).unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tick_spool_reads_untagged_batches() {
+        let path = std::env::temp_dir().join(format!("tick-spool-v1-{}.jsonl", std::process::id()));
+        let line = serde_json::to_string(&vec![ticker(1)]).unwrap();
+        std::fs::write(&path, format!("{}\n", line)).unwrap();
+
+        let spool = TickSpool::new(Some(path.clone()), 1024).unwrap();
+        assert_eq!(spool.front().unwrap()[0].stream, TickStream::Smoothed);
+        assert_eq!(spool.front().unwrap()[0].ticker.price, Decimal::from(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tick_spool_skips_torn_lines() {
+        let path =
+            std::env::temp_dir().join(format!("tick-spool-torn-{}.jsonl", std::process::id()));
+        let line = serde_json::to_string(&batch(1)).unwrap();
+        std::fs::write(&path, format!("{}\n{}", line, &line[..line.len() / 2])).unwrap();
+
+        let mut spool = TickSpool::new(Some(path.clone()), 1024).unwrap();
+        assert_eq!(spool.len(), 1);
+        assert!(spool.push(batch(2)).unwrap());
+
+        let spool = TickSpool::new(Some(path.clone()), 1024).unwrap();
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.front().unwrap()[0].ticker.price, Decimal::from(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tick_spool_is_bounded() {
+        let line = serde_json::to_string(&batch(1)).unwrap();
+        let mut spool = TickSpool::new(None, 2 * (line.len() as u64 + 1)).unwrap();
+        assert!(spool.push(batch(1)).unwrap());
+        assert!(spool.push(batch(2)).unwrap());
+        assert!(!spool.push(batch(3)).unwrap());
+        spool.pop_front();
+        assert!(spool.push(batch(3)).unwrap());
+        assert_eq!(spool.len(), 2);
+    }
+}
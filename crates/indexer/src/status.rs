@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use common::{
+    AppError, AppInternalMessage, Broadcaster, Context, SharedRef, SharedRwRef, SpawnResult,
+    TickerSymbol, Worker,
+};
+use etcd::EtcdStatusProvider;
+use exchange::Exchange;
+use jiff::Timestamp;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+/// What an instance is running, published to etcd for operators.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct InstanceStatus {
+    pub instance_id: String,
+    pub version: String,
+    /// The etcd revision of the applied config, the one loaded at startup until the first update
+    pub config_revision: Option<i64>,
+    pub exchanges: HashMap<Exchange, ExchangeStatus>,
+    pub index: HashMap<TickerSymbol, IndexStatus>,
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub updated_at: Timestamp,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ExchangeStatus {
+    pub connected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct IndexStatus {
+    pub price: Decimal,
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub timestamp: Timestamp,
+}
+
+/// Collects the status of this instance from the config handler, the feeds and the index.
+#[derive(Clone)]
+pub struct InstanceStatusTracker {
+    instance_id: String,
+    config_revision: SharedRef<Option<i64>>,
+    connections: SharedRwRef<HashMap<Exchange, SharedRef<bool>>>,
+    index: SharedRwRef<HashMap<TickerSymbol, IndexStatus>>,
+}
+
+impl InstanceStatusTracker {
+    pub fn new(context: &Context) -> Self {
+        Self {
+            instance_id: context.instance_id(),
+            config_revision: SharedRef::new(None),
+            connections: SharedRwRef::new(HashMap::new()),
+            index: SharedRwRef::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_config_revision(&self, revision: i64) {
+        *self.config_revision.lock() = Some(revision);
+    }
+
+    /// Tracks the connection state of an exchange's websocket
+    pub fn add_connection(&self, exchange: Exchange, connected: SharedRef<bool>) {
+        self.connections.write().insert(exchange, connected);
+    }
+
+    pub fn remove_connection(&self, exchange: &Exchange) {
+        self.connections.write().remove(exchange);
+    }
+
+    pub fn record_index(&self, message: &AppInternalMessage) {
+        let AppInternalMessage::Tickers(tickers) = message;
+        let mut index = self.index.write();
+        for ticker in tickers.iter() {
+            index.insert(
+                ticker.symbol.clone(),
+                IndexStatus {
+                    price: ticker.price,
+                    timestamp: ticker.timestamp,
+                },
+            );
+        }
+    }
+}
+
+impl EtcdStatusProvider for InstanceStatusTracker {
+    type Status = InstanceStatus;
+
+    fn status(&self) -> InstanceStatus {
+        let exchanges = self
+            .connections
+            .read()
+            .iter()
+            .map(|(exchange, connected)| {
+                let status = ExchangeStatus {
+                    connected: *connected.lock(),
+                };
+                (exchange.clone(), status)
+            })
+            .collect();
+        InstanceStatus {
+            instance_id: self.instance_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config_revision: *self.config_revision.lock(),
+            exchanges,
+            index: self.index.read().clone(),
+            updated_at: Timestamp::now(),
+        }
+    }
+}
+
+/// Records the last index value of every symbol in the [`InstanceStatusTracker`].
+pub struct IndexStatusWorker {
+    context: Context,
+    receiver: Broadcaster<AppInternalMessage>,
+    tracker: InstanceStatusTracker,
+}
+
+impl IndexStatusWorker {
+    pub fn new(
+        context: Context,
+        receiver: Broadcaster<AppInternalMessage>,
+        tracker: InstanceStatusTracker,
+    ) -> Self {
+        Self {
+            context,
+            receiver,
+            tracker,
+        }
+    }
+}
+
+impl Worker for IndexStatusWorker {
+    fn spawn(&mut self) -> SpawnResult {
+        let context = self.context.clone();
+        let tracker = self.tracker.clone();
+        let mut receiver = self.receiver.receiver();
+        let mut app = context.app.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = app.recv() => {
+                        log::info!("{} received exit message", context.name);
+                        return Ok(format!("{} received exit message", context.name));
+                    }
+                    message = receiver.recv() => {
+                        match message {
+                            Ok(message) => tracker.record_index(&message),
+                            Err(RecvError::Lagged(skipped)) => {
+                                log::warn!("{} lagged, skipped {} messages", context.name, skipped);
+                            }
+                            Err(RecvError::Closed) => {
+                                return Err(AppError::GenericError(format!("{} receiver closed", context.name)));
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Source, Ticker};
+    use config::Config;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_instance_status() {
+        let config = Config::builder()
+            .set_override("instance_id", "indexer-0")
+            .unwrap()
+            .build()
+            .unwrap();
+        let tracker = InstanceStatusTracker::new(&Context::from_config(config));
+        let connected = SharedRef::new(false);
+        tracker.add_connection(Exchange::Binance, connected.clone());
+        tracker.add_connection(Exchange::Kraken, SharedRef::new(true));
+        tracker.remove_connection(&Exchange::Kraken);
+        tracker.set_config_revision(42);
+        for price in [dec!(100), dec!(101)] {
+            tracker.record_index(&AppInternalMessage::Tickers(vec![Ticker {
+                symbol: TickerSymbol::BTCUSD,
+                price,
+                source: Source::IndexerWeightedAverage,
+                timestamp: Timestamp::from_second(1).unwrap(),
+            }]));
+        }
+        *connected.lock() = true;
+
+        let status = tracker.status();
+        assert_eq!(status.instance_id, "indexer-0");
+        assert_eq!(status.config_revision, Some(42));
+        assert_eq!(
+            status.exchanges,
+            HashMap::from([(Exchange::Binance, ExchangeStatus { connected: true })])
+        );
+        assert_eq!(status.index[&TickerSymbol::BTCUSD].price, dec!(101));
+
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["exchanges"]["binance"]["connected"], true);
+        assert_eq!(json["index"]["BTCUSD"]["price"], "101");
+    }
+}
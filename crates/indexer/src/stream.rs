@@ -0,0 +1,668 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use common::{
+    AppError, AppInternalMessage, AppMessage, AppResult, Broadcaster, Context, SharedRwRef, Source,
+    SpawnResult, Ticker, TickerSymbol, Worker,
+};
+use futures_util::{SinkExt, StreamExt};
+use lazy_static::lazy_static;
+use prometheus as prom;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::Instant,
+};
+use warp::{
+    http::StatusCode,
+    reply::Response,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+lazy_static! {
+    pub static ref STREAM_CLIENTS: prom::GaugeVec = prom::register_gauge_vec!(
+        "stream_clients",
+        "Clients connected to the index stream",
+        &["transport"]
+    )
+    .unwrap();
+    pub static ref STREAM_MESSAGES_DROPPED: prom::CounterVec = prom::register_counter_vec!(
+        "stream_messages_dropped",
+        "Index messages skipped by stream clients that lagged behind",
+        &["transport"]
+    )
+    .unwrap();
+    pub static ref STREAM_CLIENTS_DISCONNECTED: prom::CounterVec = prom::register_counter_vec!(
+        "stream_clients_disconnected",
+        "Stream clients disconnected for lagging behind too often",
+        &["transport"]
+    )
+    .unwrap();
+}
+
+/// Lags within this window count towards `stream_max_lags`
+const LAG_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits of the index stream.
+///
+/// Read from the static config:
+/// - `stream_max_clients`: clients connected at once, others are refused (default 1000)
+/// - `stream_max_lags`: a client lagging more often than this within a minute is disconnected
+///   (default 10)
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub max_clients: usize,
+    pub max_lags: u32,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            max_clients: 1000,
+            max_lags: 10,
+        }
+    }
+}
+
+impl StreamConfig {
+    pub fn from_context(context: &Context) -> Self {
+        let config = &context.config;
+        Self {
+            max_clients: config.get_int("stream_max_clients").unwrap_or(1000).max(0) as usize,
+            max_lags: config.get_int("stream_max_lags").unwrap_or(10).max(0) as u32,
+        }
+    }
+}
+
+/// The symbols and sources a client receives, all of them if empty
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Subscription {
+    symbols: HashSet<TickerSymbol>,
+    sources: HashSet<Source>,
+}
+
+impl Subscription {
+    pub fn new(symbols: Vec<TickerSymbol>, sources: Vec<Source>) -> Self {
+        Self {
+            symbols: symbols.into_iter().collect(),
+            sources: sources.into_iter().collect(),
+        }
+    }
+
+    fn accepts(&self, ticker: &Ticker) -> bool {
+        (self.symbols.is_empty() || self.symbols.contains(&ticker.symbol))
+            && (self.sources.is_empty() || self.sources.contains(&ticker.source))
+    }
+
+    fn filter<'a>(&self, tickers: impl IntoIterator<Item = &'a Ticker>) -> Vec<Ticker> {
+        tickers
+            .into_iter()
+            .filter(|ticker| self.accepts(ticker))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The initial subscription, e.g. `?symbols=BTCUSD,ETHUSD&sources=indexer_weighted_average`
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    pub symbols: Option<String>,
+    pub sources: Option<String>,
+}
+
+impl TryFrom<&StreamQuery> for Subscription {
+    type Error = AppError;
+
+    fn try_from(query: &StreamQuery) -> AppResult<Self> {
+        Ok(Self::new(
+            parse_list(query.symbols.as_deref())?,
+            parse_list(query.sources.as_deref())?,
+        ))
+    }
+}
+
+/// Parses a comma separated list of names as they are serialized
+fn parse_list<T: DeserializeOwned>(list: Option<&str>) -> AppResult<Vec<T>> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            serde_json::from_value(serde_json::Value::String(name.to_string()))
+                .map_err(|_| AppError::ConfigError(format!("unknown symbol or source {}", name)))
+        })
+        .collect()
+}
+
+/// Sent by websocket clients to replace their subscription, e.g.
+/// `{"action": "subscribe", "symbols": ["BTCUSD"], "sources": []}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        symbols: Vec<TickerSymbol>,
+        #[serde(default)]
+        sources: Vec<Source>,
+    },
+}
+
+/// What clients receive, as json text frames over websocket and as named events over SSE
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    /// The latest ticker of every subscribed symbol and source, sent on subscribe and after
+    /// the client lagged
+    Snapshot {
+        tickers: Vec<Ticker>,
+    },
+    /// Tickers as they are published by the index
+    Tick {
+        tickers: Vec<Ticker>,
+    },
+    /// The client fell behind and skipped this many index messages
+    Lagged {
+        skipped: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl StreamEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Snapshot { .. } => "snapshot",
+            Self::Tick { .. } => "tick",
+            Self::Lagged { .. } => "lagged",
+            Self::Error { .. } => "error",
+        }
+    }
+}
+
+/// Fans the index out to the stream clients and keeps the latest ticker of every symbol and
+/// source for their snapshots.
+#[derive(Clone)]
+pub struct StreamHub {
+    context: Context,
+    broadcaster: Broadcaster<AppInternalMessage>,
+    latest: SharedRwRef<HashMap<(TickerSymbol, Source), Ticker>>,
+    clients: Arc<Semaphore>,
+    config: StreamConfig,
+}
+
+impl StreamHub {
+    pub fn new(
+        context: Context,
+        broadcaster: Broadcaster<AppInternalMessage>,
+        config: StreamConfig,
+    ) -> Self {
+        Self {
+            context,
+            broadcaster,
+            latest: SharedRwRef::new(HashMap::new()),
+            clients: Arc::new(Semaphore::new(config.max_clients)),
+            config,
+        }
+    }
+
+    pub fn record(&self, message: &AppInternalMessage) {
+        let AppInternalMessage::Tickers(tickers) = message;
+        let mut latest = self.latest.write();
+        for ticker in tickers.iter() {
+            latest.insert(
+                (ticker.symbol.clone(), ticker.source.clone()),
+                ticker.clone(),
+            );
+        }
+    }
+
+    fn snapshot(&self, subscription: &Subscription) -> StreamEvent {
+        let mut tickers = subscription.filter(self.latest.read().values());
+        tickers.sort_by_key(|ticker| (ticker.symbol.to_string(), ticker.source.to_string()));
+        StreamEvent::Snapshot { tickers }
+    }
+
+    /// Admits a client, failing when `stream_max_clients` are already connected
+    fn connect(&self, transport: &'static str, query: &StreamQuery) -> AppResult<StreamClient> {
+        let subscription = Subscription::try_from(query)?;
+        let permit = self
+            .clients
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| AppError::GenericError("too many index stream clients".to_string()))?;
+        // Subscribed before the snapshot is taken so no tick falls in between
+        let receiver = self.broadcaster.receiver();
+        let app = self.context.app.subscribe();
+        STREAM_CLIENTS.with_label_values(&[transport]).inc();
+        Ok(StreamClient {
+            hub: self.clone(),
+            transport,
+            subscription,
+            receiver,
+            app,
+            lags: 0,
+            lag_window: Instant::now(),
+            closed: false,
+            _permit: permit,
+        })
+    }
+}
+
+/// A connected client with its own receiver of the index, so a slow client only lags itself
+struct StreamClient {
+    hub: StreamHub,
+    transport: &'static str,
+    subscription: Subscription,
+    receiver: broadcast::Receiver<AppInternalMessage>,
+    app: broadcast::Receiver<AppMessage>,
+    lags: u32,
+    lag_window: Instant,
+    closed: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl StreamClient {
+    fn snapshot(&self) -> StreamEvent {
+        self.hub.snapshot(&self.subscription)
+    }
+
+    /// Replaces the subscription, returning the snapshot of the new one
+    fn subscribe(&mut self, subscription: Subscription) -> StreamEvent {
+        self.subscription = subscription;
+        self.snapshot()
+    }
+
+    /// The next events for the client, none once it is to be disconnected
+    async fn next(&mut self) -> Option<Vec<StreamEvent>> {
+        if self.closed {
+            return None;
+        }
+        loop {
+            tokio::select! {
+                _ = self.app.recv() => return None,
+                message = self.receiver.recv() => match message {
+                    Ok(AppInternalMessage::Tickers(tickers)) => {
+                        let tickers = self.subscription.filter(tickers.iter());
+                        if !tickers.is_empty() {
+                            return Some(vec![StreamEvent::Tick { tickers }]);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => return Some(self.lagged(skipped)),
+                    Err(RecvError::Closed) => return None,
+                },
+            }
+        }
+    }
+
+    /// Tells the client it skipped messages and resyncs it with a snapshot, or disconnects it
+    /// once it lagged more than `stream_max_lags` times within a minute
+    fn lagged(&mut self, skipped: u64) -> Vec<StreamEvent> {
+        STREAM_MESSAGES_DROPPED
+            .with_label_values(&[self.transport])
+            .inc_by(skipped as f64);
+        if self.lag_window.elapsed() > LAG_WINDOW {
+            self.lags = 0;
+            self.lag_window = Instant::now();
+        }
+        self.lags += 1;
+        if self.lags > self.hub.config.max_lags {
+            log::warn!(
+                "{} disconnecting {} client lagging {} times",
+                self.hub.context.name,
+                self.transport,
+                self.lags
+            );
+            STREAM_CLIENTS_DISCONNECTED
+                .with_label_values(&[self.transport])
+                .inc();
+            self.closed = true;
+            return vec![StreamEvent::Error {
+                message: "disconnected for lagging behind".to_string(),
+            }];
+        }
+        vec![StreamEvent::Lagged { skipped }, self.snapshot()]
+    }
+
+    /// Sends the snapshot and then the events, switching subscriptions as the client asks
+    async fn serve_ws(mut self, socket: WebSocket) {
+        let (mut sender, mut incoming) = socket.split();
+        let send =
+            |event: &StreamEvent| Message::text(serde_json::to_string(event).unwrap_or_default());
+        if sender.send(send(&self.snapshot())).await.is_err() {
+            return;
+        }
+        loop {
+            let events = tokio::select! {
+                events = self.next() => match events {
+                    Some(events) => events,
+                    None => break,
+                },
+                message = incoming.next() => match message {
+                    Some(Ok(message)) if message.is_text() => {
+                        let text = message.to_str().unwrap_or_default();
+                        match serde_json::from_str::<ClientMessage>(text) {
+                            Ok(ClientMessage::Subscribe { symbols, sources }) => {
+                                vec![self.subscribe(Subscription::new(symbols, sources))]
+                            }
+                            Err(e) => vec![StreamEvent::Error { message: e.to_string() }],
+                        }
+                    }
+                    Some(Ok(message)) if message.is_close() => break,
+                    // Pings are answered by the websocket itself
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => break,
+                },
+            };
+            for event in events.iter() {
+                if sender.send(send(event)).await.is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = sender.close().await;
+    }
+
+    /// The events as SSE, starting with the snapshot
+    fn into_sse(
+        self,
+    ) -> impl futures_util::Stream<Item = Result<warp::sse::Event, serde_json::Error>> {
+        let snapshot = self.snapshot();
+        futures_util::stream::unfold(
+            (self, VecDeque::from([snapshot])),
+            |(mut client, mut pending)| async move {
+                loop {
+                    if let Some(event) = pending.pop_front() {
+                        let event = warp::sse::Event::default()
+                            .event(event.name())
+                            .json_data(&event);
+                        return Some((event, (client, pending)));
+                    }
+                    pending.extend(client.next().await?);
+                }
+            },
+        )
+    }
+}
+
+impl Drop for StreamClient {
+    fn drop(&mut self) {
+        STREAM_CLIENTS.with_label_values(&[self.transport]).dec();
+    }
+}
+
+/// Invalid subscriptions are answered with 400, clients over `stream_max_clients` with 503
+fn error_response(e: AppError) -> Response {
+    let status = match e {
+        AppError::ConfigError(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let body = warp::reply::json(&serde_json::json!({ "error": e.to_string() }));
+    warp::reply::with_status(body, status).into_response()
+}
+
+/// `/index/ws` and `/index/sse`, streaming the index to clients as it is published
+pub fn stream_routes(
+    hub: StreamHub,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let hub = warp::any().map(move || hub.clone());
+
+    let ws = warp::path!("index" / "ws")
+        .and(warp::ws())
+        .and(warp::query::<StreamQuery>())
+        .and(hub.clone())
+        .map(
+            |ws: Ws, query: StreamQuery, hub: StreamHub| match hub.connect("ws", &query) {
+                Ok(client) => ws
+                    .on_upgrade(move |socket| client.serve_ws(socket))
+                    .into_response(),
+                Err(e) => error_response(e),
+            },
+        );
+
+    let sse = warp::path!("index" / "sse")
+        .and(warp::get())
+        .and(warp::query::<StreamQuery>())
+        .and(hub)
+        .map(
+            |query: StreamQuery, hub: StreamHub| match hub.connect("sse", &query) {
+                Ok(client) => {
+                    let events = client.into_sse();
+                    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+                }
+                Err(e) => error_response(e),
+            },
+        );
+
+    ws.or(sse).unify()
+}
+
+/// Keeps the latest tickers of the [`StreamHub`] for the snapshots of new clients
+pub struct StreamSnapshotWorker {
+    context: Context,
+    hub: StreamHub,
+}
+
+impl StreamSnapshotWorker {
+    pub fn new(context: Context, hub: StreamHub) -> Self {
+        Self { context, hub }
+    }
+}
+
+impl Worker for StreamSnapshotWorker {
+    fn spawn(&mut self) -> SpawnResult {
+        let context = self.context.clone();
+        let hub = self.hub.clone();
+        let mut receiver = hub.broadcaster.receiver();
+        let mut app = context.app.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = app.recv() => {
+                        log::info!("{} received exit message", context.name);
+                        return Ok(format!("{} received exit message", context.name));
+                    }
+                    message = receiver.recv() => {
+                        match message {
+                            Ok(message) => hub.record(&message),
+                            Err(RecvError::Lagged(skipped)) => {
+                                log::warn!("{} lagged, skipped {} messages", context.name, skipped);
+                            }
+                            Err(RecvError::Closed) => {
+                                return Err(AppError::GenericError(format!("{} receiver closed", context.name)));
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use jiff::Timestamp;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn ticker(symbol: TickerSymbol, second: i64) -> Ticker {
+        Ticker {
+            symbol,
+            price: dec!(100),
+            source: Source::IndexerWeightedAverage,
+            timestamp: Timestamp::from_second(second).unwrap(),
+        }
+    }
+
+    fn stream_hub(capacity: usize, config: StreamConfig) -> StreamHub {
+        StreamHub::new(
+            Context::from_config(Config::builder().build().unwrap()).with_name("index-stream"),
+            Broadcaster::new(capacity),
+            config,
+        )
+    }
+
+    fn publish(hub: &StreamHub, tickers: Vec<Ticker>) {
+        let message = AppInternalMessage::Tickers(tickers);
+        hub.record(&message);
+        hub.broadcaster.sender().send(message).unwrap();
+    }
+
+    fn symbols(event: &serde_json::Value) -> Vec<&str> {
+        event["tickers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|ticker| ticker["symbol"].as_str().unwrap())
+            .collect()
+    }
+
+    async fn recv(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let message = client.recv().await.unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_subscription_from_query() {
+        let query = StreamQuery {
+            symbols: Some("BTCUSD, ETHUSD".to_string()),
+            sources: Some("indexer_weighted_average".to_string()),
+        };
+        let subscription = Subscription::try_from(&query).unwrap();
+        assert_eq!(
+            subscription,
+            Subscription::new(
+                vec![TickerSymbol::ETHUSD, TickerSymbol::BTCUSD],
+                vec![Source::IndexerWeightedAverage]
+            )
+        );
+        assert!(subscription.accepts(&ticker(TickerSymbol::BTCUSD, 1)));
+        assert!(!subscription.accepts(&Ticker {
+            source: Source::Binance,
+            ..ticker(TickerSymbol::BTCUSD, 1)
+        }));
+        assert_eq!(
+            Subscription::try_from(&StreamQuery::default()).unwrap(),
+            Subscription::default()
+        );
+
+        let query = StreamQuery {
+            symbols: Some("DOGEUSD".to_string()),
+            sources: None,
+        };
+        assert!(matches!(
+            Subscription::try_from(&query),
+            Err(AppError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream() {
+        let hub = stream_hub(10, StreamConfig::default());
+        publish(&hub, vec![ticker(TickerSymbol::BTCUSD, 1)]);
+        let routes = stream_routes(hub.clone());
+
+        let mut client = warp::test::ws()
+            .path("/index/ws?symbols=BTCUSD")
+            .handshake(routes)
+            .await
+            .unwrap();
+        let snapshot = recv(&mut client).await;
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(symbols(&snapshot), vec!["BTCUSD"]);
+
+        publish(
+            &hub,
+            vec![
+                ticker(TickerSymbol::ETHUSD, 2),
+                ticker(TickerSymbol::BTCUSD, 2),
+            ],
+        );
+        let tick = recv(&mut client).await;
+        assert_eq!(tick["type"], "tick");
+        assert_eq!(symbols(&tick), vec!["BTCUSD"]);
+
+        client
+            .send_text(r#"{"action": "subscribe", "symbols": ["ETHUSD"]}"#)
+            .await;
+        let snapshot = recv(&mut client).await;
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(symbols(&snapshot), vec!["ETHUSD"]);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_client() {
+        let hub = stream_hub(
+            2,
+            StreamConfig {
+                max_lags: 1,
+                ..StreamConfig::default()
+            },
+        );
+        let mut client = hub.connect("test", &StreamQuery::default()).unwrap();
+        for second in 0..4 {
+            publish(&hub, vec![ticker(TickerSymbol::BTCUSD, second)]);
+        }
+        let events = client.next().await.unwrap();
+        assert!(matches!(events[0], StreamEvent::Lagged { skipped: 2 }));
+        // Resynced with the latest ticker
+        let StreamEvent::Snapshot { tickers } = &events[1] else {
+            panic!("expected a snapshot, got {:?}", events[1]);
+        };
+        assert_eq!(tickers[0].timestamp, Timestamp::from_second(3).unwrap());
+
+        for second in 4..8 {
+            publish(&hub, vec![ticker(TickerSymbol::BTCUSD, second)]);
+        }
+        let events = client.next().await.unwrap();
+        assert!(matches!(events[0], StreamEvent::Error { .. }));
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream() {
+        let hub = stream_hub(10, StreamConfig::default());
+        publish(&hub, vec![ticker(TickerSymbol::ETHUSD, 1)]);
+        let (address, server) =
+            warp::serve(stream_routes(hub.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut response = reqwest::get(format!("http://{}/index/sse?symbols=ETHUSD", address))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let chunk = response.chunk().await.unwrap().unwrap();
+        let chunk = String::from_utf8_lossy(&chunk);
+        assert!(chunk.starts_with("event:snapshot\n"));
+        assert!(chunk.contains("ETHUSD"));
+
+        let response = warp::test::request()
+            .path("/index/sse?sources=bitfinex")
+            .reply(&stream_routes(hub))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let full = stream_hub(
+            10,
+            StreamConfig {
+                max_clients: 0,
+                ..StreamConfig::default()
+            },
+        );
+        let response = warp::test::request()
+            .path("/index/sse")
+            .reply(&stream_routes(full))
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
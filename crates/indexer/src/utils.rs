@@ -1,155 +1,365 @@
-use common::{AppInternalMessage, Broadcaster, Context, Workers};
-use exchange::{BinanceWsClient, CoinbaseWsClient, Exchange, KrakenWsClient};
-use feed_processing::FeedProcessingWorker;
+use common::{AppInternalMessage, AppResult, Broadcaster, Context, WorkerRef, WorkersHandle};
+use exchange::{BinanceWsClient, CoinbaseWsClient, DeadLetterStore, Exchange, KrakenWsClient};
+use feed_processing::{FeedProcessingWorker, FeedProcessor};
 
 use crate::{
-    config::{IndexerConfig, IndexerConfigChangeHandler},
+    config::{FeedConfig, IndexerConfigChangeHandler},
     processing::SmoothingProcessor,
 };
 
-pub fn add_binance_workers(
-    context: &Context,
-    workers: &mut Workers,
-    app_config: &IndexerConfig,
-    broadcaster: Broadcaster<AppInternalMessage>,
-    indexer_config_change_handler: &mut IndexerConfigChangeHandler,
-) {
-    let binance_config = app_config.get_exchange_config(Exchange::Binance);
-    let binance_broadcaster = Broadcaster::new(500);
-    if let Some(binance_config) = binance_config {
-        // Create Feeding Processor for Binance
-        let smoothing_processor = SmoothingProcessor::new(
-            app_config
-                .get_smoothing_config(Exchange::Binance)
-                .unwrap()
-                .clone(),
-        );
+/// Passes messages through unchanged, e.g. to merge the raw ticks of all feeds into one stream.
+#[derive(Clone)]
+pub struct ForwardProcessor;
 
-        // Create Feeding Processor Worker
-        let feeding_processor_worker = FeedProcessingWorker::new(
-            context.with_name("binance-feeding-processor-worker"),
-            binance_broadcaster.clone(),
-            broadcaster,
-            smoothing_processor.clone(),
-        );
+impl FeedProcessor<AppInternalMessage, AppInternalMessage> for ForwardProcessor {
+    fn process(&mut self, input: &AppInternalMessage) -> Option<AppInternalMessage> {
+        Some(input.clone())
+    }
+}
 
-        // Add Feeding Processor Worker to Workers
-        workers.add_worker(Box::new(feeding_processor_worker));
+/// Creates the smoothing worker and the ws consumer of an exchange's feed and registers their
+/// config change handlers. The raw ticks of the feed are also forwarded to `raw_broadcaster`,
+/// if any.
+///
+/// The workers exit with the given context.
+pub fn feed_workers(
+    context: &Context,
+    exchange: &Exchange,
+    feed_config: &FeedConfig,
+    broadcaster: Broadcaster<AppInternalMessage>,
+    raw_broadcaster: Option<Broadcaster<AppInternalMessage>>,
+    indexer_config_change_handler: &IndexerConfigChangeHandler,
+    dead_letters: &DeadLetterStore,
+) -> Vec<WorkerRef> {
+    let exchange_broadcaster = Broadcaster::new(500);
+    let mut workers: Vec<WorkerRef> = Vec::new();
+    if let Some(raw_broadcaster) = raw_broadcaster {
+        workers.push(Box::new(FeedProcessingWorker::new(
+            context.with_name(&format!("{}-raw-forwarder", exchange)),
+            exchange_broadcaster.clone(),
+            raw_broadcaster,
+            ForwardProcessor,
+        )));
+    }
 
-        // Create Binance WsClient
-        let mut binance_ws_client = BinanceWsClient::new(binance_config.clone());
+    // Create Feeding Processor for the exchange
+    let smoothing_processor = SmoothingProcessor::new(feed_config.smoothing_config().clone());
+    let feeding_processor_worker = FeedProcessingWorker::new(
+        context.with_name(&format!("{}-feeding-processor-worker", exchange)),
+        exchange_broadcaster.clone(),
+        broadcaster,
+        smoothing_processor.clone(),
+    );
 
-        // Create Binance WsConsumer
-        let binance_consumer =
-            binance_ws_client.consumer(context.clone(), binance_broadcaster.sender());
+    // Create WsConsumer of the exchange
+    let exchange_config = feed_config.exchange_config().clone();
+    let sender = exchange_broadcaster.sender();
+    let (consumer, callback, connected): (WorkerRef, _, _) = match exchange {
+        Exchange::Binance => {
+            let consumer = BinanceWsClient::new(exchange_config)
+                .with_dead_letter_store(dead_letters.clone())
+                .consumer(context.clone(), sender);
+            let callback = Box::new(consumer.callback.clone());
+            let connected = consumer.connected.clone();
+            (Box::new(consumer), callback as _, connected)
+        }
+        Exchange::Kraken => {
+            let consumer = KrakenWsClient::new(exchange_config)
+                .with_dead_letter_store(dead_letters.clone())
+                .consumer(context.clone(), sender);
+            let callback = Box::new(consumer.callback.clone());
+            let connected = consumer.connected.clone();
+            (Box::new(consumer), callback as _, connected)
+        }
+        Exchange::Coinbase => {
+            let consumer = CoinbaseWsClient::new(exchange_config)
+                .with_dead_letter_store(dead_letters.clone())
+                .consumer(context.clone(), sender);
+            let callback = Box::new(consumer.callback.clone());
+            let connected = consumer.connected.clone();
+            (Box::new(consumer), callback as _, connected)
+        }
+    };
 
-        // Add Binance WsConsumer to IndexerConfigChangeHandler
-        indexer_config_change_handler.add_exchange_config_handler(
-            Exchange::Binance,
-            Box::new(binance_consumer.callback.clone()),
-        );
-        indexer_config_change_handler
-            .add_smoothing_config_handler(Exchange::Binance, Box::new(smoothing_processor));
+    // Add WsConsumer and Feeding Processor to IndexerConfigChangeHandler
+    indexer_config_change_handler.add_exchange_config_handler(exchange.clone(), callback);
+    indexer_config_change_handler.add_connection_status(exchange.clone(), connected);
+    indexer_config_change_handler
+        .add_smoothing_config_handler(exchange.clone(), Box::new(smoothing_processor));
 
-        // Add Binance WsConsumer to Workers
-        workers.add_worker(Box::new(binance_consumer));
-    }
+    workers.push(Box::new(feeding_processor_worker));
+    workers.push(consumer);
+    workers
 }
 
-pub fn add_kraken_workers(
-    context: &Context,
-    workers: &mut Workers,
-    app_config: &IndexerConfig,
+/// Starts and stops the feeds of exchanges as groups of workers, named after the exchange.
+#[derive(Clone)]
+pub struct FeedManager {
+    context: Context,
+    workers: WorkersHandle,
     broadcaster: Broadcaster<AppInternalMessage>,
-    indexer_config_change_handler: &mut IndexerConfigChangeHandler,
-) {
-    let kraken_config = app_config.get_exchange_config(Exchange::Kraken);
-    let kraken_broadcaster = Broadcaster::new(500);
-    if let Some(kraken_config) = kraken_config {
-        // Create Feeding Processor for Kraken
-        let smoothing_processor = SmoothingProcessor::new(
-            app_config
-                .get_smoothing_config(Exchange::Kraken)
-                .unwrap()
-                .clone(),
-        );
+    raw_broadcaster: Option<Broadcaster<AppInternalMessage>>,
+    dead_letters: DeadLetterStore,
+}
 
-        // Create Feeding Processor Worker
-        let feeding_processor_worker = FeedProcessingWorker::new(
-            context.with_name("kraken-feeding-processor-worker"),
-            kraken_broadcaster.clone(),
+impl FeedManager {
+    pub fn new(
+        context: Context,
+        workers: WorkersHandle,
+        broadcaster: Broadcaster<AppInternalMessage>,
+        dead_letters: DeadLetterStore,
+    ) -> Self {
+        Self {
+            context,
+            workers,
             broadcaster,
-            smoothing_processor.clone(),
-        );
-
-        // Add Feeding Processor Worker to Workers
-        workers.add_worker(Box::new(feeding_processor_worker));
-
-        // Create Kraken WsClient
-        let mut kraken_ws_client = KrakenWsClient::new(kraken_config.clone());
+            raw_broadcaster: None,
+            dead_letters,
+        }
+    }
 
-        // Create Kraken WsConsumer
-        let kraken_consumer =
-            kraken_ws_client.consumer(context.clone(), kraken_broadcaster.sender());
+    /// Also forwards the raw ticks of every feed to the broadcaster
+    pub fn with_raw_broadcaster(
+        mut self,
+        raw_broadcaster: Broadcaster<AppInternalMessage>,
+    ) -> Self {
+        self.raw_broadcaster = Some(raw_broadcaster);
+        self
+    }
 
-        // Add Kraken WsConsumer to IndexerConfigChangeHandler
-        indexer_config_change_handler.add_exchange_config_handler(
-            Exchange::Kraken,
-            Box::new(kraken_consumer.callback.clone()),
+    pub fn start(
+        &self,
+        exchange: &Exchange,
+        feed_config: &FeedConfig,
+        indexer_config_change_handler: &IndexerConfigChangeHandler,
+    ) -> AppResult<()> {
+        let context = self.context.child(&format!("{}-feed", exchange));
+        let workers = feed_workers(
+            &context,
+            exchange,
+            feed_config,
+            self.broadcaster.clone(),
+            self.raw_broadcaster.clone(),
+            indexer_config_change_handler,
+            &self.dead_letters,
         );
-        indexer_config_change_handler
-            .add_smoothing_config_handler(Exchange::Kraken, Box::new(smoothing_processor));
+        log::info!("{} starting feed of {}", self.context.name, exchange);
+        self.workers
+            .add_group(&exchange.to_string(), context, workers)
+    }
 
-        // Add Kraken WsConsumer to Workers
-        workers.add_worker(Box::new(kraken_consumer));
+    /// Replaces the running feed of an exchange with one started from the new config
+    pub fn restart(
+        &self,
+        exchange: &Exchange,
+        feed_config: &FeedConfig,
+        indexer_config_change_handler: &IndexerConfigChangeHandler,
+    ) -> AppResult<()> {
+        indexer_config_change_handler.remove_feed_handlers(exchange);
+        log::info!("{} restarting feed of {}", self.context.name, exchange);
+        self.start(exchange, feed_config, indexer_config_change_handler)
+    }
+
+    pub fn stop(
+        &self,
+        exchange: &Exchange,
+        indexer_config_change_handler: &IndexerConfigChangeHandler,
+    ) -> AppResult<()> {
+        indexer_config_change_handler.remove_feed_handlers(exchange);
+        log::info!("{} stopping feed of {}", self.context.name, exchange);
+        self.workers.remove_group(&exchange.to_string())
     }
 }
 
-pub fn add_coinbase_workers(
-    context: &Context,
-    workers: &mut Workers,
-    app_config: &IndexerConfig,
-    broadcaster: Broadcaster<AppInternalMessage>,
-    indexer_config_change_handler: &mut IndexerConfigChangeHandler,
-) {
-    let coinbase_config = app_config.get_exchange_config(Exchange::Coinbase);
-    let coinbase_broadcaster = Broadcaster::new(500);
-    if let Some(coinbase_config) = coinbase_config {
-        // Create Feeding Processor for Coinbase
-        let smoothing_processor = SmoothingProcessor::new(
-            app_config
-                .get_smoothing_config(Exchange::Coinbase)
-                .unwrap()
-                .clone(),
-        );
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use common::{Source, TickerSymbol, Worker, Workers};
+    use config::Config;
+    use etcd::EtcdWatcherHandler;
+    use mock_exchange::{MockExchange, MockExchangeServer, Scenario};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        config::IndexerConfig,
+        processing::{WeightedAverageConfig, WeightedAverageProcessor},
+    };
+
+    #[tokio::test]
+    async fn test_pipeline_against_mock_exchanges() {
+        let binance = MockExchangeServer::start(
+            MockExchange::Binance,
+            vec![Scenario::new()
+                .subscribe()
+                .ticker("BTCUSDT", dec!(100))
+                .sleep(200)
+                .ticker("BTCUSDT", dec!(102))],
+        )
+        .await;
+        let kraken = MockExchangeServer::start(
+            MockExchange::Kraken,
+            vec![Scenario::new()
+                .subscribe()
+                .malformed("{not json")
+                .sleep(100)
+                .ticker("BTC/USD", dec!(110))],
+        )
+        .await;
+
+        let app_config: IndexerConfig = serde_json::from_value(serde_json::json!({
+            "binance": {
+                "exchange_config": {
+                    "ws_url": binance.url(),
+                    "channels": ["ticker"],
+                    "instruments": ["BTCUSDT"],
+                    "heartbeat_millis": 1000
+                },
+                "smoothing_config": {
+                    "type": "sma",
+                    "params": { "window": 2 }
+                },
+                "weight": 60.0
+            },
+            "kraken": {
+                "exchange_config": {
+                    "ws_url": kraken.url(),
+                    "channels": ["ticker"],
+                    "instruments": ["BTC/USD"],
+                    "heartbeat_millis": 1000
+                },
+                "smoothing_config": { "type": "pass_thru" },
+                "weight": 40.0
+            }
+        }))
+        .unwrap();
+
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut workers = Workers::new(context.clone(), 0);
+        let broadcaster = Broadcaster::new(100);
+        let raw_broadcaster = Broadcaster::new(100);
+        let feed_manager = FeedManager::new(
+            context.clone(),
+            workers.handle(),
+            broadcaster.clone(),
+            DeadLetterStore::default(),
+        )
+        .with_raw_broadcaster(raw_broadcaster.clone());
+        let handler =
+            IndexerConfigChangeHandler::new(context.clone()).with_feed_manager(feed_manager);
+        handler.start_feeds(&app_config).unwrap();
 
-        // Create Feeding Processor Worker
-        let feeding_processor_worker = FeedProcessingWorker::new(
-            context.with_name("coinbase-feeding-processor-worker"),
-            coinbase_broadcaster.clone(),
+        let weighted_average_config = WeightedAverageConfig::new(HashMap::from([
+            (Exchange::Binance, dec!(60)),
+            (Exchange::Kraken, dec!(40)),
+        ]))
+        .unwrap();
+        let index_broadcaster = Broadcaster::new(100);
+        workers.add_worker(Box::new(FeedProcessingWorker::new(
+            context.with_name("weighted-average-processor"),
             broadcaster,
-            smoothing_processor.clone(),
-        );
+            index_broadcaster.clone(),
+            WeightedAverageProcessor::new(weighted_average_config).unwrap(),
+        )));
+
+        let mut receiver = index_broadcaster.receiver();
+        let mut raw_receiver = raw_broadcaster.receiver();
+        let handle = workers.spawn();
+
+        // Binance is smoothed with an SMA(2) of 100 and 102 and Kraken passes 110 through
+        let expected = (dec!(101) * dec!(60) + dec!(110) * dec!(40)) / dec!(100);
+        let index = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let AppInternalMessage::Tickers(tickers) = receiver.recv().await.unwrap();
+                if let Some(ticker) = tickers.into_iter().find(|t| t.price == expected) {
+                    return ticker;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for index");
+
+        assert_eq!(index.symbol, TickerSymbol::BTCUSD);
+        assert_eq!(index.source, Source::IndexerWeightedAverage);
+
+        // The unsmoothed ticks of the feeds are forwarded as well
+        let AppInternalMessage::Tickers(raw) = raw_receiver.recv().await.unwrap();
+        assert!(raw
+            .iter()
+            .all(|t| matches!(t.source, Source::Binance | Source::Kraken)));
 
-        // Add Feeding Processor Worker to Workers
-        workers.add_worker(Box::new(feeding_processor_worker));
+        context.exit();
+        assert!(handle.await.unwrap().is_ok());
+    }
 
-        // Create Coinbase WsClient
-        let mut coinbase_ws_client = CoinbaseWsClient::new(coinbase_config.clone());
+    async fn wait_for_connection(server: &MockExchangeServer) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while server.connections() == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for a connection")
+    }
 
-        // Create Coinbase WsConsumer
-        let coinbase_consumer =
-            coinbase_ws_client.consumer(context.clone(), coinbase_broadcaster.sender());
+    #[tokio::test]
+    async fn test_connection_change_restarts_the_feed() {
+        let scenario = || {
+            Scenario::new()
+                .subscribe()
+                .ticker("BTC/USD", dec!(110))
+                .sleep(5000)
+        };
+        let old = MockExchangeServer::start(MockExchange::Kraken, vec![scenario()]).await;
+        let new = MockExchangeServer::start(MockExchange::Kraken, vec![scenario()]).await;
+        let feed = |url: &str| {
+            serde_json::json!({
+                "kraken": {
+                    "exchange_config": {
+                        "ws_url": url,
+                        "channels": ["ticker"],
+                        "instruments": ["BTC/USD"],
+                        "heartbeat_millis": 1000
+                    },
+                    "smoothing_config": { "type": "pass_thru" },
+                    "weight": 100.0
+                }
+            })
+        };
+        let app_config: IndexerConfig = serde_json::from_value(feed(old.url())).unwrap();
 
-        // Add Coinbase WsConsumer to IndexerConfigChangeHandler
-        indexer_config_change_handler.add_exchange_config_handler(
-            Exchange::Coinbase,
-            Box::new(coinbase_consumer.callback.clone()),
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let mut workers = Workers::new(context.clone(), 0);
+        let broadcaster = Broadcaster::new(100);
+        let feed_manager = FeedManager::new(
+            context.clone(),
+            workers.handle(),
+            broadcaster.clone(),
+            DeadLetterStore::default(),
         );
-        indexer_config_change_handler
-            .add_smoothing_config_handler(Exchange::Coinbase, Box::new(smoothing_processor));
+        let handler =
+            IndexerConfigChangeHandler::new(context.clone()).with_feed_manager(feed_manager);
+        handler.start_feeds(&app_config).unwrap();
+        handler.set_current_config(app_config);
+        let weighted_average_config =
+            WeightedAverageConfig::new(HashMap::from([(Exchange::Kraken, dec!(100))])).unwrap();
+        workers.add_worker(Box::new(FeedProcessingWorker::new(
+            context.with_name("weighted-average-processor"),
+            broadcaster,
+            Broadcaster::new(100),
+            WeightedAverageProcessor::new(weighted_average_config).unwrap(),
+        )));
+        let handle = workers.spawn();
+
+        wait_for_connection(&old).await;
+
+        let config: IndexerConfig = serde_json::from_value(feed(new.url())).unwrap();
+        handler.handle_config_update(config, 2).unwrap();
+        wait_for_connection(&new).await;
+        assert_eq!(old.connections(), 1);
 
-        // Add Coinbase WsConsumer to Workers
-        workers.add_worker(Box::new(coinbase_consumer));
+        context.exit();
+        assert!(handle.await.unwrap().is_ok());
     }
 }
@@ -0,0 +1,31 @@
+//! An in-process websocket server speaking the subscribe / ticker / heartbeat
+//! protocol of the supported exchanges.
+//!
+//! It is meant to be used from tests, to run the exchange callbacks and the rest
+//! of the pipeline against a real socket without network access.
+//!
+//! # Example
+//! ```no_run
+//! use mock_exchange::{MockExchange, MockExchangeServer, Scenario};
+//! use rust_decimal::Decimal;
+//!
+//! # async fn example() {
+//! let server = MockExchangeServer::start(
+//!     MockExchange::Binance,
+//!     vec![Scenario::new()
+//!         .subscribe()
+//!         .ticker("BTCUSDT", Decimal::from(100))
+//!         .malformed("{not json")
+//!         .disconnect()],
+//! )
+//! .await;
+//! println!("connect to {}", server.url());
+//! # }
+//! ```
+mod protocol;
+mod scenario;
+mod server;
+
+pub use protocol::*;
+pub use scenario::*;
+pub use server::*;
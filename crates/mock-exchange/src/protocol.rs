@@ -0,0 +1,200 @@
+use jiff::Timestamp;
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+/// The exchange protocol spoken by the mock server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockExchange {
+    Binance,
+    Kraken,
+    Coinbase,
+}
+
+impl MockExchange {
+    /// Checks if a request sent by the client is a subscribe request
+    pub fn is_subscribe(&self, request: &Value) -> bool {
+        match self {
+            MockExchange::Binance => request["method"] == "SUBSCRIBE",
+            MockExchange::Kraken => request["method"] == "subscribe",
+            MockExchange::Coinbase => request["type"] == "subscribe",
+        }
+    }
+
+    /// Responses acknowledging a subscribe request
+    pub fn subscribe_ack(&self, request: &Value) -> Vec<Message> {
+        let now = Timestamp::now().to_string();
+        let responses = match self {
+            MockExchange::Binance => vec![json!({
+                "result": null,
+                "id": request["id"],
+            })],
+            MockExchange::Kraken => kraken_symbols(request)
+                .into_iter()
+                .map(|symbol| {
+                    json!({
+                        "method": "subscribe",
+                        "result": {
+                            "channel": request["params"]["channel"],
+                            "symbol": symbol,
+                            "event_trigger": "trades",
+                            "snapshot": true,
+                        },
+                        "success": true,
+                        "time_in": now,
+                        "time_out": now,
+                    })
+                })
+                .collect(),
+            MockExchange::Coinbase => {
+                let channels = request["channels"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|channel| {
+                        json!({
+                            "name": channel,
+                            "product_ids": request["product_ids"],
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                vec![json!({
+                    "type": "subscriptions",
+                    "channels": channels,
+                })]
+            }
+        };
+        responses.into_iter().map(to_message).collect()
+    }
+
+    /// Responses rejecting a subscribe request
+    pub fn subscribe_reject(&self, request: &Value) -> Vec<Message> {
+        let now = Timestamp::now().to_string();
+        let responses = match self {
+            MockExchange::Binance => vec![json!({
+                "error": {
+                    "code": 2,
+                    "msg": "Invalid request: unknown stream",
+                },
+                "id": request["id"],
+            })],
+            MockExchange::Kraken => kraken_symbols(request)
+                .into_iter()
+                .map(|symbol| {
+                    json!({
+                        "error": "Currency pair not supported",
+                        "method": "subscribe",
+                        "success": false,
+                        "symbol": symbol,
+                        "time_in": now,
+                        "time_out": now,
+                    })
+                })
+                .collect(),
+            MockExchange::Coinbase => vec![json!({
+                "type": "error",
+                "message": "Failed to subscribe",
+                "reason": "product not found",
+            })],
+        };
+        responses.into_iter().map(to_message).collect()
+    }
+
+    /// A ticker update for the instrument with the given last price
+    pub fn ticker(&self, instrument: &str, price: Decimal) -> Message {
+        let now = Timestamp::now();
+        let price = price.to_string();
+        let ticker = match self {
+            MockExchange::Binance => json!({
+                "e": "24hrTicker",
+                "E": now.as_millisecond(),
+                "s": instrument,
+                "p": "0",
+                "P": "0",
+                "w": price,
+                "x": price,
+                "c": price,
+                "Q": "1",
+                "b": price,
+                "B": "1",
+                "a": price,
+                "A": "1",
+                "o": price,
+                "h": price,
+                "l": price,
+                "v": "1",
+                "q": price,
+                "O": 0,
+                "C": now.as_millisecond(),
+                "F": 0,
+                "L": 1,
+                "n": 1,
+            }),
+            MockExchange::Kraken => json!({
+                "channel": "ticker",
+                "type": "update",
+                "data": [{
+                    "symbol": instrument,
+                    "bid": price,
+                    "bid_qty": "1",
+                    "ask": price,
+                    "ask_qty": "1",
+                    "last": price,
+                    "volume": "1",
+                    "vwap": price,
+                    "low": price,
+                    "high": price,
+                    "change": "0",
+                    "change_pct": "0",
+                }],
+            }),
+            MockExchange::Coinbase => json!({
+                "type": "ticker",
+                "sequence": 1,
+                "product_id": instrument,
+                "price": price,
+                "open_24h": price,
+                "volume_24h": "1",
+                "low_24h": price,
+                "high_24h": price,
+                "volume_30d": "1",
+                "best_bid": price,
+                "best_bid_size": "1",
+                "best_ask": price,
+                "best_ask_size": "1",
+                "side": "buy",
+                "time": now.to_string(),
+                "trade_id": 1,
+                "last_size": "1",
+            }),
+        };
+        to_message(ticker)
+    }
+
+    /// The heartbeat the exchange sends to keep the connection alive
+    pub fn heartbeat(&self) -> Message {
+        match self {
+            MockExchange::Binance => Message::Ping(Vec::new().into()),
+            MockExchange::Kraken => to_message(json!({ "channel": "heartbeat" })),
+            MockExchange::Coinbase => to_message(json!({
+                "type": "heartbeat",
+                "last_trade_id": 1,
+                "product_id": "BTC-USD",
+                "sequence": 1,
+                "time": Timestamp::now().to_string(),
+            })),
+        }
+    }
+}
+
+fn kraken_symbols(request: &Value) -> Vec<Value> {
+    request["params"]["symbol"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn to_message(value: Value) -> Message {
+    Message::text(value.to_string())
+}
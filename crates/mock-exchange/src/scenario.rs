@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+/// A single scripted action of the mock server on a connection.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Wait for a subscribe request and acknowledge it
+    Subscribe,
+    /// Wait for a subscribe request and reply with an error
+    RejectSubscribe,
+    /// Send a ticker update for the instrument, using the exchange symbol (e.g. `BTCUSDT`)
+    Ticker { instrument: String, price: Decimal },
+    /// Send the exchange specific heartbeat
+    Heartbeat,
+    /// Send a raw text frame as is, e.g. a malformed message
+    Raw(String),
+    /// Pause before executing the next step
+    Sleep(Duration),
+    /// Close the connection
+    Disconnect,
+}
+
+/// The steps executed by the mock server on one connection.
+///
+/// After the last step the connection is kept open until the client closes it,
+/// unless the scenario ends with [`Step::Disconnect`].
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn subscribe(self) -> Self {
+        self.step(Step::Subscribe)
+    }
+
+    pub fn reject_subscribe(self) -> Self {
+        self.step(Step::RejectSubscribe)
+    }
+
+    pub fn ticker(self, instrument: &str, price: Decimal) -> Self {
+        self.step(Step::Ticker {
+            instrument: instrument.to_string(),
+            price,
+        })
+    }
+
+    pub fn heartbeat(self) -> Self {
+        self.step(Step::Heartbeat)
+    }
+
+    pub fn malformed(self, text: &str) -> Self {
+        self.step(Step::Raw(text.to_string()))
+    }
+
+    pub fn sleep(self, millis: u64) -> Self {
+        self.step(Step::Sleep(Duration::from_millis(millis)))
+    }
+
+    pub fn disconnect(self) -> Self {
+        self.step(Step::Disconnect)
+    }
+}
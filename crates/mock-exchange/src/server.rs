@@ -0,0 +1,156 @@
+use std::{collections::VecDeque, time::Duration};
+
+use common::SharedRef;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use crate::{MockExchange, Scenario, Step};
+
+/// A websocket server running scripted scenarios of an exchange protocol.
+///
+/// Every accepted connection runs the next scenario in order, so reconnects can be
+/// scripted by passing multiple scenarios. Connections beyond the given scenarios
+/// run an empty scenario. The server stops when dropped.
+pub struct MockExchangeServer {
+    url: String,
+    received: SharedRef<Vec<String>>,
+    connections: SharedRef<usize>,
+    handle: JoinHandle<()>,
+}
+
+impl MockExchangeServer {
+    pub async fn start(exchange: MockExchange, scenarios: Vec<Scenario>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let received = SharedRef::new(Vec::new());
+        let connections = SharedRef::new(0);
+
+        let handle = {
+            let received = received.clone();
+            let connections = connections.clone();
+            let mut scenarios = VecDeque::from(scenarios);
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    *connections.lock() += 1;
+                    let scenario = scenarios.pop_front().unwrap_or_default();
+                    let received = received.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            handle_connection(exchange, stream, scenario, received).await
+                        {
+                            log::warn!("mock {:?} connection error: {}", exchange, e);
+                        }
+                    });
+                }
+            })
+        };
+
+        Self {
+            url,
+            received,
+            connections,
+            handle,
+        }
+    }
+
+    /// The `ws://` url the server is listening on
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// All text frames received from clients so far
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().clone()
+    }
+
+    /// The number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        *self.connections.lock()
+    }
+}
+
+impl Drop for MockExchangeServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn handle_connection(
+    exchange: MockExchange,
+    stream: TcpStream,
+    scenario: Scenario,
+    received: SharedRef<Vec<String>>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut ws_stream = tokio_tungstenite::accept_async(stream).await?;
+
+    for step in scenario.steps {
+        match step {
+            Step::Subscribe | Step::RejectSubscribe => {
+                let Some(request) = next_subscribe(exchange, &mut ws_stream, &received).await?
+                else {
+                    return Ok(());
+                };
+                let responses = match step {
+                    Step::Subscribe => exchange.subscribe_ack(&request),
+                    _ => exchange.subscribe_reject(&request),
+                };
+                for response in responses {
+                    ws_stream.send(response).await?;
+                }
+            }
+            Step::Ticker { instrument, price } => {
+                ws_stream.send(exchange.ticker(&instrument, price)).await?;
+            }
+            Step::Heartbeat => {
+                ws_stream.send(exchange.heartbeat()).await?;
+            }
+            Step::Raw(text) => {
+                ws_stream.send(Message::text(text)).await?;
+            }
+            Step::Sleep(duration) => {
+                tokio::time::sleep(duration).await;
+            }
+            Step::Disconnect => {
+                // Dropping the stream without a close handshake, like a network failure
+                return Ok(());
+            }
+        }
+    }
+
+    // Keep the connection open, recording whatever the client sends
+    while let Some(message) = ws_stream.next().await {
+        match message? {
+            Message::Text(text) => received.lock().push(text.to_string()),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reads frames until a subscribe request arrives, recording all text frames
+async fn next_subscribe(
+    exchange: MockExchange,
+    ws_stream: &mut WebSocketStream<TcpStream>,
+    received: &SharedRef<Vec<String>>,
+) -> Result<Option<Value>, tokio_tungstenite::tungstenite::Error> {
+    loop {
+        let message = match tokio::time::timeout(Duration::from_secs(10), ws_stream.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) | Err(_) => return Ok(None),
+        };
+        if let Message::Text(text) = message {
+            received.lock().push(text.to_string());
+            if let Ok(request) = serde_json::from_str::<Value>(&text) {
+                if exchange.is_subscribe(&request) {
+                    return Ok(Some(request));
+                }
+            }
+        }
+    }
+}
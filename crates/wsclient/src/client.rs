@@ -1,7 +1,10 @@
 use common::{AppError, AppResult, Backoff, Context, MpSc, SharedRef};
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::{WsCallback, WsConsumer};
+use crate::{
+    OutboundConfig, RecorderConfig, ReplayConfig, WsCallback, WsConnectionConfig, WsConsumer,
+    WS_OUTBOUND_MESSAGES_DROPPED,
+};
 
 #[derive(Clone)]
 pub struct WsClient {
@@ -9,18 +12,41 @@ pub struct WsClient {
     connected: SharedRef<bool>,
     producer: MpSc<Message>,
     heartbeat_millis: u64,
+    connection_config: WsConnectionConfig,
+    outbound_config: OutboundConfig,
+    /// Name of the consumer driving this client, used to label metrics
+    name: SharedRef<String>,
 }
 
 impl WsClient {
     pub fn new(ws_url: String, heartbeat_millis: u64) -> Self {
+        let outbound_config = OutboundConfig::default();
         Self {
+            name: SharedRef::new(ws_url.clone()),
             ws_url,
             connected: SharedRef::new(false),
-            producer: MpSc::new(100),
+            producer: MpSc::new(outbound_config.capacity),
             heartbeat_millis,
+            connection_config: WsConnectionConfig::default(),
+            outbound_config,
         }
     }
 
+    /// Sets the proxy, tls and handshake options used when connecting
+    pub fn with_connection_config(mut self, connection_config: WsConnectionConfig) -> Self {
+        self.connection_config = connection_config;
+        self
+    }
+
+    /// Sets the capacity and rate limit of the outbound message queue
+    ///
+    /// This has to be called before the client is cloned, as it replaces the queue.
+    pub fn with_outbound_config(mut self, outbound_config: OutboundConfig) -> Self {
+        self.producer = MpSc::new(outbound_config.capacity.max(1));
+        self.outbound_config = outbound_config;
+        self
+    }
+
     pub fn ws_url(&self) -> &str {
         &self.ws_url
     }
@@ -29,13 +55,61 @@ impl WsClient {
         *self.connected.lock()
     }
 
+    /// Queues a message without waiting, failing if the outbound queue is full.
+    ///
+    /// This is meant to be used from within the [`WsCallback`], where waiting for the
+    /// queue would block the consumer that drains it.
     pub fn write(&self, message: Message) -> AppResult<()> {
         match self.producer.sender.try_send(message) {
             Ok(_) => Ok(()),
-            Err(e) => Err(AppError::ChannelSendError(format!(
-                "failed to send message to ws client: {}",
-                e
-            ))),
+            Err(e) => {
+                WS_OUTBOUND_MESSAGES_DROPPED
+                    .with_label_values(&[&*self.name.lock()])
+                    .inc();
+                Err(AppError::ChannelSendError(format!(
+                    "failed to send message to ws client: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    /// Queues a message, waiting for space in the outbound queue if it is full.
+    pub async fn send(&self, message: Message) -> AppResult<()> {
+        self.producer.sender.send(message).await.map_err(|e| {
+            WS_OUTBOUND_MESSAGES_DROPPED
+                .with_label_values(&[&*self.name.lock()])
+                .inc();
+            AppError::ChannelSendError(format!("failed to send message to ws client: {}", e))
+        })
+    }
+
+    /// Queues the messages in order without waiting, failing without queueing any of them if
+    /// the outbound queue can't hold them all.
+    ///
+    /// The consumer writes the queued messages in order, so requests queued one after the
+    /// other, like an unsubscribe followed by a subscribe, reach the exchange in that order.
+    pub fn write_all(&self, messages: Vec<Message>) -> AppResult<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        match self.producer.sender.try_reserve_many(messages.len()) {
+            Ok(permits) => {
+                for (permit, message) in permits.zip(messages) {
+                    permit.send(message);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                WS_OUTBOUND_MESSAGES_DROPPED
+                    .with_label_values(&[&*self.name.lock()])
+                    .inc_by(messages.len() as f64);
+                Err(AppError::ChannelSendError(format!(
+                    "failed to send {} messages to ws client: {}",
+                    messages.len(),
+                    e
+                )))
+            }
         }
     }
 
@@ -43,17 +117,27 @@ impl WsClient {
         self.write(Message::Close(None))
     }
 
+    /// Creates the consumer driving this client.
+    ///
+    /// Recording and replay are enabled from the static config, see [`RecorderConfig`]
+    /// and [`ReplayConfig`].
     pub fn consumer<C>(&mut self, context: Context, callback: C) -> WsConsumer<C>
     where
         C: WsCallback + Clone,
     {
+        *self.name.lock() = context.name.clone();
         WsConsumer {
+            recorder_config: RecorderConfig::from_context(&context),
+            replay_config: ReplayConfig::from_context(&context),
             ws_url: self.ws_url.clone(),
             callback,
             heartbeat_millis: self.heartbeat_millis,
+            connection_config: self.connection_config.clone(),
+            outbound_config: self.outbound_config.clone(),
             backoff: Backoff::default(),
             context,
             mpsc: self.producer.clone_with_receiver(),
+            connected: self.connected.clone(),
         }
     }
 }
@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{AppError, AppResult};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::client::Request,
+        http::{HeaderName, HeaderValue},
+    },
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Maximum size of the response we accept from a proxy to a CONNECT request
+const MAX_PROXY_RESPONSE_BYTES: usize = 8192;
+
+/// Options applied when establishing a websocket connection.
+///
+/// All fields are optional, the default configuration connects directly to the
+/// websocket url using the system root certificates.
+///
+/// # Example
+/// ```json
+/// {
+///     "proxy": { "type": "socks5", "host": "proxy.internal", "port": 1080 },
+///     "tls": { "root_ca_paths": ["/etc/ssl/custom-ca.pem"] },
+///     "headers": { "X-Api-Key": "secret" }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WsConnectionConfig {
+    /// Proxy to tunnel the connection through
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    /// Custom TLS settings
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Extra headers sent with the websocket handshake request
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// A proxy the websocket connection is tunneled through.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyConfig {
+    /// HTTP proxy supporting the CONNECT method
+    Http {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// SOCKS5 proxy
+    Socks5 {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+/// TLS settings for `wss://` connections.
+///
+/// Certificates and keys are read from PEM encoded files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Additional root certificates to trust
+    #[serde(default)]
+    pub root_ca_paths: Vec<String>,
+    /// Client certificate presented to the server
+    #[serde(default)]
+    pub client_cert_path: Option<String>,
+    /// PKCS#8 private key of the client certificate
+    #[serde(default)]
+    pub client_key_path: Option<String>,
+}
+
+impl WsConnectionConfig {
+    /// Builds the handshake request for the given url including the extra headers.
+    pub fn request(&self, ws_url: &str) -> AppResult<Request> {
+        let mut request = ws_url
+            .into_client_request()
+            .map_err(|e| AppError::WebsocketError(format!("invalid websocket url: {}", e)))?;
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+                AppError::ConfigError(format!("invalid header name {}: {}", name, e))
+            })?;
+            let value = HeaderValue::from_str(value).map_err(|e| {
+                AppError::ConfigError(format!("invalid header value for {}: {}", name, e))
+            })?;
+            request.headers_mut().insert(name, value);
+        }
+        Ok(request)
+    }
+
+    /// Builds a TLS connector from the configured certificates.
+    ///
+    /// Returns `None` if no custom TLS settings are present, in which case the default
+    /// connector is used.
+    pub fn connector(&self) -> AppResult<Option<Connector>> {
+        let Some(tls) = &self.tls else {
+            return Ok(None);
+        };
+
+        let mut builder = native_tls::TlsConnector::builder();
+        for path in tls.root_ca_paths.iter() {
+            let pem = read_file(path)?;
+            let certificate = native_tls::Certificate::from_pem(&pem).map_err(|e| {
+                AppError::ConfigError(format!("invalid root certificate {}: {}", path, e))
+            })?;
+            builder.add_root_certificate(certificate);
+        }
+
+        match (&tls.client_cert_path, &tls.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = read_file(cert_path)?;
+                let key = read_file(key_path)?;
+                let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|e| {
+                    AppError::ConfigError(format!("invalid client certificate: {}", e))
+                })?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(AppError::ConfigError(
+                    "client_cert_path and client_key_path must be set together".to_string(),
+                ));
+            }
+        }
+
+        let connector = builder
+            .build()
+            .map_err(|e| AppError::ConfigError(format!("failed to build tls connector: {}", e)))?;
+        Ok(Some(Connector::NativeTls(connector)))
+    }
+
+    /// Connects to the websocket url, tunneling through the proxy if one is configured.
+    pub async fn connect(&self, ws_url: &str) -> AppResult<WsStream> {
+        let request = self.request(ws_url)?;
+        let connector = self.connector()?;
+
+        let result = match &self.proxy {
+            Some(proxy) => {
+                let (host, port) = target_address(&request)?;
+                let stream = proxy.connect(&host, port).await?;
+                tokio_tungstenite::client_async_tls_with_config(request, stream, None, connector)
+                    .await
+            }
+            None => {
+                tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector)
+                    .await
+            }
+        };
+
+        match result {
+            Ok((ws_stream, _)) => Ok(ws_stream),
+            Err(e) => Err(AppError::WebsocketError(format!(
+                "failed to connect to {}: {}",
+                ws_url, e
+            ))),
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Opens a tcp stream to `host:port` tunneled through the proxy.
+    pub async fn connect(&self, host: &str, port: u16) -> AppResult<TcpStream> {
+        match self {
+            ProxyConfig::Http {
+                host: proxy_host,
+                port: proxy_port,
+                username,
+                password,
+            } => {
+                let mut stream = TcpStream::connect((unbracketed(proxy_host), *proxy_port))
+                    .await
+                    .map_err(|e| {
+                        AppError::WebsocketError(format!("failed to connect to proxy: {}", e))
+                    })?;
+                http_connect(&mut stream, host, port, username, password).await?;
+                Ok(stream)
+            }
+            ProxyConfig::Socks5 {
+                host: proxy_host,
+                port: proxy_port,
+                username,
+                password,
+            } => {
+                let proxy = (unbracketed(proxy_host), *proxy_port);
+                let target = (host, port);
+                let stream = match (username, password) {
+                    (Some(username), Some(password)) => {
+                        Socks5Stream::connect_with_password(proxy, target, username, password).await
+                    }
+                    _ => Socks5Stream::connect(proxy, target).await,
+                }
+                .map_err(|e| {
+                    AppError::WebsocketError(format!("failed to connect via socks5 proxy: {}", e))
+                })?;
+                Ok(stream.into_inner())
+            }
+        }
+    }
+}
+
+/// Issues an HTTP CONNECT request on the stream and waits for the proxy to accept it.
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    username: &Option<String>,
+    password: &Option<String>,
+) -> AppResult<()> {
+    let mut request = format!(
+        "CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n",
+        authority = authority(host, port)
+    );
+    if let Some(username) = username {
+        let credentials = format!("{}:{}", username, password.as_deref().unwrap_or_default());
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| AppError::WebsocketError(format!("failed to write to proxy: {}", e)))?;
+
+    // Read byte by byte until the end of the headers, so we do not consume any
+    // bytes belonging to the tunneled connection.
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_PROXY_RESPONSE_BYTES {
+            return Err(AppError::WebsocketError(
+                "proxy response headers too large".to_string(),
+            ));
+        }
+        let read = stream
+            .read(&mut byte)
+            .await
+            .map_err(|e| AppError::WebsocketError(format!("failed to read from proxy: {}", e)))?;
+        if read == 0 {
+            return Err(AppError::WebsocketError(
+                "proxy closed connection during CONNECT".to_string(),
+            ));
+        }
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(AppError::WebsocketError(format!(
+            "proxy rejected CONNECT: {}",
+            status_line
+        ))),
+    }
+}
+
+/// Host without the brackets of an IPv6 literal, as expected when resolving a socket address.
+fn unbracketed(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// `host:port` as sent in a CONNECT request, with IPv6 literals in brackets.
+fn authority(host: &str, port: u16) -> String {
+    let host = unbracketed(host);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Host and port the websocket request should be tunneled to, the host without brackets.
+fn target_address(request: &Request) -> AppResult<(String, u16)> {
+    let uri = request.uri();
+    let host = uri
+        .host()
+        .ok_or_else(|| AppError::WebsocketError(format!("no host in websocket url: {}", uri)))?;
+    let port = match uri.port_u16() {
+        Some(port) => port,
+        None => match uri.scheme_str() {
+            Some("wss") => 443,
+            _ => 80,
+        },
+    };
+    Ok((unbracketed(host).to_string(), port))
+}
+
+fn read_file(path: &str) -> AppResult<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|e| AppError::ConfigError(format!("failed to read file {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_connection_config_deserialize() {
+        let json = serde_json::json!({
+            "proxy": {
+                "type": "http",
+                "host": "proxy.internal",
+                "port": 3128,
+                "username": "user",
+                "password": "pass"
+            },
+            "tls": {
+                "root_ca_paths": ["/etc/ssl/custom-ca.pem"]
+            },
+            "headers": {
+                "X-Api-Key": "secret"
+            }
+        });
+        let config: WsConnectionConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            config.proxy,
+            Some(ProxyConfig::Http {
+                host: "proxy.internal".to_string(),
+                port: 3128,
+                username: Some("user".to_string()),
+                password: Some("pass".to_string()),
+            })
+        );
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.root_ca_paths, vec!["/etc/ssl/custom-ca.pem"]);
+        assert!(tls.client_cert_path.is_none());
+        assert_eq!(config.headers.get("X-Api-Key").unwrap(), "secret");
+
+        let config: WsConnectionConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(config, WsConnectionConfig::default());
+    }
+
+    #[test]
+    fn test_request_headers_and_target() {
+        let config = WsConnectionConfig {
+            headers: HashMap::from([("X-Api-Key".to_string(), "secret".to_string())]),
+            ..Default::default()
+        };
+        let request = config.request("wss://ws.exchange.com/socket").unwrap();
+        assert_eq!(request.headers().get("x-api-key").unwrap(), "secret");
+        assert_eq!(
+            target_address(&request).unwrap(),
+            ("ws.exchange.com".to_string(), 443)
+        );
+
+        let request = config.request("ws://localhost:9443/ws").unwrap();
+        assert_eq!(
+            target_address(&request).unwrap(),
+            ("localhost".to_string(), 9443)
+        );
+
+        let request = config.request("wss://[2001:db8::1]/ws").unwrap();
+        assert_eq!(
+            target_address(&request).unwrap(),
+            ("2001:db8::1".to_string(), 443)
+        );
+    }
+
+    #[test]
+    fn test_ipv6_authority() {
+        assert_eq!(authority("2001:db8::1", 443), "[2001:db8::1]:443");
+        assert_eq!(authority("[2001:db8::1]", 443), "[2001:db8::1]:443");
+        assert_eq!(authority("127.0.0.1", 8080), "127.0.0.1:8080");
+        assert_eq!(authority("ws.exchange.com", 443), "ws.exchange.com:443");
+        assert_eq!(unbracketed("[::1]"), "::1");
+        assert_eq!(unbracketed("proxy.internal"), "proxy.internal");
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let config = WsConnectionConfig {
+            tls: Some(TlsConfig {
+                client_cert_path: Some("/tmp/cert.pem".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(config.connector().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = vec![0u8; 1024];
+            let read = socket.read(&mut buffer).await.unwrap();
+            let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+            socket
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            request
+        });
+
+        let proxy_config = ProxyConfig::Http {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+        };
+        proxy_config.connect("ws.exchange.com", 443).await.unwrap();
+
+        let request = proxy.await.unwrap();
+        assert!(request.starts_with("CONNECT ws.exchange.com:443 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = vec![0u8; 1024];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let proxy_config = ProxyConfig::Http {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            username: None,
+            password: None,
+        };
+        assert!(proxy_config.connect("ws.exchange.com", 443).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_to_ipv6_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = vec![0u8; 1024];
+            let read = socket.read(&mut buffer).await.unwrap();
+            let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+            socket
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            request
+        });
+
+        let proxy_config = ProxyConfig::Http {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            username: None,
+            password: None,
+        };
+        let request = WsConnectionConfig::default()
+            .request("wss://[2001:db8::1]:9443/ws")
+            .unwrap();
+        let (host, port) = target_address(&request).unwrap();
+        proxy_config.connect(&host, port).await.unwrap();
+
+        let request = proxy.await.unwrap();
+        assert!(request
+            .starts_with("CONNECT [2001:db8::1]:9443 HTTP/1.1\r\nHost: [2001:db8::1]:9443\r\n"));
+    }
+}
@@ -1,11 +1,15 @@
-use common::{AppError, AppResult, Backoff, Context, MpSc, SpawnResult, Worker};
+use common::{AppError, AppResult, Backoff, Context, MpSc, SharedRef, SpawnResult, Worker};
 use futures_util::{SinkExt, StreamExt};
 use jiff::Timestamp;
 use std::time::Duration;
 use tokio::{io, sync::mpsc::Receiver};
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 
-use crate::{WsCallback, WS_CONSUMER_MESSAGES, WS_MESSAGES_NOT_RECEIVED_CONSECUTIVELY};
+use crate::{
+    OutboundConfig, RateLimiter, RecorderConfig, ReplayConfig, WsCallback, WsConnectionConfig,
+    WsRecorder, WS_CONSUMER_MESSAGES, WS_MESSAGES_NOT_RECEIVED_CONSECUTIVELY,
+    WS_OUTBOUND_QUEUE_DEPTH, WS_RECORDED_FRAMES,
+};
 
 #[derive(Clone)]
 pub struct WsConsumer<C>
@@ -15,9 +19,17 @@ where
     pub ws_url: String,
     pub callback: C,
     pub heartbeat_millis: u64,
+    pub connection_config: WsConnectionConfig,
+    pub outbound_config: OutboundConfig,
     pub backoff: Backoff,
     pub context: Context,
     pub mpsc: MpSc<Message>,
+    /// Whether the websocket is currently connected, shared with the [`crate::WsClient`]
+    pub connected: SharedRef<bool>,
+    /// Records every inbound frame to disk when set
+    pub recorder_config: Option<RecorderConfig>,
+    /// Replays recorded frames instead of connecting to the websocket when set
+    pub replay_config: Option<ReplayConfig>,
 }
 
 impl<C> WsConsumer<C>
@@ -27,6 +39,15 @@ where
     pub async fn run(&mut self) -> AppResult<String> {
         let mut receiver = self.mpsc.receiver().unwrap();
 
+        if let Some(replay_config) = self.replay_config.clone() {
+            return self.replay(&mut receiver, &replay_config).await;
+        }
+
+        let mut recorder = match self.recorder_config.clone() {
+            Some(config) => Some(WsRecorder::new(&self.context.name, config)?),
+            None => None,
+        };
+
         loop {
             match self.backoff.next() {
                 Some(delay_secs) => {
@@ -43,8 +64,8 @@ where
             }
 
             log::info!("connecting to websocket: {}", self.ws_url);
-            let ws_stream = match tokio_tungstenite::connect_async(&self.ws_url).await {
-                Ok((ws_stream, _)) => {
+            let ws_stream = match self.connection_config.connect(&self.ws_url).await {
+                Ok(ws_stream) => {
                     log::info!("connected to websocket: {}", &self.ws_url);
                     self.backoff.reset();
                     ws_stream
@@ -55,7 +76,7 @@ where
                 }
             };
 
-            let stream_result = self.stream(&mut receiver, ws_stream).await;
+            let stream_result = self.stream(&mut receiver, ws_stream, &mut recorder).await;
             self.on_disconnect()?;
 
             match stream_result {
@@ -80,6 +101,7 @@ where
         &mut self,
         receiver: &mut Receiver<Message>,
         mut ws_stream: WebSocketStream<S>,
+        recorder: &mut Option<WsRecorder>,
     ) -> AppResult<()>
     where
         S: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static,
@@ -89,6 +111,7 @@ where
         let mut num_messages_since_last_heartbeat = 0;
         let mut num_consecutive_heartbeats_no_messages_received = 0;
         let mut heartbeat = tokio::time::interval(Duration::from_millis(self.heartbeat_millis));
+        let mut rate_limiter = RateLimiter::new(self.outbound_config.rate_limit_per_sec);
 
         loop {
             tokio::select! {
@@ -105,6 +128,12 @@ where
                             num_messages_since_last_heartbeat += 1;
                             match result {
                                 Ok(message) => {
+                                    if let Some(recorder) = recorder.as_mut() {
+                                        match recorder.record(&message, received_time) {
+                                            Ok(_) => WS_RECORDED_FRAMES.with_label_values(&[&self.context.name]).inc(),
+                                            Err(e) => log::error!("{} failed to record frame: {}", self.context.name, e),
+                                        }
+                                    }
                                     self.callback.on_message(message, received_time).await?;
                                 }
                                 Err(e) => {
@@ -117,13 +146,18 @@ where
                         }
                     }
                 }
-                result = receiver.recv() => {
+                _ = tokio::time::sleep_until(rate_limiter.next_send()), if !rate_limiter.is_ready() => {}
+                result = receiver.recv(), if rate_limiter.is_ready() => {
                     match result {
                         Some(message) => {
+                            rate_limiter.consume();
                             if let Err(e) = ws_stream.send(message).await {
                                 return Err(AppError::GenericError(format!("error while sending message to websocket: {}", e)));
                             }
                             log::debug!("sent message to websocket: {}", self.ws_url);
+                            WS_OUTBOUND_QUEUE_DEPTH
+                                .with_label_values(&[&self.context.name])
+                                .set(receiver.len() as f64);
                         }
                         None => {
                             return Err(AppError::GenericError("receiver closed".to_string()));
@@ -132,6 +166,14 @@ where
                 }
                 _ = heartbeat.tick() => {
                     let _ = self.callback.on_heartbeat();
+                    WS_OUTBOUND_QUEUE_DEPTH
+                        .with_label_values(&[&self.context.name])
+                        .set(receiver.len() as f64);
+                    if let Some(recorder) = recorder.as_mut() {
+                        if let Err(e) = recorder.flush() {
+                            log::error!("{} failed to flush recording: {}", self.context.name, e);
+                        }
+                    }
                     if num_messages_since_last_heartbeat > 0 {
                         log::info!("{} received {} messages since last heartbeat", self.context.name, num_messages_since_last_heartbeat);
                         WS_CONSUMER_MESSAGES
@@ -153,12 +195,82 @@ where
         }
     }
 
+    /// Feeds recorded frames into the callback, honouring the original spacing
+    /// between frames divided by the replay speed.
+    ///
+    /// Messages written by the callback (e.g. subscriptions) are discarded.
+    async fn replay(
+        &mut self,
+        receiver: &mut Receiver<Message>,
+        replay_config: &ReplayConfig,
+    ) -> AppResult<String> {
+        let frames = replay_config.frames(&self.context.name)?;
+        log::info!(
+            "{} replaying {} frames from {} at speed {}",
+            self.context.name,
+            frames.len(),
+            replay_config.path.display(),
+            replay_config.speed
+        );
+
+        let mut app = self.context.app.subscribe();
+        self.on_connect().await?;
+
+        let mut previous_time: Option<Timestamp> = None;
+        for frame in frames {
+            let delay = match previous_time {
+                Some(previous_time) if replay_config.speed > 0.0 => {
+                    let elapsed = frame.received_time.duration_since(previous_time);
+                    Duration::try_from(elapsed)
+                        .unwrap_or_default()
+                        .div_f64(replay_config.speed)
+                }
+                _ => Duration::ZERO,
+            };
+            previous_time = Some(frame.received_time);
+
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = app.recv() => {
+                        self.on_disconnect()?;
+                        return Ok(format!("replay {} received exit message", self.context.name));
+                    }
+                    message = receiver.recv() => {
+                        if let Some(message) = message {
+                            log::debug!("{} discarding message during replay: {:?}", self.context.name, message);
+                        }
+                    }
+                    _ = &mut sleep => {
+                        break;
+                    }
+                }
+            }
+
+            self.callback
+                .on_message(frame.message()?, frame.received_time)
+                .await?;
+        }
+
+        self.on_disconnect()?;
+        log::info!("{} replay finished", self.context.name);
+        Ok(format!("replay {} finished", self.context.name))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.lock()
+    }
+
     async fn on_connect(&mut self) -> AppResult<()> {
         let timestamp = Timestamp::now();
-        self.callback.on_connect(timestamp).await
+        self.callback.on_connect(timestamp).await?;
+        *self.connected.lock() = true;
+        Ok(())
     }
 
     fn on_disconnect(&mut self) -> AppResult<()> {
+        *self.connected.lock() = false;
         self.callback.on_disconnect()
     }
 }
@@ -173,3 +285,82 @@ where
         tokio::spawn(async move { consumer.run().await })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use common::SharedRef;
+    use config::Config;
+
+    use super::*;
+    use crate::{RecordedFrame, WsClient};
+
+    #[derive(Clone, Default)]
+    struct TestCallback {
+        connected: SharedRef<bool>,
+        messages: SharedRef<Vec<(Message, Timestamp)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl WsCallback for TestCallback {
+        async fn on_connect(&mut self, _timestamp: Timestamp) -> AppResult<()> {
+            *self.connected.lock() = true;
+            Ok(())
+        }
+
+        async fn on_message(
+            &mut self,
+            message: Message,
+            received_time: Timestamp,
+        ) -> AppResult<()> {
+            self.messages.lock().push((message, received_time));
+            Ok(())
+        }
+
+        fn on_disconnect(&mut self) -> AppResult<()> {
+            Ok(())
+        }
+
+        fn on_heartbeat(&mut self) -> AppResult<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_feeds_callback() {
+        let path =
+            std::env::temp_dir().join(format!("wsclient-replay-{}.jsonl", std::process::id()));
+        let frames = (0..3)
+            .map(|i| {
+                let received_time = Timestamp::from_millisecond(1000 + i * 50).unwrap();
+                let frame =
+                    RecordedFrame::new(&Message::text(format!("message-{}", i)), received_time)
+                        .unwrap();
+                serde_json::to_string(&frame).unwrap()
+            })
+            .collect::<Vec<_>>();
+        std::fs::write(&path, frames.join("\n")).unwrap();
+
+        let context = Context::from_config(Config::builder().build().unwrap());
+        let callback = TestCallback::default();
+        let mut client = WsClient::new("ws://localhost:1".to_string(), 1000);
+        let mut consumer = client.consumer(context.with_name("test-consumer"), callback.clone());
+        consumer.replay_config = Some(ReplayConfig {
+            path: path.clone(),
+            speed: 10.0,
+        });
+
+        let result = consumer.run().await;
+        assert!(result.is_ok());
+        assert!(*callback.connected.lock());
+        assert!(!client.is_connected());
+
+        let messages = callback.messages.lock();
+        assert_eq!(messages.len(), 3);
+        for (i, (message, received_time)) in messages.iter().enumerate() {
+            assert_eq!(*message, Message::text(format!("message-{}", i)));
+            assert_eq!(received_time.as_millisecond(), 1000 + i as i64 * 50);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,15 @@
 mod callback;
 mod client;
+mod connection;
 mod consumer;
 mod metrics;
+mod outbound;
+mod recorder;
 
 pub use callback::*;
 pub use client::*;
+pub use connection::*;
 pub use consumer::*;
 pub use metrics::*;
+pub use outbound::*;
+pub use recorder::*;
@@ -8,10 +8,28 @@ lazy_static! {
         &["consumer"]
     )
     .unwrap();
-
-    pub static ref WS_MESSAGES_NOT_RECEIVED_CONSECUTIVELY: prom::GaugeVec   = prom::register_gauge_vec!(
-        "ws_messages_not_received_consecutively",
-        "WS messages not received consecutively",
+    pub static ref WS_MESSAGES_NOT_RECEIVED_CONSECUTIVELY: prom::GaugeVec =
+        prom::register_gauge_vec!(
+            "ws_messages_not_received_consecutively",
+            "WS messages not received consecutively",
+            &["consumer"]
+        )
+        .unwrap();
+    pub static ref WS_RECORDED_FRAMES: prom::CounterVec = prom::register_counter_vec!(
+        "ws_recorded_frames",
+        "WS frames recorded to disk",
+        &["consumer"]
+    )
+    .unwrap();
+    pub static ref WS_OUTBOUND_QUEUE_DEPTH: prom::GaugeVec = prom::register_gauge_vec!(
+        "ws_outbound_queue_depth",
+        "WS messages waiting in the outbound queue",
+        &["consumer"]
+    )
+    .unwrap();
+    pub static ref WS_OUTBOUND_MESSAGES_DROPPED: prom::CounterVec = prom::register_counter_vec!(
+        "ws_outbound_messages_dropped",
+        "WS outbound messages dropped",
         &["consumer"]
     )
     .unwrap();
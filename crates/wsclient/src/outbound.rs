@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Configuration of the queue holding messages to be written to the websocket.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OutboundConfig {
+    /// Number of messages that can be queued before writers have to wait
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    /// Maximum number of messages written to the websocket per second, unlimited if absent
+    #[serde(default)]
+    pub rate_limit_per_sec: Option<u32>,
+}
+
+fn default_capacity() -> usize {
+    100
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+            rate_limit_per_sec: None,
+        }
+    }
+}
+
+/// Spaces out outbound messages evenly to stay within an exchange's request limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    next_send: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit_per_sec: Option<u32>) -> Self {
+        let interval = rate_limit_per_sec
+            .filter(|rate| *rate > 0)
+            .map(|rate| Duration::from_secs(1) / rate);
+        Self {
+            interval,
+            next_send: Instant::now(),
+        }
+    }
+
+    /// Checks if a message can be sent right now
+    pub fn is_ready(&self) -> bool {
+        self.interval.is_none() || Instant::now() >= self.next_send
+    }
+
+    /// The instant at which the next message can be sent
+    pub fn next_send(&self) -> Instant {
+        self.next_send
+    }
+
+    /// Records that a message has been sent
+    pub fn consume(&mut self) {
+        if let Some(interval) = self.interval {
+            self.next_send = Instant::now().max(self.next_send) + interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbound_config_deserialize() {
+        let config: OutboundConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(config, OutboundConfig::default());
+
+        let config: OutboundConfig = serde_json::from_value(serde_json::json!({
+            "capacity": 1000,
+            "rate_limit_per_sec": 5
+        }))
+        .unwrap();
+        assert_eq!(config.capacity, 1000);
+        assert_eq!(config.rate_limit_per_sec, Some(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(Some(5));
+        assert!(limiter.is_ready());
+        limiter.consume();
+        assert!(!limiter.is_ready());
+        assert_eq!(
+            limiter.next_send() - Instant::now(),
+            Duration::from_millis(200)
+        );
+
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert!(limiter.is_ready());
+
+        let mut limiter = RateLimiter::new(None);
+        limiter.consume();
+        assert!(limiter.is_ready());
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{AppError, AppResult, Context};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+/// A raw inbound websocket frame together with the time it was received.
+///
+/// Frames are stored one per line as JSON, binary payloads are base64 encoded.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecordedFrame {
+    #[serde(with = "common::timestamp_with_tz_serializer")]
+    pub received_time: jiff::Timestamp,
+    #[serde(flatten)]
+    pub payload: RecordedPayload,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedPayload {
+    Text {
+        data: String,
+    },
+    Binary {
+        data: String,
+    },
+    Ping {
+        data: String,
+    },
+    Pong {
+        data: String,
+    },
+    Close {
+        code: Option<u16>,
+        reason: Option<String>,
+    },
+}
+
+impl RecordedFrame {
+    pub fn new(message: &Message, received_time: jiff::Timestamp) -> Option<Self> {
+        let payload = match message {
+            Message::Text(text) => RecordedPayload::Text {
+                data: text.to_string(),
+            },
+            Message::Binary(data) => RecordedPayload::Binary {
+                data: STANDARD.encode(data),
+            },
+            Message::Ping(data) => RecordedPayload::Ping {
+                data: STANDARD.encode(data),
+            },
+            Message::Pong(data) => RecordedPayload::Pong {
+                data: STANDARD.encode(data),
+            },
+            Message::Close(frame) => RecordedPayload::Close {
+                code: frame.as_ref().map(|frame| frame.code.into()),
+                reason: frame.as_ref().map(|frame| frame.reason.to_string()),
+            },
+            Message::Frame(_) => return None,
+        };
+        Some(Self {
+            received_time,
+            payload,
+        })
+    }
+
+    /// Converts the recorded frame back into the websocket message that was received
+    pub fn message(&self) -> AppResult<Message> {
+        let decode = |data: &str| {
+            STANDARD
+                .decode(data)
+                .map_err(|e| AppError::GenericError(format!("invalid recorded payload: {}", e)))
+        };
+        let message = match &self.payload {
+            RecordedPayload::Text { data } => Message::text(data.as_str()),
+            RecordedPayload::Binary { data } => Message::binary(decode(data)?),
+            RecordedPayload::Ping { data } => Message::Ping(decode(data)?.into()),
+            RecordedPayload::Pong { data } => Message::Pong(decode(data)?.into()),
+            RecordedPayload::Close { code, reason } => {
+                Message::Close(code.map(|code| CloseFrame {
+                    code: CloseCode::from(code),
+                    reason: reason.clone().unwrap_or_default().into(),
+                }))
+            }
+        };
+        Ok(message)
+    }
+}
+
+/// Configuration for recording inbound frames of a consumer.
+///
+/// Read from the static config:
+/// - `ws_record_dir`: directory the recordings are written to, recording is disabled if absent
+/// - `ws_record_max_file_bytes`: size after which a new file is started (default 100MB)
+/// - `ws_record_max_files`: number of files kept per consumer (default 10)
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    pub max_file_bytes: u64,
+    pub max_files: usize,
+}
+
+impl RecorderConfig {
+    pub fn from_context(context: &Context) -> Option<Self> {
+        let dir = context.config.get_string("ws_record_dir").ok()?;
+        let max_file_bytes = context
+            .config
+            .get_int("ws_record_max_file_bytes")
+            .unwrap_or(100 * 1024 * 1024) as u64;
+        let max_files = context.config.get_int("ws_record_max_files").unwrap_or(10) as usize;
+        Some(Self {
+            dir: PathBuf::from(dir),
+            max_file_bytes,
+            max_files,
+        })
+    }
+}
+
+/// Writes inbound frames of a single consumer to rotating files.
+///
+/// Files are named `{consumer}.{millis}-{counter}.jsonl` so that sorting them by name
+/// yields the order they were written in.
+pub struct WsRecorder {
+    name: String,
+    config: RecorderConfig,
+    writer: Option<BufWriter<File>>,
+    bytes_written: u64,
+    file_index: u64,
+}
+
+impl WsRecorder {
+    pub fn new(name: &str, config: RecorderConfig) -> AppResult<Self> {
+        fs::create_dir_all(&config.dir).map_err(|e| {
+            AppError::ConfigError(format!(
+                "failed to create recording dir {}: {}",
+                config.dir.display(),
+                e
+            ))
+        })?;
+        Ok(Self {
+            name: name.to_string(),
+            config,
+            writer: None,
+            bytes_written: 0,
+            file_index: 0,
+        })
+    }
+
+    pub fn record(&mut self, message: &Message, received_time: jiff::Timestamp) -> AppResult<()> {
+        let Some(frame) = RecordedFrame::new(message, received_time) else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(&frame)?;
+        line.push(b'\n');
+
+        if self.writer.is_none() || self.bytes_written >= self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            writer
+                .write_all(&line)
+                .map_err(|e| AppError::GenericError(format!("failed to write recording: {}", e)))?;
+            self.bytes_written += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> AppResult<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer
+                .flush()
+                .map_err(|e| AppError::GenericError(format!("failed to flush recording: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> AppResult<()> {
+        self.flush()?;
+        // Millisecond resolution is not enough to keep names unique when files are
+        // rotated quickly, so a counter is appended as a tie breaker.
+        self.file_index += 1;
+        let path = self.config.dir.join(format!(
+            "{}.{:013}-{:06}.jsonl",
+            self.name,
+            jiff::Timestamp::now().as_millisecond(),
+            self.file_index
+        ));
+        let file = File::create(&path).map_err(|e| {
+            AppError::GenericError(format!(
+                "failed to create recording {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        log::info!("{} recording frames to {}", self.name, path.display());
+        self.writer = Some(BufWriter::new(file));
+        self.bytes_written = 0;
+
+        let files = recording_files(&self.config.dir, &self.name)?;
+        if files.len() > self.config.max_files {
+            for path in files.iter().take(files.len() - self.config.max_files) {
+                if let Err(e) = fs::remove_file(path) {
+                    log::warn!("failed to remove recording {}: {}", path.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WsRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("{}", e);
+        }
+    }
+}
+
+/// Configuration for replaying recorded frames instead of connecting to the websocket.
+///
+/// Read from the static config:
+/// - `ws_replay_path`: a directory holding the recordings of the consumers, or a recording
+///   file when a single consumer is replayed
+/// - `ws_replay_speed`: speed multiplier relative to the original timing, `0` replays
+///   as fast as possible (default 1.0)
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub path: PathBuf,
+    pub speed: f64,
+}
+
+impl ReplayConfig {
+    pub fn from_context(context: &Context) -> Option<Self> {
+        let path = context.config.get_string("ws_replay_path").ok()?;
+        let speed = context.config.get_float("ws_replay_speed").unwrap_or(1.0);
+        Some(Self {
+            path: PathBuf::from(path),
+            speed,
+        })
+    }
+
+    /// Checks that the path can be replayed by the given number of consumers, a single
+    /// recording file would otherwise feed the frames of one exchange to all of them.
+    pub fn validate(&self, consumers: usize) -> AppResult<()> {
+        if consumers > 1 && !self.path.is_dir() {
+            return Err(AppError::ConfigError(format!(
+                "ws_replay_path {} must be a directory to replay {} consumers",
+                self.path.display(),
+                consumers
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads all frames to replay for the given consumer in the order they were recorded
+    pub fn frames(&self, name: &str) -> AppResult<Vec<RecordedFrame>> {
+        let files = if self.path.is_dir() {
+            let files = recording_files(&self.path, name)?;
+            if files.is_empty() {
+                return Err(AppError::ConfigError(format!(
+                    "no recordings of {} in {}",
+                    name,
+                    self.path.display()
+                )));
+            }
+            files
+        } else {
+            vec![self.path.clone()]
+        };
+
+        let mut frames = Vec::new();
+        for path in files {
+            let file = File::open(&path).map_err(|e| {
+                AppError::ConfigError(format!(
+                    "failed to open recording {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| {
+                    AppError::GenericError(format!(
+                        "failed to read recording {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                frames.push(serde_json::from_str::<RecordedFrame>(&line)?);
+            }
+        }
+        Ok(frames)
+    }
+}
+
+/// Recording files of a consumer sorted from oldest to newest
+fn recording_files(dir: &Path, name: &str) -> AppResult<Vec<PathBuf>> {
+    let prefix = format!("{}.", name);
+    let entries = fs::read_dir(dir).map_err(|e| {
+        AppError::ConfigError(format!(
+            "failed to read recording dir {}: {}",
+            dir.display(),
+            e
+        ))
+    })?;
+    let mut files = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".jsonl"))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("wsclient-recorder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_recorded_frame_round_trip() {
+        let received_time: jiff::Timestamp = "2025-02-12T21:12:33.778451Z".parse().unwrap();
+        let messages = vec![
+            Message::text("{\"price\":\"100\"}"),
+            Message::binary(vec![1, 2, 3]),
+            Message::Ping(vec![4].into()),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "bye".into(),
+            })),
+        ];
+
+        for message in messages {
+            let frame = RecordedFrame::new(&message, received_time).unwrap();
+            let json = serde_json::to_string(&frame).unwrap();
+            let decoded: RecordedFrame = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, frame);
+            assert_eq!(decoded.message().unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_recorder_rotation_and_replay() {
+        let dir = test_dir("rotation");
+        let config = RecorderConfig {
+            dir: dir.clone(),
+            max_file_bytes: 1,
+            max_files: 2,
+        };
+
+        let mut recorder = WsRecorder::new("test-consumer", config).unwrap();
+        for i in 0..5 {
+            let received_time = jiff::Timestamp::from_millisecond(1000 + i).unwrap();
+            recorder
+                .record(&Message::text(format!("message-{}", i)), received_time)
+                .unwrap();
+        }
+        drop(recorder);
+
+        // Every message goes to its own file and only the last two are kept
+        assert_eq!(recording_files(&dir, "test-consumer").unwrap().len(), 2);
+
+        let replay = ReplayConfig {
+            path: dir.clone(),
+            speed: 0.0,
+        };
+        let frames = replay.frames("test-consumer").unwrap();
+        let messages = frames
+            .iter()
+            .map(|frame| frame.message().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![Message::text("message-3"), Message::text("message-4")]
+        );
+        assert!(replay.frames("other-consumer").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_file_requires_a_single_consumer() {
+        let dir = test_dir("single-consumer");
+        fs::create_dir_all(&dir).unwrap();
+        let file = ReplayConfig {
+            path: dir.join("recording.jsonl"),
+            speed: 0.0,
+        };
+        assert!(file.validate(1).is_ok());
+        assert!(file.validate(2).is_err());
+
+        let dir_config = ReplayConfig {
+            path: dir.clone(),
+            speed: 0.0,
+        };
+        assert!(dir_config.validate(2).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,7 @@
 #!/usr/bin/env bash
 set -e
 
+# The tables are created by the migrations the indexer applies on startup
 psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" <<-EOSQL
     CREATE DATABASE $APP_DB_NAME;
 EOSQL
-
-# Connect to the newly created database to create tables
-psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$APP_DB_NAME" <<-EOSQL
-    DROP TABLE IF EXISTS tickers;
-    CREATE TABLE tickers (
-        id SERIAL PRIMARY KEY,
-        symbol VARCHAR(20) NOT NULL,
-        price DECIMAL NOT NULL,
-        source VARCHAR(50) NOT NULL,
-        timestamp BIGINT NOT NULL,
-        created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
-    );
-    CREATE INDEX IF NOT EXISTS idx_tickers_symbol_timestamp ON tickers(symbol, timestamp);
-EOSQL
\ No newline at end of file